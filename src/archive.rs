@@ -0,0 +1,213 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    commit,
+    error::{WitError, builder::*},
+    object,
+    object_id::ObjectId,
+    repository::Repository,
+    tree
+};
+
+const BLOCK: usize = 512;
+// Archives are padded to a whole record of 20 blocks, as tar and git do
+const RECORD: usize = 20 * BLOCK;
+// The largest size the 11 octal digits of a ustar header can hold
+const MAX_SIZE: u64 = 0o77777777777;
+
+// Counts what went out, for the final record padding
+struct Counted<'a, W: Write> {
+    out: &'a mut W,
+    written: usize,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Writes the tree tree_ish names as a ustar archive, like git archive. A
+// commit's committer date becomes every entry's mtime; a bare tree has no
+// date, so it gets the current time, as it does in git.
+pub fn write_tar(repo: &Repository, tree_ish: &str, out: &mut impl Write, prefix: Option<&str>) -> Result<(), Box<WitError>> {
+    let tree = object::find(repo, tree_ish, Some("tree"), true)?;
+    let mtime = match object::find(repo, tree_ish, Some("commit"), true) {
+        Ok(sha) => commit::read(repo, &sha.to_string())?.committer()?.time,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+    };
+
+    let mut out = Counted { out, written: 0 };
+    let prefix = match prefix {
+        Some(prefix) if !prefix.is_empty() => format!("{}/", prefix.trim_end_matches('/')),
+        _ => String::new()
+    };
+    if !prefix.is_empty() {
+        write_entry(&mut out, &prefix, b'5', 0o775, 0, mtime, "")?;
+    }
+    write_tree(repo, &mut out, &tree, &prefix, mtime)?;
+
+    out.write_all(&[0; 2 * BLOCK])?;
+    let padding = (RECORD - out.written % RECORD) % RECORD;
+    out.write_all(&vec![0; padding])?;
+    Ok(())
+}
+
+// Every entry under the tree, each directory right before what it holds
+fn write_tree<W: Write>(repo: &Repository, out: &mut Counted<W>, sha: &ObjectId, base: &str, mtime: i64) -> Result<(), Box<WitError>> {
+    for leaf in tree::read(repo, &sha.to_string())?.leaves() {
+        let name = leaf.path().to_str().ok_or(path_conversion_err())?;
+        let path = format!("{}{}", base, name);
+        match leaf.mode() {
+            "40000" | "040000" => {
+                write_entry(out, &format!("{}/", path), b'5', 0o775, 0, mtime, "")?;
+                write_tree(repo, out, leaf.sha(), &format!("{}/", path), mtime)?;
+            },
+            // Submodules are not part of the archive, only the directory they live in
+            "160000" => write_entry(out, &format!("{}/", path), b'5', 0o775, 0, mtime, "")?,
+            "120000" => {
+                let target = String::from_utf8(object::read_raw(repo, leaf.sha())?.1)?;
+                write_entry(out, &path, b'2', 0o777, 0, mtime, &target)?;
+            },
+            mode => {
+                // The permissions git archive gives files, its default tar.umask of 002 applied
+                let perm = if mode == "100755" { 0o775 } else { 0o664 };
+                let (_, size) = object::stat(repo, leaf.sha())?;
+                write_entry(out, &path, b'0', perm, size as u64, mtime, "")?;
+                object::read_to_writer(repo, leaf.sha(), out)?;
+                out.write_all(&vec![0; (BLOCK - size % BLOCK) % BLOCK])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// A header, preceded by GNU long name records for a path or link target too
+// long for its field. The data, if any, is up to the caller.
+fn write_entry(out: &mut impl Write, path: &str, kind: u8, perm: u32, size: u64, mtime: i64, link: &str) -> Result<(), Box<WitError>> {
+    if size > MAX_SIZE {
+        return Err(malformed_object_err(format!("{} is too large for a tar archive", path)));
+    }
+    if link.len() > 100 {
+        write_long_name(out, b'K', link, mtime)?;
+    }
+    if path.len() > 100 {
+        write_long_name(out, b'L', path, mtime)?;
+    }
+    out.write_all(&header(path.as_bytes(), kind, perm, size, mtime, link.as_bytes()))?;
+    Ok(())
+}
+
+fn write_long_name(out: &mut impl Write, kind: u8, name: &str, mtime: i64) -> Result<(), Box<WitError>> {
+    // The name is NUL terminated, and the NUL counts towards the size
+    let mut data = name.as_bytes().to_vec();
+    data.push(0);
+    out.write_all(&header(b"././@LongLink", kind, 0o644, data.len() as u64, mtime, b""))?;
+    data.resize(data.len().div_ceil(BLOCK) * BLOCK, 0);
+    out.write_all(&data)?;
+    Ok(())
+}
+
+// A ustar header block; names longer than their 100 byte fields are cut off,
+// so the long name records that come before them have to carry the rest
+fn header(path: &[u8], kind: u8, perm: u32, size: u64, mtime: i64, link: &[u8]) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let mut field = |offset: usize, len: usize, value: &[u8]| {
+        let len = value.len().min(len);
+        block[offset..offset + len].copy_from_slice(&value[..len]);
+    };
+    field(0, 100, path);
+    field(100, 8, format!("{:07o}", perm).as_bytes());
+    field(108, 8, b"0000000");
+    field(116, 8, b"0000000");
+    field(124, 12, format!("{:011o}", size).as_bytes());
+    field(136, 12, format!("{:011o}", mtime.max(0)).as_bytes());
+    field(148, 8, b"        ");
+    field(156, 1, &[kind]);
+    field(157, 100, link);
+    field(257, 8, b"ustar\x0000");
+    field(265, 32, b"root");
+    field(297, 32, b"root");
+    field(329, 8, b"0000000");
+    field(337, 8, b"0000000");
+
+    // Summed with the checksum field itself as spaces
+    let checksum = block.iter().map(|&b| b as u32).sum::<u32>();
+    block[148..156].copy_from_slice(format!("{:07o}\0", checksum).as_bytes());
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn tar(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("tar").args(args).current_dir(dir).output().expect("tar is needed to run this test");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn tar_matches_git_archive() {
+        let dir = test_util::TempDir::new();
+        let long = format!("deep/{}/file.txt", "d".repeat(120));
+        let git = |args: &[&str]| test_util::git(dir.path(), &[&["-c", "user.name=A U Thor", "-c", "user.email=author@example.com"], args].concat());
+        git(&["init", "-q", "."]);
+        fs::create_dir_all(dir.path().join(Path::new(&long).parent().unwrap())).unwrap();
+        fs::write(dir.path().join(&long), b"far down\n").unwrap();
+        fs::write(dir.path().join("README"), b"hello\n").unwrap();
+        fs::write(dir.path().join("big"), vec![b'x'; 5000]).unwrap();
+        fs::write(dir.path().join("run.sh"), b"#!/bin/sh\n").unwrap();
+        git(&["add", "."]);
+        git(&["update-index", "--chmod=+x", "run.sh"]);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("README", dir.path().join("link")).unwrap();
+            std::os::unix::fs::symlink("t".repeat(150), dir.path().join("far-link")).unwrap();
+            git(&["add", "link", "far-link"]);
+        }
+        git(&["commit", "-qm", "first"]);
+        let repo = Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap();
+
+        let mut out = Vec::new();
+        write_tar(&repo, "HEAD", &mut out, Some("pre/")).unwrap();
+        assert_eq!(out.len() % RECORD, 0);
+        fs::write(dir.path().join("wit.tar"), &out).unwrap();
+        git(&["archive", "--prefix=pre/", "-o", "git.tar", "HEAD"]);
+        assert_eq!(tar(dir.path(), &["-tvf", "wit.tar"]), tar(dir.path(), &["-tvf", "git.tar"]));
+
+        fs::create_dir(dir.path().join("out")).unwrap();
+        tar(&dir.path().join("out"), &["-xf", "../wit.tar"]);
+        assert_eq!(fs::read(dir.path().join("out/pre").join(&long)).unwrap(), b"far down\n");
+        assert_eq!(fs::read(dir.path().join("out/pre/big")).unwrap(), vec![b'x'; 5000]);
+    }
+
+    #[test]
+    fn headers_carry_their_checksum() {
+        let block = header(b"a", b'0', 0o664, 6, 1112911993, b"");
+        let mut summed = block;
+        summed[148..156].copy_from_slice(b"        ");
+        let checksum = summed.iter().map(|&b| b as u32).sum::<u32>();
+        assert_eq!(&block[148..156], format!("{:07o}\0", checksum).as_bytes());
+        assert_eq!(&block[124..135], b"00000000006");
+        assert_eq!(&block[257..263], b"ustar\0");
+
+        let mut out = Vec::new();
+        assert!(write_entry(&mut out, "huge", b'0', 0o664, MAX_SIZE + 1, 0, "").is_err());
+        write_entry(&mut out, &"n".repeat(101), b'0', 0o664, 0, 0, "").unwrap();
+        // A long name record, the name padded to a block, then the header
+        assert_eq!(out.len(), 3 * BLOCK);
+        assert_eq!(out[156], b'L');
+    }
+}
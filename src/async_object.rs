@@ -0,0 +1,62 @@
+// Async counterparts of object::read and object::write, for callers that
+// want many objects in flight at once
+use async_compression::tokio::{bufread::ZlibDecoder, write::ZlibEncoder};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    error::{WitError, builder::*},
+    object::{self, WitObject},
+    object_id::ObjectId,
+    repository::Repository
+};
+
+pub async fn read_raw(repo: &Repository, sha: &ObjectId) -> Result<(String, Vec<u8>), Box<WitError>> {
+    let path = object::loose_path(repo, sha)?;
+    let raw = match fs::read(&path).await {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(unknown_object_err(format!("Unknown object {}.", sha)))?
+        },
+        Err(e) => Err(e)?
+    };
+
+    let mut decoded = Vec::new();
+    ZlibDecoder::new(&raw[..]).read_to_end(&mut decoded).await?;
+    object::split_header(sha, decoded)
+}
+
+pub async fn read<'a>(repo: &'a Repository, sha: &ObjectId) -> Result<WitObject<'a>, Box<WitError>> {
+    let (fmt, data) = read_raw(repo, sha).await?;
+    object::build(&fmt, Some(repo), Some(data))
+}
+
+pub async fn write(obj: WitObject<'_>, actually_write: bool) -> Result<ObjectId, Box<WitError>> {
+    let data = obj.serialize()?;
+    let fmt = String::from_utf8(obj.fmt())?;
+    let format = obj.repo().map(|repo| repo.object_format()).unwrap_or_default();
+    let sha = object::hash_raw(format, &fmt, &data);
+    if !actually_write {
+        return Ok(sha);
+    }
+
+    let repo = obj.repo().ok_or(repo_not_found_err("No repo found for object".to_owned()))?;
+    let path = object::loose_path(repo, &sha)?;
+    if fs::try_exists(&path).await? {
+        return Ok(sha);
+    }
+    if let Some(dir) = path.parent() {
+        if !fs::try_exists(dir).await? {
+            fs::create_dir_all(dir).await?;
+            Repository::adjust_shared_perm(repo, dir)?;
+        }
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new());
+    encoder.write_all(&object::header(&fmt, data.len())).await?;
+    encoder.write_all(&data).await?;
+    encoder.shutdown().await?;
+    fs::write(&path, encoder.into_inner()).await?;
+    Repository::adjust_shared_perm(repo, &path)?;
+    Ok(sha)
+}
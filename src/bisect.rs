@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::fs;
+
+use crate::{
+    commit,
+    error::{WitError, builder::*},
+    object,
+    object_id::ObjectId,
+    reference,
+    repository::Repository
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BisectStep {
+    // This commit should be tested next
+    Test(String),
+    // This is the first bad commit
+    Found(String),
+    // Nothing is left between the good and bad commits
+    Finished,
+}
+
+fn state_file(repo: &Repository, name: &str) -> Result<std::path::PathBuf, Box<WitError>> {
+    Repository::file(repo, vec![name], false)
+}
+
+fn in_progress(repo: &Repository) -> Result<(), Box<WitError>> {
+    if !state_file(repo, "BISECT_START")?.exists() {
+        return Err(missing_data_err("Not bisecting; run bisect start first.".to_owned()));
+    }
+    Ok(())
+}
+
+pub fn start(repo: &Repository) -> Result<(), Box<WitError>> {
+    // Remember where HEAD was so reset can go back to it
+    let head = fs::read_to_string(state_file(repo, "HEAD")?)?;
+    let head = head.trim();
+    let head = head.strip_prefix("ref: refs/heads/").unwrap_or(head);
+
+    Repository::write_file(repo, &state_file(repo, "BISECT_START")?, format!("{}\n", head).as_bytes())?;
+    Repository::write_file(repo, &state_file(repo, "BISECT_GOOD")?, b"")?;
+    if state_file(repo, "BISECT_BAD")?.exists() {
+        fs::remove_file(state_file(repo, "BISECT_BAD")?)?;
+    }
+    Ok(())
+}
+
+pub fn good(repo: &Repository, rev: &str) -> Result<(), Box<WitError>> {
+    in_progress(repo)?;
+    let sha = object::find(repo, rev, Some("commit"), true)?;
+    let path = state_file(repo, "BISECT_GOOD")?;
+    let mut goods = fs::read_to_string(&path).unwrap_or_default();
+    if !goods.lines().any(|line| line == sha.to_string()) {
+        goods += &format!("{}\n", sha);
+    }
+    Repository::write_file(repo, &path, goods.as_bytes())
+}
+
+pub fn bad(repo: &Repository, rev: &str) -> Result<(), Box<WitError>> {
+    in_progress(repo)?;
+    let sha = object::find(repo, rev, Some("commit"), true)?;
+    Repository::write_file(repo, &state_file(repo, "BISECT_BAD")?, format!("{}\n", sha).as_bytes())
+}
+
+// The bad commit and every commit that could still be the first bad one,
+// children before parents
+fn candidates(repo: &Repository) -> Result<(String, Vec<String>), Box<WitError>> {
+    in_progress(repo)?;
+    let bad_path = state_file(repo, "BISECT_BAD")?;
+    if !bad_path.exists() {
+        return Err(missing_data_err("No bad commit; run bisect bad <rev>.".to_owned()));
+    }
+    let bad = fs::read_to_string(bad_path)?.trim().to_owned();
+    let goods = fs::read_to_string(state_file(repo, "BISECT_GOOD")?)?
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<String>>();
+    if goods.is_empty() {
+        return Err(missing_data_err("No good commit; run bisect good <rev>.".to_owned()));
+    }
+
+    let mut excluded = HashSet::new();
+    for good in &goods {
+        excluded.extend(commit::ancestors(repo, good)?);
+    }
+
+    let mut candidates = commit::topo_order(repo, &bad, &excluded)?;
+    candidates.reverse();
+    Ok((bad, candidates))
+}
+
+pub fn next(repo: &Repository) -> Result<BisectStep, Box<WitError>> {
+    let (bad, candidates) = candidates(repo)?;
+    match candidates.len() {
+        0 => Ok(BisectStep::Finished),
+        1 => Ok(BisectStep::Found(bad)),
+        len => Ok(BisectStep::Test(candidates[len / 2].clone()))
+    }
+}
+
+pub fn remaining(repo: &Repository) -> Result<usize, Box<WitError>> {
+    Ok(candidates(repo)?.1.len())
+}
+
+// Ends the bisection and points HEAD back where it was when it started
+pub fn reset(repo: &Repository) -> Result<(), Box<WitError>> {
+    in_progress(repo)?;
+    let start = fs::read_to_string(state_file(repo, "BISECT_START")?)?.trim().to_owned();
+    // A detached HEAD was saved as its sha, of either object format
+    if ObjectId::from_hex(&start).is_ok() {
+        reference::detach_head(repo, &start, "bisect reset")?;
+    } else {
+        reference::attach_head(repo, &format!("refs/heads/{}", start), "bisect reset")?;
+    }
+
+    for name in ["BISECT_START", "BISECT_GOOD", "BISECT_BAD"] {
+        let path = state_file(repo, name)?;
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
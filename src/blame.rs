@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{
+    blob,
+    commit,
+    diff::{self, Edit},
+    error::{WitError, builder::*},
+    object::{self, WitObject},
+    object_id::ObjectId,
+    repository::Repository,
+    signature::Signature,
+    tree::{self, ChangeKind, RenameOptions}
+};
+
+// Where a line of the file came from
+#[derive(Debug, Clone)]
+pub struct BlameLine {
+    // The commit that introduced the line
+    pub sha: String,
+    pub author: Signature,
+    // The line's number, from 1, in the file as that commit left it
+    pub line: usize,
+    // The path the file had in that commit, which renames can change
+    pub path: PathBuf,
+    pub text: String,
+    // Whether the walk ran out of history here; the line may be older
+    pub boundary: bool,
+}
+
+// (line in the final file, line in the current commit's version) for every
+// line nobody has claimed yet
+type Pending = Vec<(usize, usize)>;
+
+// Attributes each line of path, as of commit_ish, to the commit that last
+// changed it. Only first parents are followed. When a commit changes the
+// file, the lines its diff leaves alone are passed on to the parent and the
+// rest are its own; a rename is followed back to the old path, and whatever
+// is left when the file is added, or history ends, belongs to that commit.
+pub fn file(repo: &Repository, commit_ish: &str, path: &str) -> Result<Vec<BlameLine>, Box<WitError>> {
+    let start = object::find(repo, commit_ish, Some("commit"), true)?.to_string();
+    let mut path = PathBuf::from(path);
+    let mut sha = blob_at(repo, &start, &path)?.ok_or(
+        unknown_object_err(format!("No such path {} in {}", path.display(), commit_ish))
+    )?;
+    let data = blob::read(repo, &sha)?.data().clone();
+    let text = diff::lines(&data).iter().map(|line| {
+        let line = String::from_utf8_lossy(line);
+        line.strip_suffix('\n').unwrap_or(&line).to_owned()
+    }).collect::<Vec<String>>();
+
+    let mut blamed = vec![None; text.len()];
+    let mut pending = (0..text.len()).map(|i| (i, i)).collect::<Pending>();
+    let mut authors = HashMap::new();
+    let mut current = start;
+    while !pending.is_empty() {
+        let parent = commit::parents(repo, &current)?.into_iter().next();
+        let mut claim = |lines: &[(usize, usize)], boundary: bool| -> Result<(), Box<WitError>> {
+            if !authors.contains_key(&current) {
+                authors.insert(current.clone(), commit::read(repo, &current)?.author()?);
+            }
+            for &(final_line, line) in lines {
+                blamed[final_line] = Some((current.clone(), line + 1, path.clone(), boundary));
+            }
+            Ok(())
+        };
+        let Some(parent) = parent else {
+            claim(&pending, true)?;
+            break;
+        };
+
+        let (old_path, old_sha) = match blob_at(repo, &parent, &path)? {
+            Some(old_sha) => (path.clone(), old_sha),
+            None => match renamed_from(repo, &parent, &current, &path)? {
+                Some(found) => found,
+                None => {
+                    claim(&pending, false)?;
+                    break;
+                }
+            }
+        };
+        if old_sha != sha {
+            let old = blob::read(repo, &old_sha)?.data().clone();
+            let new = blob::read(repo, &sha)?.data().clone();
+            let mut kept = HashMap::new();
+            for edit in diff::myers(&diff::lines(&old), &diff::lines(&new)) {
+                if let Edit::Equal(old_line, new_line) = edit {
+                    kept.insert(new_line, old_line);
+                }
+            }
+            let (passed, changed): (Pending, Pending) = pending.into_iter().partition(|(_, line)| kept.contains_key(line));
+            claim(&changed, false)?;
+            pending = passed.into_iter().map(|(final_line, line)| (final_line, kept[&line])).collect();
+        }
+        current = parent;
+        path = old_path;
+        sha = old_sha;
+    }
+
+    let mut lines = Vec::new();
+    for (blame, text) in blamed.into_iter().zip(text) {
+        let (sha, line, path, boundary) = blame.ok_or(missing_data_err("A line was left unblamed".to_owned()))?;
+        lines.push(BlameLine { author: authors[&sha].clone(), sha, line, path, text, boundary });
+    }
+    Ok(lines)
+}
+
+// The sha of the blob at path in the commit, if there is one
+fn blob_at(repo: &Repository, sha: &str, path: &Path) -> Result<Option<ObjectId>, Box<WitError>> {
+    let commit = commit::read(repo, sha)?;
+    let mut tree = ObjectId::from_hex(commit.tree().ok_or(malformed_object_err(format!("Commit {} has no tree", sha)))?)?;
+    let components = path.components().collect::<Vec<Component>>();
+    for (i, component) in components.iter().enumerate() {
+        let WitObject::TreeObject(current) = object::read(repo, &tree)? else {
+            return Ok(None);
+        };
+        let Some(leaf) = current.leaves().iter().find(|leaf| leaf.path().as_os_str() == component.as_os_str()) else {
+            return Ok(None);
+        };
+        if i + 1 == components.len() {
+            return Ok(if leaf.object_type() == "blob" { Some(*leaf.sha()) } else { None });
+        }
+        if !leaf.is_tree() {
+            return Ok(None);
+        }
+        tree = *leaf.sha();
+    }
+    Ok(None)
+}
+
+// The path and blob the file at path had in parent, if child renamed it
+fn renamed_from(repo: &Repository, parent: &str, child: &str, path: &Path) -> Result<Option<(PathBuf, ObjectId)>, Box<WitError>> {
+    let entries = tree::diff(repo, Some(parent), child)?;
+    let (entries, _) = tree::detect_renames(repo, entries, &RenameOptions::default())?;
+    Ok(entries.into_iter().find_map(|entry| match entry.kind {
+        ChangeKind::Renamed { from, .. } if entry.path == path => entry.old_sha.map(|sha| (from, sha)),
+        _ => None
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::fs;
+
+    // (sha, line, path, boundary, text) for every line, from git blame's
+    // --line-porcelain output
+    fn git_blame(dir: &Path, path: &str) -> Vec<(String, usize, PathBuf, bool, String)> {
+        let output = test_util::git(dir, &["blame", "--line-porcelain", "--first-parent", "HEAD", "--", path]);
+        let mut lines = Vec::new();
+        let (mut sha, mut line, mut file, mut boundary) = (String::new(), 0, PathBuf::new(), false);
+        for row in output.lines() {
+            if let Some(text) = row.strip_prefix('\t') {
+                lines.push((sha.clone(), line, file.clone(), boundary, text.to_owned()));
+                boundary = false;
+            } else if let Some(name) = row.strip_prefix("filename ") {
+                file = PathBuf::from(name);
+            } else if row == "boundary" {
+                boundary = true;
+            } else if row.len() > 40 && row.as_bytes()[..40].iter().all(u8::is_ascii_hexdigit) {
+                sha = row[..40].to_owned();
+                line = row.split(' ').nth(1).unwrap().parse().unwrap();
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn lines_go_to_the_commit_that_last_changed_them() {
+        let dir = test_util::TempDir::new();
+        let git = |args: &[&str]| test_util::git(dir.path(), &[&["-c", "user.name=A U Thor", "-c", "user.email=author@example.com"], args].concat());
+        let write = |path: &str, data: &str| fs::write(dir.path().join(path), data).unwrap();
+        git(&["init", "-q", "."]);
+        write("a.txt", "one\ntwo\nthree\nfour\nfive\n");
+        git(&["add", "."]);
+        git(&["commit", "-qm", "first"]);
+        write("a.txt", "one\ntwo\nTHREE\nfour\nfive\nsix\n");
+        git(&["commit", "-qam", "second"]);
+        git(&["mv", "a.txt", "b.txt"]);
+        write("b.txt", "zero\none\ntwo\nTHREE\nfour\nfive\nsix\n");
+        git(&["commit", "-qam", "rename"]);
+        // A side branch merged in; only first parents are followed
+        git(&["checkout", "-q", "-b", "side"]);
+        write("b.txt", "zero\none\ntwo\nTHREE\nfour\nfive\nsix\nseven\n");
+        git(&["commit", "-qam", "side"]);
+        git(&["checkout", "-q", "-"]);
+        write("other", "x\n");
+        git(&["add", "other"]);
+        git(&["commit", "-qm", "unrelated"]);
+        git(&["merge", "-q", "--no-edit", "side"]);
+
+        let repo = Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap();
+        let lines = file(&repo, "HEAD", "b.txt").unwrap();
+        let ours = lines.iter()
+            .map(|line| (line.sha.clone(), line.line, line.path.clone(), line.boundary, line.text.clone()))
+            .collect::<Vec<(String, usize, PathBuf, bool, String)>>();
+        assert_eq!(ours, git_blame(dir.path(), "b.txt"));
+        assert_eq!(ours.iter().filter(|line| line.3).count(), 4);
+        assert_eq!(lines[0].author.name, "A U Thor");
+        assert_eq!(lines[1].path, Path::new("a.txt"));
+        assert!(file(&repo, "HEAD", "missing").is_err());
+    }
+}
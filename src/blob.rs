@@ -1,7 +1,10 @@
+use std::fmt;
+
 use crate::{
-    object::Object, 
-    repository::Repository, 
-    error::WitError
+    object::{self, Object, WitObject},
+    object_id::ObjectId,
+    repository::Repository,
+    error::{WitError, builder::malformed_object_err}
 };
 
 
@@ -13,7 +16,7 @@ pub struct Blob<'a> {
 impl<'a> Blob<'a> {
     pub fn new(repo: Option<&'a Repository>, data: Vec<u8>) -> Self {
         Self {
-            repo: repo,
+            repo,
             blobdata: data,
         }
     }
@@ -40,4 +43,28 @@ impl<'a> Object for Blob<'a> {
     fn repo(&self) -> Option<&'a Repository> {
         self.repo
     }
-}
\ No newline at end of file
+}
+
+impl<'a> fmt::Display for Blob<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Ok(text) = std::str::from_utf8(&self.blobdata) {
+            return write!(f, "{}", text);
+        }
+        // Binary data gets a hex dump, 16 bytes per line
+        for (i, chunk) in self.blobdata.chunks(16).enumerate() {
+            let bytes = chunk.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ");
+            let ascii = chunk.iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect::<String>();
+            writeln!(f, "{:08x}  {:<47}  {}", i * 16, bytes, ascii)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn read<'a>(repo: &'a Repository, sha: &ObjectId) -> Result<Blob<'a>, Box<WitError>> {
+    match object::read(repo, sha)? {
+        WitObject::BlobObject(blob) => Ok(blob),
+        _ => Err(malformed_object_err(format!("Object {} is not a blob", sha)))
+    }
+}
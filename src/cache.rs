@@ -0,0 +1,198 @@
+use std::fmt;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use linked_hash_map::LinkedHashMap;
+
+use crate::{object_id::ObjectId, pack::PackIndex};
+
+// Bytes of object bodies kept when core.objectCacheLimit doesn't say otherwise
+pub const DEFAULT_LIMIT: usize = 16 << 20;
+
+// Recently read objects, so walking history doesn't inflate the trees that
+// commits share over and over. The least recently used go first once the
+// bodies add up to more than the limit.
+#[derive(Debug, Clone)]
+pub struct ObjectCache {
+    objects: LinkedHashMap<ObjectId, (String, Rc<[u8]>)>,
+    size: usize,
+    limit: usize,
+}
+
+impl ObjectCache {
+    pub fn new(limit: usize) -> Self {
+        ObjectCache { objects: LinkedHashMap::new(), size: 0, limit }
+    }
+
+    // The body is shared with the cache rather than copied out of it
+    pub fn get(&mut self, sha: &ObjectId) -> Option<(String, Rc<[u8]>)> {
+        self.objects.get_refresh(sha).map(|(fmt, data)| (fmt.clone(), data.clone()))
+    }
+
+    pub fn insert(&mut self, sha: ObjectId, fmt: &str, data: Rc<[u8]>) {
+        // One giant blob would push out everything else
+        if data.len() > self.limit || self.objects.contains_key(&sha) {
+            return;
+        }
+        self.size += data.len();
+        self.objects.insert(sha, (fmt.to_owned(), data));
+        while self.size > self.limit {
+            match self.objects.pop_front() {
+                Some((_, (_, data))) => self.size -= data.len(),
+                None => break
+            }
+        }
+    }
+}
+
+// The pack indexes last read, with the pack directory's mtime at the time.
+// Adding or removing a pack changes the mtime, so a stale list is noticed
+// even when another process did it.
+#[derive(Clone, Default)]
+pub struct PackCache {
+    loaded: Option<(Option<SystemTime>, Rc<Vec<PackIndex>>)>,
+}
+
+impl PackCache {
+    pub fn get(&self, mtime: Option<SystemTime>) -> Option<Rc<Vec<PackIndex>>> {
+        match &self.loaded {
+            Some((loaded, indexes)) if *loaded == mtime => Some(indexes.clone()),
+            _ => None
+        }
+    }
+
+    pub fn set(&mut self, mtime: Option<SystemTime>, indexes: Rc<Vec<PackIndex>>) {
+        self.loaded = Some((mtime, indexes));
+    }
+
+    pub fn clear(&mut self) {
+        self.loaded = None;
+    }
+}
+
+// The indexes themselves are far too big to print
+impl fmt::Debug for PackCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.loaded {
+            Some((_, indexes)) => write!(f, "PackCache({} packs)", indexes.len()),
+            None => write!(f, "PackCache(not loaded)")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{object::{self, WitObject}, object_id::ObjectFormat, repository::Repository, test_util};
+
+    fn id(n: u8) -> ObjectId {
+        object::hash_raw(ObjectFormat::Sha1, "blob", &[n])
+    }
+
+    #[test]
+    fn least_recently_used_go_first() {
+        let mut cache = ObjectCache::new(30);
+        for n in 0..3 {
+            cache.insert(id(n), "blob", Rc::new([n; 10]));
+        }
+        assert_eq!(cache.size, 30);
+        // Reading 0 makes 1 the oldest, and hands out the cached body itself
+        assert_eq!(cache.get(&id(0)), Some(("blob".to_owned(), Rc::from(vec![0; 10]))));
+        assert!(Rc::ptr_eq(&cache.get(&id(0)).unwrap().1, &cache.get(&id(0)).unwrap().1));
+        cache.insert(id(3), "blob", Rc::new([3; 10]));
+        assert_eq!(cache.get(&id(1)), None);
+        assert!(cache.get(&id(0)).is_some() && cache.get(&id(2)).is_some());
+
+        // Room for a big one is made by dropping as many as it takes
+        cache.insert(id(4), "tree", Rc::new([4; 25]));
+        assert_eq!(cache.size, 25);
+        assert_eq!(cache.objects.len(), 1);
+    }
+
+    #[test]
+    fn oversized_and_repeated_objects_are_not_counted() {
+        let mut cache = ObjectCache::new(10);
+        cache.insert(id(0), "blob", Rc::new([0; 5]));
+        cache.insert(id(1), "blob", Rc::new([1; 11]));
+        assert_eq!(cache.get(&id(1)), None);
+        cache.insert(id(0), "blob", Rc::new([0; 5]));
+        assert_eq!(cache.size, 5);
+        assert!(cache.get(&id(0)).is_some());
+
+        let mut disabled = ObjectCache::new(0);
+        disabled.insert(id(0), "blob", Rc::new([0; 1]));
+        assert_eq!(disabled.get(&id(0)), None);
+    }
+
+    #[test]
+    fn config_sets_the_limit() {
+        let (dir, repo) = test_util::repo();
+        let blob = object::write_raw(&repo, "blob", b"cached\n").unwrap();
+        object::read_raw(&repo, &blob).unwrap();
+        assert!(repo.cached_object(&blob).is_some());
+
+        test_util::git(dir.path(), &["config", "core.objectCacheLimit", "0"]);
+        let repo = Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap();
+        object::read_raw(&repo, &blob).unwrap();
+        assert!(repo.cached_object(&blob).is_none());
+    }
+
+    // A timing rather than a check, so it only runs when asked for with
+    // cargo test --release -- --ignored --nocapture walking_history
+    #[test]
+    #[ignore]
+    fn walking_history_with_and_without_the_cache() {
+        let (dir, repo) = test_util::repo();
+        let shared = (0..50).map(|n| (format!("lib/{}.rs", n), format!("// file {}\n", n).into_bytes())).collect::<Vec<(String, Vec<u8>)>>();
+        let mut head = Vec::new();
+        for n in 0..1000 {
+            let counter = format!("{}\n", n).into_bytes();
+            let mut files = shared.iter().map(|(path, data)| (path.as_str(), &data[..])).collect::<Vec<(&str, &[u8])>>();
+            files.push(("counter", &counter));
+            let tree = test_util::tree(&repo, &files);
+            head = vec![test_util::commit(&repo, &tree, &head.iter().map(|sha: &String| sha.as_str()).collect::<Vec<&str>>(), "step")];
+        }
+
+        // What log -p does: every commit's tree against its parent's, with
+        // whole ids so that only reading the objects is measured
+        fn files(repo: &Repository, sha: &ObjectId) -> usize {
+            match object::read(repo, sha).unwrap() {
+                WitObject::TreeObject(tree) => tree.leaves().iter().map(|leaf| if leaf.is_tree() { files(repo, leaf.sha()) } else { 1 }).sum(),
+                _ => panic!("{} is not a tree", sha)
+            }
+        }
+        let walk = |repo: &Repository| {
+            let start = std::time::Instant::now();
+            let (mut count, mut next) = (0, Some(ObjectId::from_hex(&head[0]).unwrap()));
+            while let Some(sha) = next.take() {
+                let WitObject::CommitObject(commit) = object::read(repo, &sha).unwrap() else { panic!() };
+                count += files(repo, &ObjectId::from_hex(commit.tree().unwrap()).unwrap());
+                if let Some(parent) = commit.parents().first() {
+                    let parent = ObjectId::from_hex(parent).unwrap();
+                    let WitObject::CommitObject(parent_commit) = object::read(repo, &parent).unwrap() else { panic!() };
+                    count += files(repo, &ObjectId::from_hex(parent_commit.tree().unwrap()).unwrap());
+                    next = Some(parent);
+                }
+            }
+            (count, start.elapsed())
+        };
+        let cached = walk(&Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap());
+        test_util::git(dir.path(), &["config", "core.objectCacheLimit", "0"]);
+        let uncached = walk(&Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap());
+        assert_eq!(cached.0, uncached.0);
+        println!("1000 commits: {:?} with the cache, {:?} without", cached.1, uncached.1);
+    }
+
+    #[test]
+    fn pack_lists_go_stale_with_the_mtime() {
+        let mut cache = PackCache::default();
+        let now = Some(SystemTime::now());
+        assert!(cache.get(now).is_none());
+        cache.set(now, Rc::new(Vec::new()));
+        assert!(cache.get(now).is_some());
+        assert!(cache.get(None).is_none());
+        assert_eq!(format!("{:?}", cache), "PackCache(0 packs)");
+        cache.clear();
+        assert!(cache.get(now).is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{
+    blob,
+    commit,
+    error::{WitError, builder::*},
+    index::{Index, IndexEntry},
+    object,
+    reference,
+    repository::Repository,
+    tree::{self, Leaf},
+    util::set_executable
+};
+
+// Path -> leaf for every file in a commit
+pub type Files = BTreeMap<PathBuf, Leaf>;
+
+pub fn commit_files(repo: &Repository, sha: &str) -> Result<Files, Box<WitError>> {
+    let commit = commit::read(repo, sha)?;
+    let tree = commit.tree().ok_or(malformed_object_err(format!("Commit {} has no tree", sha)))?;
+    tree::flatten(repo, tree)
+}
+
+// Moves HEAD, the index and the worktree to target, a branch name or any
+// commit. Branches are checked out symbolically; anything else detaches HEAD.
+pub fn switch(repo: &Repository, target: &str) -> Result<(), Box<WitError>> {
+    let branch = format!("refs/heads/{}", target);
+    let is_branch = reference::exists(repo, &branch);
+    let sha = object::find(repo, target, Some("commit"), true)?.to_string();
+
+    // An unborn HEAD has no files to replace
+    let head = reference::resolve(repo, "HEAD").ok();
+    let old = match &head {
+        Some(head) => commit_files(repo, head)?,
+        None => Files::new()
+    };
+    let new = commit_files(repo, &sha)?;
+
+    let dirty = dirty_paths(repo, &Index::read(repo)?, &old, &new)?;
+    if !dirty.is_empty() {
+        return Err(uncommitted_changes_err(dirty));
+    }
+    update_worktree(repo, &old, &new)?;
+
+    let from = match reference::head_target(repo) {
+        Some(target) => target.strip_prefix("refs/heads/").unwrap_or(&target).to_owned(),
+        None => head.unwrap_or_default()
+    };
+    let message = format!("checkout: moving from {} to {}", from, target);
+    if is_branch {
+        reference::attach_head(repo, &branch, &message)
+    } else {
+        reference::detach_head(repo, &sha, &message)
+    }
+}
+
+// Paths that differ between old and new whose index entry or worktree file
+// doesn't match old, so replacing them would lose work
+pub fn dirty_paths(repo: &Repository, index: &Index, old: &Files, new: &Files) -> Result<Vec<PathBuf>, Box<WitError>> {
+    let root = repo.worktree()?;
+    let mut paths = old.keys().chain(new.keys()).collect::<Vec<&PathBuf>>();
+    paths.sort();
+    paths.dedup();
+
+    let mut dirty = Vec::new();
+    for path in paths {
+        let (before, after) = (old.get(path), new.get(path));
+        if before == after {
+            continue;
+        }
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        let entry = index.get(name);
+        let staged = match (entry, before) {
+            (Some(entry), Some(leaf)) => {
+                entry.sha() != leaf.sha().to_string() || Some(entry.mode()) != u32::from_str_radix(leaf.mode(), 8).ok()
+            },
+            (None, None) => false,
+            _ => true
+        };
+        if staged {
+            dirty.push(path.clone());
+            continue;
+        }
+
+        let dest = root.join(path);
+        let metadata = match fs::symlink_metadata(&dest) {
+            Ok(metadata) => metadata,
+            // Already gone; only a problem if new wants it removed anyway
+            Err(_) => {
+                if before.is_some() && after.is_some() {
+                    dirty.push(path.clone());
+                }
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            continue;
+        }
+        if entry.map(|entry| entry.is_fresh(&metadata)).unwrap_or(false) {
+            continue;
+        }
+        // Stat data changed or the file is untracked, so compare contents
+        let sha = object::hash(dest.to_str().ok_or(path_conversion_err())?, "blob", Some(repo), false)?;
+        let expected = before.or(after).map(|leaf| *leaf.sha());
+        if Some(sha) != expected {
+            dirty.push(path.clone());
+        }
+    }
+    Ok(dirty)
+}
+
+// Rewrites the worktree files that differ between old and new, or are
+// missing, and refreshes their index entries
+pub fn update_worktree(repo: &Repository, old: &Files, new: &Files) -> Result<(), Box<WitError>> {
+    let lock = Index::lock(repo)?;
+    let mut index = Index::read(repo)?;
+    let root = repo.worktree()?.to_path_buf();
+
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        let dest = tree::safe_dest(&root, path)?;
+        if fs::symlink_metadata(&dest).map(|meta| !meta.is_dir()).unwrap_or(false) {
+            fs::remove_file(&dest)?;
+        }
+        index.remove(path.to_str().ok_or(path_conversion_err())?);
+        // Drop directories the removal left empty
+        let mut parent = dest.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != root) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+
+    for (path, leaf) in new {
+        let dest = tree::safe_dest(&root, path)?;
+        if old.get(path) == Some(leaf) && fs::symlink_metadata(&dest).is_ok() {
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::symlink_metadata(&dest).map(|meta| !meta.is_dir()).unwrap_or(false) {
+            fs::remove_file(&dest)?;
+        }
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        match leaf.mode() {
+            // Submodules just get an empty directory
+            "160000" => {
+                fs::create_dir_all(&dest)?;
+                continue;
+            },
+            #[cfg(unix)]
+            "120000" => std::os::unix::fs::symlink(String::from_utf8(blob::read(repo, leaf.sha())?.data().clone())?, &dest)?,
+            mode => {
+                fs::write(&dest, blob::read(repo, leaf.sha())?.data())?;
+                set_executable(&dest, mode == "100755")?;
+            }
+        }
+        index.add(IndexEntry::create(repo, name, leaf.sha(), leaf.mode())?);
+    }
+    index.write_locked(repo, lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reflog, test_util};
+
+    #[test]
+    fn switch_moves_head_index_and_worktree() {
+        let (dir, repo) = test_util::repo();
+        let first = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"one\n"), ("gone", b"bye\n")]), &[], "first");
+        let second = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"two\n"), ("sub/b", b"new\n")]), &[&first], "second");
+        reference::update(&repo, "refs/heads/main", &first, None, "first").unwrap();
+        reference::update(&repo, "refs/heads/topic", &second, None, "second").unwrap();
+
+        switch(&repo, &first).unwrap();
+        assert_eq!(reference::head_target(&repo), None);
+        assert_eq!(fs::read(dir.path().join("gone")).unwrap(), b"bye\n");
+
+        switch(&repo, "topic").unwrap();
+        assert_eq!(reference::head_target(&repo).as_deref(), Some("refs/heads/topic"));
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"two\n");
+        assert_eq!(fs::read(dir.path().join("sub/b")).unwrap(), b"new\n");
+        assert!(!dir.path().join("gone").exists());
+
+        let index = Index::read(&repo).unwrap();
+        let paths = index.entries().iter().map(|entry| entry.path()).collect::<Vec<&str>>();
+        assert_eq!(paths, vec!["a", "sub/b"]);
+        assert!(dirty_paths(&repo, &index, &commit_files(&repo, &second).unwrap(), &commit_files(&repo, &first).unwrap()).unwrap().is_empty());
+
+        switch(&repo, "main").unwrap();
+        assert!(!dir.path().join("sub").exists());
+        assert_eq!(reflog::read(&repo, "HEAD").unwrap().last().unwrap().message, "checkout: moving from topic to main");
+    }
+
+    #[test]
+    fn switch_refuses_to_lose_changes() {
+        let (dir, repo) = test_util::repo();
+        let first = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"one\n"), ("same", b"x\n")]), &[], "first");
+        let second = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"two\n"), ("same", b"x\n")]), &[&first], "second");
+        reference::update(&repo, "refs/heads/main", &first, None, "first").unwrap();
+        switch(&repo, "main").unwrap();
+
+        // Edits to a file both commits agree on are carried along
+        fs::write(dir.path().join("same"), b"edited\n").unwrap();
+        fs::write(dir.path().join("a"), b"edited\n").unwrap();
+        let err = switch(&repo, &second).unwrap_err();
+        assert!(matches!(*err, WitError::UncommittedChanges(ref paths) if paths == &[PathBuf::from("a")]));
+        assert_eq!(reference::head_target(&repo).as_deref(), Some("refs/heads/main"));
+
+        fs::write(dir.path().join("a"), b"one\n").unwrap();
+        switch(&repo, &second).unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"two\n");
+        assert_eq!(fs::read(dir.path().join("same")).unwrap(), b"edited\n");
+    }
+
+    #[test]
+    fn untracked_files_in_the_way_are_dirty() {
+        let (dir, repo) = test_util::repo();
+        let first = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"one\n")]), &[], "first");
+        fs::write(dir.path().join("a"), b"mine\n").unwrap();
+        assert!(matches!(*switch(&repo, &first).unwrap_err(), WitError::UncommittedChanges(_)));
+        // The same contents are not in the way
+        fs::write(dir.path().join("a"), b"one\n").unwrap();
+        switch(&repo, &first).unwrap();
+    }
+}
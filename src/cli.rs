@@ -21,6 +21,30 @@ impl<'a> CliExecute<'a> for Command<'a> {
             Some(("show-ref", _)) => commands::show_ref(),
             Some(("tag", args)) => commands::tag(args),
             Some(("rev-parse", args)) => commands::rev_parse(args),
+            Some(("format-patch", args)) => commands::format_patch(args),
+            Some(("am", args)) => commands::am(args),
+            Some(("bisect", args)) => commands::bisect(args),
+            Some(("diff", args)) => commands::diff(args),
+            Some(("unpack-objects", args)) => commands::unpack_objects(args),
+            Some(("config", args)) => commands::config(args),
+            Some(("worktree", args)) => commands::worktree(args),
+            Some(("rebase", args)) => commands::rebase(args),
+            Some(("switch", args)) => commands::switch(args),
+            Some(("submodule", _)) => commands::submodule(),
+            Some(("merge", args)) => commands::merge(args),
+            Some(("show", args)) => commands::show(args),
+            Some(("clone", args)) => commands::clone(args),
+            Some(("repack", _)) => commands::repack(),
+            Some(("fsck", _)) => commands::fsck(),
+            Some(("rm", args)) => commands::rm(args),
+            Some(("mv", args)) => commands::mv(args),
+            Some(("commit", args)) => commands::commit(args),
+            Some(("fetch", args)) => commands::fetch(args),
+            Some(("add", args)) => commands::add(args),
+            Some(("shortlog", args)) => commands::shortlog(args),
+            Some(("merge-base", args)) => commands::merge_base(args),
+            Some(("archive", args)) => commands::archive(args),
+            Some(("blame", args)) => commands::blame(args),
             Some((invalid_cmd, _)) => {
                 Err(cli_unknown_command_err(invalid_cmd))
             }
@@ -49,6 +73,29 @@ pub fn setup<'a>() -> Command<'a> {
             .required(false)
             .help("Directory location for the new repo. If omitted, defaults to pwd.")
             .display_order(0)
+        )
+        .arg(
+            arg!(--shared [permissions])
+            .required(false)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("group")
+            .help("Share the repository with a group: group, all, umask, or an octal mode like 0640")
+            .display_order(1)
+        )
+        .arg(
+            arg!(--bare)
+            .required(false)
+            .help("Create a bare repository, with no worktree")
+            .display_order(2)
+        )
+        .arg(
+            arg!(-b --"initial-branch" <name>)
+            .id("initial_branch")
+            .required(false)
+            .default_value("main")
+            .help("The name of the branch HEAD starts on")
+            .display_order(3)
         ),
         // cat-file
         Command::new("cat-file")
@@ -57,7 +104,7 @@ pub fn setup<'a>() -> Command<'a> {
         .arg_required_else_help(true)
         .arg(
             arg!([file_type])
-            .required(true)
+            .required_unless_present_any(["batch", "batch_check"])
             .possible_values([
                 "blob",
                 "commit",
@@ -69,9 +116,30 @@ pub fn setup<'a>() -> Command<'a> {
         )
         .arg(
             arg!([object])
-            .required(true)
+            .required_unless_present_any(["batch", "batch_check"])
             .help("The object to display")
             .display_order(1)
+        )
+        .arg(
+            arg!(-p --pretty)
+            .required(false)
+            .help("Pretty-print the object's contents")
+            .display_order(2)
+        )
+        .arg(
+            arg!(--batch)
+            .required(false)
+            .conflicts_with_all(&["file_type", "object", "batch_check"])
+            .help("Print the type, size and contents of each object named on stdin")
+            .display_order(3)
+        )
+        .arg(
+            arg!(--"batch-check")
+            .id("batch_check")
+            .required(false)
+            .conflicts_with_all(&["file_type", "object"])
+            .help("Print the type and size of each object named on stdin")
+            .display_order(4)
         ),
         // hash-object
         Command::new("hash-object")
@@ -98,10 +166,17 @@ pub fn setup<'a>() -> Command<'a> {
             .display_order(1)
         )
         .arg(
-            arg!([file])
-            .required(true)
-            .help("Read the object from <file>")
+            arg!(--stdin)
+            .required(false)
+            .help("Read the object from stdin instead of a file")
             .display_order(2)
+        )
+        .arg(
+            arg!([file])
+            .required_unless_present("stdin")
+            .conflicts_with("stdin")
+            .help("Read the object from <file>, or stdin if it is -")
+            .display_order(3)
         ),
         // log
         Command::new("log")
@@ -120,11 +195,17 @@ pub fn setup<'a>() -> Command<'a> {
         .arg_required_else_help(true)
         .display_order(4)
         .about("List the contents of a tree object")
+        .arg(
+            arg!(recursive: -r)
+            .required(false)
+            .help("Recurse into sub-trees")
+            .display_order(0)
+        )
         .arg(
             arg!([object])
             .required(true)
             .help("The tree object to list")
-            .display_order(0)
+            .display_order(1)
         ),
         // checkout
         Command::new("checkout")
@@ -144,6 +225,11 @@ pub fn setup<'a>() -> Command<'a> {
             .default_value("master")
             .display_order(1)
             .help("The EMPTY directory to checkout on.")
+        )
+        .arg(
+            arg!(--"recurse-submodules")
+            .display_order(2)
+            .help("Clone submodules and check out the commits they are recorded at.")
         ),
         // show-ref
         Command::new("show-ref")
@@ -161,16 +247,30 @@ pub fn setup<'a>() -> Command<'a> {
             .help("Create a tag object")
             .display_order(0)
         )
+        .arg(
+            arg!(-m --message <message>)
+            .required(false)
+            .requires("create_tag_object")
+            .help("The annotated tag's message")
+            .display_order(1)
+        )
+        .arg(
+            arg!(-l --list <pattern>)
+            .required(false)
+            .conflicts_with_all(&["name", "create_tag_object"])
+            .help("Only list tags whose names match the regex")
+            .display_order(2)
+        )
         .arg(
             arg!([name])
             .required(false)
             .help("Name of the tag")
-            .display_order(1)
+            .display_order(3)
         )
         .arg(
             arg!([object])
             .help("Object the new tag will point to")
-            .display_order(2)
+            .display_order(4)
             .default_value("HEAD")
         ),
         // rev-parse
@@ -198,40 +298,472 @@ pub fn setup<'a>() -> Command<'a> {
             .help("The object to parse")
             .display_order(1)
         ),
+        // format-patch
+        Command::new("format-patch")
+        .display_order(9)
+        .about("Prepare patches for e-mail submission")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-o --"output-directory" <dir>)
+            .id("output_directory")
+            .required(false)
+            .default_value(".")
+            .help("Write the patch files into <dir>")
+            .display_order(0)
+        )
+        .arg(
+            arg!([range])
+            .required(true)
+            .help("Commits to format, as <since>..<until> or <since>")
+            .display_order(1)
+        ),
+        // am
+        Command::new("am")
+        .display_order(10)
+        .about("Apply a series of patches from a mailbox")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([mbox])
+            .required(true)
+            .multiple_values(true)
+            .help("The patch files to apply")
+            .display_order(0)
+        ),
+        // bisect
+        Command::new("bisect")
+        .display_order(11)
+        .about("Use binary search to find the commit that introduced a bug")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommands(vec![
+            Command::new("start")
+            .about("Start a new bisection"),
+            Command::new("good")
+            .about("Mark a commit as good")
+            .arg(
+                arg!([rev])
+                .required(false)
+                .default_value("HEAD")
+                .help("The good commit")
+            ),
+            Command::new("bad")
+            .about("Mark a commit as bad")
+            .arg(
+                arg!([rev])
+                .required(false)
+                .default_value("HEAD")
+                .help("The bad commit")
+            ),
+            Command::new("next")
+            .about("Show the next commit to test"),
+            Command::new("reset")
+            .about("End the bisection and restore HEAD"),
+        ]),
+        // diff
+        Command::new("diff")
+        .display_order(12)
+        .about("Show changes between two commits or trees")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([old])
+            .required(true)
+            .help("The commit or tree to compare from")
+            .display_order(0)
+        )
+        .arg(
+            arg!([new])
+            .required(true)
+            .help("The commit or tree to compare to")
+            .display_order(1)
+        )
+        .arg(
+            arg!(-M --"find-renames" [threshold])
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("50%")
+            .help("Show deleted and added files that are at least threshold% similar as renames")
+            .display_order(2)
+        ),
+        // unpack-objects
+        Command::new("unpack-objects")
+        .display_order(13)
+        .about("Unpack objects from a packed archive read from stdin")
+        .arg(
+            arg!(-r --recover)
+            .required(false)
+            .help("Keep the objects before a corrupt or truncated entry")
+            .display_order(0)
+        ),
+        // config
+        Command::new("config")
+        .display_order(14)
+        .about("Get and set repository options")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(--unset)
+            .required(false)
+            .conflicts_with("value")
+            .help("Remove the key from the repository config")
+            .display_order(0)
+        )
+        .arg(
+            arg!(--"get-all")
+            .id("get_all")
+            .required(false)
+            .conflicts_with_all(&["value", "unset"])
+            .help("Print every value for the key")
+            .display_order(1)
+        )
+        .arg(
+            arg!([key])
+            .required(true)
+            .help("The option, as section.key or section.subsection.key")
+            .display_order(2)
+        )
+        .arg(
+            arg!([value])
+            .required(false)
+            .help("Set the option to this value in the repository config")
+            .display_order(3)
+        ),
+        // worktree
+        Command::new("worktree")
+        .display_order(15)
+        .about("Manage multiple working trees")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommands(vec![
+            Command::new("add")
+            .about("Check out a branch into a new linked worktree")
+            .arg(
+                arg!([path])
+                .required(true)
+                .help("Where to create the worktree")
+            )
+            .arg(
+                arg!([branch])
+                .required(false)
+                .help("The branch to check out; defaults to the last part of the path")
+            ),
+            Command::new("list")
+            .about("List the worktrees of this repository"),
+            Command::new("remove")
+            .about("Delete a linked worktree")
+            .arg(
+                arg!([name])
+                .required(true)
+                .help("The worktree's name, as shown by list")
+            ),
+        ]),
+        // rebase
+        Command::new("rebase")
+        .display_order(16)
+        .about("Reapply the current branch's commits on top of another commit")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(--continue)
+            .required(false)
+            .conflicts_with_all(&["upstream", "abort"])
+            .help("Commit the resolved conflicts and keep going")
+            .display_order(0)
+        )
+        .arg(
+            arg!(--abort)
+            .required(false)
+            .conflicts_with("upstream")
+            .help("Stop rebasing and restore the original branch")
+            .display_order(1)
+        )
+        .arg(
+            arg!([upstream])
+            .required_unless_present_any(["continue", "abort"])
+            .help("The commit to replay the branch onto")
+            .display_order(2)
+        ),
+        // switch
+        Command::new("switch")
+        .display_order(17)
+        .about("Switch the worktree, index and HEAD to a branch or commit")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([target])
+            .required(true)
+            .help("A branch to check out, or a commit to detach HEAD at")
+            .display_order(0)
+        ),
+        // submodule
+        Command::new("submodule")
+        .display_order(18)
+        .about("List the submodules in .gitmodules and the commits HEAD records for them"),
+        // merge
+        Command::new("merge")
+        .display_order(19)
+        .about("Join another line of history into the current one")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([commit])
+            .required(true)
+            .help("The branch or commit to merge into HEAD")
+            .display_order(0)
+        ),
+        // show
+        Command::new("show")
+        .display_order(20)
+        .about("Show a commit with its changes, or any other object")
+        .arg(
+            arg!([object])
+            .default_value("HEAD")
+            .help("The object to show")
+            .display_order(0)
+        ),
+        // clone
+        Command::new("clone")
+        .display_order(21)
+        .about("Clone a repository over smart HTTP")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([url])
+            .required(true)
+            .help("The http:// or https:// url of the repository")
+            .display_order(0)
+        )
+        .arg(
+            arg!([directory])
+            .help("Where to clone to, by default named after the repository")
+            .display_order(1)
+        ),
+        // repack
+        Command::new("repack")
+        .alias("gc")
+        .display_order(22)
+        .about("Pack every reachable object and remove the loose copies"),
+        // fsck
+        Command::new("fsck")
+        .display_order(23)
+        .about("Check the integrity of every object"),
+        // rm
+        Command::new("rm")
+        .display_order(24)
+        .about("Remove files from the index and the worktree")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(--cached)
+            .help("Only remove the files from the index, keeping them in the worktree")
+            .display_order(0)
+        )
+        .arg(
+            arg!(-f --force)
+            .help("Remove files even if they have changes that would be lost")
+            .display_order(1)
+        )
+        .arg(
+            arg!([paths])
+            .required(true)
+            .multiple_values(true)
+            .help("The files to remove; directories are removed recursively")
+            .display_order(2)
+        ),
+        // mv
+        Command::new("mv")
+        .display_order(25)
+        .about("Move or rename a file or directory in the index and the worktree")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([source])
+            .required(true)
+            .help("The file or directory to move")
+            .display_order(0)
+        )
+        .arg(
+            arg!([destination])
+            .required(true)
+            .help("Where to move it to, which must not exist yet")
+            .display_order(1)
+        ),
+        // commit
+        Command::new("commit")
+        .display_order(26)
+        .about("Record the staged changes as a new commit on the current branch")
+        .arg(
+            arg!(-m --message <message>)
+            .required(false)
+            .help("The commit message; a merge being finished defaults to its own")
+            .display_order(0)
+        )
+        .arg(
+            arg!(--author <author>)
+            .required(false)
+            .help("Override the author, given as 'Name <email>'")
+            .display_order(1)
+        )
+        .arg(
+            arg!(--"allow-empty")
+            .help("Commit even if the tree is unchanged")
+            .display_order(2)
+        ),
+        // fetch
+        Command::new("fetch")
+        .display_order(27)
+        .about("Download objects and refs from a remote over smart HTTP")
+        .arg(
+            arg!([remote])
+            .default_value("origin")
+            .help("The remote to fetch from")
+            .display_order(0)
+        ),
+        // add
+        Command::new("add")
+        .display_order(28)
+        .about("Stage worktree files in the index, resolving any conflicts on them")
+        .arg_required_else_help(true)
+        .arg(
+            arg!([paths])
+            .required(true)
+            .multiple_values(true)
+            .help("The files to stage; directories are staged recursively")
+            .display_order(0)
+        ),
+        // shortlog
+        Command::new("shortlog")
+        .display_order(29)
+        .about("Summarize history by author, with .mailmap applied")
+        .arg(
+            arg!(-s --summary)
+            .help("Only show how many commits each author made")
+            .display_order(0)
+        )
+        .arg(
+            arg!(-e --email)
+            .help("Show each author's email")
+            .display_order(1)
+        )
+        .arg(
+            arg!([commit])
+            .default_value("HEAD")
+            .help("The commit to start from")
+            .display_order(2)
+        ),
+        // merge-base
+        Command::new("merge-base")
+        .display_order(30)
+        .about("Find the best common ancestor of commits; with more than two, one common to all of them")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-a --all)
+            .help("Show every best common ancestor, not just the first")
+            .display_order(0)
+        )
+        .arg(
+            arg!([commits])
+            .required(true)
+            .min_values(2)
+            .help("The commits to find an ancestor of")
+            .display_order(1)
+        ),
+        // archive
+        Command::new("archive")
+        .display_order(31)
+        .about("Write the files of a commit or tree as a tar archive")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(--prefix <prefix>)
+            .required(false)
+            .help("A directory to put every path in")
+            .display_order(0)
+        )
+        .arg(
+            arg!(-o --output <file>)
+            .required(false)
+            .help("Write the archive to a file instead of stdout")
+            .display_order(1)
+        )
+        .arg(
+            arg!(<tree_ish>)
+            .help("The commit or tree to archive")
+            .display_order(2)
+        ),
+        // blame
+        Command::new("blame")
+        .display_order(32)
+        .about("Show the commit that last changed each line of a file")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-n --"show-number")
+            .help("Show each line's number in the commit it came from")
+            .display_order(0)
+        )
+        .arg(
+            arg!([commit])
+            .help("The commit to start from, HEAD if only a path is given")
+            .display_order(1)
+        )
+        .arg(
+            arg!([path])
+            .help("The file to blame, relative to the top of the worktree")
+            .display_order(2)
+        )
     ])
 }
 
 mod commands {
     use std::{
+        collections::HashSet,
         env::current_dir,
-        io::{ stdout, Write },
+        io::{ stderr, stdin, stdout, BufRead, Read, Write },
         fs,
         str::from_utf8,
-        path::PathBuf
+        path::{ Path, PathBuf }
     };
     use clap::ArgMatches;
+    use regex::Regex;
     use crate::{
         error::{ builder::*, WitError },
-        repository::Repository,
+        archive,
+        blame,
+        repository::{ HookResult, Repository, SharedMode },
         object::{ self, WitObject },
-        tree::Tree,
-        reference::{ self, Ref::* },
-        tag
+        object_id::ObjectId,
+        tree::{ self, Tree },
+        reference,
+        tag,
+        patch,
+        bisect::{ self, BisectStep },
+        checkout,
+        rebase::{ self, RebaseStep },
+        merge::{ self, MergeOutcome },
+        config::ConfigGet,
+        diff,
+        pack,
+        submodule,
+        transport,
+        index,
+        commit,
+        signature::Signature,
+        util::format_iso,
+        worktree
     };
 
     pub fn init(sub_matches: &ArgMatches) -> Result<(), Box<WitError>> {
         let pwd = match current_dir() {
             Ok(dir) => dir,
-            Err(_) => Err(io_err(String::from("Could not find pwd")))?
+            Err(_) => Err(other_err(String::from("Could not find pwd")))?
         };
         let pwd = match pwd.to_str() {
             Some(string) => {
                 String::from(string)
             },
-            None => Err(io_err(String::from("Could not read pwd")))?
+            None => Err(other_err(String::from("Could not read pwd")))?
         };
 
-        if let Err(e) = Repository::create(sub_matches.value_of("path").unwrap_or(pwd.as_str())) {
+        let shared = SharedMode::parse(sub_matches.value_of("shared").unwrap_or("umask"))?;
+        let path = Path::new(sub_matches.value_of("path").unwrap_or(pwd.as_str()));
+        let bare = sub_matches.is_present("bare");
+        let branch = sub_matches.value_of("initial_branch").unwrap_or("main");
+        let created = match shared {
+            SharedMode::Umask => Repository::create(path, bare, branch),
+            shared => Repository::create_shared(path, bare, branch, shared)
+        };
+        if let Err(e) = created {
             println!("{}", e);
             eprintln!("Could not create repo.");
         }
@@ -239,102 +771,131 @@ mod commands {
     }
 
     pub fn cat_file(args: &ArgMatches) -> Result<(), Box<WitError>> {
-        let repo: Repository = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo: Repository = Repository::discover(Path::new("."))?;
+
+        if args.is_present("batch") || args.is_present("batch_check") {
+            let mut out = stdout();
+            return cat_file_batch(&repo, args.is_present("batch_check"), &mut stdin().lock(), &mut out);
+        }
 
         let data = object::find(
             &repo,
-            args.value_of("object").ok_or(io_err(format!("No object specified")))?,
-            Some(args.value_of("file_type").ok_or(io_err(format!("No file type specified")))?),
+            args.value_of("object").ok_or(cli_argument_err("object"))?,
+            Some(args.value_of("file_type").ok_or(cli_argument_err("file_type"))?),
             true
         )?;
-        let obj = object::read(
-            &repo,
-            data.as_str()
-        )?;
-
         let mut out = stdout();
-        out.write(obj.serialize()?.as_slice())?;
+        if args.is_present("pretty") {
+            print!("{}", object::read(&repo, &data)?);
+            return Ok(());
+        }
+
+        object::read_to_writer(&repo, &data, &mut out)?;
         out.flush()?;
         Ok(())
     }
 
+    // One object name per line; the output is flushed after each object so
+    // callers can interleave requests and responses over a pipe
+    pub fn cat_file_batch(repo: &Repository, check_only: bool, reader: &mut dyn BufRead, writer: &mut dyn Write) -> Result<(), Box<WitError>> {
+        for line in reader.lines() {
+            let line = line?;
+            let name = line.trim();
+            let found = match object::find(repo, name, None, false) {
+                Ok(sha) => Some(sha),
+                Err(e) if matches!(*e, WitError::AmbiguousReference { .. }) => {
+                    writeln!(writer, "{} ambiguous", name)?;
+                    writer.flush()?;
+                    continue;
+                },
+                Err(_) => None
+            };
+            match found.filter(|sha| object::exists(repo, sha)) {
+                // The header is enough for the check, so the contents are never inflated
+                Some(sha) if check_only => {
+                    let (fmt, size) = object::stat(repo, &sha)?;
+                    writeln!(writer, "{} {} {}", sha, fmt, size)?;
+                },
+                Some(sha) => {
+                    let (fmt, data) = object::read_shared(repo, &sha)?;
+                    writeln!(writer, "{} {} {}", sha, fmt, data.len())?;
+                    writer.write_all(&data)?;
+                    writeln!(writer)?;
+                },
+                None => writeln!(writer, "{} missing", name)?
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
     pub fn hash_object(args: &ArgMatches) -> Result<(), Box<WitError>> {
-        let r = Repository::new(".", false)?;
-        let repo = if args.is_present("write") {
-            Some(&r)
-        } else {
-            None
-        };
+        // Hashing alone works outside a repository
+        let write = args.is_present("write");
+        let repo = Repository::find(Path::new("."), write)?;
 
+        let file = match args.is_present("stdin") {
+            true => "-",
+            false => args.value_of("file").ok_or(cli_argument_err("file"))?
+        };
         let sha = object::hash(
-            from_utf8(
-                &fs::read(
-                    args.value_of("file").ok_or(
-                        cli_argument_err("file")
-                    )?
-                )?[..]
-            )?,
+            file,
             args.value_of("type").ok_or(
                 cli_argument_err("type")
             )?,
-            repo
-        );
-        println!("{sha:?}");
+            repo.as_ref(),
+            write
+        )?;
+        println!("{}", sha);
         Ok(())
     }
 
     pub fn log(args: &ArgMatches) -> Result<(), Box<WitError>> {
         let commit = args.value_of("commit").ok_or(cli_argument_err("commit"))?;
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         println!("digraph log {{\n");
         object::graphviz(
             &repo,
-            object::find(&repo, commit, None, true)?,
-            &mut Vec::new()
+            object::find(&repo, commit, None, true)?.to_string(),
+            &mut HashSet::new()
         )?;
         println!("}}");
         Ok(())
     }
 
     pub fn ls_tree(args: &ArgMatches) -> Result<(), Box<WitError>> {
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         let obj_name = args.value_of("object").ok_or(
             cli_argument_err("object")
         )?;
-        let obj = match object::read(&repo, &object::find(&repo, obj_name, Some("tree"), true)?)? {
-            WitObject::TreeObject(tree) => tree,
-            _ => Err(malformed_object_err(format!("Object {} is not a tree", obj_name)))?
+        let leaves = if args.is_present("recursive") {
+            tree::flatten(&repo, obj_name)?.into_values().collect()
+        } else {
+            tree::read(&repo, obj_name)?.leaves().clone()
         };
 
-        let mut mode_str = String::new();
-        for leaf in obj.leaves() {
-            mode_str.clear();
-            for _ in 0..(6 - leaf.mode().len()) {
-                mode_str.push('0');
-            }
-            mode_str.push_str(leaf.mode());
-
+        for leaf in leaves {
             println!(
-                "{} {} {}\t{}",
-                mode_str,
-                String::from_utf8(object::read(&repo, leaf.sha())?.serialize()?)?,
+                "{:0>6} {} {}\t{}",
+                leaf.mode(),
+                leaf.object_type(),
                 leaf.sha(),
-                leaf.path().to_str().unwrap()
+                leaf.path().display()
             );
         }
         Ok(())
     }
 
     pub fn checkout(args: &ArgMatches) -> Result<(), Box<WitError>> {
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         let obj_name = args.value_of("commit").ok_or(cli_argument_err("commit"))?;
         let obj: Tree = match object::read(&repo, &object::find(&repo, obj_name, Some("tree"), true)?)? {
             WitObject::CommitObject(commit) => {
                 match object::read(
                     &repo,
-                    &commit.kvlm().get("tree").ok_or(
+                    &ObjectId::from_hex(&commit.kvlm().get("tree").ok_or(
                         missing_data_err(format!("No tree in commit {}", obj_name))
-                    )?[0]
+                    )?[0])?
                 )? {
                     WitObject::TreeObject(tree) => tree,
                     _ => Err(malformed_object_err(format!("Could not find tree from object {}", obj_name)))?
@@ -351,26 +912,26 @@ mod commands {
             if !path.is_dir() {
                 Err(not_a_directory_err(&path))?
             }
-            if !path.read_dir()?.next().is_none() {
+            if path.read_dir()?.next().is_some() {
                 Err(dir_not_empty_err(&path))?
             }
         } else {
             fs::create_dir_all(&path)?;
         }
 
-        object::checkout(&repo, &obj, &path.canonicalize()?)?;
+        object::checkout(&repo, &obj, &path.canonicalize()?, args.is_present("recurse-submodules"))?;
         Ok(())
     }
 
     pub fn show_ref() -> Result<(), Box<WitError>> {
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         let refs = reference::list(&repo, None)?;
-        reference::show(&repo, &refs, true, "refs")?;
+        reference::show(&refs, true, "refs")?;
         Ok(())
     }
 
     pub fn tag(args: &ArgMatches) -> Result<(), Box<WitError>> {
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         if args.is_present("name") {
             tag::create(
                 &repo,
@@ -380,20 +941,17 @@ mod commands {
                 args.value_of("object").ok_or(
                     cli_argument_err("object")
                 )?,
-                args.is_present("create_tag_object")
-            )
+                args.is_present("create_tag_object"),
+                args.value_of("message"),
+                None
+            )?;
         } else {
-            let refs = reference::list(&repo, None)?;
-            let tags = match refs.get("tags").unwrap() {
-                Indirect(refs) => refs,
-                Direct(direct) => Err(
-                    unknown_reference_err(
-                        format!("Expected an indirect reference, got direct reference {}", direct)
-                    )
-                )?
-            };
-            reference::show(&repo, tags, true, "")
+            let pattern = args.value_of("list").map(Regex::new).transpose()?;
+            for info in tag::list(&repo, pattern.as_ref())? {
+                println!("{}", info.name);
+            }
         }
+        Ok(())
     }
 
     pub fn rev_parse(args: &ArgMatches) -> Result<(), Box<WitError>> {
@@ -403,7 +961,7 @@ mod commands {
                 cli_argument_err("type")
             )?);
         }
-        let repo = Repository::find(".", true)?.ok_or(pwd_not_repo_err())?;
+        let repo = Repository::discover(Path::new("."))?;
         println!(
             "{}",
             object::find(&repo, args.value_of("name").ok_or(
@@ -412,4 +970,462 @@ mod commands {
         );
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn format_patch(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let written = patch::format_patch(
+            &repo,
+            args.value_of("range").ok_or(cli_argument_err("range"))?,
+            &PathBuf::from(args.value_of("output_directory").ok_or(cli_argument_err("output_directory"))?)
+        )?;
+        for path in written {
+            println!("{}", path.display());
+        }
+        Ok(())
+    }
+
+    pub fn am(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let files = args.values_of("mbox").ok_or(cli_argument_err("mbox"))?
+            .map(PathBuf::from)
+            .collect::<Vec<PathBuf>>();
+        patch::am(&repo, &files)
+    }
+
+    pub fn bisect(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        match args.subcommand() {
+            Some(("start", _)) => return bisect::start(&repo),
+            Some(("good", sub_args)) => bisect::good(&repo, sub_args.value_of("rev").ok_or(cli_argument_err("rev"))?)?,
+            Some(("bad", sub_args)) => bisect::bad(&repo, sub_args.value_of("rev").ok_or(cli_argument_err("rev"))?)?,
+            Some(("next", _)) => (),
+            Some(("reset", _)) => return bisect::reset(&repo),
+            Some((invalid_cmd, _)) => Err(cli_unknown_command_err(invalid_cmd))?,
+            None => Err(cli_no_command_err())?
+        }
+
+        // Like git, report the next step after every good or bad
+        match bisect::next(&repo) {
+            Ok(BisectStep::Test(sha)) => {
+                println!("Bisecting: {} revisions left to test after this", bisect::remaining(&repo)? / 2);
+                println!("[{}]", sha);
+            },
+            Ok(BisectStep::Found(sha)) => println!("{} is the first bad commit", sha),
+            Ok(BisectStep::Finished) => println!("No commits left to test between the good and bad commits"),
+            // Waiting for a good or bad commit
+            Err(_) if args.subcommand_matches("next").is_none() => (),
+            Err(e) => Err(e)?
+        }
+        Ok(())
+    }
+
+    pub fn diff(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let old = object::find(&repo, args.value_of("old").ok_or(cli_argument_err("old"))?, Some("tree"), true)?;
+        let new = object::find(&repo, args.value_of("new").ok_or(cli_argument_err("new"))?, Some("tree"), true)?;
+        let mut entries = tree::diff(&repo, Some(&old.to_string()), &new.to_string())?;
+        if let Some(threshold) = args.value_of("find-renames") {
+            let mut options = tree::RenameOptions {
+                threshold: threshold.trim_end_matches('%').parse::<u8>()?.min(100),
+                ..Default::default()
+            };
+            if let Some(limit) = repo.config()?.get_int("diff.renamelimit")? {
+                options.limit = limit.max(0) as usize;
+            }
+            let (renamed, limited) = tree::detect_renames(&repo, entries, &options)?;
+            if limited {
+                eprintln!("warning: inexact rename detection was skipped due to too many files.");
+                eprintln!("warning: you may want to set your diff.renameLimit variable higher and retry the command.");
+            }
+            entries = renamed;
+        }
+        for entry in &entries {
+            stdout().write_all(&diff::entry_patch(&repo, entry, 3)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn unpack_objects(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let mut data = Vec::new();
+        stdin().read_to_end(&mut data)?;
+        let unpacked = pack::unpack(&repo, &data, args.is_present("recover"))?;
+        if !unpacked.complete {
+            eprintln!("Recovered {} objects from an incomplete pack", unpacked.objects.len());
+        }
+        Ok(())
+    }
+
+    pub fn config(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let mut repo = Repository::discover(Path::new("."))?;
+        let key = args.value_of("key").ok_or(cli_argument_err("key"))?;
+        let path = Repository::file(&repo, vec!["config"], false)?;
+
+        if args.is_present("unset") {
+            repo.conf.unset(key)?;
+            repo.conf.write(&path)?;
+            return Repository::adjust_shared_perm(&repo, &path);
+        }
+        if let Some(value) = args.value_of("value") {
+            repo.conf.set(key, value)?;
+            repo.conf.write(&path)?;
+            return Repository::adjust_shared_perm(&repo, &path);
+        }
+
+        let config = repo.config()?;
+        let values = if args.is_present("get_all") {
+            config.get_all(key)
+        } else {
+            config.get(key).into_iter().collect()
+        };
+        if values.is_empty() {
+            return Err(missing_data_err(format!("{} is not set", key)));
+        }
+        for value in values {
+            println!("{}", value);
+        }
+        Ok(())
+    }
+
+    pub fn worktree(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        match args.subcommand() {
+            Some(("add", sub_args)) => {
+                let path = Path::new(sub_args.value_of("path").ok_or(cli_argument_err("path"))?);
+                let branch = match sub_args.value_of("branch") {
+                    Some(branch) => branch.to_owned(),
+                    None => path.file_name().and_then(|name| name.to_str()).ok_or(path_conversion_err())?.to_owned()
+                };
+                worktree::add(&repo, path, &branch)
+            },
+            Some(("list", _)) => {
+                for info in worktree::list(&repo)? {
+                    let head = info.head.as_deref().map(|sha| &sha[..7.min(sha.len())]).unwrap_or("0000000");
+                    let branch = info.branch.map(|branch| format!("[{}]", branch)).unwrap_or("(detached HEAD)".to_owned());
+                    println!("{}  {} {}", info.path.display(), head, branch);
+                }
+                Ok(())
+            },
+            Some(("remove", sub_args)) => worktree::remove(&repo, sub_args.value_of("name").ok_or(cli_argument_err("name"))?),
+            Some((cmd, _)) => Err(cli_unknown_command_err(cmd)),
+            None => Err(cli_no_command_err())
+        }
+    }
+
+    pub fn rebase(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let step = if args.is_present("abort") {
+            return rebase::abort(&repo);
+        } else if args.is_present("continue") {
+            rebase::resume(&repo)?
+        } else {
+            rebase::start(&repo, args.value_of("upstream").ok_or(cli_argument_err("upstream"))?)?
+        };
+
+        match step {
+            RebaseStep::Finished => {
+                println!("Successfully rebased.");
+                Ok(())
+            },
+            RebaseStep::Conflict(sha, paths) => {
+                for path in paths {
+                    println!("CONFLICT (content): Merge conflict in {}", path.display());
+                }
+                Err(in_progress_err(format!(
+                    "Could not apply {}; resolve the conflicts and run rebase --continue, or rebase --abort",
+                    &sha[..7]
+                )))
+            }
+        }
+    }
+
+    pub fn switch(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        checkout::switch(&repo, args.value_of("target").ok_or(cli_argument_err("target"))?)
+    }
+
+    pub fn submodule() -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let files = checkout::commit_files(&repo, &reference::resolve(&repo, "HEAD")?)?;
+        for submodule in submodule::load(&repo)? {
+            // A submodule that HEAD doesn't record yet has no commit to show
+            match files.get(&submodule.path) {
+                Some(leaf) if leaf.mode() == "160000" => print!("{}", leaf.sha()),
+                _ => print!("{}", "-".repeat(repo.object_format().hex_len()))
+            }
+            println!(" {} ({})", submodule.path.display(), submodule.url);
+        }
+        Ok(())
+    }
+
+    pub fn merge(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let theirs = args.value_of("commit").ok_or(cli_argument_err("commit"))?;
+        match merge::merge(&repo, "HEAD", theirs)? {
+            MergeOutcome::UpToDate => println!("Already up to date."),
+            MergeOutcome::FastForward(sha) => {
+                println!("Fast-forward to {}", &sha[..7]);
+                // The argument says whether this was a squash merge
+                hook(&repo, "post-merge", &["0"])?;
+            },
+            MergeOutcome::Merged(sha) => {
+                println!("Merge made by the 'wit' strategy: {}", &sha[..7]);
+                hook(&repo, "post-merge", &["0"])?;
+            },
+            MergeOutcome::Conflicts(paths) => {
+                for path in paths {
+                    println!("CONFLICT (content): Merge conflict in {}", path.display());
+                }
+                return Err(in_progress_err("Automatic merge failed; fix conflicts and then commit the result.".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn show(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let name = args.value_of("object").ok_or(cli_argument_err("object"))?;
+        object::show(&repo, name, &mut stdout().lock())
+    }
+
+    pub fn clone(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let url = args.value_of("url").ok_or(cli_argument_err("url"))?;
+        let dest = match args.value_of("directory") {
+            Some(directory) => PathBuf::from(directory),
+            None => {
+                let name = url.trim_end_matches('/').rsplit('/').next().unwrap_or(url);
+                PathBuf::from(name.strip_suffix(".git").unwrap_or(name))
+            }
+        };
+        println!("Cloning into '{}'...", dest.display());
+        transport::clone(url, &dest)?;
+        Ok(())
+    }
+
+    pub fn repack() -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let count = pack::repack(&repo)?;
+        println!("Packed {} objects", count);
+        Ok(())
+    }
+
+    pub fn fsck() -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let report = object::fsck(&repo)?;
+        for (kind, problems) in [("corrupt", &report.corrupt), ("missing", &report.missing), ("dangling", &report.dangling)] {
+            for problem in problems {
+                println!("{} {}: {}", kind, problem.sha, problem.reason);
+            }
+        }
+        if !report.is_clean() {
+            return Err(malformed_object_err(format!(
+                "{} corrupt and {} missing objects",
+                report.corrupt.len(),
+                report.missing.len()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn add(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let cwd = current_dir()?;
+        let paths = args.values_of("paths").ok_or(cli_argument_err("paths"))?
+            .map(|path| cwd.join(path))
+            .collect::<Vec<PathBuf>>();
+        index::add(&repo, &paths)?;
+        Ok(())
+    }
+
+    pub fn rm(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        // Paths on the command line are relative to where wit was run
+        let cwd = current_dir()?;
+        let paths = args.values_of("paths").ok_or(cli_argument_err("paths"))?
+            .map(|path| cwd.join(path))
+            .collect::<Vec<PathBuf>>();
+        for path in index::remove(&repo, &paths, args.is_present("cached"), args.is_present("force"))? {
+            println!("rm '{}'", path);
+        }
+        Ok(())
+    }
+
+    pub fn mv(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let cwd = current_dir()?;
+        let from = cwd.join(args.value_of("source").ok_or(cli_argument_err("source"))?);
+        let to = cwd.join(args.value_of("destination").ok_or(cli_argument_err("destination"))?);
+        index::rename(&repo, &from, &to)
+    }
+
+    pub fn commit(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let message = match args.value_of("message") {
+            Some(message) => message.to_owned(),
+            None => fs::read_to_string(Repository::path(&repo, vec!["MERGE_MSG"])).map_err(|_| cli_argument_err("message"))?
+        };
+        let committer = Signature::from_config(&repo)?;
+        let author = match args.value_of("author") {
+            Some(author) => Signature::from_ident(author)?,
+            None => committer.clone()
+        };
+        if let Some(result) = hook(&repo, "pre-commit", &[])? {
+            if !result.success() {
+                return Err(hook_failed_err("pre-commit", result.code));
+            }
+        }
+        let sha = commit::from_index(&repo, &message, author, committer, args.is_present("allow-empty"))?;
+        // Too late to stop anything, so how it went doesn't matter
+        hook(&repo, "post-commit", &[])?;
+
+        let branch = reference::head_target(&repo)
+            .map(|target| target.strip_prefix("refs/heads/").unwrap_or(&target).to_owned())
+            .unwrap_or("detached HEAD".to_owned());
+        println!("[{} {}] {}", branch, &sha[..7], message.lines().next().unwrap_or(""));
+        Ok(())
+    }
+
+    pub fn fetch(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let remote = args.value_of("remote").ok_or(cli_argument_err("remote"))?;
+        let result = transport::fetch(&repo, remote)?;
+        if result.updated.is_empty() && result.rejected.is_empty() {
+            return Ok(());
+        }
+
+        let short = |name: &str| {
+            ["refs/heads/", "refs/tags/", "refs/remotes/"].iter()
+                .find_map(|prefix| name.strip_prefix(prefix))
+                .unwrap_or(name)
+                .to_owned()
+        };
+        println!("From {}", result.url);
+        for update in &result.updated {
+            let (flag, summary) = match &update.old {
+                None if update.name.starts_with("refs/tags/") => ('*', "[new tag]".to_owned()),
+                None => ('*', "[new branch]".to_owned()),
+                Some(old) if update.forced => ('+', format!("{}...{}", &old[..7], &update.new[..7])),
+                Some(old) => (' ', format!("{}..{}", &old[..7], &update.new[..7]))
+            };
+            println!(" {} {:<17} {} -> {}", flag, summary, short(&update.remote_ref), short(&update.name));
+        }
+        for update in &result.rejected {
+            println!(" ! {:<17} {} -> {}  (non-fast-forward)", "[rejected]", short(&update.remote_ref), short(&update.name));
+        }
+        eprintln!("Received {} objects", result.objects);
+        Ok(())
+    }
+
+    pub fn shortlog(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let start = args.value_of("commit").ok_or(cli_argument_err("commit"))?;
+        for (author, subjects) in object::shortlog(&repo, start)? {
+            let name = match args.is_present("email") {
+                true => author.ident(),
+                false => author.name.clone()
+            };
+            if args.is_present("summary") {
+                println!("{:>6}\t{}", subjects.len(), name);
+                continue;
+            }
+            println!("{} ({}):", name, subjects.len());
+            for subject in subjects {
+                println!("      {}", subject);
+            }
+            println!();
+        }
+        Ok(())
+    }
+
+    pub fn merge_base(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let commits = args.values_of("commits").ok_or(cli_argument_err("commits"))?
+            .map(|name| Ok(object::find(&repo, name, Some("commit"), true)?.to_string()))
+            .collect::<Result<Vec<String>, Box<WitError>>>()?;
+        let bases = match commits.as_slice() {
+            [a, b] => merge::merge_bases(&repo, a, b)?,
+            _ => merge::octopus_bases(&repo, &commits)?
+        };
+        if bases.is_empty() {
+            return Err(unknown_reference_err("The commits have no common ancestor".to_owned()));
+        }
+        let count = if args.is_present("all") { bases.len() } else { 1 };
+        for base in &bases[..count] {
+            println!("{}", base);
+        }
+        Ok(())
+    }
+
+    pub fn archive(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let tree_ish = args.value_of("tree_ish").ok_or(cli_argument_err("tree_ish"))?;
+        let prefix = args.value_of("prefix");
+        match args.value_of("output") {
+            Some(path) => {
+                let mut out = std::io::BufWriter::new(fs::File::create(path)?);
+                archive::write_tar(&repo, tree_ish, &mut out, prefix)?;
+                out.flush()?;
+            },
+            None => {
+                let mut out = stdout().lock();
+                archive::write_tar(&repo, tree_ish, &mut out, prefix)?;
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Lays lines out like git blame: abbreviated sha, ^ marking where history
+    // ran out, the path if renames make it vary, then author, date and number
+    pub fn blame(args: &ArgMatches) -> Result<(), Box<WitError>> {
+        let repo = Repository::discover(Path::new("."))?;
+        let (commit, path) = match (args.value_of("commit"), args.value_of("path")) {
+            (Some(commit), Some(path)) => (commit, path),
+            (Some(path), None) => ("HEAD", path),
+            _ => return Err(cli_argument_err("path"))
+        };
+        let lines = blame::file(&repo, commit, path)?;
+        let show_path = lines.iter().any(|line| line.path != Path::new(path));
+        let path_width = lines.iter().map(|line| line.path.display().to_string().len()).max().unwrap_or(0);
+        let name_width = lines.iter().map(|line| line.author.name.chars().count()).max().unwrap_or(0);
+        let number_width = lines.len().to_string().len();
+        let mut out = stdout().lock();
+        for (i, line) in lines.iter().enumerate() {
+            let sha = match line.boundary {
+                true => format!("^{}", &line.sha[..7]),
+                false => line.sha[..8].to_owned()
+            };
+            let path = match show_path {
+                true => format!(" {:<width$}", line.path.display().to_string(), width = path_width),
+                false => String::new()
+            };
+            let number = match args.is_present("show-number") {
+                true => format!(" {:>width$}", line.line, width = number_width),
+                false => String::new()
+            };
+            writeln!(
+                out,
+                "{}{}{} ({:<name_width$} {} {:>number_width$}) {}",
+                sha,
+                path,
+                number,
+                line.author.name,
+                format_iso(line.author.time, line.author.offset),
+                i + 1,
+                line.text
+            )?;
+        }
+        Ok(())
+    }
+
+    // Runs a hook, passing on what it printed. Like git, both of its outputs go to stderr.
+    fn hook(repo: &Repository, name: &str, args: &[&str]) -> Result<Option<HookResult>, Box<WitError>> {
+        let result = repo.run_hook(name, args)?;
+        if let Some(result) = &result {
+            let mut err = stderr();
+            err.write_all(&result.stdout)?;
+            err.write_all(&result.stderr)?;
+        }
+        Ok(result)
+    }
+}
@@ -1,9 +1,20 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
 use crate::{
-    object::Object, 
-    repository::Repository, 
-    error::WitError
+    checkout::Files,
+    index::Index,
+    object::{self, Object, WitObject},
+    object_id::ObjectId,
+    reference,
+    repository::Repository,
+    error::{WitError, builder::{in_progress_err, malformed_object_err, missing_data_err}},
+    signature::Signature,
+    tree::{self, Leaf}
 };
-use crate::kvlm::{ KVLMExt, KVLM };
+use crate::kvlm::{ self, KVLMExt, KVLM };
 
 pub struct Commit<'a> {
     repo: Option<&'a Repository>,
@@ -13,7 +24,7 @@ pub struct Commit<'a> {
 impl<'a> Commit<'a> {
     pub fn new(repo: Option<&'a Repository>) -> Self {
         Self {
-            repo: repo,
+            repo,
             kvlm: KVLM::new(),
         }
     }
@@ -21,6 +32,34 @@ impl<'a> Commit<'a> {
     pub fn kvlm(&self) -> &KVLM {
         &self.kvlm
     }
+
+    pub fn kvlm_mut(&mut self) -> &mut KVLM {
+        &mut self.kvlm
+    }
+
+    pub fn tree(&self) -> Option<&String> {
+        self.kvlm.get("tree").and_then(|v| v.first())
+    }
+
+    pub fn parents(&self) -> Vec<String> {
+        self.kvlm.get("parent").cloned().unwrap_or_default()
+    }
+
+    pub fn author(&self) -> Result<Signature, Box<WitError>> {
+        Signature::parse(self.kvlm.get("author").and_then(|v| v.first()).ok_or(
+            malformed_object_err("Commit has no author".to_owned())
+        )?)
+    }
+
+    pub fn committer(&self) -> Result<Signature, Box<WitError>> {
+        Signature::parse(self.kvlm.get("committer").and_then(|v| v.first()).ok_or(
+            malformed_object_err("Commit has no committer".to_owned())
+        )?)
+    }
+
+    pub fn message(&self) -> &str {
+        self.kvlm.get("").and_then(|v| v.first()).map(|m| m.as_str()).unwrap_or("")
+    }
 }
 
 impl<'a> Object for Commit<'a> {
@@ -41,3 +80,141 @@ impl<'a> Object for Commit<'a> {
         self.repo
     }
 }
+
+impl<'a> fmt::Display for Commit<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        kvlm::display(&self.kvlm, f)
+    }
+}
+
+// Writes a commit object. Without an explicit author the identity comes from
+// user.name and user.email; the committer always does.
+pub fn create(repo: &Repository, tree: &ObjectId, parents: &[String], message: &str, author: Option<Signature>) -> Result<ObjectId, Box<WitError>> {
+    let committer = Signature::from_config(repo)?;
+    let author = author.unwrap_or_else(|| committer.clone());
+    write(repo, tree, parents, message, &author, &committer)
+}
+
+// Writes a commit object with exactly these identities
+pub fn write(repo: &Repository, tree: &ObjectId, parents: &[String], message: &str, author: &Signature, committer: &Signature) -> Result<ObjectId, Box<WitError>> {
+    let mut commit = Commit::new(Some(repo));
+    let kvlm = commit.kvlm_mut();
+    kvlm.insert("tree".to_owned(), vec![tree.to_string()]);
+    if !parents.is_empty() {
+        kvlm.insert("parent".to_owned(), parents.to_vec());
+    }
+    kvlm.insert("author".to_owned(), vec![author.to_string()]);
+    kvlm.insert("committer".to_owned(), vec![committer.to_string()]);
+    kvlm.insert("".to_owned(), vec![message.to_owned()]);
+    object::write(WitObject::CommitObject(commit), true)
+}
+
+// Commits what is staged in the index on top of HEAD and moves the current
+// branch, or a detached HEAD, to the result. A merge stopped by conflicts
+// is finished here: the commit MERGE_HEAD names becomes the second parent.
+pub fn from_index(repo: &Repository, message: &str, author: Signature, committer: Signature, allow_empty: bool) -> Result<String, Box<WitError>> {
+    if message.trim().is_empty() {
+        return Err(missing_data_err("Aborting commit due to empty commit message.".to_owned()));
+    }
+    let mut message = message.to_owned();
+    if !message.ends_with('\n') {
+        message.push('\n');
+    }
+
+    let index = Index::read(repo)?;
+    let unmerged = index.unmerged();
+    if !unmerged.is_empty() {
+        return Err(in_progress_err(format!(
+            "Committing is not possible because you have unmerged files: {}",
+            unmerged.iter().map(|path| path.display().to_string()).collect::<Vec<String>>().join(", ")
+        )));
+    }
+    let mut files = Files::new();
+    for entry in index.entries() {
+        let leaf = Leaf::new(format!("{:o}", entry.mode()), PathBuf::from(entry.path()), ObjectId::from_hex(entry.sha())?);
+        files.insert(PathBuf::from(entry.path()), leaf);
+    }
+    let tree = tree::write(repo, &files)?;
+
+    // An unborn branch gets a root commit
+    let head = reference::resolve(repo, "HEAD").ok();
+    let mut parents = head.iter().cloned().collect::<Vec<String>>();
+    let merge_head = Repository::path(repo, vec!["MERGE_HEAD"]);
+    if merge_head.is_file() {
+        parents.extend(fs::read_to_string(&merge_head)?.lines().map(|sha| sha.trim().to_owned()).filter(|sha| !sha.is_empty()));
+    }
+
+    if !allow_empty && parents.len() <= 1 {
+        let unchanged = match &head {
+            Some(head) => Some(&tree.to_string()) == read(repo, head)?.tree(),
+            None => files.is_empty()
+        };
+        if unchanged {
+            return Err(missing_data_err("Nothing to commit; stage changes first, or allow an empty commit.".to_owned()));
+        }
+    }
+
+    let sha = write(repo, &tree, &parents, &message, &author, &committer)?.to_string();
+    let kind = match (head.is_some(), parents.len() > 1) {
+        (false, _) => "commit (initial)",
+        (true, true) => "commit (merge)",
+        (true, false) => "commit"
+    };
+    let subject = message.lines().next().unwrap_or("");
+    reference::update_head(repo, &sha, &format!("{}: {}", kind, subject))?;
+
+    for name in ["MERGE_HEAD", "MERGE_MSG"] {
+        let path = Repository::path(repo, vec![name]);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(sha)
+}
+
+pub fn read<'a>(repo: &'a Repository, sha: &str) -> Result<Commit<'a>, Box<WitError>> {
+    match object::read(repo, &object::find(repo, sha, Some("commit"), true)?)? {
+        WitObject::CommitObject(commit) => Ok(commit),
+        _ => Err(malformed_object_err(format!("Object {} is not a commit", sha)))
+    }
+}
+
+// sha's parents, or none if the repository is shallow at sha
+pub fn parents(repo: &Repository, sha: &str) -> Result<Vec<String>, Box<WitError>> {
+    if repo.is_shallow(sha) {
+        return Ok(Vec::new());
+    }
+    Ok(read(repo, sha)?.parents())
+}
+
+// Every commit reachable from sha, including sha itself
+pub fn ancestors(repo: &Repository, sha: &str) -> Result<HashSet<String>, Box<WitError>> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![sha.to_owned()];
+    while let Some(sha) = stack.pop() {
+        if seen.insert(sha.clone()) {
+            stack.extend(parents(repo, &sha)?);
+        }
+    }
+    Ok(seen)
+}
+
+// Commits reachable from sha but not in `exclude`, parents before their children
+pub fn topo_order(repo: &Repository, sha: &str, exclude: &HashSet<String>) -> Result<Vec<String>, Box<WitError>> {
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = vec![(sha.to_owned(), false)];
+    while let Some((sha, expanded)) = stack.pop() {
+        if expanded {
+            order.push(sha);
+            continue;
+        }
+        if exclude.contains(&sha) || !seen.insert(sha.clone()) {
+            continue;
+        }
+        let parents = parents(repo, &sha)?;
+        stack.push((sha, true));
+        stack.extend(parents.into_iter().rev().map(|p| (p, false)));
+    }
+    Ok(order)
+}
@@ -0,0 +1,389 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{WitError, builder::*};
+
+#[derive(Debug, Clone)]
+enum Line {
+    // Comments, blank lines and anything else kept exactly as written
+    Raw(String),
+    Section {
+        name: String,
+        subsection: Option<String>,
+        raw: Option<String>,
+    },
+    // raw is None once the entry has been changed and must be regenerated
+    Entry {
+        section: String,
+        subsection: Option<String>,
+        key: String,
+        value: String,
+        raw: Option<String>,
+    },
+}
+
+// One config file in git's INI dialect. Untouched lines are written back
+// as they were read, so comments and ordering survive a rewrite.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    lines: Vec<Line>,
+}
+
+// Lookups shared by a single file and a stack of them
+pub trait ConfigGet {
+    // The last value for section[.subsection].key
+    fn get(&self, key: &str) -> Option<&str>;
+
+    fn get_str(&self, key: &str) -> Option<String> {
+        self.get(key).map(|value| value.to_owned())
+    }
+
+    fn get_bool(&self, key: &str) -> Result<Option<bool>, Box<WitError>> {
+        self.get(key).map(parse_bool).transpose()
+    }
+
+    fn get_int(&self, key: &str) -> Result<Option<i64>, Box<WitError>> {
+        self.get(key).map(parse_int).transpose()
+    }
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, Box<WitError>> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" | "" => Ok(false),
+        _ => Err(malformed_object_err(format!("Bad boolean config value '{}'", value)))
+    }
+}
+
+// Integers may have a k, m or g suffix
+pub fn parse_int(value: &str) -> Result<i64, Box<WitError>> {
+    let value = value.trim();
+    let (number, scale) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1)
+    };
+    number.parse::<i64>()?.checked_mul(scale).ok_or(
+        malformed_object_err(format!("Config value '{}' is out of range", value))
+    )
+}
+
+// Splits "section.key" or "section.sub.section.key"; the subsection is
+// everything between the first and last dot
+fn split_key(key: &str) -> Result<(String, Option<String>, String), Box<WitError>> {
+    let bad_key = || malformed_object_err(format!("Invalid config key '{}'", key));
+    let first = key.find('.').ok_or_else(bad_key)?;
+    let last = key.rfind('.').ok_or_else(bad_key)?;
+    let section = key[..first].to_lowercase();
+    let name = key[last + 1..].to_lowercase();
+    if section.is_empty() || name.is_empty() {
+        return Err(bad_key());
+    }
+    let subsection = if first == last { None } else { Some(key[first + 1..last].to_owned()) };
+    Ok((section, subsection, name))
+}
+
+fn parse_section(line: &str) -> Result<(String, Option<String>), Box<WitError>> {
+    let bad_header = || malformed_object_err(format!("Bad config section header {}", line));
+    let inner = line.trim().strip_prefix('[').ok_or_else(bad_header)?;
+    let end = inner.rfind(']').ok_or_else(bad_header)?;
+    let inner = &inner[..end];
+
+    match inner.find(|c: char| c.is_whitespace()) {
+        Some(space) => {
+            let quoted = inner[space..].trim();
+            let quoted = quoted.strip_prefix('"').and_then(|q| q.strip_suffix('"')).ok_or_else(bad_header)?;
+            let mut subsection = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => subsection.extend(chars.next()),
+                    c => subsection.push(c)
+                }
+            }
+            Ok((inner[..space].to_lowercase(), Some(subsection)))
+        },
+        // The old [section.subsection] form, where the subsection is not case sensitive
+        None => match inner.split_once('.') {
+            Some((name, sub)) => Ok((name.to_lowercase(), Some(sub.to_lowercase()))),
+            None => Ok((inner.to_lowercase(), None))
+        }
+    }
+}
+
+// Parses the value part of an entry: quotes, escapes, comments and
+// whitespace. Returns None if the value continues on the next line.
+fn parse_value(raw: &str) -> Result<Option<String>, Box<WitError>> {
+    let mut value = String::new();
+    let mut quoted = false;
+    // Whitespace is only kept if something follows it
+    let mut pending = String::new();
+    let mut chars = raw.trim_start().chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => break,
+            '"' => {
+                value.push_str(&pending);
+                pending.clear();
+                quoted = !quoted;
+            },
+            '\\' => {
+                value.push_str(&pending);
+                pending.clear();
+                match chars.next() {
+                    None | Some('\n') => return Ok(None),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => { value.pop(); },
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some(c) => return Err(malformed_object_err(format!("Bad escape \\{} in config value", c)))
+                }
+            },
+            '#' | ';' if !quoted => break,
+            c if c.is_whitespace() && !quoted => pending.push(c),
+            c => {
+                value.push_str(&pending);
+                pending.clear();
+                value.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(malformed_object_err(format!("Unterminated quote in config value {}", raw)));
+    }
+    Ok(Some(value))
+}
+
+fn quote_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    let mut out = String::new();
+    for c in value.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c)
+        }
+    }
+    if needs_quotes {
+        format!("\"{}\"", out)
+    } else {
+        out
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config { lines: Vec::new() }
+    }
+
+    pub fn read(path: &Path) -> Result<Config, Box<WitError>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, Box<WitError>> {
+        let mut lines = Vec::new();
+        let mut section = String::new();
+        let mut subsection = None;
+        let mut raw_lines = text.split_inclusive('\n');
+
+        while let Some(line) = raw_lines.next() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                lines.push(Line::Raw(line.to_owned()));
+            } else if trimmed.starts_with('[') {
+                (section, subsection) = parse_section(trimmed)?;
+                lines.push(Line::Section { name: section.clone(), subsection: subsection.clone(), raw: Some(line.to_owned()) });
+            } else {
+                if section.is_empty() {
+                    return Err(malformed_object_err(format!("Config entry outside of a section: {}", trimmed)));
+                }
+                let (key, rest) = match trimmed.find('=') {
+                    Some(eq) => (trimmed[..eq].trim(), Some(&line[line.find('=').unwrap_or(0) + 1..])),
+                    None => (trimmed, None)
+                };
+                if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                    return Err(malformed_object_err(format!("Invalid config key '{}'", key)));
+                }
+
+                let mut raw = line.to_owned();
+                let value = match rest {
+                    // A bare key means true
+                    None => "true".to_owned(),
+                    Some(rest) => {
+                        let mut rest = rest.to_owned();
+                        loop {
+                            match parse_value(&rest)? {
+                                Some(value) => break value,
+                                None => {
+                                    // Backslash-newline continues the value on the next line
+                                    let next = raw_lines.next().unwrap_or("");
+                                    raw += next;
+                                    let line = rest.trim_end_matches(['\n', '\r']);
+                                    rest = line.strip_suffix('\\').unwrap_or(line).to_owned() + next;
+                                    if next.is_empty() {
+                                        break parse_value(&rest)?.unwrap_or_default();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                };
+                lines.push(Line::Entry {
+                    section: section.clone(),
+                    subsection: subsection.clone(),
+                    key: key.to_lowercase(),
+                    value,
+                    raw: Some(raw)
+                });
+            }
+        }
+        Ok(Config { lines })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<WitError>> {
+        fs::write(path, self.serialize())?;
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        for line in &self.lines {
+            match line {
+                Line::Raw(raw) | Line::Section { raw: Some(raw), .. } | Line::Entry { raw: Some(raw), .. } => {
+                    out += raw;
+                    if !raw.ends_with('\n') {
+                        out += "\n";
+                    }
+                },
+                Line::Section { name, subsection: None, raw: None } => out += &format!("[{}]\n", name),
+                Line::Section { name, subsection: Some(sub), raw: None } => {
+                    out += &format!("[{} \"{}\"]\n", name, sub.replace('\\', "\\\\").replace('"', "\\\""));
+                },
+                Line::Entry { key, value, raw: None, .. } => out += &format!("\t{} = {}\n", key, quote_value(value))
+            }
+        }
+        out
+    }
+
+    fn matches(line: &Line, section: &str, subsection: &Option<String>, key: Option<&str>) -> bool {
+        match line {
+            Line::Section { name, subsection: sub, .. } => key.is_none() && name == section && sub == subsection,
+            Line::Entry { section: name, subsection: sub, key: entry_key, .. } => {
+                name == section && sub == subsection && key.map(|key| key == entry_key).unwrap_or(true)
+            },
+            Line::Raw(_) => false
+        }
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let Ok((section, subsection, name)) = split_key(key) else {
+            return Vec::new();
+        };
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry { value, .. } if Self::matches(line, &section, &subsection, Some(&name)) => Some(value.as_str()),
+            _ => None
+        }).collect()
+    }
+
+    // The distinct subsection names of section, in file order
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let section = section.to_lowercase();
+        let mut names: Vec<String> = Vec::new();
+        for line in &self.lines {
+            if let Line::Section { name, subsection: Some(sub), .. } | Line::Entry { section: name, subsection: Some(sub), .. } = line {
+                if *name == section && !names.contains(sub) {
+                    names.push(sub.clone());
+                }
+            }
+        }
+        names
+    }
+
+    // Replaces the last value for key, or adds it to the end of its section
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<WitError>> {
+        let (section, subsection, name) = split_key(key)?;
+        if let Some(Line::Entry { value: old, raw, .. }) = self.lines.iter_mut().rev()
+            .find(|line| Self::matches(line, &section, &subsection, Some(&name)))
+        {
+            *old = value.to_owned();
+            *raw = None;
+            return Ok(());
+        }
+
+        let entry = Line::Entry { section: section.clone(), subsection: subsection.clone(), key: name, value: value.to_owned(), raw: None };
+        let last_in_section = self.lines.iter().rposition(|line| Self::matches(line, &section, &subsection, None));
+        match last_in_section {
+            Some(pos) => self.lines.insert(pos + 1, entry),
+            None => {
+                self.lines.push(Line::Section { name: section, subsection, raw: None });
+                self.lines.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes every value for key
+    pub fn unset(&mut self, key: &str) -> Result<(), Box<WitError>> {
+        let (section, subsection, name) = split_key(key)?;
+        self.lines.retain(|line| !(matches!(line, Line::Entry { .. }) && Self::matches(line, &section, &subsection, Some(&name))));
+        Ok(())
+    }
+}
+
+impl ConfigGet for Config {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).pop()
+    }
+}
+
+// Several config files, later ones overriding earlier ones
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSet {
+    layers: Vec<Config>,
+}
+
+impl ConfigSet {
+    // Just the global config, for when there is no repository yet
+    pub fn user() -> Result<ConfigSet, Box<WitError>> {
+        let mut layers = Vec::new();
+        for path in Self::user_paths() {
+            if path.is_file() {
+                layers.push(Config::read(&path)?);
+            }
+        }
+        Ok(ConfigSet { layers })
+    }
+
+    // Adds a layer that overrides every one before it
+    pub fn push(&mut self, layer: Config) {
+        self.layers.push(layer);
+    }
+
+    // ~/.config/git/config, then ~/.gitconfig, like git's global config
+    pub fn user_paths() -> Vec<PathBuf> {
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let xdg = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(".config")));
+        xdg.map(|xdg| xdg.join("git").join("config")).into_iter()
+            .chain(home.map(|home| home.join(".gitconfig")))
+            .collect()
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.layers.iter().flat_map(|layer| layer.get_all(key)).collect()
+    }
+}
+
+impl ConfigGet for ConfigSet {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.layers.iter().rev().find_map(|layer| layer.get(key))
+    }
+}
@@ -0,0 +1,110 @@
+// Logins for HTTP remotes, from whatever credential.helper names. Helpers
+// speak git's credential protocol: the action as an argument, then
+// key=value lines describing the remote on stdin and the login on stdout.
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{
+    config::{parse_bool, ConfigGet, ConfigSet},
+    error::{WitError, builder::*}
+};
+
+pub struct Credential {
+    helpers: Vec<String>,
+    // Whether helpers are told the path, so logins can differ per repository
+    use_path: bool,
+}
+
+impl Credential {
+    pub fn new(config: &ConfigSet) -> Result<Credential, Box<WitError>> {
+        let mut helpers = Vec::new();
+        for helper in config.get_all("credential.helper") {
+            // An empty value drops the helpers configured before it
+            if helper.is_empty() {
+                helpers.clear();
+            } else {
+                helpers.push(helper.to_owned());
+            }
+        }
+        let use_path = config.get_bool("credential.usehttppath")?.unwrap_or(false);
+        Ok(Credential { helpers, use_path })
+    }
+
+    // The username and password from the first helper that has both
+    pub fn get(&self, protocol: &str, host: &str, path: &str) -> Result<(String, String), Box<WitError>> {
+        let description = self.describe(protocol, host, path, None);
+        for helper in &self.helpers {
+            let (mut username, mut password) = (None, None);
+            for line in run(helper, "get", &description)?.lines() {
+                match line.split_once('=') {
+                    Some(("username", value)) => username = Some(value.to_owned()),
+                    Some(("password", value)) => password = Some(value.to_owned()),
+                    Some(("quit", value)) if parse_bool(value).unwrap_or(false) => {
+                        return Err(missing_data_err(format!("Credential helper {} refused to log in to {}", helper, host)));
+                    },
+                    _ => ()
+                }
+            }
+            if let (Some(username), Some(password)) = (username, password) {
+                return Ok((username, password));
+            }
+        }
+        Err(missing_data_err(format!("No credentials for {}://{}; configure credential.helper", protocol, host)))
+    }
+
+    // Tells every helper the login worked, so they can remember it
+    pub fn store(&self, protocol: &str, host: &str, path: &str, username: &str, password: &str) -> Result<(), Box<WitError>> {
+        let description = self.describe(protocol, host, path, Some((username, password)));
+        for helper in &self.helpers {
+            run(helper, "store", &description)?;
+        }
+        Ok(())
+    }
+
+    // Tells every helper the login was refused, so they forget it
+    pub fn erase(&self, protocol: &str, host: &str, path: &str, username: &str, password: &str) -> Result<(), Box<WitError>> {
+        let description = self.describe(protocol, host, path, Some((username, password)));
+        for helper in &self.helpers {
+            run(helper, "erase", &description)?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self, protocol: &str, host: &str, path: &str, login: Option<(&str, &str)>) -> String {
+        let mut description = format!("protocol={}\nhost={}\n", protocol, host);
+        let path = path.trim_start_matches('/');
+        if self.use_path && !path.is_empty() {
+            description += &format!("path={}\n", path);
+        }
+        if let Some((username, password)) = login {
+            description += &format!("username={}\npassword={}\n", username, password);
+        }
+        description + "\n"
+    }
+}
+
+// Runs a helper the way git does: "!cmd" is a shell snippet, an absolute
+// path is run as is, and anything else is git credential-<helper>. A helper
+// that fails just has nothing to say.
+fn run(helper: &str, action: &str, input: &str) -> Result<String, Box<WitError>> {
+    let program = helper.split_whitespace().next().unwrap_or("");
+    let command = match helper.strip_prefix('!') {
+        Some(command) => command.to_owned(),
+        None if Path::new(program).is_absolute() => helper.to_owned(),
+        None => format!("git credential-{}", helper)
+    };
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} {}", command, action))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // A helper may exit without reading its input
+    let _ = child.stdin.take().ok_or(other_err("No stdin for the credential helper".to_owned()))?.write_all(input.as_bytes());
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(String::new());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
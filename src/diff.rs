@@ -0,0 +1,514 @@
+use crate::{
+    blob,
+    error::{WitError, builder::*},
+    repository::Repository,
+    tree::{self, ChangeKind, DiffEntry}
+};
+
+// Same heuristic as git: a NUL byte in the first 8000 bytes means binary
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|byte| *byte == 0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    // (old line, new line)
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+    // Each line is prefixed with ' ', '-' or '+'. They are kept as bytes so
+    // content in other encodings comes through untouched.
+    pub lines: Vec<Vec<u8>>,
+}
+
+// Splits content into lines, keeping the newline on each line
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&data[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+// Myers' O(ND) shortest edit script. Only the diagonals -d..=d can have been
+// reached after d steps, so that is all the trace keeps of each one, which
+// holds it to O(D^2) rather than O(D(N+M)).
+pub fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max as usize + 1;
+    let mut v = vec![0isize; 2 * offset + 1];
+    let mut trace = Vec::new();
+
+    'outer: for d in 0..=max {
+        let start = offset - d as usize;
+        trace.push(v[start..=start + 2 * d as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, window) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        // Diagonals outside the window were never reached, so are still 0
+        let v = |k: isize| window.get((k + d) as usize).copied().filter(|_| k >= -d).unwrap_or(0);
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v(k - 1) < v(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = v(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal(x as usize - 1, y as usize - 1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(y as usize - 1));
+            } else {
+                edits.push(Edit::Delete(x as usize - 1));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    edits.reverse();
+    edits
+}
+
+// Groups an edit script into hunks with `context` unchanged lines around each change
+pub fn hunks(old: &[&[u8]], new: &[&[u8]], edits: &[Edit], context: usize) -> Vec<Hunk> {
+    let changes = edits.iter().enumerate()
+        .filter(|(_, e)| !matches!(e, Edit::Equal(_, _)))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
+    // Ranges of edits [start, end) that make up each hunk
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for i in changes {
+        let start = i.saturating_sub(context);
+        let end = (i + context + 1).min(edits.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end))
+        }
+    }
+
+    // Line numbers before each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut o, mut n) = (0, 0);
+    for edit in edits {
+        positions.push((o, n));
+        match edit {
+            Edit::Equal(_, _) => { o += 1; n += 1; },
+            Edit::Delete(_) => o += 1,
+            Edit::Insert(_) => n += 1
+        }
+    }
+    positions.push((o, n));
+
+    ranges.into_iter().map(|(start, end)| {
+        let mut lines = Vec::new();
+        for edit in &edits[start..end] {
+            let (prefix, line) = match *edit {
+                Edit::Equal(i, _) => (b' ', old[i]),
+                Edit::Delete(i) => (b'-', old[i]),
+                Edit::Insert(j) => (b'+', new[j])
+            };
+            let mut prefixed = vec![prefix];
+            prefixed.extend_from_slice(line);
+            lines.push(prefixed);
+        }
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        Hunk {
+            old_start: old_start + 1,
+            old_count: old_end - old_start,
+            new_start: new_start + 1,
+            new_count: new_end - new_start,
+            lines
+        }
+    }).collect()
+}
+
+fn range(start: usize, count: usize) -> String {
+    match count {
+        // An empty range names the line before it
+        0 => format!("{},0", start - 1),
+        1 => format!("{}", start),
+        _ => format!("{},{}", start, count)
+    }
+}
+
+impl Hunk {
+    pub fn header(&self) -> String {
+        format!("@@ -{} +{} @@", range(self.old_start, self.old_count), range(self.new_start, self.new_count))
+    }
+}
+
+pub fn unified(old: &[u8], new: &[u8], old_path: &str, new_path: &str, context: usize) -> Vec<u8> {
+    if old == new {
+        return Vec::new();
+    }
+    if is_binary(old) || is_binary(new) {
+        return format!("Binary files {} and {} differ\n", old_path, new_path).into_bytes();
+    }
+
+    let (old_lines, new_lines) = (lines(old), lines(new));
+    let edits = myers(&old_lines, &new_lines);
+    let hunks = hunks(&old_lines, &new_lines, &edits, context);
+    if hunks.is_empty() {
+        return Vec::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_path, new_path).into_bytes();
+    for hunk in hunks {
+        out.extend(hunk.header().as_bytes());
+        out.push(b'\n');
+        for line in hunk.lines {
+            out.extend(&line);
+            if !line.ends_with(b"\n") {
+                out.extend(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+// The "diff --git" section for one changed path
+pub fn entry_patch(repo: &Repository, entry: &DiffEntry, context: usize) -> Result<Vec<u8>, Box<WitError>> {
+    let name = entry.path.to_str().ok_or(path_conversion_err())?;
+    let old_data = match &entry.old_sha { Some(sha) => blob::read(repo, sha)?.data().clone(), None => Vec::new() };
+    let new_data = match &entry.new_sha { Some(sha) => blob::read(repo, sha)?.data().clone(), None => Vec::new() };
+    let zero = "0".repeat(repo.object_format().hex_len());
+    let old_sha = entry.old_sha.map(|sha| sha.to_string()).unwrap_or(zero.clone());
+    let new_sha = entry.new_sha.map(|sha| sha.to_string()).unwrap_or(zero);
+    let old_mode = entry.old_mode.as_deref().unwrap_or("");
+    let new_mode = entry.new_mode.as_deref().unwrap_or("");
+
+    // A rename reads from its old path
+    let old_name = match &entry.kind {
+        ChangeKind::Renamed { from, .. } => from.to_str().ok_or(path_conversion_err())?,
+        _ => name
+    };
+
+    let mut patch = format!("diff --git a/{} b/{}\n", old_name, name);
+    match &entry.kind {
+        ChangeKind::Added => {
+            patch += &format!("new file mode {}\n", new_mode);
+            patch += &format!("index {}..{}\n", &old_sha[..7], &new_sha[..7]);
+        },
+        ChangeKind::Deleted => {
+            patch += &format!("deleted file mode {}\n", old_mode);
+            patch += &format!("index {}..{}\n", &old_sha[..7], &new_sha[..7]);
+        },
+        ChangeKind::Modified | ChangeKind::ModeChanged => {
+            if old_mode != new_mode {
+                patch += &format!("old mode {}\nnew mode {}\n", old_mode, new_mode);
+            }
+            if entry.kind == ChangeKind::Modified {
+                patch += &format!("index {}..{}", &old_sha[..7], &new_sha[..7]);
+                if old_mode == new_mode {
+                    patch += &format!(" {}", new_mode);
+                }
+                patch += "\n";
+            }
+        },
+        ChangeKind::Renamed { similarity, .. } => {
+            if old_mode != new_mode {
+                patch += &format!("old mode {}\nnew mode {}\n", old_mode, new_mode);
+            }
+            patch += &format!("similarity index {}%\nrename from {}\nrename to {}\n", similarity, old_name, name);
+            if old_sha != new_sha {
+                patch += &format!("index {}..{}", &old_sha[..7], &new_sha[..7]);
+                if old_mode == new_mode {
+                    patch += &format!(" {}", new_mode);
+                }
+                patch += "\n";
+            }
+        }
+    }
+
+    let mut patch = patch.into_bytes();
+    patch.extend(unified(
+        &old_data,
+        &new_data,
+        &(if entry.old_sha.is_some() { format!("a/{}", old_name) } else { "/dev/null".to_owned() }),
+        &(if entry.new_sha.is_some() { format!("b/{}", name) } else { "/dev/null".to_owned() }),
+        context
+    ));
+    Ok(patch)
+}
+
+// A full patch between two trees; with no old tree every file shows as added
+pub fn tree_patch(repo: &Repository, old_tree: Option<&str>, new_tree: &str) -> Result<Vec<u8>, Box<WitError>> {
+    let mut patch = Vec::new();
+    for entry in tree::diff(repo, old_tree, new_tree)? {
+        patch.extend(entry_patch(repo, &entry, 3)?);
+    }
+    Ok(patch)
+}
+
+// Parses the "@@ -l,c +l,c @@" header of a hunk
+pub fn parse_hunk_header(header: &str) -> Result<Hunk, Box<WitError>> {
+    let bad_header = || malformed_object_err(format!("Malformed hunk header {}", header));
+    let ranges = header.strip_prefix("@@ ").and_then(|h| h.split(" @@").next()).ok_or_else(bad_header)?;
+    let mut ranges = ranges.split(' ');
+
+    let mut parse_range = |prefix: char| -> Result<(usize, usize), Box<WitError>> {
+        let range = ranges.next().and_then(|r| r.strip_prefix(prefix)).ok_or_else(bad_header)?;
+        let mut parts = range.split(',');
+        let start = parts.next().ok_or_else(bad_header)?.parse::<usize>()?;
+        let count = match parts.next() {
+            Some(count) => count.parse::<usize>()?,
+            None => 1
+        };
+        // Empty ranges name the line before them
+        Ok((if count == 0 { start + 1 } else { start }, count))
+    };
+
+    let (old_start, old_count) = parse_range('-')?;
+    let (new_start, new_count) = parse_range('+')?;
+    Ok(Hunk { old_start, old_count, new_start, new_count, lines: Vec::new() })
+}
+
+// Applies hunks to content; context and removed lines must match exactly
+pub fn apply(old: &[u8], hunks: &[Hunk]) -> Result<Vec<u8>, Box<WitError>> {
+    let old_lines = lines(old);
+    let mut result = Vec::new();
+    let mut pos = 0;
+
+    for hunk in hunks {
+        let start = hunk.old_start - 1;
+        if start < pos || start > old_lines.len() {
+            return Err(malformed_object_err(format!("Hunk {} is out of range", hunk.header())));
+        }
+        for line in &old_lines[pos..start] {
+            result.extend_from_slice(line);
+        }
+        pos = start;
+
+        for line in &hunk.lines {
+            let (prefix, content) = line.split_at(1.min(line.len()));
+            match prefix {
+                b" " | b"-" => {
+                    if old_lines.get(pos).copied() != Some(content) {
+                        return Err(malformed_object_err(format!("Hunk {} does not apply", hunk.header())));
+                    }
+                    if prefix == b" " {
+                        result.extend_from_slice(content);
+                    }
+                    pos += 1;
+                },
+                b"+" => result.extend_from_slice(content),
+                _ => return Err(malformed_object_err(format!("Malformed hunk line {}", String::from_utf8_lossy(line))))
+            }
+        }
+    }
+
+    for line in &old_lines[pos..] {
+        result.extend_from_slice(line);
+    }
+    Ok(result)
+}
+
+// Line-level three-way merge. Returns the merged content and whether any
+// chunk conflicted; conflicting chunks are written between markers.
+pub fn merge3(base: &[u8], ours: &[u8], theirs: &[u8], ours_label: &str, theirs_label: &str) -> (Vec<u8>, bool) {
+    let (base, ours, theirs) = (lines(base), lines(ours), lines(theirs));
+    let ours_match = matches(&base, &ours);
+    let theirs_match = matches(&base, &theirs);
+
+    let mut merged = Vec::new();
+    let mut conflicted = false;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    while i < base.len() || j < ours.len() || k < theirs.len() {
+        // Lines all three agree on are copied through
+        if i < base.len() && ours_match[i] == Some(j) && theirs_match[i] == Some(k) {
+            merged.extend(base[i]);
+            i += 1;
+            j += 1;
+            k += 1;
+            continue;
+        }
+
+        // Otherwise everything up to the next line all three agree on is one chunk
+        let (end_i, end_j, end_k) = (i..base.len())
+            .find_map(|x| match (ours_match[x], theirs_match[x]) {
+                (Some(y), Some(z)) => Some((x, y, z)),
+                _ => None
+            })
+            .unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (old, mine, other) = (&base[i..end_i], &ours[j..end_j], &theirs[k..end_k]);
+        if mine == old || mine == other {
+            other.iter().for_each(|line| merged.extend(*line));
+        } else if other == old {
+            mine.iter().for_each(|line| merged.extend(*line));
+        } else {
+            conflicted = true;
+            merged.extend(format!("<<<<<<< {}\n", ours_label).as_bytes());
+            append_lines(&mut merged, mine);
+            merged.extend(b"=======\n");
+            append_lines(&mut merged, other);
+            merged.extend(format!(">>>>>>> {}\n", theirs_label).as_bytes());
+        }
+        (i, j, k) = (end_i, end_j, end_k);
+    }
+    (merged, conflicted)
+}
+
+// For each line of a, the line of b it is kept as, if any
+fn matches(a: &[&[u8]], b: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; a.len()];
+    for edit in myers(a, b) {
+        if let Edit::Equal(x, y) = edit {
+            matched[x] = Some(y);
+        }
+    }
+    matched
+}
+
+// Lines inside conflict markers, with a newline ending the last one so the
+// next marker starts on its own line
+fn append_lines(data: &mut Vec<u8>, lines: &[&[u8]]) {
+    lines.iter().for_each(|line| data.extend(*line));
+    if !lines.is_empty() && !data.ends_with(b"\n") {
+        data.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    fn text(hunk: &Hunk) -> String {
+        hunk.lines.iter().map(|line| String::from_utf8_lossy(line)).collect()
+    }
+
+    // What git diff prints from the first hunk on
+    fn git_diff(old: &[u8], new: &[u8]) -> String {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("old"), old).unwrap();
+        fs::write(dir.path().join("new"), new).unwrap();
+        let out = test_util::git(dir.path(), &["diff", "--no-index", "--no-color", "--no-indent-heuristic", "old", "new"]);
+        out[out.find("@@").unwrap_or(out.len())..].to_owned()
+    }
+
+    fn ours(old: &[u8], new: &[u8]) -> String {
+        let out = String::from_utf8(unified(old, new, "a/old", "b/new", 3)).unwrap();
+        out[out.find("@@").unwrap_or(out.len())..].to_owned()
+    }
+
+    #[test]
+    fn myers_finds_a_shortest_script() {
+        let (a, b) = (b"abcabba".to_vec(), b"cbabac".to_vec());
+        let edits = myers(&a, &b);
+        assert_eq!(edits.iter().filter(|edit| !matches!(edit, Edit::Equal(_, _))).count(), 5);
+        // Replaying the script gives b back
+        let replayed = edits.iter().filter_map(|edit| match *edit {
+            Edit::Equal(i, _) => Some(a[i]),
+            Edit::Insert(j) => Some(b[j]),
+            Edit::Delete(_) => None
+        }).collect::<Vec<u8>>();
+        assert_eq!(replayed, b);
+        assert!(myers::<u8>(&[], &[]).is_empty());
+        assert_eq!(myers(&[1], &[]), vec![Edit::Delete(0)]);
+    }
+
+    #[test]
+    fn hunks_share_context() {
+        let old = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+        let new = (1..=20).map(|i| match i {
+            3 => "three\n".to_owned(),
+            8 => "eight\n".to_owned(),
+            19 => "nineteen\n".to_owned(),
+            i => format!("{}\n", i)
+        }).collect::<String>();
+        let (old_lines, new_lines) = (lines(old.as_bytes()), lines(new.as_bytes()));
+        let hunks = hunks(&old_lines, &new_lines, &myers(&old_lines, &new_lines), 3);
+        // 3 and 8 are close enough for their context to overlap, 19 is not
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].header(), "@@ -1,11 +1,11 @@");
+        assert_eq!(hunks[1].header(), "@@ -16,5 +16,5 @@");
+        assert!(text(&hunks[1]).starts_with(" 16\n 17\n 18\n-19\n+nineteen\n"));
+    }
+
+    #[test]
+    fn unified_matches_git() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"a\nb\nc\n", b"a\nB\nc\n"),
+            (b"", b"new\nfile\n"),
+            (b"gone\n", b""),
+            (b"1\n2\n3\n4\n5\n6\n7\n8\n9\n", b"0\n1\n2\n3\n5\n6\n7\n8\n9\n10\n"),
+            (b"end", b"end\nmore\n"),
+            (b"a\nend\n", b"a\nend"),
+            (b"x", b"y"),
+        ];
+        for (old, new) in cases {
+            assert_eq!(ours(old, new), git_diff(old, new), "{:?} -> {:?}", String::from_utf8_lossy(old), String::from_utf8_lossy(new));
+        }
+    }
+
+    #[test]
+    fn bytes_pass_through_unchanged() {
+        let (old, new) = (b"caf\xe9\nsame\n".to_vec(), b"cr\xe8me\nsame\n".to_vec());
+        let patch = unified(&old, &new, "a/f", "b/f", 3);
+        assert!(patch.windows(6).any(|window| window == b"-caf\xe9\n"));
+        let (old_lines, new_lines) = (lines(&old), lines(&new));
+        let hunks = hunks(&old_lines, &new_lines, &myers(&old_lines, &new_lines), 3);
+        assert_eq!(apply(&old, &hunks).unwrap(), new);
+    }
+
+    #[test]
+    fn binary_and_identical_content() {
+        assert!(unified(b"same\n", b"same\n", "a/f", "b/f", 3).is_empty());
+        assert_eq!(unified(b"\0bin", b"text\n", "a/f", "b/f", 3), b"Binary files a/f and b/f differ\n");
+    }
+
+    #[test]
+    fn apply_checks_context() {
+        let old = b"one\ntwo\nthree\n";
+        let (old_lines, new_lines) = (lines(old), lines(b"one\n2\nthree\n"));
+        let hunks = hunks(&old_lines, &new_lines, &myers(&old_lines, &new_lines), 1);
+        assert_eq!(apply(old, &hunks).unwrap(), b"one\n2\nthree\n");
+        assert!(apply(b"one\nthree\n", &hunks).is_err());
+
+        let header = parse_hunk_header("@@ -3,0 +4,2 @@ fn main").unwrap();
+        assert_eq!((header.old_start, header.old_count, header.new_start, header.new_count), (4, 0, 4, 2));
+        assert_eq!(header.header(), "@@ -3,0 +4,2 @@");
+        assert!(parse_hunk_header("@@ -x +1 @@").is_err());
+    }
+}
@@ -2,53 +2,195 @@ use std::array::TryFromSliceError;
 use std::fmt::Display;
 use std::error::Error;
 use std::num::ParseIntError;
+use std::path::PathBuf;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
-use self::WitErrorType::*;
-
-#[derive(Debug, Clone)]
-pub enum WitErrorType {
-    DebugError,
-    IOError,
-    RepoCreationError,
-    InheritedError,
-    VersionMismatchError,
-    MalformedObjectError,
-    UnknownObjectTypeError,
-    PathConversionError,
-    CLIArgumentError,
-    CLIUnknownCommandError,
-    CLINoCommandError,
-    UTF8ConversionError,
-    InvalidModeLengthError,
-    RepoNotFoundError,
-    AmbiguousReferenceError,
-    UnknownReferenceError,
-    MissingDataError,
-    NotADirectoryError,
-    DirectoryNotEmptyError,
+use crate::object_id::ObjectId;
+
+// Every failure in the crate, with whatever data the caller might need
+#[derive(Debug)]
+pub enum WitError {
+    Debug,
+    IoError(std::io::Error),
+    // Anything without a variant of its own, described by its message
+    Other(String),
+    RepoCreation(String),
+    VersionMismatch(String),
+    MalformedObject(String),
+    UnknownObject(String),
+    PathConversion,
+    CliArgument(String),
+    CliUnknownCommand(String),
+    CliNoCommand,
+    Utf8(String),
+    Utf8Error(Utf8Error),
+    FromUtf8Error(FromUtf8Error),
+    ParseIntError(ParseIntError),
+    TryFromSliceError(TryFromSliceError),
+    RegexError(regex::Error),
+    InvalidModeLength(usize),
+    RepoNotFound(String),
+    AmbiguousReference { name: String, candidates: Vec<ObjectId> },
+    UnknownReference(String),
+    // A symbolic ref points at a branch with no commits yet
+    UnbornBranch(String),
+    MissingData(String),
+    NotADirectory(PathBuf),
+    DirectoryNotEmpty(PathBuf),
+    // Another multi-step operation has to finish first
+    OperationInProgress(String),
+    // Someone else holds the lock file at this path
+    LockHeld(PathBuf),
+    // Local changes to these paths would be lost
+    UncommittedChanges(Vec<PathBuf>),
+    // A hook that can veto an operation exited unsuccessfully
+    HookFailed { name: String, code: Option<i32> },
+    // A compare-and-swap ref update found something other than expected
+    ReferenceMismatch { name: String, expected: Option<String>, actual: Option<String> },
+    // A ref name git check-ref-format would refuse
+    InvalidReference(String),
 }
 
-impl Display for WitErrorType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
+// What went wrong, for callers that only care about the broad category and
+// not the data a particular variant carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WitErrorKind {
+    Debug,
+    Io,
+    Other,
+    RepoCreation,
+    VersionMismatch,
+    MalformedObject,
+    UnknownObject,
+    PathConversion,
+    Cli,
+    Utf8,
+    // A number or fixed size field that could not be parsed
+    Parse,
+    Regex,
+    RepoNotFound,
+    AmbiguousReference,
+    UnknownReference,
+    UnbornBranch,
+    MissingData,
+    NotADirectory,
+    DirectoryNotEmpty,
+    OperationInProgress,
+    LockHeld,
+    UncommittedChanges,
+    HookFailed,
+    ReferenceMismatch,
+    InvalidReference,
 }
 
-#[derive(Debug)]
-pub struct WitError {
-    error: WitErrorType,
-    message: String,
+impl WitError {
+    pub fn kind(&self) -> WitErrorKind {
+        match self {
+            WitError::Debug => WitErrorKind::Debug,
+            WitError::IoError(_) => WitErrorKind::Io,
+            WitError::Other(_) => WitErrorKind::Other,
+            WitError::RepoCreation(_) => WitErrorKind::RepoCreation,
+            WitError::VersionMismatch(_) => WitErrorKind::VersionMismatch,
+            WitError::MalformedObject(_) | WitError::InvalidModeLength(_) => WitErrorKind::MalformedObject,
+            WitError::UnknownObject(_) => WitErrorKind::UnknownObject,
+            WitError::PathConversion => WitErrorKind::PathConversion,
+            WitError::CliArgument(_) | WitError::CliUnknownCommand(_) | WitError::CliNoCommand => WitErrorKind::Cli,
+            WitError::Utf8(_) | WitError::Utf8Error(_) | WitError::FromUtf8Error(_) => WitErrorKind::Utf8,
+            WitError::ParseIntError(_) | WitError::TryFromSliceError(_) => WitErrorKind::Parse,
+            WitError::RegexError(_) => WitErrorKind::Regex,
+            WitError::RepoNotFound(_) => WitErrorKind::RepoNotFound,
+            WitError::AmbiguousReference { .. } => WitErrorKind::AmbiguousReference,
+            WitError::UnknownReference(_) => WitErrorKind::UnknownReference,
+            WitError::UnbornBranch(_) => WitErrorKind::UnbornBranch,
+            WitError::MissingData(_) => WitErrorKind::MissingData,
+            WitError::NotADirectory(_) => WitErrorKind::NotADirectory,
+            WitError::DirectoryNotEmpty(_) => WitErrorKind::DirectoryNotEmpty,
+            WitError::OperationInProgress(_) => WitErrorKind::OperationInProgress,
+            WitError::LockHeld(_) => WitErrorKind::LockHeld,
+            WitError::UncommittedChanges(_) => WitErrorKind::UncommittedChanges,
+            WitError::HookFailed { .. } => WitErrorKind::HookFailed,
+            WitError::ReferenceMismatch { .. } => WitErrorKind::ReferenceMismatch,
+            WitError::InvalidReference(_) => WitErrorKind::InvalidReference,
+        }
+    }
+
+    // Everything an ambiguous name could refer to
+    pub fn candidates(&self) -> &[ObjectId] {
+        match self {
+            WitError::AmbiguousReference { candidates, .. } => candidates,
+            _ => &[]
+        }
+    }
 }
 
-impl Error for WitError {}
+impl Error for WitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WitError::IoError(err) => Some(err),
+            WitError::Utf8Error(err) => Some(err),
+            WitError::FromUtf8Error(err) => Some(err),
+            WitError::ParseIntError(err) => Some(err),
+            WitError::TryFromSliceError(err) => Some(err),
+            WitError::RegexError(err) => Some(err),
+            _ => None
+        }
+    }
+}
 
-impl WitError {
-    pub fn new(error_type: WitErrorType, message: String) -> Self {
-        WitError {
-            error: error_type,
-            message
+impl Display for WitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WitError::Debug => write!(f, "Generic debug error."),
+            WitError::IoError(err) => write!(f, "IoError: {}", err),
+            WitError::Other(message) => write!(f, "Other: {}", message),
+            WitError::RepoCreation(message) => write!(f, "RepoCreation: {}", message),
+            WitError::VersionMismatch(message) => write!(f, "VersionMismatch: {}", message),
+            WitError::MalformedObject(message) => write!(f, "MalformedObject: {}", message),
+            WitError::UnknownObject(message) => write!(f, "UnknownObject: {}", message),
+            WitError::PathConversion => write!(f, "PathConversion: Could not convert path to string"),
+            WitError::CliArgument(arg) => write!(f, "CliArgument: Required argument '{}' was not provided.", arg),
+            WitError::CliUnknownCommand(command) => write!(f, "CliUnknownCommand: Unknown command '{}'", command),
+            WitError::CliNoCommand => write!(f, "CliNoCommand: No command provided."),
+            WitError::Utf8(message) => write!(f, "Utf8: {}", message),
+            WitError::Utf8Error(err) => write!(f, "Utf8Error: {}", err),
+            WitError::FromUtf8Error(err) => write!(f, "Utf8Error: {}", err),
+            WitError::ParseIntError(err) => write!(f, "ParseIntError: {}", err),
+            WitError::TryFromSliceError(err) => write!(f, "TryFromSliceError: {}", err),
+            WitError::RegexError(err) => write!(f, "RegexError: {}", err),
+            WitError::InvalidModeLength(length) => write!(f, "InvalidModeLength: Invalid mode length: {}", length),
+            WitError::RepoNotFound(message) => write!(f, "RepoNotFound: {}", message),
+            WitError::AmbiguousReference { name, candidates } => {
+                write!(f, "AmbiguousReference: Ambiguous reference {}: {} candidates", name, candidates.len())
+            },
+            WitError::UnknownReference(message) => write!(f, "UnknownReference: {}", message),
+            WitError::UnbornBranch(refname) => write!(f, "UnbornBranch: {} does not have any commits yet", refname),
+            WitError::MissingData(message) => write!(f, "MissingData: {}", message),
+            WitError::NotADirectory(path) => write!(f, "NotADirectory: {}", path.display()),
+            WitError::DirectoryNotEmpty(path) => write!(f, "DirectoryNotEmpty: {}", path.display()),
+            WitError::OperationInProgress(message) => write!(f, "OperationInProgress: {}", message),
+            WitError::LockHeld(path) => write!(
+                f,
+                "LockHeld: Unable to create {}: File exists. If no other wit process is running, remove the file.",
+                path.display()
+            ),
+            WitError::UncommittedChanges(paths) => write!(
+                f,
+                "UncommittedChanges: Your local changes to the following files would be overwritten: {}",
+                paths.iter().map(|path| path.display().to_string()).collect::<Vec<String>>().join(", ")
+            ),
+            WitError::HookFailed { name, code } => match code {
+                Some(code) => write!(f, "HookFailed: The {} hook exited with status {}", name, code),
+                None => write!(f, "HookFailed: The {} hook was killed by a signal", name)
+            },
+            WitError::ReferenceMismatch { name, expected, actual } => write!(
+                f,
+                "ReferenceMismatch: {} is at {}, expected {}",
+                name,
+                actual.as_deref().unwrap_or("nothing"),
+                expected.as_deref().unwrap_or("nothing")
+            ),
+            WitError::InvalidReference(name) => write!(f, "InvalidReference: '{}' is not a valid ref name", name),
         }
     }
 }
@@ -56,148 +198,229 @@ impl WitError {
 // Conversions for WitError
 impl From<std::io::Error> for WitError {
     fn from(err: std::io::Error) -> Self {
-        WitError::new(InheritedError, err.to_string())
+        WitError::IoError(err)
     }
 }
 
 impl From<regex::Error> for Box<WitError> {
     fn from(err: regex::Error) -> Self {
-        Box::new(WitError::new(InheritedError, err.to_string()))
+        Box::new(WitError::RegexError(err))
     }
 }
 
 impl From<TryFromSliceError> for Box<WitError> {
     fn from(err: TryFromSliceError) -> Self {
-        Box::new(WitError::new(InheritedError, err.to_string()))
+        Box::new(WitError::TryFromSliceError(err))
     }
 }
 
 impl From<std::io::Error> for Box<WitError> {
     fn from(err: std::io::Error) -> Self {
-        Box::new(WitError::new(InheritedError, err.to_string()))
+        Box::new(WitError::IoError(err))
     }
 }
 
 impl From<Box<std::io::Error>> for Box<WitError> {
     fn from(err: Box<std::io::Error>) -> Self {
-        Box::new(WitError::from(*err))
+        Box::new(WitError::IoError(*err))
     }
 }
 
 impl From<ParseIntError> for Box<WitError> {
     fn from(err: ParseIntError) -> Self {
-        Box::new(WitError::new(InheritedError, err.to_string()))
+        Box::new(WitError::ParseIntError(err))
     }
 }
 
 impl From<Utf8Error> for Box<WitError> {
     fn from(err: Utf8Error) -> Self {
-        Box::new(WitError::new(InheritedError, err.to_string()))
+        Box::new(WitError::Utf8Error(err))
     }
 }
 
 impl From<FromUtf8Error> for Box<WitError> {
     fn from(err: FromUtf8Error) -> Self {
-        Box::new(WitError::new(UTF8ConversionError, err.to_string()))
-    }
-}
-
-impl Display for WitError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {}", self.error, self.message)
+        Box::new(WitError::FromUtf8Error(err))
     }
 }
 
 pub mod builder {
-    use super::{WitError, WitErrorType::*};
+    use super::WitError;
+    use crate::object_id::ObjectId;
 
     // For debug use only
     #[allow(dead_code)]
     pub fn debug_err() -> Box<WitError> {
-        Box::new(WitError::new(DebugError, String::from("Generic debug error.")))
+        Box::new(WitError::Debug)
     }
 
-    pub fn io_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(IOError, message))
+    pub fn other_err(message: String) -> Box<WitError> {
+        Box::new(WitError::Other(message))
     }
 
     pub fn repo_creation_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(RepoCreationError, message))
+        Box::new(WitError::RepoCreation(message))
     }
 
     pub fn version_mismatch_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(VersionMismatchError, message))
+        Box::new(WitError::VersionMismatch(message))
     }
 
     pub fn malformed_object_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(MalformedObjectError, message))
+        Box::new(WitError::MalformedObject(message))
     }
 
     pub fn path_conversion_err() -> Box<WitError> {
-        Box::new(WitError::new(MalformedObjectError, format!("Could not convert path to string")))
+        Box::new(WitError::PathConversion)
     }
 
     pub fn cli_argument_err(arg: &str) -> Box<WitError> {
-        Box::new(WitError::new(
-            CLIArgumentError,
-            format!("Required argument '{}' was not provided.", arg)
-        ))
+        Box::new(WitError::CliArgument(arg.to_owned()))
     }
 
     pub fn cli_unknown_command_err(command: &str) -> Box<WitError> {
-        Box::new(WitError::new(
-            CLIUnknownCommandError,
-            format!("Unknown command '{}'", command)
-        ))
+        Box::new(WitError::CliUnknownCommand(command.to_owned()))
     }
 
     pub fn cli_no_command_err() -> Box<WitError> {
-        Box::new(WitError::new(
-            CLINoCommandError,
-            format!("No command provided.")
-        ))
+        Box::new(WitError::CliNoCommand)
     }
 
     pub fn unknown_object_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(UnknownObjectTypeError, message))
+        Box::new(WitError::UnknownObject(message))
     }
 
     pub fn utf8_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(UTF8ConversionError, message))
+        Box::new(WitError::Utf8(message))
     }
 
     pub fn mode_err(length: usize) -> Box<WitError> {
-        Box::new(WitError::new(InvalidModeLengthError, format!("Invalid mode length: {}", length)))
+        Box::new(WitError::InvalidModeLength(length))
     }
 
     pub fn repo_not_found_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(RepoNotFoundError, message))
+        Box::new(WitError::RepoNotFound(message))
     }
 
-    pub fn pwd_not_repo_err() -> Box<WitError> {
-        Box::new(WitError::new(
-            RepoNotFoundError,
-            format!("No repository found in {}", std::env::current_dir().unwrap().display())
-        ))
+    // A name that matches more than one object
+    pub fn ambiguous_reference_err(name: &str, candidates: Vec<ObjectId>) -> Box<WitError> {
+        Box::new(WitError::AmbiguousReference { name: name.to_owned(), candidates })
     }
 
-    pub fn ambiguous_reference_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(AmbiguousReferenceError, message))
+    pub fn unknown_reference_err(message: String) -> Box<WitError> {
+        Box::new(WitError::UnknownReference(message))
     }
 
-    pub fn unknown_reference_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(UnknownReferenceError, message))
+    pub fn unborn_branch_err(refname: &str) -> Box<WitError> {
+        Box::new(WitError::UnbornBranch(refname.to_owned()))
     }
 
     pub fn missing_data_err(message: String) -> Box<WitError> {
-        Box::new(WitError::new(MissingDataError, message))
+        Box::new(WitError::MissingData(message))
+    }
+
+    pub fn not_a_directory_err(path: &std::path::Path) -> Box<WitError> {
+        Box::new(WitError::NotADirectory(path.to_owned()))
     }
 
-    pub fn not_a_directory_err(path: &std::path::PathBuf) -> Box<WitError> {
-        Box::new(WitError::new(NotADirectoryError, path.display().to_string()))
+    pub fn dir_not_empty_err(path: &std::path::Path) -> Box<WitError> {
+        Box::new(WitError::DirectoryNotEmpty(path.to_owned()))
     }
 
-    pub fn dir_not_empty_err(path: &std::path::PathBuf) -> Box<WitError> {
-        Box::new(WitError::new(DirectoryNotEmptyError, path.display().to_string()))
+    pub fn in_progress_err(message: String) -> Box<WitError> {
+        Box::new(WitError::OperationInProgress(message))
     }
-}
\ No newline at end of file
+
+    pub fn lock_held_err(path: &std::path::Path) -> Box<WitError> {
+        Box::new(WitError::LockHeld(path.to_owned()))
+    }
+
+    pub fn hook_failed_err(name: &str, code: Option<i32>) -> Box<WitError> {
+        Box::new(WitError::HookFailed { name: name.to_owned(), code })
+    }
+
+    pub fn ref_mismatch_err(name: &str, expected: Option<&str>, actual: Option<String>) -> Box<WitError> {
+        Box::new(WitError::ReferenceMismatch {
+            name: name.to_owned(),
+            expected: expected.map(|sha| sha.to_owned()),
+            actual
+        })
+    }
+
+    pub fn uncommitted_changes_err(paths: Vec<std::path::PathBuf>) -> Box<WitError> {
+        Box::new(WitError::UncommittedChanges(paths))
+    }
+
+    pub fn invalid_ref_err(name: &str) -> Box<WitError> {
+        Box::new(WitError::InvalidReference(name.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use super::builder::*;
+
+    fn parse(value: &str) -> Result<u32, Box<WitError>> {
+        Ok(value.parse::<u32>()?)
+    }
+
+    #[test]
+    fn conversions_keep_their_source() {
+        let err = parse("x").unwrap_err();
+        assert!(matches!(*err, WitError::ParseIntError(_)));
+        assert!(err.source().unwrap().downcast_ref::<ParseIntError>().is_some());
+
+        let err: Box<WitError> = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(err.to_string(), "IoError: gone");
+        assert_eq!(err.source().unwrap().to_string(), "gone");
+
+        let err: Box<WitError> = String::from_utf8(vec![0xff]).unwrap_err().into();
+        assert!(matches!(*err, WitError::FromUtf8Error(_)));
+        assert!(err.source().is_some());
+        assert!(missing_data_err("nothing here".to_owned()).source().is_none());
+    }
+
+    #[test]
+    fn builders_make_typed_errors() {
+        assert!(matches!(*unknown_object_err("x".to_owned()), WitError::UnknownObject(_)));
+        assert!(matches!(*in_progress_err("x".to_owned()), WitError::OperationInProgress(_)));
+        assert!(matches!(*path_conversion_err(), WitError::PathConversion));
+        assert_eq!(repo_not_found_err("No repo".to_owned()).to_string(), "RepoNotFound: No repo");
+        let err = uncommitted_changes_err(vec![PathBuf::from("a"), PathBuf::from("b/c")]);
+        assert!(err.to_string().ends_with("would be overwritten: a, b/c"));
+    }
+
+    #[test]
+    fn kinds_group_variants() {
+        assert_eq!(parse("x").unwrap_err().kind(), WitErrorKind::Parse);
+        let err: Box<WitError> = std::io::Error::new(std::io::ErrorKind::NotFound, "gone").into();
+        assert_eq!(err.kind(), WitErrorKind::Io);
+        assert_eq!(cli_argument_err("file").kind(), cli_no_command_err().kind());
+        assert_eq!(mode_err(7).kind(), WitErrorKind::MalformedObject);
+        assert_eq!(ambiguous_reference_err("ce01", Vec::new()).kind(), WitErrorKind::AmbiguousReference);
+        assert_eq!(invalid_ref_err("refs/heads/a..b").kind(), WitErrorKind::InvalidReference);
+
+        // A bare message is not dressed up as an io::Error
+        let err = other_err("Cannot set up TLS".to_owned());
+        assert_eq!(err.kind(), WitErrorKind::Other);
+        assert_eq!(err.to_string(), "Other: Cannot set up TLS");
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn structured_variants() {
+        let sha = ObjectId::from_hex("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let err = ambiguous_reference_err("ce01", vec![sha, sha]);
+        assert_eq!(err.candidates(), &[sha, sha]);
+        assert_eq!(err.to_string(), "AmbiguousReference: Ambiguous reference ce01: 2 candidates");
+        assert!(missing_data_err("x".to_owned()).candidates().is_empty());
+
+        let err = ref_mismatch_err("refs/heads/main", None, Some("abc".to_owned()));
+        assert_eq!(err.to_string(), "ReferenceMismatch: refs/heads/main is at abc, expected nothing");
+        let err = hook_failed_err("pre-commit", None);
+        assert_eq!(err.to_string(), "HookFailed: The pre-commit hook was killed by a signal");
+    }
+}
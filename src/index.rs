@@ -1,50 +1,156 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-use crate::error::WitError;
-use crate::object::Find;
-use crate::util::hex;
+use crate::checkout::{commit_files, Files};
+use crate::error::{WitError, builder::*};
+use crate::lockfile::Lockfile;
+use crate::object::{self, Find};
+use crate::object_id::{ObjectFormat, ObjectId};
+use crate::reference;
+use crate::repository::Repository;
+use crate::util::{hex, unhex};
 
 
 pub struct Index {
-    header: [u8; 12],
     signature: [u8; 4],
-    version: String,
-    nindex: u32,
+    version: u32,
 
     entries: Vec<IndexEntry>,
 }
 
 impl Index {
-    pub fn open(path: &PathBuf) -> Result<Index, Box<WitError>> {
+    pub fn new() -> Index {
+        Index {
+            signature: *b"DIRC",
+            version: 2,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn open(path: &Path, format: ObjectFormat) -> Result<Index, Box<WitError>> {
         let raw = std::fs::read(path)?;
-        Self::from(raw)
+        Self::from(raw, format)
+    }
+
+    // The repository's index, or an empty one if it has not been written yet
+    pub fn read(repo: &Repository) -> Result<Index, Box<WitError>> {
+        let path = Repository::path(repo, vec!["index"]);
+        if path.exists() {
+            Self::open(&path, repo.object_format())
+        } else {
+            Ok(Self::new())
+        }
     }
 
-    pub fn from(raw: Vec<u8>) -> Result<Index, Box<WitError>> {
-        let header: [u8; 12] = raw[..12].try_into()?;
-        let nindex = u32::from_be_bytes(header[8..12].try_into()?);
-        
-        let content = raw[12..].to_vec();
+    pub fn from(raw: Vec<u8>, format: ObjectFormat) -> Result<Index, Box<WitError>> {
+        if raw.len() < 12 {
+            return Err(malformed_object_err("Index is too short".to_owned()));
+        }
+        let signature: [u8; 4] = raw[..4].try_into()?;
+        if &signature != b"DIRC" {
+            return Err(malformed_object_err("Bad index signature".to_owned()));
+        }
+        let version = u32::from_be_bytes(raw[4..8].try_into()?);
+        if version != 2 && version != 3 {
+            return Err(version_mismatch_err(format!("Unsupported index version {}", version)));
+        }
+        let nindex = u32::from_be_bytes(raw[8..12].try_into()?);
+
         let mut entries = Vec::<IndexEntry>::new();
-        let mut curs: usize = 0;
-        for i in 0..nindex {
-            let null_idx = content.find_exact(b'\x00', curs+62);
+        let mut curs: usize = 12;
+        // Stat data, then the object id, then the flags
+        let flags_start = 40 + format.raw_len();
+        for _ in 0..nindex {
+            let flags = u16::from_be_bytes(raw[curs+flags_start..curs+flags_start+2].try_into()?);
+            // Version 3 entries with the extended flag carry two more flag bytes
+            let header_len = if flags & 0x4000 != 0 { flags_start + 4 } else { flags_start + 2 };
+            let null_idx = raw.find_from(b'\x00', curs + header_len)?;
             entries.push(
                 IndexEntry::from(
-                    content[curs..null_idx].to_vec(),
+                    raw[curs..null_idx].to_vec(),
+                    header_len,
+                    format
                 )?
             );
-            curs = null_idx + 1;
+            // Entries are NUL padded to a multiple of 8 bytes
+            let len = null_idx - curs;
+            curs += (len + 8) & !7;
         }
 
         Ok(Index {
-            header,
-            signature: header[..4].try_into()?,
-            version: hex(&header[4..8].to_owned()),
-            nindex,
+            signature,
+            version,
             entries,
         })
     }
+
+    // Inserts the entry in (path, stage) order. A stage 0 entry replaces every
+    // entry for its path, which resolves a conflict; a conflict stage only
+    // replaces the resolved entry and its own stage.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let stage = entry.stage();
+        self.entries.retain(|e| e.file_path != entry.file_path || (stage != 0 && e.stage() != 0 && e.stage() != stage));
+        let pos = self.entries.iter()
+            .position(|e| (&e.file_path, e.stage()) > (&entry.file_path, stage))
+            .unwrap_or(self.entries.len());
+        self.entries.insert(pos, entry);
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    // The resolved entry for path; a conflicted path has none
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.entries.iter().find(|e| e.path() == path && e.stage() == 0)
+    }
+
+    // Drops every stage of path, returning the first
+    pub fn remove(&mut self, path: &str) -> Option<IndexEntry> {
+        let pos = self.entries.iter().position(|e| e.path() == path)?;
+        let entry = self.entries.remove(pos);
+        self.entries.retain(|e| e.path() != path);
+        Some(entry)
+    }
+
+    // Paths with conflict stages instead of a resolved entry
+    pub fn unmerged(&self) -> Vec<PathBuf> {
+        let mut paths = self.entries.iter().filter(|e| e.stage() != 0).map(|e| PathBuf::from(e.path())).collect::<Vec<PathBuf>>();
+        paths.dedup();
+        paths
+    }
+
+    pub fn serialize(&self, format: ObjectFormat) -> Result<Vec<u8>, Box<WitError>> {
+        let mut raw = Vec::new();
+        raw.extend(self.signature);
+        raw.extend(self.version.to_be_bytes());
+        raw.extend((self.entries.len() as u32).to_be_bytes());
+
+        for entry in &self.entries {
+            let start = raw.len();
+            raw.extend(entry.serialize()?);
+            let len = raw.len() - start;
+            raw.resize(start + ((len + 8) & !7), b'\x00');
+        }
+
+        let digest = format.digest(&[&raw]);
+        raw.extend(digest.as_bytes());
+        Ok(raw)
+    }
+
+    pub fn write(&self, repo: &Repository) -> Result<(), Box<WitError>> {
+        self.write_locked(repo, Self::lock(repo)?)
+    }
+
+    // Takes the index lock, for callers that must hold it before touching the worktree
+    pub fn lock(repo: &Repository) -> Result<Lockfile, Box<WitError>> {
+        Lockfile::acquire(&Repository::file(repo, vec!["index"], false)?)
+    }
+
+    pub fn write_locked(&self, repo: &Repository, mut lock: Lockfile) -> Result<(), Box<WitError>> {
+        lock.write(&self.serialize(repo.object_format())?)?;
+        lock.commit(repo)
+    }
 }
 
 pub struct IndexEntry {
@@ -96,7 +202,8 @@ pub struct IndexEntry {
 }
 
 impl IndexEntry {
-    pub fn from(raw: Vec<u8>) -> Result<Self, Box<WitError>> {
+    pub fn from(raw: Vec<u8>, header_len: usize, format: ObjectFormat) -> Result<Self, Box<WitError>> {
+        let flags_start = 40 + format.raw_len();
         Ok(Self {
             ctime: (
                 u32::from_be_bytes(raw[0..4].try_into()?),
@@ -112,9 +219,410 @@ impl IndexEntry {
             uid: u32::from_be_bytes(raw[28..32].try_into()?),
             gid: u32::from_be_bytes(raw[32..36].try_into()?),
             size: u32::from_be_bytes(raw[36..40].try_into()?),
-            hash: hex(&raw[40..60].to_vec()),
-            flags: u16::from_be_bytes(raw[60..62].try_into()?),
-            file_path: String::from_utf8(raw[62..].to_vec())?,
+            hash: hex(&raw[40..flags_start]),
+            // Extended flags are not kept
+            flags: u16::from_be_bytes(raw[flags_start..flags_start+2].try_into()?) & !0x4000,
+            file_path: String::from_utf8(raw[header_len..].to_vec())?,
+        })
+    }
+
+    // A stage 0 entry for the file at worktree/path, with stat data read from disk
+    pub fn create(repo: &Repository, path: &str, sha: &ObjectId, mode: &str) -> Result<Self, Box<WitError>> {
+        let metadata = fs::symlink_metadata(repo.worktree()?.join(path))?;
+        let mut entry = Self::stat(&metadata);
+        entry.mode = u32::from_str_radix(mode, 8)?;
+        entry.hash = sha.to_string();
+        entry.flags = path.len().min(0xFFF) as u16;
+        entry.file_path = path.to_owned();
+        Ok(entry)
+    }
+
+    // An entry for one side of a conflict: stage 1 is the merge base, 2 ours
+    // and 3 theirs. There is no file for it, so no stat data either.
+    pub fn unmerged(path: &str, sha: &ObjectId, mode: &str, stage: u16) -> Result<Self, Box<WitError>> {
+        Ok(Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode: u32::from_str_radix(mode, 8)?,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash: sha.to_string(),
+            flags: (stage & 0x3) << 12 | path.len().min(0xFFF) as u16,
+            file_path: path.to_owned()
         })
     }
-}
\ No newline at end of file
+
+    #[cfg(unix)]
+    fn stat(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode: 0,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            hash: String::new(),
+            flags: 0,
+            file_path: String::new()
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn stat(metadata: &fs::Metadata) -> Self {
+        use std::time::UNIX_EPOCH;
+        let mtime = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| (d.as_secs() as u32, d.subsec_nanos()))
+            .unwrap_or((0, 0));
+        Self {
+            ctime: mtime,
+            mtime,
+            dev: 0,
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            size: metadata.len() as u32,
+            hash: String::new(),
+            flags: 0,
+            file_path: String::new()
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Box<WitError>> {
+        let mut raw = Vec::new();
+        raw.extend(self.ctime.0.to_be_bytes());
+        raw.extend(self.ctime.1.to_be_bytes());
+        raw.extend(self.mtime.0.to_be_bytes());
+        raw.extend(self.mtime.1.to_be_bytes());
+        raw.extend(self.dev.to_be_bytes());
+        raw.extend(self.ino.to_be_bytes());
+        raw.extend(self.mode.to_be_bytes());
+        raw.extend(self.uid.to_be_bytes());
+        raw.extend(self.gid.to_be_bytes());
+        raw.extend(self.size.to_be_bytes());
+        raw.extend(unhex(&self.hash)?);
+        raw.extend(self.flags.to_be_bytes());
+        raw.extend(self.file_path.as_bytes());
+        Ok(raw)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.file_path
+    }
+
+    pub fn sha(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn stage(&self) -> u16 {
+        (self.flags >> 12) & 0x3
+    }
+
+    // Whether the file looks untouched since it was indexed, going by its
+    // modification time and size like git does
+    pub fn is_fresh(&self, metadata: &fs::Metadata) -> bool {
+        let now = Self::stat(metadata);
+        now.mtime == self.mtime && now.size == self.size
+    }
+}
+
+// path as the index names it, relative to the root of the worktree. Relative
+// paths are taken to be relative to the root already.
+fn index_path(repo: &Repository, path: &Path) -> Result<String, Box<WitError>> {
+    let root = repo.worktree()?;
+    let mut full = PathBuf::new();
+    for component in root.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                full.pop();
+            },
+            Component::CurDir => (),
+            component => full.push(component)
+        }
+    }
+    let relative = full.strip_prefix(root).map_err(|_| {
+        repo_not_found_err(format!("{} is outside the worktree at {}", path.display(), root.display()))
+    })?;
+    Ok(relative.to_str().ok_or(path_conversion_err())?.to_owned())
+}
+
+// Whether name is prefix or inside it; the empty prefix is the whole worktree
+fn is_under(name: &str, prefix: &str) -> bool {
+    prefix.is_empty() || name == prefix || name.strip_prefix(prefix).map(|rest| rest.starts_with('/')).unwrap_or(false)
+}
+
+// The index entries at or under each of paths
+fn matching(repo: &Repository, index: &Index, paths: &[PathBuf]) -> Result<Vec<String>, Box<WitError>> {
+    let mut names = Vec::new();
+    for path in paths {
+        let prefix = index_path(repo, path)?;
+        let before = names.len();
+        names.extend(index.entries.iter().filter(|entry| is_under(entry.path(), &prefix)).map(|entry| entry.path().to_owned()));
+        if names.len() == before {
+            return Err(unknown_reference_err(format!("pathspec '{}' did not match any files", path.display())));
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+// Drops paths from the index, and from the worktree unless cached_only.
+// Directories take everything under them. As with git rm, unless force is
+// set a file is refused when removing it would lose work: if its index
+// entry differs from both HEAD and the worktree, or without cached_only if
+// either does. Returns the paths removed.
+pub fn remove(repo: &Repository, paths: &[PathBuf], cached_only: bool, force: bool) -> Result<Vec<String>, Box<WitError>> {
+    let lock = Index::lock(repo)?;
+    let mut index = Index::read(repo)?;
+    let root = repo.worktree()?.to_path_buf();
+    let names = matching(repo, &index, paths)?;
+
+    if !force {
+        let head = match reference::resolve(repo, "HEAD") {
+            Ok(head) => commit_files(repo, &head)?,
+            Err(_) => Files::new()
+        };
+        let mut refused = Vec::new();
+        for entry in index.entries.iter().filter(|entry| names.binary_search(&entry.file_path).is_ok()) {
+            let staged = head.get(Path::new(entry.path())).map(|leaf| leaf.sha().to_string()).as_deref() != Some(entry.sha());
+            let dest = root.join(entry.path());
+            // A file that is already gone has nothing left to lose
+            let modified = match fs::symlink_metadata(&dest) {
+                Ok(metadata) if !metadata.is_dir() && !entry.is_fresh(&metadata) => {
+                    object::hash(dest.to_str().ok_or(path_conversion_err())?, "blob", Some(repo), false)?.to_string() != entry.sha()
+                },
+                _ => false
+            };
+            if (staged && modified) || (!cached_only && (staged || modified)) {
+                refused.push(PathBuf::from(entry.path()));
+            }
+        }
+        if !refused.is_empty() {
+            return Err(uncommitted_changes_err(refused));
+        }
+    }
+
+    for name in &names {
+        index.remove(name);
+        if cached_only {
+            continue;
+        }
+        let dest = root.join(name);
+        if fs::symlink_metadata(&dest).map(|meta| !meta.is_dir()).unwrap_or(false) {
+            fs::remove_file(&dest)?;
+        }
+        // Drop directories the removal left empty
+        let mut parent = dest.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != root) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+            parent = dir.parent();
+        }
+    }
+    index.write_locked(repo, lock)?;
+    Ok(names)
+}
+
+// Moves from to to in the worktree and the index, keeping the blobs staged
+// for it; a directory takes everything under it. Missing parents of to are
+// created, but to itself must not exist yet.
+pub fn rename(repo: &Repository, from: &Path, to: &Path) -> Result<(), Box<WitError>> {
+    let lock = Index::lock(repo)?;
+    let mut index = Index::read(repo)?;
+    let root = repo.worktree()?.to_path_buf();
+    let (old, new) = (index_path(repo, from)?, index_path(repo, to)?);
+    if old.is_empty() || new.is_empty() {
+        return Err(other_err("Cannot move the root of the worktree".to_owned()));
+    }
+    if is_under(&new, &old) {
+        return Err(other_err(format!("Cannot move {} into itself", old)));
+    }
+    let names = matching(repo, &index, &[PathBuf::from(&old)])?;
+    if let Some(path) = index.unmerged().into_iter().find(|path| is_under(path.to_str().unwrap_or(""), &old)) {
+        return Err(in_progress_err(format!("{} has unresolved conflicts", path.display())));
+    }
+    if fs::symlink_metadata(root.join(&new)).is_ok() || index.entries.iter().any(|entry| is_under(entry.path(), &new)) {
+        return Err(other_err(format!("Destination {} already exists", new)));
+    }
+
+    if let Some(parent) = root.join(&new).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(root.join(&old), root.join(&new))?;
+    for name in names {
+        let entry = index.remove(&name).ok_or(unknown_reference_err(format!("{} is not in the index", name)))?;
+        let moved = format!("{}{}", new, &name[old.len()..]);
+        // Fresh stat data, since the move changed the file's ctime
+        index.add(IndexEntry::create(repo, &moved, &ObjectId::from_hex(entry.sha())?, &format!("{:o}", entry.mode()))?);
+    }
+    index.write_locked(repo, lock)
+}
+
+// The mode a worktree file is staged with
+fn file_mode(metadata: &fs::Metadata) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.file_type().is_symlink() {
+            return "120000";
+        }
+        if metadata.permissions().mode() & 0o111 != 0 {
+            return "100755";
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    "100644"
+}
+
+// The files at or under path in the worktree, as index paths
+fn worktree_files(repo: &Repository, name: &str, names: &mut Vec<String>) -> Result<(), Box<WitError>> {
+    let dest = repo.worktree()?.join(name);
+    let metadata = match fs::symlink_metadata(&dest) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(())
+    };
+    if !metadata.is_dir() {
+        names.push(name.to_owned());
+        return Ok(());
+    }
+    for entry in fs::read_dir(&dest)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_str().ok_or(path_conversion_err())?;
+        if file_name == ".git" {
+            continue;
+        }
+        let child = if name.is_empty() { file_name.to_owned() } else { format!("{}/{}", name, file_name) };
+        worktree_files(repo, &child, names)?;
+    }
+    Ok(())
+}
+
+// Stages the worktree files at or under each of paths, which resolves any
+// conflict on them. Indexed files that are gone from the worktree are
+// dropped. Returns the paths staged or dropped.
+pub fn add(repo: &Repository, paths: &[PathBuf]) -> Result<Vec<String>, Box<WitError>> {
+    let lock = Index::lock(repo)?;
+    let mut index = Index::read(repo)?;
+    let root = repo.worktree()?.to_path_buf();
+
+    let mut names = Vec::new();
+    for path in paths {
+        let prefix = index_path(repo, path)?;
+        let before = names.len();
+        worktree_files(repo, &prefix, &mut names)?;
+        names.extend(index.entries.iter().filter(|entry| is_under(entry.path(), &prefix)).map(|entry| entry.path().to_owned()));
+        if names.len() == before {
+            return Err(unknown_reference_err(format!("pathspec '{}' did not match any files", path.display())));
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    for name in &names {
+        let dest = root.join(name);
+        let metadata = match fs::symlink_metadata(&dest) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                index.remove(name);
+                continue;
+            }
+        };
+        let mode = file_mode(&metadata);
+        let sha = if mode == "120000" {
+            let target = fs::read_link(&dest)?;
+            object::write_raw(repo, "blob", target.to_str().ok_or(path_conversion_err())?.as_bytes())?
+        } else {
+            object::hash(dest.to_str().ok_or(path_conversion_err())?, "blob", Some(repo), true)?
+        };
+        index.add(IndexEntry::create(repo, name, &sha, mode)?);
+    }
+    index.write_locked(repo, lock)?;
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checkout, test_util};
+
+    // A checked out commit of a, dir/b and dir/c
+    fn setup() -> (test_util::TempDir, Repository) {
+        let (dir, repo) = test_util::repo();
+        let tree = test_util::tree(&repo, &[("a", b"a\n"), ("dir/b", b"b\n"), ("dir/c", b"c\n")]);
+        let first = test_util::commit(&repo, &tree, &[], "first");
+        reference::update(&repo, "refs/heads/main", &first, None, "test").unwrap();
+        checkout::switch(&repo, "main").unwrap();
+        (dir, repo)
+    }
+
+    fn staged(dir: &Path) -> Vec<String> {
+        test_util::git(dir, &["ls-files"]).lines().map(|line| line.to_owned()).collect()
+    }
+
+    #[test]
+    fn remove_drops_files_and_directories() {
+        let (dir, repo) = setup();
+        assert_eq!(remove(&repo, &[PathBuf::from("dir")], false, false).unwrap(), vec!["dir/b", "dir/c"]);
+        assert!(!dir.path().join("dir").exists());
+        assert_eq!(staged(dir.path()), vec!["a"]);
+
+        assert_eq!(remove(&repo, &[PathBuf::from("a")], true, false).unwrap(), vec!["a"]);
+        assert!(dir.path().join("a").is_file());
+        assert!(staged(dir.path()).is_empty());
+        assert!(matches!(*remove(&repo, &[PathBuf::from("a")], false, false).unwrap_err(), WitError::UnknownReference(_)));
+    }
+
+    #[test]
+    fn remove_refuses_to_lose_work() {
+        let (dir, repo) = setup();
+        fs::write(dir.path().join("a"), b"edited\n").unwrap();
+        let err = remove(&repo, &[PathBuf::from("a")], false, false).unwrap_err();
+        assert!(matches!(*err, WitError::UncommittedChanges(ref paths) if paths == &[PathBuf::from("a")]));
+        // The edit survives in the worktree, so only dropping the entry is fine
+        remove(&repo, &[PathBuf::from("a")], true, false).unwrap();
+
+        // Staged, then edited again: the staged blob would be lost either way
+        fs::write(dir.path().join("dir/b"), b"staged\n").unwrap();
+        add(&repo, &[PathBuf::from("dir/b")]).unwrap();
+        fs::write(dir.path().join("dir/b"), b"edited\n").unwrap();
+        assert!(remove(&repo, &[PathBuf::from("dir/b")], true, false).is_err());
+        remove(&repo, &[PathBuf::from("dir/b")], false, true).unwrap();
+        assert!(!dir.path().join("dir/b").exists());
+        assert_eq!(staged(dir.path()), vec!["dir/c"]);
+    }
+
+    #[test]
+    fn rename_moves_files_and_directories() {
+        let (dir, repo) = setup();
+        let sha = Index::read(&repo).unwrap().get("a").unwrap().sha().to_owned();
+        rename(&repo, Path::new("a"), Path::new("new/place/a2")).unwrap();
+        assert_eq!(fs::read(dir.path().join("new/place/a2")).unwrap(), b"a\n");
+        assert_eq!(Index::read(&repo).unwrap().get("new/place/a2").unwrap().sha(), sha);
+
+        rename(&repo, Path::new("dir"), Path::new("moved")).unwrap();
+        assert_eq!(staged(dir.path()), vec!["moved/b", "moved/c", "new/place/a2"]);
+        assert!(!dir.path().join("dir").exists());
+        // Nothing shows as modified, so the stat data was refreshed
+        assert_eq!(test_util::git(dir.path(), &["diff", "--name-only"]), "");
+
+        assert!(rename(&repo, Path::new("moved/b"), Path::new("moved/c")).is_err());
+        assert!(rename(&repo, Path::new("moved"), Path::new("moved/inner")).is_err());
+        assert!(rename(&repo, Path::new("."), Path::new("elsewhere")).is_err());
+        assert!(rename(&repo, Path::new("missing"), Path::new("elsewhere")).is_err());
+        assert_eq!(staged(dir.path()), vec!["moved/b", "moved/c", "new/place/a2"]);
+    }
+}
@@ -1,7 +1,9 @@
+use std::fmt;
 use std::str::from_utf8;
 use linked_hash_map::LinkedHashMap;
 use crate::object::{ Find, Replace };
 
+#[allow(clippy::upper_case_acronyms)]
 pub type KVLM = LinkedHashMap<String, Vec<String>>;
 
 pub trait KVLMExt {
@@ -17,7 +19,7 @@ impl KVLMExt for KVLM {
 
     fn parse(&mut self, raw: Vec<u8>, start: usize) -> KVLM {
         let spc: Option<usize> = raw.find_some(b' ', start);
-        let nl: usize = raw.find_exact(b'\n', start);
+        let nl: usize = raw.find_some(b'\n', start).unwrap_or(raw.len());
 
         let spc = {
             // Scope for shadowing spc and nl as isizes and extracting spc as usize
@@ -30,7 +32,7 @@ impl KVLMExt for KVLM {
             if spc < 0 || nl < spc {
                 self.insert(
                     "".to_owned(),
-                    match from_utf8(&raw[start..]) {
+                    match from_utf8(&raw[(start + 1).min(raw.len())..]) {
                         Ok(string) => vec![string.to_owned()],
                         Err(_) => panic!("Error converting {:?} to utf8", &raw[start..])
                     }
@@ -47,7 +49,7 @@ impl KVLMExt for KVLM {
                 Some(new_line) => new_line,
                 None => break
             };
-            if raw[end+1] != b' ' {
+            if raw.get(end+1) != Some(&b' ') {
                 break;
             }
         }
@@ -69,7 +71,7 @@ impl KVLMExt for KVLM {
 
         for key in self.keys() {
             let key = key.clone();
-            if key == String::from("") {
+            if key.is_empty() {
                 continue;
             }
             let val = &self[&key];
@@ -82,9 +84,22 @@ impl KVLMExt for KVLM {
         }
 
         ret += "\n";
-        for entry in &self["\n"] {
+        for entry in self.get("").into_iter().flatten() {
             ret += entry.as_str();
         }
         ret
     }
-}
\ No newline at end of file
+}
+
+// "key: value" lines followed by the message, for Display impls
+pub fn display(kvlm: &KVLM, f: &mut fmt::Formatter) -> fmt::Result {
+    for (key, values) in kvlm.iter().filter(|(key, _)| !key.is_empty()) {
+        for value in values {
+            writeln!(f, "{}: {}", key, value.replace('\n', "\n    "))?;
+        }
+    }
+    for message in kvlm.get("").into_iter().flatten() {
+        write!(f, "\n{}", message)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::{WitError, builder::*},
+    repository::Repository
+};
+
+// Exclusive ownership of a file while it is rewritten. The new contents go to
+// <path>.lock, which replaces the file on commit; dropping the lock without
+// committing removes it.
+pub struct Lockfile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl Lockfile {
+    pub fn acquire(path: &Path) -> Result<Lockfile, Box<WitError>> {
+        let mut lock_path = OsString::from(path.as_os_str());
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let file = match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(lock_held_err(&lock_path))?,
+            Err(e) => Err(e)?
+        };
+        Ok(Lockfile {
+            path: path.to_owned(),
+            lock_path,
+            file,
+            committed: false
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Box<WitError>> {
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    // Flushes the new contents to disk and moves them over the locked file
+    pub fn commit(mut self, repo: &Repository) -> Result<(), Box<WitError>> {
+        self.file.sync_all()?;
+        Repository::adjust_shared_perm(repo, &self.lock_path)?;
+        fs::rename(&self.lock_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn commit_replaces_and_drop_abandons() {
+        let (_dir, repo) = test_util::repo();
+        let path = repo.gitdir().join("file");
+        fs::write(&path, b"old").unwrap();
+
+        let mut lock = Lockfile::acquire(&path).unwrap();
+        lock.write(b"abandoned").unwrap();
+        assert!(Lockfile::acquire(&path).is_err());
+        drop(lock);
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert!(!repo.gitdir().join("file.lock").exists());
+
+        let mut lock = Lockfile::acquire(&path).unwrap();
+        lock.write(b"new").unwrap();
+        lock.commit(&repo).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!repo.gitdir().join("file.lock").exists());
+    }
+}
@@ -0,0 +1,133 @@
+// .mailmap: canonical names and emails for authors who committed under
+// several. Each line is one of
+//   Proper Name <commit@email>
+//   <proper@email> <commit@email>
+//   Proper Name <proper@email> <commit@email>
+//   Proper Name <proper@email> Commit Name <commit@email>
+// Emails, and the commit name of the last form, match case-insensitively.
+use std::fs;
+
+use crate::{
+    error::WitError,
+    repository::Repository,
+    signature::Signature
+};
+
+struct Entry {
+    name: Option<String>,
+    email: Option<String>,
+    // Lowercased, like the lookups
+    commit_name: Option<String>,
+    commit_email: String,
+}
+
+#[derive(Default)]
+pub struct Mailmap {
+    entries: Vec<Entry>,
+}
+
+impl Mailmap {
+    // The .mailmap at the top of the worktree; bare repositories and
+    // worktrees without one get an empty map
+    pub fn load(repo: &Repository) -> Result<Mailmap, Box<WitError>> {
+        let Ok(root) = repo.worktree() else {
+            return Ok(Mailmap::default());
+        };
+        match fs::read_to_string(root.join(".mailmap")) {
+            Ok(data) => Ok(Self::parse(&data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Mailmap::default()),
+            Err(e) => Err(e)?
+        }
+    }
+
+    // Lines that match none of the forms are skipped, as git does
+    pub fn parse(data: &str) -> Mailmap {
+        let mut entries = Vec::new();
+        for line in data.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let Some((name, email, rest)) = split_ident(line) else {
+                continue;
+            };
+            let entry = match split_ident(rest) {
+                Some((commit_name, commit_email, _)) => Entry {
+                    name,
+                    email: Some(email),
+                    commit_name: commit_name.map(|name| name.to_lowercase()),
+                    commit_email: commit_email.to_lowercase(),
+                },
+                None => Entry { name, email: None, commit_name: None, commit_email: email.to_lowercase() }
+            };
+            entries.push(entry);
+        }
+        Mailmap { entries }
+    }
+
+    // signature with its canonical name and email. An entry naming the
+    // commit name as well as the email beats one with just the email, and
+    // later lines beat earlier ones.
+    pub fn map(&self, signature: &Signature) -> Signature {
+        let (name, email) = (signature.name.to_lowercase(), signature.email.to_lowercase());
+        let matches = |entry: &&Entry| entry.commit_email == email;
+        let entry = self.entries.iter().rev().filter(matches).find(|entry| entry.commit_name.as_deref() == Some(name.as_str()))
+            .or_else(|| self.entries.iter().rev().filter(matches).find(|entry| entry.commit_name.is_none()));
+        let mut mapped = signature.clone();
+        if let Some(entry) = entry {
+            if let Some(name) = &entry.name {
+                mapped.name = name.clone();
+            }
+            if let Some(email) = &entry.email {
+                mapped.email = email.clone();
+            }
+        }
+        mapped
+    }
+}
+
+// The "Name <email>" at the start of line and what follows it. The name may
+// be missing.
+fn split_ident(line: &str) -> Option<(Option<String>, String, &str)> {
+    let (name, rest) = line.split_once('<')?;
+    let (email, rest) = rest.split_once('>')?;
+    let name = name.trim();
+    Some(((!name.is_empty()).then(|| name.to_owned()), email.trim().to_owned(), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAILMAP: &str = "\
+# Comments and lines with no email are skipped
+nobody here
+Proper Name <commit@example.com>
+<proper@example.com> <old@EXAMPLE.com>
+Both Fixed <both@example.com> <two@example.com>
+Named Only <named@example.com> Old Name <shared@example.com>
+Fallback <fallback@example.com> <shared@example.com> # trailing comment
+";
+
+    fn map(mailmap: &Mailmap, name: &str, email: &str) -> (String, String) {
+        let mapped = mailmap.map(&Signature::new(name, email, 0, 0));
+        (mapped.name, mapped.email)
+    }
+
+    #[test]
+    fn every_form_maps() {
+        let mailmap = Mailmap::parse(MAILMAP);
+        assert_eq!(mailmap.entries.len(), 5);
+        let owned = |name: &str, email: &str| (name.to_owned(), email.to_owned());
+        assert_eq!(map(&mailmap, "whoever", "Commit@Example.com"), owned("Proper Name", "Commit@Example.com"));
+        assert_eq!(map(&mailmap, "Old", "old@example.com"), owned("Old", "proper@example.com"));
+        assert_eq!(map(&mailmap, "Two", "two@example.com"), owned("Both Fixed", "both@example.com"));
+        // The commit name has to match for the four-part form, in any case
+        assert_eq!(map(&mailmap, "old NAME", "shared@example.com"), owned("Named Only", "named@example.com"));
+        assert_eq!(map(&mailmap, "Someone Else", "shared@example.com"), owned("Fallback", "fallback@example.com"));
+        assert_eq!(map(&mailmap, "Stranger", "stranger@example.com"), owned("Stranger", "stranger@example.com"));
+    }
+
+    #[test]
+    fn later_lines_win() {
+        let mailmap = Mailmap::parse("First <a@example.com>\nSecond <a@example.com>\n");
+        assert_eq!(map(&mailmap, "x", "a@example.com").0, "Second");
+    }
+}
@@ -2,17 +2,43 @@ use std::process::ExitCode;
 use cli::CliExecute;
 
 mod repository;
+mod config;
 mod object;
+mod object_id;
+mod cache;
+// Nothing in the CLI is async yet
+#[cfg(feature = "tokio-io")]
+#[allow(dead_code)]
+mod async_object;
 mod blob;
 mod commit;
 mod tree;
 mod reference;
+mod reflog;
+mod lockfile;
 mod tag;
 mod index;
 mod kvlm;
 mod error;
 mod util;
+mod signature;
+mod mailmap;
+mod diff;
+mod patch;
+mod archive;
+mod bisect;
+mod blame;
+mod rebase;
+mod merge;
+mod checkout;
+mod pack;
+mod transport;
+mod credential;
+mod worktree;
+mod submodule;
 mod cli;
+#[cfg(test)]
+mod test_util;
 
 pub fn main() -> ExitCode {
     let app = cli::setup();
@@ -20,6 +46,9 @@ pub fn main() -> ExitCode {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             println!("{}", e);
+            for candidate in e.candidates() {
+                println!("- {}", candidate);
+            }
             ExitCode::FAILURE
         }
     }
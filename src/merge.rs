@@ -0,0 +1,393 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{
+    blob::{self, Blob},
+    checkout::{commit_files, dirty_paths, update_worktree, Files},
+    commit,
+    diff,
+    error::{WitError, builder::*},
+    index::{Index, IndexEntry},
+    object::{self, WitObject},
+    reference,
+    repository::Repository,
+    tree::{self, Leaf}
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    // theirs is already part of ours; nothing changed
+    UpToDate,
+    // ours was an ancestor of theirs, so HEAD just moved to it
+    FastForward(String),
+    // The merge commit, with ours and theirs as its parents
+    Merged(String),
+    // Both sides changed these paths differently; the worktree has markers
+    // and the index has the base, ours and theirs at stages 1, 2 and 3
+    Conflicts(Vec<PathBuf>),
+}
+
+// The lowest common ancestor of a and b, or None if their histories are unrelated
+pub fn merge_base(repo: &Repository, a: &str, b: &str) -> Result<Option<String>, Box<WitError>> {
+    Ok(merge_bases(repo, a, b)?.into_iter().next())
+}
+
+// Every common ancestor of a and b that no other common ancestor descends
+// from, newest first; there is more than one after criss-cross merges.
+//
+// Found by painting: walking back from both commits, newest first, each
+// commit is marked with the sides it can be reached from. One reached from
+// both is a common ancestor, and everything below it is painted stale, so
+// the walk ends once only stale commits are left.
+pub fn merge_bases(repo: &Repository, a: &str, b: &str) -> Result<Vec<String>, Box<WitError>> {
+    const OURS: u8 = 1;
+    const THEIRS: u8 = 2;
+    const STALE: u8 = 4;
+    if a == b {
+        return Ok(vec![a.to_owned()]);
+    }
+    let time = |sha: &str| -> Result<i64, Box<WitError>> {
+        Ok(commit::read(repo, sha)?.committer().map(|committer| committer.time).unwrap_or(0))
+    };
+
+    let mut paint = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for (sha, side) in [(a, OURS), (b, THEIRS)] {
+        paint.insert(sha.to_owned(), side);
+        queue.push((time(sha)?, sha.to_owned()));
+    }
+    let mut bases = Vec::new();
+    while queue.iter().any(|(_, sha)| paint[sha] & STALE == 0) {
+        let Some((_, sha)) = queue.pop() else {
+            break;
+        };
+        let mut flags = paint[&sha];
+        if flags & (OURS | THEIRS) == OURS | THEIRS {
+            if flags & STALE == 0 {
+                bases.push(sha.clone());
+            }
+            flags |= STALE;
+            paint.insert(sha.clone(), flags);
+        }
+        for parent in commit::parents(repo, &sha)? {
+            let painted = paint.entry(parent.clone()).or_insert(0);
+            if *painted & flags == flags {
+                continue;
+            }
+            *painted |= flags;
+            queue.push((time(&parent)?, parent));
+        }
+    }
+    reduce(repo, bases)
+}
+
+// The best common ancestors of all of shas, like git merge-base --octopus
+pub fn octopus_bases(repo: &Repository, shas: &[String]) -> Result<Vec<String>, Box<WitError>> {
+    let Some((first, rest)) = shas.split_first() else {
+        return Ok(Vec::new());
+    };
+    let mut bases = vec![first.clone()];
+    for sha in rest {
+        let mut next = Vec::new();
+        for base in &bases {
+            for found in merge_bases(repo, base, sha)? {
+                if !next.contains(&found) {
+                    next.push(found);
+                }
+            }
+        }
+        bases = reduce(repo, next)?;
+    }
+    Ok(bases)
+}
+
+// Drops the commits that another of them descends from. Clock skew can let
+// the paint walk reach a common ancestor before one of its descendants.
+fn reduce(repo: &Repository, shas: Vec<String>) -> Result<Vec<String>, Box<WitError>> {
+    if shas.len() < 2 {
+        return Ok(shas);
+    }
+    let mut below = Vec::new();
+    for sha in &shas {
+        let mut ancestors = commit::ancestors(repo, sha)?;
+        ancestors.remove(sha);
+        below.push(ancestors);
+    }
+    Ok(shas.iter().filter(|sha| !below.iter().any(|ancestors| ancestors.contains(*sha))).cloned().collect())
+}
+
+// Merges theirs into ours, which has to be the commit HEAD is at. HEAD,
+// the index and the worktree are updated to the result.
+pub fn merge(repo: &Repository, ours: &str, theirs: &str) -> Result<MergeOutcome, Box<WitError>> {
+    let ours_sha = object::find(repo, ours, Some("commit"), true)?.to_string();
+    let theirs_sha = object::find(repo, theirs, Some("commit"), true)?.to_string();
+    if reference::resolve(repo, "HEAD")? != ours_sha {
+        return Err(missing_data_err(format!("Can only merge into HEAD, which is not at {}", ours)));
+    }
+    if Repository::path(repo, vec!["MERGE_HEAD"]).is_file() {
+        return Err(in_progress_err("You have not concluded your merge (MERGE_HEAD exists); commit it first.".to_owned()));
+    }
+
+    let base = merge_base(repo, &ours_sha, &theirs_sha)?;
+    if base.as_ref() == Some(&theirs_sha) {
+        return Ok(MergeOutcome::UpToDate);
+    }
+    let old = commit_files(repo, &ours_sha)?;
+    let index = Index::read(repo)?;
+    if !index.unmerged().is_empty() {
+        return Err(in_progress_err("Merging is not possible because you have unmerged files.".to_owned()));
+    }
+
+    if base.as_ref() == Some(&ours_sha) {
+        let new = commit_files(repo, &theirs_sha)?;
+        let dirty = dirty_paths(repo, &index, &old, &new)?;
+        if !dirty.is_empty() {
+            return Err(uncommitted_changes_err(dirty));
+        }
+        update_worktree(repo, &old, &new)?;
+        reference::update_head(repo, &theirs_sha, &format!("merge {}: Fast-forward", theirs))?;
+        return Ok(MergeOutcome::FastForward(theirs_sha));
+    }
+
+    let base_files = match &base {
+        Some(base) => commit_files(repo, base)?,
+        None => Files::new()
+    };
+    let new = commit_files(repo, &theirs_sha)?;
+    let (merged, conflicts) = merge_files(repo, &base_files, &old, &new, ours, theirs)?;
+
+    // Conflicted paths get rewritten too, so they have to be clean as well
+    let mut touched = merged.clone();
+    for (path, _) in &conflicts {
+        match new.get(path) {
+            Some(leaf) => touched.insert(path.clone(), leaf.clone()),
+            None => touched.remove(path)
+        };
+    }
+    let dirty = dirty_paths(repo, &index, &old, &touched)?;
+    if !dirty.is_empty() {
+        return Err(uncommitted_changes_err(dirty));
+    }
+
+    let kind = if reference::exists(repo, &format!("refs/heads/{}", theirs)) { "branch" } else { "commit" };
+    let message = format!("Merge {} '{}'\n", kind, theirs);
+    if !conflicts.is_empty() {
+        update_worktree(repo, &old, &merged)?;
+        let root = repo.worktree()?;
+        for (path, data) in &conflicts {
+            if let Some(data) = data {
+                fs::write(tree::safe_dest(root, path)?, data)?;
+            }
+        }
+        stage_conflicts(repo, &conflicts, &base_files, &old, &new)?;
+        // Where git expects them, so the merge can be finished with git commit
+        Repository::write_file(repo, &Repository::path(repo, vec!["MERGE_HEAD"]), format!("{}\n", theirs_sha).as_bytes())?;
+        Repository::write_file(repo, &Repository::path(repo, vec!["MERGE_MSG"]), message.as_bytes())?;
+        return Ok(MergeOutcome::Conflicts(conflicts.into_iter().map(|(path, _)| path).collect()));
+    }
+
+    // The commit is made first so a missing identity fails before any files change
+    let tree = tree::write(repo, &merged)?;
+    let sha = commit::create(repo, &tree, &[ours_sha, theirs_sha], &message, None)?.to_string();
+    update_worktree(repo, &old, &merged)?;
+    reference::update_head(repo, &sha, &format!("merge {}: Merge made by the 'wit' strategy.", theirs))?;
+    Ok(MergeOutcome::Merged(sha))
+}
+
+// Conflicted paths, with the marked-up content to leave in the worktree if there is any
+pub type Conflicts = Vec<(PathBuf, Option<Vec<u8>>)>;
+
+// The path-by-path merge of ours and theirs against base. Conflicted paths keep
+// our version, or theirs if we deleted it, in the returned files.
+pub fn merge_files(repo: &Repository, base: &Files, ours: &Files, theirs: &Files, ours_label: &str, theirs_label: &str) -> Result<(Files, Conflicts), Box<WitError>> {
+    let mut paths = base.keys().chain(ours.keys()).chain(theirs.keys()).cloned().collect::<Vec<PathBuf>>();
+    paths.sort();
+    paths.dedup();
+
+    let mut merged = ours.clone();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (before, mine, other) = (base.get(&path), ours.get(&path), theirs.get(&path));
+        if mine == other || before == other {
+            continue;
+        }
+        if before == mine {
+            match other {
+                Some(leaf) => merged.insert(path, leaf.clone()),
+                None => merged.remove(&path)
+            };
+            continue;
+        }
+
+        // Both sides changed the path, and not in the same way
+        let (mine, other) = match (mine, other) {
+            (Some(mine), Some(other)) => (mine, other),
+            (None, Some(other)) => {
+                merged.insert(path.clone(), other.clone());
+                conflicts.push((path, None));
+                continue;
+            },
+            _ => {
+                conflicts.push((path, None));
+                continue;
+            }
+        };
+        let is_file = |leaf: &Leaf| leaf.mode().starts_with("100");
+        if !is_file(mine) || !is_file(other) || before.map(|leaf| !is_file(leaf)).unwrap_or(false) {
+            conflicts.push((path, None));
+            continue;
+        }
+
+        let old = match before {
+            Some(leaf) => blob::read(repo, leaf.sha())?.data().to_vec(),
+            None => Vec::new()
+        };
+        let (mine_data, other_data) = (blob::read(repo, mine.sha())?.data().to_vec(), blob::read(repo, other.sha())?.data().to_vec());
+        if diff::is_binary(&old) || diff::is_binary(&mine_data) || diff::is_binary(&other_data) {
+            conflicts.push((path, None));
+            continue;
+        }
+        let (data, conflicted) = diff::merge3(&old, &mine_data, &other_data, ours_label, theirs_label);
+        if conflicted {
+            conflicts.push((path, Some(data)));
+            continue;
+        }
+
+        // A mode change on one side survives the other side's content change
+        let mode = match before {
+            Some(leaf) if leaf.mode() == mine.mode() => other.mode(),
+            _ => mine.mode()
+        };
+        let sha = object::write(WitObject::BlobObject(Blob::new(Some(repo), data)), true)?;
+        merged.insert(path.clone(), Leaf::new(mode.to_owned(), path, sha));
+    }
+    Ok((merged, conflicts))
+}
+
+// Replaces the index entries of conflicted paths with every side that has
+// the path, for the user to resolve
+pub fn stage_conflicts(repo: &Repository, conflicts: &Conflicts, base: &Files, ours: &Files, theirs: &Files) -> Result<(), Box<WitError>> {
+    let lock = Index::lock(repo)?;
+    let mut index = Index::read(repo)?;
+    for (path, _) in conflicts {
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        index.remove(name);
+        for (stage, side) in [(1, base), (2, ours), (3, theirs)] {
+            if let Some(leaf) = side.get(path) {
+                index.add(IndexEntry::unmerged(name, leaf.sha(), leaf.mode(), stage)?);
+            }
+        }
+    }
+    index.write_locked(repo, lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    // A repository with an identity, so merges can commit, and a first
+    // commit on main
+    fn setup(files: &[(&str, &[u8])]) -> (test_util::TempDir, Repository, String) {
+        let (dir, _) = test_util::repo();
+        test_util::git(dir.path(), &["config", "user.name", "A U Thor"]);
+        test_util::git(dir.path(), &["config", "user.email", "author@example.com"]);
+        let repo = Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap();
+        let first = test_util::commit(&repo, &test_util::tree(&repo, files), &[], "first");
+        reference::update(&repo, "refs/heads/main", &first, None, "first").unwrap();
+        (dir, repo, first)
+    }
+
+    #[test]
+    fn bases_are_found_by_painting() {
+        let (_dir, repo) = test_util::repo();
+        let tree = test_util::tree(&repo, &[]);
+        let root = test_util::commit(&repo, &tree, &[], "root");
+        let a = test_util::commit(&repo, &tree, &[&root], "a");
+        let b = test_util::commit(&repo, &tree, &[&root], "b");
+        let a2 = test_util::commit(&repo, &tree, &[&a], "a2");
+        assert_eq!(merge_base(&repo, &a2, &b).unwrap().as_ref(), Some(&root));
+        assert_eq!(merge_base(&repo, &a2, &a).unwrap().as_ref(), Some(&a));
+        assert_eq!(merge_base(&repo, &b, &b).unwrap().as_ref(), Some(&b));
+
+        // Criss-cross: both merges have a and b as parents
+        let x = test_util::commit(&repo, &tree, &[&a, &b], "x");
+        let y = test_util::commit(&repo, &tree, &[&b, &a], "y");
+        let mut bases = merge_bases(&repo, &x, &y).unwrap();
+        bases.sort();
+        let mut expected = vec![a.clone(), b.clone()];
+        expected.sort();
+        assert_eq!(bases, expected);
+        assert_eq!(octopus_bases(&repo, &[x, y, a2]).unwrap(), vec![a]);
+
+        let other = test_util::commit(&repo, &tree, &[], "unrelated");
+        assert_eq!(merge_base(&repo, &other, &b).unwrap(), None);
+    }
+
+    #[test]
+    fn merge3_matches_git_merge_file() {
+        let dir = test_util::TempDir::new();
+        let base = b"1\n2\n3\n4\n5\n6\n7\n";
+        let ours = b"1\nours\n3\n4\n5\n6\n7\n";
+        let cases: [&[u8]; 2] = [b"1\n2\n3\n4\n5\ntheirs\n7\n", b"1\nTHEIRS\n3\n4\n5\n6\n7\n"];
+        for theirs in cases {
+            let (merged, conflicted) = diff::merge3(base, ours, theirs, "ours", "theirs");
+            for (name, data) in [("base", &base[..]), ("ours", ours), ("theirs", theirs)] {
+                fs::write(dir.path().join(name), data).unwrap();
+            }
+            let expected = test_util::git(dir.path(), &["merge-file", "-p", "-L", "ours", "-L", "base", "-L", "theirs", "ours", "base", "theirs"]);
+            assert_eq!(String::from_utf8(merged).unwrap(), expected);
+            assert_eq!(conflicted, expected.contains("<<<<<<<"));
+        }
+    }
+
+    #[test]
+    fn fast_forward_and_up_to_date() {
+        let (dir, repo, first) = setup(&[("a", b"one\n")]);
+        crate::checkout::switch(&repo, "main").unwrap();
+        let second = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"two\n")]), &[&first], "second");
+        assert_eq!(merge(&repo, "main", &second).unwrap(), MergeOutcome::FastForward(second.clone()));
+        assert_eq!(reference::resolve(&repo, "refs/heads/main").unwrap(), second);
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"two\n");
+        assert_eq!(merge(&repo, "main", &first).unwrap(), MergeOutcome::UpToDate);
+    }
+
+    #[test]
+    fn clean_three_way_merge_commits_both_parents() {
+        let (dir, repo, first) = setup(&[("a", b"1\n2\n3\n4\n5\n"), ("b", b"b\n")]);
+        let ours = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"one\n2\n3\n4\n5\n"), ("b", b"b\n")]), &[&first], "ours");
+        reference::update(&repo, "refs/heads/main", &ours, Some(&first), "ours").unwrap();
+        crate::checkout::switch(&repo, "main").unwrap();
+        let theirs = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"1\n2\n3\n4\nfive\n"), ("c", b"c\n")]), &[&first], "theirs");
+        reference::update(&repo, "refs/heads/topic", &theirs, None, "theirs").unwrap();
+
+        let MergeOutcome::Merged(sha) = merge(&repo, &ours, "topic").unwrap() else {
+            panic!("expected a merge commit");
+        };
+        assert_eq!(commit::parents(&repo, &sha).unwrap(), vec![ours, theirs]);
+        assert_eq!(reference::resolve(&repo, "HEAD").unwrap(), sha);
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"one\n2\n3\n4\nfive\n");
+        assert!(!dir.path().join("b").exists());
+        assert_eq!(fs::read(dir.path().join("c")).unwrap(), b"c\n");
+        assert_eq!(commit::read(&repo, &sha).unwrap().message(), "Merge branch 'topic'\n");
+    }
+
+    #[test]
+    fn conflicts_are_staged_and_marked() {
+        let (dir, repo, first) = setup(&[("a", b"base\n")]);
+        let ours = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"ours\n")]), &[&first], "ours");
+        reference::update(&repo, "refs/heads/main", &ours, Some(&first), "ours").unwrap();
+        crate::checkout::switch(&repo, "main").unwrap();
+        let theirs = test_util::commit(&repo, &test_util::tree(&repo, &[("a", b"theirs\n")]), &[&first], "theirs");
+
+        assert_eq!(merge(&repo, "main", &theirs).unwrap(), MergeOutcome::Conflicts(vec![PathBuf::from("a")]));
+        assert_eq!(reference::resolve(&repo, "HEAD").unwrap(), ours);
+        let marked = format!("<<<<<<< main\nours\n=======\ntheirs\n>>>>>>> {}\n", theirs);
+        assert_eq!(fs::read_to_string(dir.path().join("a")).unwrap(), marked);
+        let stages = Index::read(&repo).unwrap().entries().iter().map(|entry| entry.stage()).collect::<Vec<u16>>();
+        assert_eq!(stages, vec![1, 2, 3]);
+        assert!(Repository::path(&repo, vec!["MERGE_HEAD"]).is_file());
+        assert!(matches!(*merge(&repo, "main", &theirs).unwrap_err(), WitError::OperationInProgress(_)));
+    }
+}
@@ -1,27 +1,34 @@
-use std::io::prelude::*;
-use std::fs;
-use std::path::PathBuf;
+use std::io::{self, prelude::*, BufReader};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::str::from_utf8;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use flate2::{
     Compression,
     read::ZlibDecoder,
     write::ZlibEncoder
 };
-use crypto::{
-    sha1::Sha1,
-    digest::Digest
-};
 use regex::Regex;
 
 use crate::blob::Blob;
-use crate::commit::Commit;
+use crate::commit::{self, Commit};
 use crate::error::{WitError, builder::*};
+use crate::object_id::{Hasher, ObjectFormat, ObjectId};
 use crate::repository::Repository;
 use crate::tag::Tag;
-use crate::tree::Tree;
+use crate::tree::{self, Tree};
 use crate::object::WitObject::*;
 use crate::reference;
+use crate::reflog;
+use crate::pack::{self, PackIndex};
+use crate::submodule::{self, SubmoduleConfig};
+use crate::mailmap::Mailmap;
+use crate::signature::Signature;
 
 pub trait Find<T> {
     fn find(&self, element: T) -> Result<usize, Box<WitError>> { self.find_from(element, 0) }
@@ -29,12 +36,6 @@ pub trait Find<T> {
     fn find_from(&self, element: T, start: usize) -> Result<usize, Box<WitError>>;
 
     fn find_some(&self, element: T, start: usize) -> Option<usize>;
-
-    #[allow(unused_variables)]
-    fn find_signed(&self, element: T, start: usize) -> isize {0}
-
-    #[allow(unused_variables)]
-    fn find_exact(&self, element: T, start: usize) -> usize {0}
 }
 
 pub trait Replace {
@@ -45,12 +46,7 @@ impl Replace for Vec<u8> {
     fn replace(&mut self, from: &str, to: &str) -> Vec<u8> {
         let mut result = Vec::new();
         let mut start = 0;
-        loop {
-            let end = match self.find_some(from.as_bytes()[0], start) {
-                Some(val) => val,
-                None => break
-            };
-
+        while let Some(end) = self.find_some(from.as_bytes()[0], start) {
             result.extend_from_slice(&self[start..end]);
             result.extend_from_slice(to.as_bytes());
             start = end + from.len();
@@ -60,33 +56,23 @@ impl Replace for Vec<u8> {
     }
 }
 
-impl<T: PartialEq + std::fmt::Debug> Find<T> for Vec<T> {
+impl<T: PartialEq + std::fmt::Debug> Find<T> for [T] {
     fn find_from(&self, element: T, start: usize) -> Result<usize, Box<WitError>> {
-        self.iter().skip(start).position(|el| *el == element).ok_or(
-            io_err(format!("{:?} not found.", element))
+        self.find_some(element, start).ok_or(
+            malformed_object_err("Element not found.".to_owned())
         )
     }
 
     fn find_some(&self, element: T, start: usize) -> Option<usize> {
-        self.iter().skip(start).position(|el| *el == element)
-    }
-
-    fn find_signed(&self, element: T, start: usize) -> isize {
-        match self.iter().skip(start).position(|el| *el == element) {
-            Some(idx) => idx as isize,
-            None => -1
-        }
-    }
-
-    fn find_exact(&self, element: T, start: usize) -> usize {
-        self.iter().skip(start).position(|el| *el == element).unwrap()
+        self.iter().skip(start).position(|el| *el == element).map(|idx| idx + start)
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum WitObject<'a> {
     BlobObject(Blob<'a>),
     CommitObject(Commit<'a>),
-    TreeObject(Tree),
+    TreeObject(Tree<'a>),
     TagObject(Tag<'a>)
 }
 
@@ -119,6 +105,17 @@ impl<'a> WitObject<'a> {
     }
 }
 
+impl<'a> fmt::Display for WitObject<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WitObject::BlobObject(blob) => write!(f, "{}", blob),
+            WitObject::CommitObject(commit) => write!(f, "{}", commit),
+            WitObject::TreeObject(tree) => write!(f, "{}", tree),
+            WitObject::TagObject(tag) => write!(f, "{}", tag)
+        }
+    }
+}
+
 pub trait Object {
     fn serialize(&self) -> Result<Vec<u8>, Box<WitError>>;
     fn deserialize(&mut self, data: Vec<u8>) -> Result<(), Box<WitError>>;
@@ -127,82 +124,211 @@ pub trait Object {
     fn repo(&self) -> Option<&Repository>;
 }
 
-pub fn read<'a>(repo: &'a Repository, sha: &'a str) -> Result<WitObject<'a>, Box<WitError>> {
-    let path = Repository::file(&repo, vec!["objects", &sha[..2], &sha[2..]], false)?;
+pub fn read<'a>(repo: &'a Repository, sha: &ObjectId) -> Result<WitObject<'a>, Box<WitError>> {
+    let (fmt, data) = read_shared(repo, sha)?;
+    match fmt.as_str() {
+        // Trees are parsed straight from the cached body
+        "tree" => Ok(WitObject::TreeObject(Tree::from(Some(repo), &data)?)),
+        _ => build(&fmt, Some(repo), Some(data.to_vec()))
+    }
+}
+
+// Where the loose object would be, if sha is the repository's kind of id
+pub fn loose_path(repo: &Repository, sha: &ObjectId) -> Result<PathBuf, Box<WitError>> {
+    if sha.format() != repo.object_format() {
+        Err(malformed_object_err(format!("{} is not a {} object id", sha, repo.object_format().name())))?
+    }
+    let (dir, file) = sha.loose_path();
+    Ok(Repository::path(repo, vec!["objects", &dir, &file]))
+}
+
+// The type and body of an object, loose or packed, without parsing the body
+pub fn read_raw(repo: &Repository, sha: &ObjectId) -> Result<(String, Vec<u8>), Box<WitError>> {
+    if let Some((fmt, data)) = repo.cached_object(sha) {
+        return Ok((fmt, data.to_vec()));
+    }
+    let (fmt, data) = read_uncached(repo, sha)?;
+    repo.cache_object(sha, &fmt, Rc::from(&data[..]));
+    Ok((fmt, data))
+}
 
-    let raw = fs::read(path)?;
-    let mut decoded = Vec::<u8>::new();
-    ZlibDecoder::new(&raw[..]).read_exact(&mut decoded)?;
+// Like read_raw, but sharing the body with the object cache, for callers
+// that only need to look at it
+pub fn read_shared(repo: &Repository, sha: &ObjectId) -> Result<(String, Rc<[u8]>), Box<WitError>> {
+    if let Some(object) = repo.cached_object(sha) {
+        return Ok(object);
+    }
+    let (fmt, data) = read_uncached(repo, sha)?;
+    let data = Rc::<[u8]>::from(data);
+    repo.cache_object(sha, &fmt, data.clone());
+    Ok((fmt, data))
+}
+
+fn read_uncached(repo: &Repository, sha: &ObjectId) -> Result<(String, Vec<u8>), Box<WitError>> {
+    let path = loose_path(repo, sha)?;
+    if path.is_file() {
+        let raw = fs::read(path)?;
+        let mut decoded = Vec::<u8>::new();
+        ZlibDecoder::new(&raw[..]).read_to_end(&mut decoded)?;
+        split_header(sha, decoded)
+    } else {
+        pack::read_object(repo, sha)?.ok_or(unknown_object_err(format!("Unknown object {}.", sha)))
+    }
+}
 
-    let x = decoded.find(b' ') ?;
+// Splits an inflated loose object into its type and body
+pub fn split_header(sha: &ObjectId, decoded: Vec<u8>) -> Result<(String, Vec<u8>), Box<WitError>> {
+    let x = decoded.find(b' ')?;
     let fmt = &decoded[..x];
 
-    let y = decoded[x..].to_vec().find(b'\x00')?;
+    let y = decoded.find_from(b'\x00', x)?;
 
-    let size = from_utf8(&decoded[x..y])?.parse::<usize>()?;
+    let size = from_utf8(&decoded[x+1..y])?.parse::<usize>()?;
     if size != decoded.len() - y - 1 {
         Err(malformed_object_err(format!("Malformed object {}: bad length", sha)))?
     }
 
-    build(from_utf8(&fmt)?, Some(repo), Some(raw[y+1..].to_vec()))
+    Ok((from_utf8(fmt)?.to_owned(), decoded[y+1..].to_vec()))
 }
 
-pub fn find<'a>(repo: &'a Repository, name: &str, fmt: Option<&str>, follow: bool) -> Result<String, Box<WitError>> {
-    let sha = self::resolve(repo, name)?.ok_or(
+// Inflates just enough of a loose object to read its header, leaving the
+// reader at the start of the body
+fn open_loose(repo: &Repository, sha: &ObjectId) -> Result<(String, usize, impl BufRead), Box<WitError>> {
+    let path = loose_path(repo, sha)?;
+    if !path.is_file() {
+        Err(unknown_object_err(format!("Unknown object {}.", sha)))?
+    }
+    let mut reader = BufReader::new(ZlibDecoder::new(File::open(path)?));
+
+    // "<type> <size>\0" is never longer than this
+    let mut header = Vec::new();
+    (&mut reader).take(64).read_until(b'\x00', &mut header)?;
+    if header.pop() != Some(b'\x00') {
+        Err(malformed_object_err(format!("Malformed object {}: bad header", sha)))?
+    }
+    let (fmt, size) = from_utf8(&header)?.split_once(' ').ok_or(
+        malformed_object_err(format!("Malformed object {}: bad header", sha))
+    )?;
+    Ok((fmt.to_owned(), size.parse::<usize>()?, reader))
+}
+
+pub fn read_type(repo: &Repository, sha: &ObjectId) -> Result<String, Box<WitError>> {
+    Ok(stat(repo, sha)?.0)
+}
+
+// The type and size of an object, loose or packed, from its header alone
+pub fn stat(repo: &Repository, sha: &ObjectId) -> Result<(String, usize), Box<WitError>> {
+    if let Some((fmt, data)) = repo.cached_object(sha) {
+        return Ok((fmt, data.len()));
+    }
+    if !loose_path(repo, sha)?.is_file() {
+        return pack::stat_object(repo, sha)?.ok_or(unknown_object_err(format!("Unknown object {}.", sha)));
+    }
+    let (fmt, size, _) = open_loose(repo, sha)?;
+    Ok((fmt, size))
+}
+
+// Copies the body of an object to writer without holding all of it in
+// memory, returning the object's type
+pub fn read_to_writer(repo: &Repository, sha: &ObjectId, writer: &mut impl Write) -> Result<String, Box<WitError>> {
+    // Packed objects are only ever read whole
+    if !loose_path(repo, sha)?.is_file() {
+        let (fmt, data) = read_shared(repo, sha)?;
+        writer.write_all(&data)?;
+        return Ok(fmt);
+    }
+    let (fmt, size, mut reader) = open_loose(repo, sha)?;
+    let copied = io::copy(&mut reader, writer)?;
+    if copied != size as u64 {
+        Err(malformed_object_err(format!("Malformed object {}: bad length", sha)))?
+    }
+    Ok(fmt)
+}
+
+pub fn exists(repo: &Repository, sha: &ObjectId) -> bool {
+    loose_path(repo, sha).map(|path| path.is_file()).unwrap_or(false) || pack::contains(repo, sha)
+}
+
+pub fn find(repo: &Repository, name: &str, fmt: Option<&str>, follow: bool) -> Result<ObjectId, Box<WitError>> {
+    let sha = self::resolve(repo, name, None)?.ok_or(
         unknown_reference_err(format!("Unknown reference {}.", name))
     )?;
     if sha.len() > 1 {
-        let mut candidates = String::new();
-        sha.iter().for_each(|s| {
-            candidates.push_str(&("\n- ".to_owned() + s));
-        });
-        Err(ambiguous_reference_err(format!("Ambiguous reference {}: Candidates are:{}\n", name, candidates)))?
+        Err(ambiguous_reference_err(name, sha.clone()))?
     }
-    let mut sha = sha.get(0).unwrap().to_string();
+    let mut sha = *sha.first().ok_or(
+        unknown_reference_err(format!("Unknown reference {}.", name))
+    )?;
 
     if fmt.is_none() {
-        return Ok(sha.to_string());
+        return Ok(sha);
     }
 
     loop {
-        let obj = self::read(repo, &sha)?;
-        let obj_fmt = obj.fmt();
         let fmt = fmt.unwrap().as_bytes();
-        if obj_fmt == fmt {
-            return Ok(sha.to_string());
+        if read_type(repo, &sha)?.as_bytes() == fmt {
+            return Ok(sha);
         }
         if !follow {
-            return Err(unknown_object_err(format!("Unknown object {}.", sha)))?;
+            Err(unknown_object_err(format!("Unknown object {}.", sha)))?;
         }
 
-
-        if let TagObject(mut tag) = obj {
-            sha = tag.kvlm().get("object").unwrap().get(0).unwrap().to_string();
-        } else if let CommitObject(commit) = obj {
-            if fmt == b"tree" {
-                sha = commit.kvlm().get("tree").unwrap().get(0).unwrap().to_string();
-            }
-        }
-
-        return Err(unknown_object_err(format!("Unknown object {}.", sha)))?;
+        let obj = self::read(repo, &sha)?;
+        sha = match obj {
+            TagObject(mut tag) => ObjectId::from_hex(tag.kvlm().get("object").and_then(|v| v.first()).ok_or(
+                malformed_object_err(format!("Tag {} has no object", sha))
+            )?)?,
+            CommitObject(commit) if fmt == b"tree" => ObjectId::from_hex(commit.kvlm().get("tree").and_then(|v| v.first()).ok_or(
+                malformed_object_err(format!("Commit {} has no tree", sha))
+            )?)?,
+            _ => return Err(unknown_object_err(format!("Unknown object {}.", sha)))?
+        };
     }
 }
 
-pub fn resolve(repo: &Repository, name: &str) -> Result<Option<Vec<String>>, Box<WitError>> {
-    let mut candidates: Vec<String> = Vec::new();
-    let hash_re = Regex::new("^[0-9a-fA-F]{4,40}$")?;
+// Branches, tags and remotes, in the same order git checks them
+fn full_refname(repo: &Repository, name: &str) -> Option<String> {
+    ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"].iter()
+        .map(|prefix| format!("{}{}", prefix, name))
+        .find(|ref_path| ref_path.starts_with("refs/") && reference::exists(repo, ref_path))
+}
+
+// Everything name could refer to. A short sha is looked up among the loose
+// objects and in packs, which are read from disk unless the caller passes
+// the ones it already has open.
+pub fn resolve(repo: &Repository, name: &str, packs: Option<&[PackIndex]>) -> Result<Option<Vec<ObjectId>>, Box<WitError>> {
+    let mut candidates: Vec<ObjectId> = Vec::new();
+    let hex_len = repo.object_format().hex_len();
+    let hash_re = Regex::new(&format!("^[0-9a-fA-F]{{4,{}}}$", hex_len))?;
 
-    if name.trim().len() == 0 {
+    if name.trim().is_empty() {
         return Ok(None);
     }
     if name == "HEAD" {
-        return Ok(Some(vec![ reference::resolve(repo, "HEAD")? ]));
+        return Ok(Some(vec![ ObjectId::from_hex(&reference::resolve(repo, "HEAD")?)? ]));
+    }
+
+    // <ref>@{n}: where the ref was n moves ago. A bare @{n} is the current branch.
+    if let Some(caps) = Regex::new(r"^(.*)@\{(\d+)\}$")?.captures(name) {
+        let refname = match &caps[1] {
+            "" => reference::head_target(repo).unwrap_or("HEAD".to_owned()),
+            "HEAD" => "HEAD".to_owned(),
+            short => full_refname(repo, short).ok_or(
+                unknown_reference_err(format!("Unknown reference {}.", short))
+            )?
+        };
+        let n = caps[2].parse::<usize>()?;
+        return Ok(Some(vec![ ObjectId::from_hex(&reflog::nth(repo, &refname, n)?)? ]));
+    }
+
+    if let Some(ref_path) = full_refname(repo, name) {
+        return Ok(Some(vec![ ObjectId::from_hex(&reference::resolve(repo, &ref_path)?)? ]));
     }
 
     if hash_re.is_match(name) {
         let name = name.to_lowercase();
-        if name.len() == 40 {
-            return Ok(Some(vec![ name ]));
+        if name.len() == hex_len {
+            return Ok(Some(vec![ ObjectId::from_hex(&name)? ]));
         }
 
         let prefix = &name[0..2];
@@ -215,106 +341,264 @@ pub fn resolve(repo: &Repository, name: &str) -> Result<Option<Vec<String>>, Box
                     utf8_error(format!("Cannot convert filename to string"))
                 )?;*/
                 let f = f.to_str().ok_or(
-                    utf8_err(format!("Cannot convert filename to string"))
+                    utf8_err("Cannot convert filename to string".to_owned())
                 )?;
+                // Case-insensitive filesystems may hand back any case, and
+                // anything that isn't an object name (editor and OS litter) is skipped
+                let f = f.to_lowercase();
+                if f.len() != hex_len - 2 || !f.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    continue;
+                }
                 if f.starts_with(rem) {
-                    candidates.push(prefix.to_owned() + f)
+                    candidates.push(ObjectId::from_hex(&(prefix.to_owned() + &f))?)
                 }
             }
         }
+
+        let opened;
+        let packs = match packs {
+            Some(packs) => packs,
+            None => {
+                opened = pack::indexes(repo)?;
+                &opened[..]
+            }
+        };
+        for index in packs {
+            candidates.extend(index.with_prefix(&name));
+        }
+        // An object can be both loose and packed
+        candidates.sort();
+        candidates.dedup();
     }
 
     Ok(Some(candidates))
 }
 
-pub fn write(obj: WitObject, actually_write: bool) -> Result<String, Box<WitError>> {
+pub fn write(obj: WitObject, actually_write: bool) -> Result<ObjectId, Box<WitError>> {
     let data = obj.serialize()?;
-    let mut result = Vec::new();
-    result.extend(obj.fmt());
-    result.extend(vec![b' ']);
-    result.extend(data.len().to_string().as_bytes().to_vec());
-    result.extend(vec![b'\x00']);
-    result.extend(data);
+    if actually_write {
+        let repo = obj.repo().ok_or(repo_not_found_err("No repo found for object".to_owned()))?;
+        write_raw(repo, from_utf8(&obj.fmt())?, &data)
+    } else {
+        let format = obj.repo().map(|repo| repo.object_format()).unwrap_or_default();
+        Ok(hash_raw(format, from_utf8(&obj.fmt())?, &data))
+    }
+}
 
-    let mut sha = Sha1::new();
-    sha.input(&result);
-    let sha = sha.result_str();
+pub fn header(fmt: &str, size: usize) -> Vec<u8> {
+    format!("{} {}\x00", fmt, size).into_bytes()
+}
 
-    if actually_write {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&result)?;
+pub fn hash_raw(format: ObjectFormat, fmt: &str, data: &[u8]) -> ObjectId {
+    format.digest(&[&header(fmt, data.len()), data])
+}
 
-        fs::write(
-            Repository::file(
-                obj.repo().ok_or(repo_not_found_err(format!("No repo found for object")))?, 
-                vec!["objects"],
-                actually_write
-            )?, // Path
-            encoder.finish()? // Data
-        )?;
+// Stores an already serialized object body as a loose object
+pub fn write_raw(repo: &Repository, fmt: &str, data: &[u8]) -> Result<ObjectId, Box<WitError>> {
+    let sha = hash_raw(repo.object_format(), fmt, data);
+    if !exists(repo, &sha) {
+        write_stream(repo.object_format(), Some(repo), fmt, data.len(), &mut &data[..])?;
     }
-
+    // Whatever was just written is likely to be read again soon
+    repo.cache_object(&sha, fmt, Rc::from(data));
     Ok(sha)
 }
 
-fn build<'a>(fmt: &str, repo: Option<&'a Repository>, data: Option<Vec<u8>>) -> Result<WitObject<'a>, Box<WitError>> {
-    match fmt {
-        "blob" => Ok(WitObject::BlobObject(Blob::new(repo, data.ok_or(missing_data_err("Data is required to construct a blob.".to_owned()))?))),
-        "commit" => Ok(WitObject::CommitObject(Commit::new(repo))),
-        "tree" => Ok(WitObject::TreeObject(Tree::from(&data.ok_or(missing_data_err("Data is required to construct a tree.".to_owned()))?)?)),
-        "tag" => Ok(WitObject::TagObject(Tag::new(repo))),
-        _ => Err(unknown_object_err(format!("Unknown object type {}", fmt)))
+// Feeds everything written to it through the hasher and, if there is one,
+// the encoder
+struct Tee<W: Write> {
+    hasher: Hasher,
+    encoder: Option<ZlibEncoder<W>>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match &mut self.encoder {
+            Some(encoder) => encoder.write(buf)?,
+            None => buf.len()
+        };
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+fn stream_into<W: Write>(mut tee: Tee<W>, fmt: &str, size: usize, reader: &mut dyn Read) -> Result<ObjectId, Box<WitError>> {
+    tee.write_all(&header(fmt, size))?;
+    let copied = io::copy(reader, &mut tee)?;
+    if copied != size as u64 {
+        Err(malformed_object_err(format!("Expected {} bytes of {}, got {}", size, fmt, copied)))?
+    }
+    if let Some(encoder) = tee.encoder {
+        encoder.finish()?;
     }
+    Ok(tee.hasher.finish())
 }
 
-pub fn hash<'a>(fd: &str, fmt: &str, repo: Option<&'a Repository>) -> Result<String, Box<WitError>>{
-    write(build(fmt, repo, Some(fs::read(fd)?))?, repo.is_none())
+// Hashes a `size` byte object read from reader, and stores it in repo if
+// one is given. Only a fixed size buffer is held at a time, so the object
+// goes to a temporary file until its name is known.
+pub fn write_stream(format: ObjectFormat, repo: Option<&Repository>, fmt: &str, size: usize, reader: &mut dyn Read) -> Result<ObjectId, Box<WitError>> {
+    let Some(repo) = repo else {
+        return stream_into(Tee::<File> { hasher: Hasher::new(format), encoder: None }, fmt, size, reader);
+    };
+
+    static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let temp_name = format!("tmp_obj_{}_{}", process::id(), TEMP_COUNT.fetch_add(1, Ordering::Relaxed));
+    let temp = Repository::file(repo, vec!["objects", &temp_name], true)?;
+    let tee = Tee {
+        hasher: Hasher::new(format),
+        encoder: Some(ZlibEncoder::new(File::create(&temp)?, Compression::default()))
+    };
+    let sha = match stream_into(tee, fmt, size, reader) {
+        Ok(sha) => sha,
+        Err(e) => {
+            fs::remove_file(&temp)?;
+            return Err(e);
+        }
+    };
+
+    let (dir, file) = sha.loose_path();
+    let path = Repository::file(repo, vec!["objects", &dir, &file], true)?;
+    if path.exists() {
+        fs::remove_file(&temp)?;
+    } else {
+        fs::rename(&temp, &path)?;
+        Repository::adjust_shared_perm(repo, &path)?;
+    }
+    Ok(sha)
 }
 
-pub fn graphviz(repo: &Repository, sha: String, seen: &mut Vec<String>) -> Result<(), Box<WitError>> {
-    if seen.contains(&sha) {
-        return Ok(())
+pub fn build<'a>(fmt: &str, repo: Option<&'a Repository>, data: Option<Vec<u8>>) -> Result<WitObject<'a>, Box<WitError>> {
+    match fmt {
+        "blob" => Ok(WitObject::BlobObject(Blob::new(repo, data.ok_or(missing_data_err("Data is required to construct a blob.".to_owned()))?))),
+        "commit" => {
+            let mut commit = Commit::new(repo);
+            commit.deserialize(data.ok_or(missing_data_err("Data is required to construct a commit.".to_owned()))?)?;
+            Ok(WitObject::CommitObject(commit))
+        },
+        "tree" => Ok(WitObject::TreeObject(Tree::from(repo, &data.ok_or(missing_data_err("Data is required to construct a tree.".to_owned()))?)?)),
+        "tag" => {
+            let mut tag = Tag::new(repo);
+            tag.deserialize(data.ok_or(missing_data_err("Data is required to construct a tag.".to_owned()))?)?;
+            Ok(WitObject::TagObject(tag))
+        },
+        _ => Err(unknown_object_err(format!("Unknown object type {}", fmt)))
     }
+}
 
-    seen.push(sha.clone());
-    let commit = match self::read(repo, &sha)? {
-        WitObject::CommitObject(commit) => {
-            match commit.fmt().as_slice() {
-                b"commit" => commit,
-                _ => return Err(malformed_object_err(format!("Malformed commit {}", sha)))
-            }
+// Hashes the file at path as an object of type fmt, storing it with
+// actually_write. Blobs are streamed from disk; anything else is parsed
+// first to check it. A path of "-" reads stdin instead.
+pub fn hash(path: &str, fmt: &str, repo: Option<&Repository>, actually_write: bool) -> Result<ObjectId, Box<WitError>>{
+    // A pipe's size isn't known up front, and the header needs it, so stdin is read whole
+    let piped = match path {
+        "-" => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Some(data)
         },
-        obj => Err(unknown_object_err(
-            format!("Cannot log a non-commit object; found {}", String::from_utf8(obj.fmt()).unwrap_or("<invalid>".to_owned()))
-        ))?
+        _ => None
     };
+    if fmt != "blob" {
+        let data = match piped {
+            Some(data) => data,
+            None => fs::read(path)?
+        };
+        return write(build(fmt, repo, Some(data))?, actually_write);
+    }
 
-    if !commit.kvlm().contains_key("parent") {
-        return Ok(())
+    let format = repo.map(|repo| repo.object_format()).unwrap_or_default();
+    let target = match actually_write {
+        true => Some(repo.ok_or(repo_not_found_err("No repo found for object".to_owned()))?),
+        false => None
+    };
+    if let Some(data) = piped {
+        return write_stream(format, target, fmt, data.len(), &mut data.as_slice());
     }
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len() as usize;
+    write_stream(format, target, fmt, size, &mut file)
+}
+
+// Prints an edge for every parent of every commit reachable from sha. The
+// walk keeps its own stack, so long histories can't overflow the call stack.
+pub fn graphviz(repo: &Repository, sha: String, seen: &mut HashSet<String>) -> Result<(), Box<WitError>> {
+    let mut stack = vec![sha];
+    while let Some(sha) = stack.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        let commit = match self::read(repo, &ObjectId::from_hex(&sha)?)? {
+            WitObject::CommitObject(commit) => {
+                match commit.fmt().as_slice() {
+                    b"commit" => commit,
+                    _ => return Err(malformed_object_err(format!("Malformed commit {}", sha)))
+                }
+            },
+            obj => Err(unknown_object_err(
+                format!("Cannot log a non-commit object; found {}", String::from_utf8(obj.fmt()).unwrap_or("<invalid>".to_owned()))
+            ))?
+        };
 
-    let parents = commit.kvlm().get("parent").ok_or(malformed_object_err("No parent".to_owned()))?;
-    for parent in parents {
-        println!("c_{} -> c_{}", sha, parent);
-        graphviz(repo, parent.clone(), seen)?;
+        // Parents past a shallow boundary are not in the repository
+        if repo.is_shallow(&sha) {
+            continue;
+        }
+
+        let parents = commit.parents();
+        for parent in &parents {
+            println!("c_{} -> c_{}", sha, parent);
+        }
+        // Reversed so the first parent's history comes out first
+        stack.extend(parents.into_iter().rev());
     }
     Ok(())
 }
 
-pub fn checkout<'a>(repo: &'a Repository, tree: &Tree, path: &PathBuf) -> Result<(), Box<WitError>> {
+// Writes tree out under path. Submodules get an empty directory, or a clone
+// of the recorded commit when recurse is set.
+pub fn checkout(repo: &Repository, tree: &Tree, path: &Path, recurse: bool) -> Result<(), Box<WitError>> {
+    let mut submodules = Vec::new();
+    if let Some(leaf) = tree.leaves().iter().find(|leaf| leaf.path() == Path::new(".gitmodules")) {
+        if let WitObject::BlobObject(blob) = read(repo, leaf.sha())? {
+            submodules = submodule::parse(&String::from_utf8_lossy(blob.data()))?;
+        }
+    }
+    checkout_tree(repo, tree, path, path, &submodules, recurse)
+}
+
+fn checkout_tree(repo: &Repository, tree: &Tree, path: &Path, root: &Path, submodules: &[SubmoduleConfig], recurse: bool) -> Result<(), Box<WitError>> {
     let mut obj: WitObject;
     let mut dest: PathBuf;
     for leaf in tree.leaves() {
-        obj = read(repo, &leaf.sha())?;
-        dest = PathBuf::from(path).join(&leaf.path());
+        dest = tree::safe_dest(root, &path.strip_prefix(root).unwrap_or(Path::new("")).join(leaf.path()))?;
+
+        // Gitlinks name a commit in another repository, not an object in this one
+        if leaf.mode() == "160000" {
+            fs::create_dir_all(&dest)?;
+            let relative = dest.strip_prefix(root).unwrap_or(&dest);
+            match submodules.iter().find(|submodule| submodule.path == relative) {
+                Some(submodule) if recurse => submodule::clone(repo, submodule, leaf.sha(), &dest)?,
+                _ => ()
+            }
+            continue;
+        }
 
+        obj = read(repo, leaf.sha())?;
         match obj {
             WitObject::BlobObject(blob) => {
                 fs::write(&dest, blob.data())?;
             },
             WitObject::TreeObject(tree) => {
                 fs::create_dir_all(&dest)?;
-                checkout(repo, &tree, &dest)?;
+                checkout_tree(repo, &tree, &dest, root, submodules, recurse)?;
             },
             _ => return Err(unknown_object_err(
                 format!(
@@ -326,4 +610,449 @@ pub fn checkout<'a>(repo: &'a Repository, tree: &Tree, path: &PathBuf) -> Result
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+// The subjects of the commits reachable from start, grouped by author after
+// .mailmap has been applied. Authors with the most commits come first; each
+// author's subjects are oldest first.
+pub fn shortlog(repo: &Repository, start: &str) -> Result<Vec<(Signature, Vec<String>)>, Box<WitError>> {
+    let start = find(repo, start, Some("commit"), true)?.to_string();
+    let mailmap = Mailmap::load(repo)?;
+    let mut groups: Vec<(Signature, Vec<String>)> = Vec::new();
+    // Lowercased email -> position in groups
+    let mut by_email = HashMap::new();
+    for sha in commit::topo_order(repo, &start, &HashSet::new())? {
+        let commit = commit::read(repo, &sha)?;
+        let author = mailmap.map(&commit.author()?);
+        let subject = commit.message().lines().next().unwrap_or("").to_owned();
+        let i = *by_email.entry(author.email.to_lowercase()).or_insert_with(|| {
+            groups.push((author, Vec::new()));
+            groups.len() - 1
+        });
+        groups[i].1.push(subject);
+    }
+    // Ties go by name, as with git shortlog -n
+    groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.name.cmp(&b.0.name)));
+    Ok(groups)
+}
+
+// Prints an object the way git show does: a commit with its patch against
+// the first parent, a tree's leaves, a blob's content, or a tag followed by
+// whatever it points at
+pub fn show(repo: &Repository, name: &str, out: &mut dyn Write) -> Result<(), Box<WitError>> {
+    let sha = find(repo, name, None, true)?;
+    match read(repo, &sha)? {
+        CommitObject(commit) => {
+            writeln!(out, "commit {}", sha)?;
+            writeln!(out, "{}", commit.to_string().trim_end())?;
+            let tree = commit.tree().ok_or(malformed_object_err(format!("Commit {} has no tree", sha)))?;
+            let parent_tree = match commit.parents().first() {
+                Some(parent) => crate::commit::read(repo, parent)?.tree().cloned(),
+                None => None
+            };
+            let patch = crate::diff::tree_patch(repo, parent_tree.as_deref(), tree)?;
+            if !patch.is_empty() {
+                writeln!(out)?;
+                out.write_all(&patch)?;
+            }
+        },
+        TreeObject(tree) => {
+            writeln!(out, "tree {}\n", name)?;
+            write!(out, "{}", tree)?;
+        },
+        BlobObject(blob) => out.write_all(blob.data())?,
+        TagObject(mut tag) => {
+            let tag_name = tag.kvlm().get("tag").and_then(|v| v.first()).cloned().unwrap_or(name.to_owned());
+            writeln!(out, "tag {}", tag_name)?;
+            writeln!(out, "{}\n", tag.to_string().trim_end())?;
+            let object = tag.object().ok_or(malformed_object_err(format!("Tag {} has no object", sha)))?.clone();
+            show(repo, &object, out)?;
+        }
+    }
+    Ok(())
+}
+
+// One object fsck found a problem with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckProblem {
+    pub sha: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    // Objects whose stored bytes are damaged, or that refer to things wrongly
+    pub corrupt: Vec<FsckProblem>,
+    // Objects something reachable refers to that the repository doesn't have
+    pub missing: Vec<FsckProblem>,
+    // Objects the repository has that nothing reachable refers to
+    pub dangling: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    // Dangling objects are harmless, so they don't count
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty()
+    }
+}
+
+// Checks every object reachable from the refs and HEAD, then every object
+// that isn't. Problems are collected rather than returned, so one bad
+// object doesn't hide the rest.
+pub fn fsck(repo: &Repository) -> Result<FsckReport, Box<WitError>> {
+    let mut report = FsckReport::default();
+    let problem = |sha: &dyn fmt::Display, reason: String| FsckProblem { sha: sha.to_string(), reason };
+
+    let mut refs = Vec::new();
+    reference::flatten(&reference::list(repo, None)?, "refs/", &mut refs);
+    if let Ok(head) = reference::resolve(repo, "HEAD") {
+        refs.push(("HEAD".to_owned(), head));
+    }
+    // (sha, the type whatever refers to it expects, what refers to it)
+    let mut stack = Vec::new();
+    for (name, sha) in refs {
+        match ObjectId::from_hex(&sha) {
+            Ok(id) => stack.push((id, None, name)),
+            Err(_) => report.corrupt.push(problem(&name, format!("invalid sha {:?}", sha)))
+        }
+    }
+
+    let hex = Regex::new(&format!("^[0-9a-f]{{{}}}$", repo.object_format().hex_len()))?;
+    let mut types = HashMap::new();
+    let mut seen = HashSet::new();
+    while let Some((sha, expected, referrer)) = stack.pop() {
+        if !seen.insert(sha) {
+            if let (Some(expected), Some(fmt)) = (expected, types.get(&sha)) {
+                if expected != fmt {
+                    report.corrupt.push(problem(&referrer, format!("{} is a {}, not a {}", sha, fmt, expected)));
+                }
+            }
+            continue;
+        }
+        if !exists(repo, &sha) {
+            let kind = expected.unwrap_or("object");
+            report.missing.push(problem(&sha, format!("{} referenced by {}", kind, referrer)));
+            continue;
+        }
+        let (fmt, data) = match verify(repo, &sha) {
+            Ok(object) => object,
+            Err(reason) => {
+                report.corrupt.push(problem(&sha, reason));
+                continue;
+            }
+        };
+        if let Some(expected) = expected {
+            if fmt != expected {
+                report.corrupt.push(problem(&referrer, format!("{} is a {}, not a {}", sha, fmt, expected)));
+            }
+        }
+
+        let (links, problems) = links(repo, &sha, &fmt, data, &hex);
+        report.corrupt.extend(problems.into_iter().map(|reason| problem(&sha, reason)));
+        stack.extend(links.into_iter().map(|(link, expected)| (link, Some(expected), sha.to_string())));
+        types.insert(sha, fmt);
+    }
+
+    // Everything else in the repository is unreachable, but may still be damaged
+    let mut unreachable = Vec::new();
+    let objects = Repository::path(repo, vec!["objects"]);
+    for dir in fs::read_dir(&objects)? {
+        let dir = dir?;
+        let prefix = dir.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !dir.path().is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let name = format!("{}{}", prefix, file?.file_name().to_string_lossy());
+            if let Ok(sha) = ObjectId::from_hex(&name) {
+                unreachable.push(sha);
+            }
+        }
+    }
+    for index in pack::indexes(repo)?.iter() {
+        unreachable.extend((0..index.len()).map(|i| index.sha(i)));
+    }
+    unreachable.sort();
+    unreachable.dedup();
+    for sha in unreachable.into_iter().filter(|sha| !seen.contains(sha)) {
+        match verify(repo, &sha) {
+            Ok((fmt, _)) => report.dangling.push(problem(&sha, format!("unreachable {}", fmt))),
+            Err(reason) => report.corrupt.push(problem(&sha, reason))
+        }
+    }
+    Ok(report)
+}
+
+// The type and body of sha, after checking the stored bytes really hash to it
+fn verify(repo: &Repository, sha: &ObjectId) -> Result<(String, Vec<u8>), String> {
+    let path = loose_path(repo, sha).map_err(|err| err.to_string())?;
+    let (fmt, data) = if path.is_file() {
+        let raw = fs::read(&path).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(&raw[..]).read_to_end(&mut decoded).map_err(|err| format!("cannot inflate: {}", err))?;
+        let header = decoded.iter().position(|&b| b == 0).ok_or("no header".to_owned())?;
+        let (fmt, size) = from_utf8(&decoded[..header]).ok().and_then(|header| header.split_once(' ')).ok_or("bad header".to_owned())?;
+        if size.parse::<usize>().ok() != Some(decoded.len() - header - 1) {
+            return Err(format!("header says {} bytes, found {}", size, decoded.len() - header - 1));
+        }
+        (fmt.to_owned(), decoded[header + 1..].to_vec())
+    } else {
+        pack::read_object(repo, sha).map_err(|err| format!("cannot unpack: {}", err))?.ok_or("not found".to_owned())?
+    };
+
+    let actual = hash_raw(repo.object_format(), &fmt, &data);
+    if actual != *sha {
+        return Err(format!("contents hash to {}", actual));
+    }
+    Ok((fmt, data))
+}
+
+const MODES: [&str; 5] = ["100644", "100755", "120000", "40000", "160000"];
+
+// Everything sha refers to with the type it should have, and whatever is
+// wrong with the references themselves
+fn links(repo: &Repository, sha: &ObjectId, fmt: &str, data: Vec<u8>, hex: &Regex) -> (Vec<(ObjectId, &'static str)>, Vec<String>) {
+    let mut links = Vec::new();
+    let mut problems = Vec::new();
+    let mut link = |field: &str, value: &str, expected: &'static str, problems: &mut Vec<String>| {
+        match ObjectId::from_hex(value) {
+            Ok(id) if hex.is_match(value) => links.push((id, expected)),
+            _ => problems.push(format!("invalid {} {:?}", field, value))
+        }
+    };
+
+    match build(fmt, Some(repo), Some(data)) {
+        Ok(CommitObject(commit)) => {
+            match commit.kvlm().get("tree").map(|trees| trees.as_slice()) {
+                Some([tree]) => link("tree", tree, "tree", &mut problems),
+                _ => problems.push("commit needs exactly one tree".to_owned())
+            }
+            // Parents past a shallow boundary are not in the repository
+            if !repo.is_shallow(&sha.to_string()) {
+                for parent in commit.parents() {
+                    link("parent", &parent, "commit", &mut problems);
+                }
+            }
+        },
+        Ok(TreeObject(tree)) => {
+            for leaf in tree.leaves() {
+                if !MODES.contains(&leaf.mode()) {
+                    problems.push(format!("unknown mode {} for {}", leaf.mode(), leaf.path().display()));
+                } else if leaf.mode() != "160000" {
+                    // Submodule commits live in another repository
+                    links.push((*leaf.sha(), if leaf.is_tree() { "tree" } else { "blob" }));
+                }
+            }
+        },
+        Ok(TagObject(mut tag)) => {
+            let kvlm = tag.kvlm();
+            let object = kvlm.get("object").and_then(|v| v.first()).cloned();
+            let fmt = kvlm.get("type").and_then(|v| v.first()).map(|fmt| fmt.as_str());
+            match (["commit", "tree", "blob", "tag"].into_iter().find(|known| Some(*known) == fmt), object) {
+                (Some(expected), Some(object)) => link("object", &object, expected, &mut problems),
+                (Some(_), None) => problems.push("tag has no object".to_owned()),
+                (None, _) => problems.push(format!("tag has unknown type {}", fmt.unwrap_or("<none>")))
+            }
+        },
+        Ok(BlobObject(_)) => (),
+        Err(err) => problems.push(err.to_string())
+    }
+    (links, problems)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    // Leftover temporary files in objects/, which a failed write must not leave
+    fn temp_objects(repo: &Repository) -> usize {
+        fs::read_dir(repo.gitdir().join("objects")).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("tmp_obj_"))
+            .count()
+    }
+
+    #[test]
+    fn large_blobs_stream_through() {
+        let (dir, repo) = test_util::repo();
+        let data = (0..3 << 20).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+        let path = dir.path().join("big");
+        fs::write(&path, &data).unwrap();
+
+        let dry = hash(path.to_str().unwrap(), "blob", Some(&repo), false).unwrap();
+        assert!(!exists(&repo, &dry));
+        let sha = hash(path.to_str().unwrap(), "blob", Some(&repo), true).unwrap();
+        assert_eq!(sha, dry);
+        assert_eq!(sha, hash_raw(ObjectFormat::Sha1, "blob", &data));
+        assert_eq!(test_util::git(dir.path(), &["hash-object", "big"]).trim(), sha.to_string());
+
+        assert_eq!(stat(&repo, &sha).unwrap(), ("blob".to_owned(), data.len()));
+        let mut out = Vec::new();
+        assert_eq!(read_to_writer(&repo, &sha, &mut out).unwrap(), "blob");
+        assert!(out == data);
+        assert_eq!(temp_objects(&repo), 0);
+    }
+
+    #[test]
+    fn short_streams_are_rejected() {
+        let (_dir, repo) = test_util::repo();
+        assert!(write_stream(ObjectFormat::Sha1, Some(&repo), "blob", 10, &mut &b"short"[..]).is_err());
+        assert!(write_stream(ObjectFormat::Sha1, None, "blob", 2, &mut &b"too long"[..]).is_err());
+        assert_eq!(temp_objects(&repo), 0);
+        let sha = write_stream(ObjectFormat::Sha1, Some(&repo), "blob", 6, &mut &b"hello\n"[..]).unwrap();
+        assert_eq!(read_raw(&repo, &sha).unwrap(), ("blob".to_owned(), b"hello\n".to_vec()));
+    }
+
+    #[test]
+    fn other_types_are_checked_before_writing() {
+        let (dir, repo) = test_util::repo();
+        let path = dir.path().join("not-a-tree");
+        fs::write(&path, b"garbage").unwrap();
+        assert!(hash(path.to_str().unwrap(), "tree", Some(&repo), true).is_err());
+        assert!(hash(path.to_str().unwrap(), "nonsense", Some(&repo), true).is_err());
+    }
+
+    #[test]
+    fn fsck_reports_missing_corrupt_and_dangling() {
+        let (_dir, repo) = test_util::repo();
+        let tree = test_util::tree(&repo, &[("a", b"hello\n")]);
+        let first = test_util::commit(&repo, &tree, &[], "first");
+        reference::update(&repo, "refs/heads/main", &first, None, "test").unwrap();
+        let dangling = write_raw(&repo, "blob", b"nobody points here\n").unwrap();
+        let report = fsck(&repo).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.dangling.len(), 1);
+        assert_eq!((report.dangling[0].sha.as_str(), report.dangling[0].reason.as_str()), (dangling.to_string().as_str(), "unreachable blob"));
+
+        // A tree naming a blob that was never written, and one calling a tree a blob
+        let absent = hash_raw(ObjectFormat::Sha1, "blob", b"never written\n");
+        let tree_id = ObjectId::from_hex(&tree).unwrap();
+        let mut leaves = std::collections::BTreeMap::new();
+        leaves.insert(PathBuf::from("absent"), tree::Leaf::new("100644".to_owned(), PathBuf::from("absent"), absent));
+        leaves.insert(PathBuf::from("dir"), tree::Leaf::new("100644".to_owned(), PathBuf::from("dir"), tree_id));
+        let broken = tree::write(&repo, &leaves).unwrap();
+        let second = test_util::commit(&repo, &broken.to_string(), &[&first], "second");
+        reference::update(&repo, "refs/heads/broken", &second, None, "test").unwrap();
+
+        // And a blob whose stored bytes no longer match its sha
+        let blob = hash_raw(ObjectFormat::Sha1, "blob", b"hello\n");
+        let path = loose_path(&repo, &blob).unwrap();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 6\0jello\n").unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let report = fsck(&repo).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].sha, absent.to_string());
+        assert_eq!(report.missing[0].reason, format!("blob referenced by {}", broken));
+        let corrupt = report.corrupt.iter().map(|problem| (problem.sha.as_str(), problem.reason.as_str())).collect::<Vec<(&str, &str)>>();
+        let hashed = hash_raw(ObjectFormat::Sha1, "blob", b"jello\n");
+        assert!(corrupt.contains(&(&blob.to_string(), &format!("contents hash to {}", hashed))), "{:?}", corrupt);
+        assert!(corrupt.contains(&(&broken.to_string(), &format!("{} is a tree, not a blob", tree))), "{:?}", corrupt);
+    }
+
+    #[test]
+    fn stat_reads_only_the_header() {
+        let (_dir, repo) = test_util::repo();
+        let blob = write_raw(&repo, "blob", b"hello\n").unwrap();
+        let tree = ObjectId::from_hex(&test_util::tree(&repo, &[("a", b"hello\n")])).unwrap();
+        assert!(exists(&repo, &blob));
+        assert_eq!(stat(&repo, &blob).unwrap(), ("blob".to_owned(), 6));
+        assert_eq!(read_type(&repo, &tree).unwrap(), "tree");
+
+        // A body that doesn't match its header is only noticed when read
+        let broken = hash_raw(ObjectFormat::Sha1, "blob", b"not stored\n");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"blob 1000\0short").unwrap();
+        let path = loose_path(&repo, &broken).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        assert_eq!(stat(&repo, &broken).unwrap(), ("blob".to_owned(), 1000));
+        assert!(read_raw(&repo, &broken).is_err());
+
+        let absent = hash_raw(ObjectFormat::Sha1, "blob", b"never written\n");
+        assert!(!exists(&repo, &absent));
+        assert!(matches!(*stat(&repo, &absent).unwrap_err(), WitError::UnknownObject(_)));
+        let sha256 = hash_raw(ObjectFormat::Sha256, "blob", b"hello\n");
+        assert!(!exists(&repo, &sha256));
+        assert!(stat(&repo, &sha256).is_err());
+    }
+
+    #[test]
+    fn packed_objects_can_be_queried() {
+        let (_dir, repo) = test_util::repo();
+        let tree = ObjectId::from_hex(&test_util::tree(&repo, &[("a", &[b'x'; 3000])])).unwrap();
+        let blob = *tree::read(&repo, &tree.to_string()).unwrap().leaves()[0].sha();
+        test_util::pack_of(&repo, &[tree, blob]);
+        assert!(!loose_path(&repo, &blob).unwrap().exists());
+
+        assert!(exists(&repo, &blob) && exists(&repo, &tree));
+        assert_eq!(stat(&repo, &blob).unwrap(), ("blob".to_owned(), 3000));
+        assert_eq!(read_type(&repo, &tree).unwrap(), "tree");
+        let mut out = Vec::new();
+        assert_eq!(read_to_writer(&repo, &blob, &mut out).unwrap(), "blob");
+        assert_eq!(out, vec![b'x'; 3000]);
+    }
+
+    #[test]
+    fn shas_are_lowercased_and_checked() {
+        let (_dir, repo) = test_util::repo();
+        let blob = write_raw(&repo, "blob", b"hello\n").unwrap();
+        let hex = blob.to_string();
+        assert_eq!(find(&repo, &hex.to_uppercase(), None, false).unwrap(), blob);
+        assert_eq!(find(&repo, &hex[..7].to_uppercase(), Some("blob"), false).unwrap().to_string(), hex);
+
+        // Whatever case the filesystem hands back, and whatever else is in the directory
+        let path = loose_path(&repo, &blob).unwrap();
+        let dir = path.parent().unwrap();
+        fs::rename(&path, dir.join(hex[2..].to_uppercase())).unwrap();
+        fs::write(dir.join(".DS_Store"), b"").unwrap();
+        assert_eq!(resolve(&repo, &hex[..6], None).unwrap(), Some(vec![blob]));
+        assert_eq!(resolve(&repo, &hex[..6], None).unwrap().unwrap()[0].to_string(), hex);
+
+        for short in ["a", "", "ce0", "zzzz", "+f00"] {
+            assert!(find(&repo, short, None, false).is_err());
+            assert!(ObjectId::from_hex(short).is_err());
+        }
+        assert!(crate::util::unhex("+f").is_err());
+        assert!(crate::util::unhex("abc").is_err());
+        assert_eq!(crate::util::unhex("AbCd").unwrap(), vec![0xab, 0xcd]);
+    }
+
+    #[test]
+    fn shortlog_matches_git() {
+        let (dir, repo) = test_util::repo();
+        let tree = ObjectId::from_hex(&test_util::tree(&repo, &[])).unwrap();
+        let authors = [
+            ("Ann", "ann@example.com"), ("Bob", "bob@example.com"), ("Ann", "ann@example.com"),
+            ("Cy", "cy@old.example.com"), ("Bob", "bob@example.com"), ("Dee", "dee@example.com"), ("Ann", "ANN@example.com")
+        ];
+        let mut parents = Vec::new();
+        for (i, (name, email)) in authors.iter().enumerate() {
+            let author = Signature::new(name, email, 1112911993 + i as i64, 0);
+            let sha = commit::write(&repo, &tree, &parents, &format!("change {}\n\nbody", i), &author, &author).unwrap();
+            parents = vec![sha.to_string()];
+        }
+        reference::update(&repo, "refs/heads/main", &parents[0], None, "test").unwrap();
+        fs::write(dir.path().join(".mailmap"), "Cy Young <cy@example.com> <cy@old.example.com>\n").unwrap();
+
+        // Emails group whatever their case, which git doesn't do
+        let groups = shortlog(&repo, "main").unwrap();
+        assert_eq!(groups[0].0.email, "ann@example.com");
+        assert_eq!(groups[0].1, vec!["change 0", "change 2", "change 6"]);
+
+        // Up to there git agrees, once printed the same way
+        let before = commit::parents(&repo, &parents[0]).unwrap().remove(0);
+        let mut printed = String::new();
+        for (author, subjects) in shortlog(&repo, &before).unwrap() {
+            printed += &format!("{} <{}> ({}):\n", author.name, author.email, subjects.len());
+            for subject in subjects {
+                printed += &format!("      {}\n", subject);
+            }
+            printed += "\n";
+        }
+        assert_eq!(printed, test_util::git(dir.path(), &["shortlog", "-ne", &before]));
+        assert!(printed.contains("Cy Young <cy@example.com> (1):"));
+    }
+}
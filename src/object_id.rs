@@ -0,0 +1,239 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::{
+    error::{WitError, builder::*},
+    util::{hex, unhex}
+};
+
+// The hash a repository names its objects with, from extensions.objectformat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    pub fn parse(name: &str) -> Result<ObjectFormat, Box<WitError>> {
+        match name.to_lowercase().as_str() {
+            "sha1" => Ok(ObjectFormat::Sha1),
+            "sha256" => Ok(ObjectFormat::Sha256),
+            _ => Err(version_mismatch_err(format!("Unknown object format {}", name)))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectFormat::Sha1 => "sha1",
+            ObjectFormat::Sha256 => "sha256"
+        }
+    }
+
+    // Length of an id in bytes
+    pub fn raw_len(&self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32
+        }
+    }
+
+    // Length of an id in hex
+    pub fn hex_len(&self) -> usize {
+        self.raw_len() * 2
+    }
+
+    // Hashes the parts as if they were one buffer
+    pub fn digest(&self, parts: &[&[u8]]) -> ObjectId {
+        let mut hasher = Hasher::new(*self);
+        parts.iter().for_each(|part| hasher.update(part));
+        hasher.finish()
+    }
+}
+
+// Incremental hashing in either format
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn new(format: ObjectFormat) -> Hasher {
+        match format {
+            ObjectFormat::Sha1 => Hasher::Sha1(Sha1::new()),
+            ObjectFormat::Sha256 => Hasher::Sha256(Sha256::new())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(sha) => sha.update(data),
+            Hasher::Sha256(sha) => sha.update(data)
+        }
+    }
+
+    pub fn finish(self) -> ObjectId {
+        match self {
+            Hasher::Sha1(sha) => ObjectId::Sha1(sha.finalize().into()),
+            Hasher::Sha256(sha) => ObjectId::Sha256(sha.finalize().into())
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ObjectId {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
+
+impl ObjectId {
+    // The width of the hex string decides the format
+    pub fn from_hex(sha: &str) -> Result<ObjectId, Box<WitError>> {
+        if sha.len() != 40 && sha.len() != 64 {
+            return Err(malformed_object_err(format!("Invalid object id {}", sha)));
+        }
+        Self::from_bytes(&unhex(sha)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectId, Box<WitError>> {
+        match bytes.len() {
+            20 => Ok(ObjectId::Sha1(bytes.try_into()?)),
+            32 => Ok(ObjectId::Sha256(bytes.try_into()?)),
+            len => Err(malformed_object_err(format!("Invalid object id length {}", len)))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            ObjectId::Sha1(id) => id,
+            ObjectId::Sha256(id) => id
+        }
+    }
+
+    pub fn format(&self) -> ObjectFormat {
+        match self {
+            ObjectId::Sha1(_) => ObjectFormat::Sha1,
+            ObjectId::Sha256(_) => ObjectFormat::Sha256
+        }
+    }
+
+    // The objects/ directory and file name of the loose object
+    pub fn loose_path(&self) -> (String, String) {
+        let sha = self.to_string();
+        (sha[..2].to_owned(), sha[2..].to_owned())
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex(self.as_bytes()))
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = Box<WitError>;
+
+    fn from_str(sha: &str) -> Result<ObjectId, Box<WitError>> {
+        Self::from_hex(sha)
+    }
+}
+
+impl TryFrom<&str> for ObjectId {
+    type Error = Box<WitError>;
+
+    fn try_from(sha: &str) -> Result<ObjectId, Box<WitError>> {
+        Self::from_hex(sha)
+    }
+}
+
+impl TryFrom<&String> for ObjectId {
+    type Error = Box<WitError>;
+
+    fn try_from(sha: &String) -> Result<ObjectId, Box<WitError>> {
+        Self::from_hex(sha)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commit,
+        object::{self, WitObject},
+        repository::Repository,
+        test_util::{self, TempDir}
+    };
+
+    const HELLO_SHA1: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+    const HELLO_SHA256: &str = "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4";
+
+    #[test]
+    fn hashes_match_git() {
+        assert_eq!(object::hash_raw(ObjectFormat::Sha1, "blob", b"hello\n").to_string(), HELLO_SHA1);
+        assert_eq!(object::hash_raw(ObjectFormat::Sha256, "blob", b"hello\n").to_string(), HELLO_SHA256);
+        // Fed in pieces, the hash is the same
+        let mut hasher = Hasher::new(ObjectFormat::Sha256);
+        hasher.update(b"blob 6\0hel");
+        hasher.update(b"lo\n");
+        assert_eq!(hasher.finish().to_string(), HELLO_SHA256);
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for hex in [HELLO_SHA1, HELLO_SHA256] {
+            let id = ObjectId::from_hex(hex).unwrap();
+            assert_eq!(id.to_string(), hex);
+            assert_eq!(id.format().hex_len(), hex.len());
+            assert_eq!(ObjectId::from_bytes(id.as_bytes()).unwrap(), id);
+        }
+        let (dir, file) = ObjectId::from_hex(HELLO_SHA1).unwrap().loose_path();
+        assert_eq!((dir.as_str(), file.len()), ("ce", 38));
+        assert!(ObjectId::from_hex(&HELLO_SHA1[..39]).is_err());
+        assert!(ObjectId::from_hex(&"g".repeat(40)).is_err());
+        assert!(ObjectId::from_bytes(&[0; 21]).is_err());
+        assert_eq!(ObjectFormat::parse("SHA256").unwrap(), ObjectFormat::Sha256);
+        assert!(ObjectFormat::parse("md5").is_err());
+    }
+
+    #[test]
+    fn sha256_repositories() {
+        let dir = TempDir::new();
+        test_util::git(dir.path(), &["init", "-q", "--object-format=sha256", "."]);
+        std::fs::write(dir.path().join("hello"), b"hello\n").unwrap();
+        test_util::git(dir.path(), &["add", "hello"]);
+        test_util::git(dir.path(), &["-c", "user.name=A", "-c", "user.email=a@example.com", "commit", "-q", "-m", "first"]);
+
+        let repo = Repository::open(&dir.path().join(".git"), Some(dir.path())).unwrap();
+        assert_eq!(repo.object_format(), ObjectFormat::Sha256);
+        let head = object::find(&repo, "HEAD", Some("commit"), true).unwrap();
+        assert_eq!(head.format(), ObjectFormat::Sha256);
+        let tree = commit::read(&repo, &head.to_string()).unwrap().tree().unwrap().clone();
+        let WitObject::TreeObject(tree) = object::read(&repo, &ObjectId::from_hex(&tree).unwrap()).unwrap() else {
+            panic!("not a tree");
+        };
+        assert_eq!(tree.leaves()[0].sha().to_string(), HELLO_SHA256);
+
+        // What wit writes, git can read back
+        let tree = test_util::tree(&repo, &[("new", b"new\n")]);
+        let sha = test_util::commit(&repo, &tree, &[&head.to_string()], "second\n");
+        assert_eq!(sha.len(), 64);
+        assert_eq!(test_util::git(dir.path(), &["cat-file", "-t", &sha]), "commit\n");
+        assert_eq!(test_util::git(dir.path(), &["rev-parse", &format!("{}^", sha)]).trim(), head.to_string());
+    }
+}
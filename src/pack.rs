@@ -0,0 +1,1089 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use flate2::{Crc, Compression, Decompress, FlushDecompress, Status, read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::{
+    commit,
+    error::{WitError, builder::*},
+    index::Index,
+    object::{self, WitObject},
+    object_id::{Hasher, ObjectFormat, ObjectId},
+    reference,
+    repository::Repository,
+    tree
+};
+
+// How much pack data is received between checkpoints of a download
+const CHECKPOINT_INTERVAL: usize = 1 << 20;
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+pub struct Unpacked {
+    // Every object written to the object store, in pack order
+    pub objects: Vec<ObjectId>,
+    // False if the pack was cut short or had a bad entry
+    pub complete: bool,
+}
+
+enum Entry {
+    Base(String, Vec<u8>),
+    OfsDelta(usize, Vec<u8>),
+    RefDelta(ObjectId, Vec<u8>),
+}
+
+fn type_name(kind: u8) -> Result<&'static str, Box<WitError>> {
+    match kind {
+        OBJ_COMMIT => Ok("commit"),
+        OBJ_TREE => Ok("tree"),
+        OBJ_BLOB => Ok("blob"),
+        OBJ_TAG => Ok("tag"),
+        _ => Err(malformed_object_err(format!("Unknown pack object type {}", kind)))
+    }
+}
+
+fn truncated() -> Box<WitError> {
+    missing_data_err("Pack data is truncated".to_owned())
+}
+
+// Inflates the zlib stream at the start of `data`, returning the
+// output and how many input bytes it took up
+fn inflate(data: &[u8], size: usize) -> Result<(Vec<u8>, usize), Box<WitError>> {
+    let mut decompress = Decompress::new(true);
+    let mut out = Vec::with_capacity(size + 1);
+    loop {
+        let consumed = decompress.total_in() as usize;
+        let status = decompress.decompress_vec(&data[consumed..], &mut out, FlushDecompress::None)
+            .map_err(|e| malformed_object_err(format!("Bad zlib stream in pack: {}", e)))?;
+        match status {
+            Status::StreamEnd => break,
+            _ if decompress.total_in() as usize == data.len() => return Err(truncated()),
+            _ => out.reserve(out.capacity().max(1024))
+        }
+    }
+    if out.len() != size {
+        return Err(malformed_object_err(format!("Pack entry inflated to {} bytes, expected {}", out.len(), size)));
+    }
+    Ok((out, decompress.total_in() as usize))
+}
+
+// Parses the entry at `start`, returning it and the offset just past it.
+// data begins at pack offset `origin`, which is 0 unless it is only a slice
+// of the pack, and delta base offsets are given relative to the whole pack.
+fn parse_entry(data: &[u8], start: usize, origin: usize, format: ObjectFormat) -> Result<(Entry, usize), Box<WitError>> {
+    let (kind, size, base, pos) = parse_header(data, start, origin, format)?;
+    let (body, used) = inflate(&data[pos..], size)?;
+    let entry = match base {
+        Base::Offset(base) => Entry::OfsDelta(base, body),
+        Base::Sha(base) => Entry::RefDelta(base, body),
+        Base::None => Entry::Base(type_name(kind)?.to_owned(), body)
+    };
+    Ok((entry, pos + used))
+}
+
+// What a delta entry is relative to
+enum Base {
+    None,
+    // The pack offset of the base entry
+    Offset(usize),
+    Sha(ObjectId),
+}
+
+// The type code, size and delta base of the entry at `start`, and where its
+// compressed data begins. For a delta the size is that of the delta itself.
+fn parse_header(data: &[u8], start: usize, origin: usize, format: ObjectFormat) -> Result<(u8, usize, Base, usize), Box<WitError>> {
+    let mut pos = start;
+    let mut byte = *data.get(pos).ok_or_else(truncated)?;
+    pos += 1;
+    let kind = (byte >> 4) & 7;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = *data.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+
+    let base = match kind {
+        OBJ_OFS_DELTA => {
+            let mut byte = *data.get(pos).ok_or_else(truncated)?;
+            pos += 1;
+            let mut offset = (byte & 0x7f) as usize;
+            while byte & 0x80 != 0 {
+                byte = *data.get(pos).ok_or_else(truncated)?;
+                pos += 1;
+                offset = ((offset + 1) << 7) | (byte & 0x7f) as usize;
+            }
+            if offset > origin + start {
+                return Err(malformed_object_err(format!("Delta at {} points before the pack", origin + start)));
+            }
+            Base::Offset(origin + start - offset)
+        },
+        OBJ_REF_DELTA => {
+            if data.len() < pos + format.raw_len() {
+                return Err(truncated());
+            }
+            pos += format.raw_len();
+            Base::Sha(ObjectId::from_bytes(&data[pos-format.raw_len()..pos])?)
+        },
+        _ => Base::None
+    };
+    Ok((kind, size, base, pos))
+}
+
+fn delta_size(delta: &[u8], pos: &mut usize) -> Result<usize, Box<WitError>> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+// Rebuilds an object from its base and a git delta
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, Box<WitError>> {
+    let bad_delta = || malformed_object_err("Malformed delta".to_owned());
+    let mut pos = 0;
+    if delta_size(delta, &mut pos)? != base.len() {
+        return Err(malformed_object_err("Delta base has the wrong size".to_owned()));
+    }
+    let size = delta_size(delta, &mut pos)?;
+    let mut out = Vec::with_capacity(size);
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // Copy from the base; the low bits say which offset and size bytes follow
+            let mut fields = [0usize; 2];
+            for (bit, field) in (0..7).map(|bit| (bit, if bit < 4 { 0 } else { 1 })) {
+                if op & (1 << bit) != 0 {
+                    let byte = *delta.get(pos).ok_or_else(bad_delta)? as usize;
+                    pos += 1;
+                    fields[field] |= byte << (8 * (if bit < 4 { bit } else { bit - 4 }));
+                }
+            }
+            let (offset, len) = (fields[0], if fields[1] == 0 { 0x10000 } else { fields[1] });
+            out.extend_from_slice(base.get(offset..offset + len).ok_or_else(bad_delta)?);
+        } else if op != 0 {
+            let len = op as usize;
+            out.extend_from_slice(delta.get(pos..pos + len).ok_or_else(bad_delta)?);
+            pos += len;
+        } else {
+            return Err(bad_delta());
+        }
+    }
+
+    if out.len() != size {
+        return Err(malformed_object_err("Delta result has the wrong size".to_owned()));
+    }
+    Ok(out)
+}
+
+// Writes every object in a pack as a loose object, like unpack-objects.
+// With `recover`, a truncated or corrupt pack is not an error: everything
+// before the first bad entry is kept.
+pub fn unpack(repo: &Repository, data: &[u8], recover: bool) -> Result<Unpacked, Box<WitError>> {
+    if data.len() < 12 || &data[..4] != b"PACK" {
+        if recover {
+            return Ok(Unpacked { objects: Vec::new(), complete: false });
+        }
+        return Err(malformed_object_err("Bad pack signature".to_owned()));
+    }
+    let version = u32::from_be_bytes(data[4..8].try_into()?);
+    if version != 2 && version != 3 {
+        return Err(version_mismatch_err(format!("Unsupported pack version {}", version)));
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into()?);
+
+    let mut objects = Vec::new();
+    // Pack offset -> sha of every entry that has been written
+    let mut offsets: HashMap<usize, ObjectId> = HashMap::new();
+    // Deltas against objects that are not written yet
+    let mut waiting: Vec<(usize, ObjectId, Vec<u8>)> = Vec::new();
+    let format = repo.object_format();
+    let mut pos = 12;
+    let mut complete = true;
+
+    for _ in 0..count {
+        let (entry, end) = match parse_entry(data, pos, 0, format) {
+            Ok(parsed) => parsed,
+            Err(_) if recover => {
+                complete = false;
+                break;
+            },
+            Err(e) => return Err(e)
+        };
+        let written = match entry {
+            Entry::Base(fmt, body) => Some(object::write_raw(repo, &fmt, &body)?),
+            Entry::OfsDelta(base, delta) => {
+                let base = offsets.get(&base).ok_or(
+                    malformed_object_err(format!("Delta at {} has no base", pos))
+                )?;
+                Some(write_delta(repo, base, &delta)?)
+            },
+            Entry::RefDelta(base, delta) if object::exists(repo, &base) => Some(write_delta(repo, &base, &delta)?),
+            Entry::RefDelta(base, delta) => {
+                waiting.push((pos, base, delta));
+                None
+            }
+        };
+        if let Some(sha) = written {
+            offsets.insert(pos, sha);
+            objects.push(sha);
+        }
+        pos = end;
+    }
+
+    if complete {
+        if data.len() < pos + format.raw_len() {
+            complete = false;
+        } else {
+            if format.digest(&[&data[..pos]]).as_bytes() != &data[pos..pos + format.raw_len()] {
+                return Err(malformed_object_err("Pack checksum mismatch".to_owned()));
+            }
+        }
+    }
+
+    // Ref deltas may name bases that come later in the pack
+    while !waiting.is_empty() {
+        let before = waiting.len();
+        let mut still_waiting = Vec::new();
+        for (offset, base, delta) in waiting {
+            if object::exists(repo, &base) {
+                let sha = write_delta(repo, &base, &delta)?;
+                offsets.insert(offset, sha);
+                objects.push(sha);
+            } else {
+                still_waiting.push((offset, base, delta));
+            }
+        }
+        waiting = still_waiting;
+        if waiting.len() == before {
+            if recover {
+                complete = false;
+                break;
+            }
+            return Err(missing_data_err(format!("Delta base {} is missing", waiting[0].1)));
+        }
+    }
+
+    Ok(Unpacked { objects, complete })
+}
+
+fn write_delta(repo: &Repository, base: &ObjectId, delta: &[u8]) -> Result<ObjectId, Box<WitError>> {
+    let (fmt, base) = object::read_shared(repo, base)?;
+    object::write_raw(repo, &fmt, &apply_delta(&base, delta)?)
+}
+
+// A pack being received into objects/pack/tmp_pack_<key>. Periodic
+// checkpoints record how much of the file is known good, so an
+// interrupted download can pick up where it left off.
+pub struct PackDownload {
+    path: PathBuf,
+    checkpoint_path: PathBuf,
+    file: File,
+    // Checksum of everything written so far
+    sha: Hasher,
+    written: usize,
+    since_checkpoint: usize,
+}
+
+impl PackDownload {
+    // Opens the download for `key`, keeping whatever a previous attempt
+    // checkpointed if it still matches
+    pub fn open(repo: &Repository, key: &str) -> Result<PackDownload, Box<WitError>> {
+        let dir = Repository::dir(repo, vec!["objects", "pack"], true)?;
+        let path = dir.join(format!("tmp_pack_{}", key));
+        let checkpoint_path = dir.join(format!("tmp_pack_{}.checkpoint", key));
+
+        let mut sha = Hasher::new(ObjectFormat::Sha1);
+        let mut written = 0;
+        if let (Ok(checkpoint), Ok(mut data)) = (fs::read_to_string(&checkpoint_path), fs::read(&path)) {
+            if let Some((offset, digest)) = checkpoint.trim().split_once(' ') {
+                let offset = offset.parse::<usize>().unwrap_or(usize::MAX);
+                if offset <= data.len() {
+                    data.truncate(offset);
+                    if ObjectFormat::Sha1.digest(&[&data]).to_string() == digest {
+                        sha = Hasher::new(ObjectFormat::Sha1);
+                        sha.update(&data);
+                        written = offset;
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).write(true).truncate(false).open(&path)?;
+        file.set_len(written as u64)?;
+        let mut download = PackDownload { path, checkpoint_path, file, sha, written, since_checkpoint: 0 };
+        download.seek_end()?;
+        Ok(download)
+    }
+
+    fn seek_end(&mut self) -> Result<(), Box<WitError>> {
+        use std::io::{Seek, SeekFrom};
+        self.file.seek(SeekFrom::Start(self.written as u64))?;
+        Ok(())
+    }
+
+    // Bytes already on disk; a resumed request should start here
+    pub fn offset(&self) -> usize {
+        self.written
+    }
+
+    pub fn checkpoint(&mut self) -> Result<(), Box<WitError>> {
+        self.file.sync_data()?;
+        fs::write(&self.checkpoint_path, format!("{} {}\n", self.written, self.sha.clone().finish()))?;
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>, Box<WitError>> {
+        let mut data = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut data)?;
+        data.truncate(self.written);
+        Ok(data)
+    }
+
+    // Forgets the partial pack, for when starting over is the only option
+    pub fn discard(self) -> Result<(), Box<WitError>> {
+        fs::remove_file(&self.path)?;
+        if self.checkpoint_path.exists() {
+            fs::remove_file(&self.checkpoint_path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for PackDownload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.sha.update(&buf[..len]);
+        self.written += len;
+        self.since_checkpoint += len;
+        if self.since_checkpoint >= CHECKPOINT_INTERVAL {
+            self.checkpoint().map_err(|e| std::io::Error::other(e.to_string()))?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Somewhere packs can be requested from
+pub trait PackSource {
+    // Whether a request can start partway through the pack, as with ranged
+    // requests over dumb HTTP
+    fn resumable(&self) -> bool;
+
+    // Streams a pack with `wants` and everything they need that is not
+    // reachable from `haves` into `out`, skipping the first `offset` bytes
+    fn fetch(&mut self, wants: &[String], haves: &[String], offset: usize, out: &mut dyn Write) -> Result<(), Box<WitError>>;
+}
+
+// Downloads and unpacks the objects for `wants`, retrying up to `attempts`
+// times. Resumable sources continue from the last checkpoint; otherwise the
+// complete objects from a failed attempt are kept and advertised as haves so
+// the next pack is smaller.
+pub fn fetch(repo: &Repository, source: &mut dyn PackSource, wants: &[String], haves: &[String], attempts: usize) -> Result<Unpacked, Box<WitError>> {
+    let mut sorted = wants.to_vec();
+    sorted.sort();
+    let key = ObjectFormat::Sha1.digest(&[sorted.join("\n").as_bytes()]).to_string()[..16].to_owned();
+
+    let mut haves = haves.to_vec();
+    let mut error = None;
+    for _ in 0..attempts.max(1) {
+        let mut download = PackDownload::open(repo, &key)?;
+        if !source.resumable() && download.offset() > 0 {
+            download.discard()?;
+            download = PackDownload::open(repo, &key)?;
+        }
+
+        let offset = download.offset();
+        match source.fetch(wants, &haves, offset, &mut download) {
+            Ok(()) => {
+                let unpacked = unpack(repo, &download.data()?, false)?;
+                download.discard()?;
+                return Ok(unpacked);
+            },
+            Err(e) => {
+                if source.resumable() {
+                    download.checkpoint()?;
+                } else {
+                    let unpacked = unpack(repo, &download.data()?, true)?;
+                    download.discard()?;
+                    for sha in complete_commits(repo, &unpacked.objects, &haves)? {
+                        if !haves.contains(&sha) {
+                            haves.push(sha);
+                        }
+                    }
+                }
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| missing_data_err("Pack download failed".to_owned())))
+}
+
+// The commits among `objects` whose whole history and trees are present,
+// which makes them safe to send as haves
+fn complete_commits(repo: &Repository, objects: &[ObjectId], haves: &[String]) -> Result<Vec<String>, Box<WitError>> {
+    let mut known: HashMap<String, bool> = haves.iter().map(|sha| (sha.clone(), true)).collect();
+    let mut trees = HashSet::new();
+    let mut complete = Vec::new();
+
+    for id in objects {
+        if !matches!(object::read(repo, id), Ok(WitObject::CommitObject(_))) {
+            continue;
+        }
+        let sha = id.to_string();
+        // Post-order walk so parents are decided before their children
+        let mut stack = vec![(sha.clone(), false)];
+        while let Some((sha, expanded)) = stack.pop() {
+            if known.contains_key(&sha) {
+                continue;
+            }
+            if !present(repo, &sha) {
+                known.insert(sha, false);
+                continue;
+            }
+            let commit = commit::read(repo, &sha)?;
+            let parents = commit::parents(repo, &sha)?;
+            if expanded {
+                let ok = match commit.tree() {
+                    Some(tree) => parents.iter().all(|p| known.get(p).copied().unwrap_or(false))
+                        && has_tree(repo, tree, &mut trees)?,
+                    None => false
+                };
+                known.insert(sha, ok);
+            } else {
+                stack.push((sha, true));
+                stack.extend(parents.into_iter().map(|p| (p, false)));
+            }
+        }
+        if known.get(&sha).copied().unwrap_or(false) {
+            complete.push(sha);
+        }
+    }
+    Ok(complete)
+}
+
+fn present(repo: &Repository, sha: &str) -> bool {
+    ObjectId::from_hex(sha).map(|sha| object::exists(repo, &sha)).unwrap_or(false)
+}
+
+fn has_tree(repo: &Repository, sha: &str, seen: &mut HashSet<String>) -> Result<bool, Box<WitError>> {
+    if seen.contains(sha) {
+        return Ok(true);
+    }
+    if !present(repo, sha) {
+        return Ok(false);
+    }
+    for leaf in tree::read(repo, sha)?.leaves() {
+        let present = match leaf.object_type() {
+            "tree" => has_tree(repo, &leaf.sha().to_string(), seen)?,
+            "blob" => object::exists(repo, leaf.sha()),
+            // Submodule commits live in another repository
+            _ => true
+        };
+        if !present {
+            return Ok(false);
+        }
+    }
+    seen.insert(sha.to_owned());
+    Ok(true)
+}
+
+fn type_code(fmt: &str) -> Result<u8, Box<WitError>> {
+    match fmt {
+        "commit" => Ok(OBJ_COMMIT),
+        "tree" => Ok(OBJ_TREE),
+        "blob" => Ok(OBJ_BLOB),
+        "tag" => Ok(OBJ_TAG),
+        _ => Err(unknown_object_err(format!("Unknown object type {}", fmt)))
+    }
+}
+
+// The type and size varint that starts every pack entry
+fn entry_header(kind: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(kind << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size > 0 {
+        *header.last_mut().unwrap() |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+// Packs the given objects into output_pack with its index at output_idx,
+// then deletes the loose copies
+pub fn write_pack(repo: &Repository, shas: &[ObjectId], output_pack: &Path, output_idx: &Path) -> Result<ObjectId, Box<WitError>> {
+    let pack_sha = write(repo, shas, output_pack)?;
+    if output_idx != output_pack.with_extension("idx") {
+        fs::rename(output_pack.with_extension("idx"), output_idx)?;
+    }
+    for sha in shas {
+        let path = object::loose_path(repo, sha)?;
+        if path.is_file() {
+            fs::remove_file(&path)?;
+            if let Some(dir) = path.parent() {
+                if dir.read_dir()?.next().is_none() {
+                    fs::remove_dir(dir)?;
+                }
+            }
+        }
+    }
+    Ok(pack_sha)
+}
+
+// Writes a v2 pack of the given objects to out, and its v2 index next to it
+// with an .idx extension. Objects are stored whole, largest first within each
+// type, as git does before searching for deltas. Returns the pack checksum.
+pub fn write(repo: &Repository, objects: &[ObjectId], out: &Path) -> Result<ObjectId, Box<WitError>> {
+    let mut shas = objects.to_vec();
+    shas.sort();
+    shas.dedup();
+
+    let mut objects = Vec::with_capacity(shas.len());
+    for sha in shas {
+        let (fmt, data) = object::read_shared(repo, &sha)?;
+        objects.push((type_code(&fmt)?, sha, data));
+    }
+    objects.sort_by(|a, b| a.0.cmp(&b.0).then(b.2.len().cmp(&a.2.len())));
+
+    let mut pack = b"PACK".to_vec();
+    pack.extend(2u32.to_be_bytes());
+    pack.extend((objects.len() as u32).to_be_bytes());
+
+    // (sha, crc32, offset) for the index
+    let mut entries = Vec::with_capacity(objects.len());
+    for (kind, sha, data) in &objects {
+        let offset = pack.len();
+        let mut encoder = ZlibEncoder::new(entry_header(*kind, data.len()), Compression::default());
+        encoder.write_all(data)?;
+        let entry = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        pack.extend(entry);
+        entries.push((sha.as_bytes(), crc.sum(), offset as u64));
+    }
+    let format = repo.object_format();
+    let pack_sha = format.digest(&[&pack]);
+    pack.extend(pack_sha.as_bytes());
+
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let mut idx = b"\xfftOc".to_vec();
+    idx.extend(2u32.to_be_bytes());
+    let mut count = 0u32;
+    for byte in 0..=255u8 {
+        count += entries.iter().skip(count as usize).take_while(|entry| entry.0[0] == byte).count() as u32;
+        idx.extend(count.to_be_bytes());
+    }
+    for (sha, _, _) in &entries {
+        idx.extend(*sha);
+    }
+    for (_, crc, _) in &entries {
+        idx.extend(crc.to_be_bytes());
+    }
+    // Offsets that don't fit in 31 bits go in a trailing table of 64-bit offsets
+    let mut large = Vec::new();
+    for (_, _, offset) in &entries {
+        if *offset < 0x8000_0000 {
+            idx.extend((*offset as u32).to_be_bytes());
+        } else {
+            idx.extend((0x8000_0000 | large.len() as u32).to_be_bytes());
+            large.push(*offset);
+        }
+    }
+    for offset in large {
+        idx.extend(offset.to_be_bytes());
+    }
+    idx.extend(pack_sha.as_bytes());
+    let idx_sha = format.digest(&[&idx]);
+    idx.extend(idx_sha.as_bytes());
+
+    // The index goes last, so a pack is only ever visible once it is complete
+    Repository::write_file(repo, out, &pack)?;
+    Repository::write_file(repo, &out.with_extension("idx"), &idx)?;
+    repo.forget_packs();
+    Ok(pack_sha)
+}
+
+// Every object reachable from the refs, HEAD and the index
+pub fn reachable(repo: &Repository) -> Result<Vec<ObjectId>, Box<WitError>> {
+    let mut refs = Vec::new();
+    reference::flatten(&reference::list(repo, None)?, "refs/", &mut refs);
+    let mut stack = refs.into_iter().map(|(_, sha)| sha).collect::<Vec<String>>();
+    stack.extend(reference::resolve(repo, "HEAD").ok());
+    if repo.worktree().is_ok() {
+        stack.extend(Index::read(repo)?.entries().iter().filter(|entry| entry.mode() != 0o160000).map(|entry| entry.sha().to_owned()));
+    }
+
+    let mut seen = HashSet::new();
+    let mut objects = Vec::new();
+    while let Some(sha) = stack.pop() {
+        let id = ObjectId::from_hex(&sha)?;
+        if !seen.insert(id) {
+            continue;
+        }
+        objects.push(id);
+        match object::read(repo, &id)? {
+            WitObject::CommitObject(commit) => {
+                stack.extend(commit.tree().cloned());
+                stack.extend(commit::parents(repo, &sha)?);
+            },
+            WitObject::TreeObject(tree) => {
+                // Submodule commits live in another repository
+                stack.extend(tree.leaves().iter().filter(|leaf| leaf.mode() != "160000").map(|leaf| leaf.sha().to_string()));
+            },
+            WitObject::TagObject(tag) => stack.extend(tag.object().cloned()),
+            WitObject::BlobObject(_) => ()
+        }
+    }
+    Ok(objects)
+}
+
+// Packs everything reachable into one new pack, then deletes the loose
+// copies and any old pack whose objects all made it into the new one.
+// Returns the number of objects packed.
+pub fn repack(repo: &Repository) -> Result<usize, Box<WitError>> {
+    let objects = reachable(repo)?;
+    if objects.is_empty() {
+        return Ok(0);
+    }
+    let old = indexes(repo)?;
+
+    let dir = Repository::dir(repo, vec!["objects", "pack"], true)?;
+    let tmp = dir.join("tmp_pack_repack.pack");
+    let sha = write_pack(repo, &objects, &tmp, &tmp.with_extension("idx"))?;
+    let pack = dir.join(format!("pack-{}.pack", sha));
+    fs::rename(&tmp, &pack)?;
+    fs::rename(tmp.with_extension("idx"), pack.with_extension("idx"))?;
+
+    let packed = objects.iter().collect::<HashSet<&ObjectId>>();
+    for index in old.iter() {
+        if index.pack == pack || !(0..index.len()).all(|i| packed.contains(&index.sha(i))) {
+            continue;
+        }
+        // The index first, so the pack is never listed without its data
+        fs::remove_file(index.pack.with_extension("idx"))?;
+        fs::remove_file(&index.pack)?;
+    }
+    repo.forget_packs();
+    Ok(objects.len())
+}
+
+// A pack's .idx, read whole, for finding objects in the pack without
+// unpacking it
+pub struct PackIndex {
+    pack: PathBuf,
+    format: ObjectFormat,
+    fanout: Vec<u32>,
+    shas: Vec<u8>,
+    offsets: Vec<u64>,
+    // The same offsets in pack order, to find where each entry ends
+    sorted: Vec<u64>,
+    // Where the trailing checksum starts
+    data_end: u64,
+}
+
+impl PackIndex {
+    pub fn open(idx: &Path, format: ObjectFormat) -> Result<PackIndex, Box<WitError>> {
+        let data = fs::read(idx)?;
+        let bad_index = || malformed_object_err(format!("Bad pack index {}", idx.display()));
+        if data.len() < 8 + 256 * 4 || &data[..4] != b"\xfftOc" {
+            return Err(bad_index());
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 {
+            return Err(version_mismatch_err(format!("Unsupported pack index version {}", version)));
+        }
+        let fanout = data[8..8 + 256 * 4].chunks(4).map(|n| u32::from_be_bytes(n.try_into().unwrap())).collect::<Vec<u32>>();
+        let count = fanout[255] as usize;
+
+        let shas_start = 8 + 256 * 4;
+        let offsets_start = shas_start + count * format.raw_len() + count * 4;
+        let large_start = offsets_start + count * 4;
+        if data.len() < large_start {
+            return Err(bad_index());
+        }
+        let mut offsets = Vec::with_capacity(count);
+        for n in data[offsets_start..large_start].chunks(4) {
+            let offset = u32::from_be_bytes(n.try_into()?);
+            if offset & 0x8000_0000 == 0 {
+                offsets.push(offset as u64);
+            } else {
+                let at = large_start + (offset & 0x7fff_ffff) as usize * 8;
+                let large = data.get(at..at + 8).ok_or_else(bad_index)?;
+                offsets.push(u64::from_be_bytes(large.try_into()?));
+            }
+        }
+        let mut sorted = offsets.clone();
+        sorted.sort();
+
+        let pack = idx.with_extension("pack");
+        let data_end = fs::metadata(&pack)?.len().saturating_sub(format.raw_len() as u64);
+        Ok(PackIndex {
+            pack,
+            format,
+            fanout,
+            shas: data[shas_start..shas_start + count * format.raw_len()].to_vec(),
+            offsets,
+            sorted,
+            data_end
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn sha(&self, i: usize) -> ObjectId {
+        let len = self.format.raw_len();
+        ObjectId::from_bytes(&self.shas[i * len..(i + 1) * len]).unwrap()
+    }
+
+    // Where sha is in the index, found with the fanout table and a binary search
+    fn position(&self, sha: &ObjectId) -> Option<usize> {
+        let raw = sha.as_bytes();
+        let len = self.format.raw_len();
+        let first = *raw.first()? as usize;
+        let (mut lo, mut hi) = (if first == 0 { 0 } else { self.fanout[first - 1] as usize }, self.fanout[first] as usize);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.shas[mid * len..(mid + 1) * len].cmp(raw) {
+                std::cmp::Ordering::Equal => return Some(mid),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid
+            }
+        }
+        None
+    }
+
+    pub fn contains(&self, sha: &ObjectId) -> bool {
+        self.position(sha).is_some()
+    }
+
+    // Every sha in the pack starting with the lowercase hex prefix, which
+    // has to be at least two digits long for the fanout to narrow it down
+    pub fn with_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let first = match prefix.get(..2).and_then(|byte| u8::from_str_radix(byte, 16).ok()) {
+            Some(first) => first as usize,
+            None => return Vec::new()
+        };
+        let start = if first == 0 { 0 } else { self.fanout[first - 1] as usize };
+        (start..self.fanout[first] as usize)
+            .map(|i| self.sha(i))
+            .filter(|sha| sha.to_string().starts_with(prefix))
+            .collect()
+    }
+
+    // The type and body of sha, if it is in this pack
+    pub fn read(&self, repo: &Repository, sha: &ObjectId) -> Result<Option<(String, Vec<u8>)>, Box<WitError>> {
+        match self.position(sha) {
+            Some(i) => Ok(Some(self.read_at(repo, &mut File::open(&self.pack)?, self.offsets[i])?)),
+            None => Ok(None)
+        }
+    }
+
+    // The type and size of sha, if it is in this pack
+    pub fn stat(&self, repo: &Repository, sha: &ObjectId) -> Result<Option<(String, usize)>, Box<WitError>> {
+        match self.position(sha) {
+            Some(i) => Ok(Some(self.stat_at(repo, &mut File::open(&self.pack)?, self.offsets[i])?)),
+            None => Ok(None)
+        }
+    }
+
+    // Reads only the entry header, and for a delta the start of the delta,
+    // which gives the size of the result. The type is the base's.
+    fn stat_at(&self, repo: &Repository, file: &mut File, offset: u64) -> Result<(String, usize), Box<WitError>> {
+        use std::io::{Seek, SeekFrom};
+        // Room for the longest size and base offset varints, or a base sha
+        let mut header = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut *file).take(32 + self.format.raw_len() as u64).read_to_end(&mut header)?;
+        let (kind, size, base, pos) = parse_header(&header, 0, offset as usize, self.format)?;
+        if let Base::None = base {
+            return Ok((type_name(kind)?.to_owned(), size));
+        }
+
+        // A delta starts with the size of its base and then of the result
+        let mut delta = Vec::new();
+        file.seek(SeekFrom::Start(offset + pos as u64))?;
+        ZlibDecoder::new(&mut *file).take(20).read_to_end(&mut delta)?;
+        let mut at = 0;
+        delta_size(&delta, &mut at)?;
+        let size = delta_size(&delta, &mut at)?;
+
+        let fmt = match base {
+            Base::Offset(base) => self.stat_at(repo, file, base as u64)?.0,
+            Base::Sha(base) => match self.position(&base) {
+                Some(i) => self.stat_at(repo, file, self.offsets[i])?.0,
+                None => object::stat(repo, &base)?.0
+            },
+            Base::None => unreachable!()
+        };
+        Ok((fmt, size))
+    }
+
+    // Reads just the entry at offset, which ends where the next one starts,
+    // and resolves it against its delta base if it has one
+    fn read_at(&self, repo: &Repository, file: &mut File, offset: u64) -> Result<(String, Vec<u8>), Box<WitError>> {
+        use std::io::{Seek, SeekFrom};
+        let next = self.sorted.partition_point(|other| *other <= offset);
+        let end = self.sorted.get(next).copied().unwrap_or(self.data_end);
+        let mut data = vec![0u8; end.saturating_sub(offset) as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+
+        let (entry, _) = parse_entry(&data, 0, offset as usize, self.format)?;
+        match entry {
+            Entry::Base(fmt, body) => Ok((fmt, body)),
+            Entry::OfsDelta(base, delta) => {
+                let (fmt, base) = self.read_at(repo, file, base as u64)?;
+                Ok((fmt, apply_delta(&base, &delta)?))
+            },
+            Entry::RefDelta(base, delta) => {
+                let (fmt, base) = match self.position(&base) {
+                    Some(i) => self.read_at(repo, file, self.offsets[i])?,
+                    None => object::read_raw(repo, &base)?
+                };
+                Ok((fmt, apply_delta(&base, &delta)?))
+            }
+        }
+    }
+}
+
+// The indexes of every pack in the repository, read from disk only when
+// the pack directory changed since the last call
+pub fn indexes(repo: &Repository) -> Result<Rc<Vec<PackIndex>>, Box<WitError>> {
+    let dir = Repository::path(repo, vec!["objects", "pack"]);
+    let mtime = fs::metadata(&dir).and_then(|metadata| metadata.modified()).ok();
+    if let Some(indexes) = repo.cached_packs(mtime) {
+        return Ok(indexes);
+    }
+    let indexes = Rc::new(read_indexes(repo, &dir)?);
+    repo.cache_packs(mtime, indexes.clone());
+    Ok(indexes)
+}
+
+fn read_indexes(repo: &Repository, dir: &Path) -> Result<Vec<PackIndex>, Box<WitError>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "idx").unwrap_or(false) && path.with_extension("pack").is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths.iter().map(|path| PackIndex::open(path, repo.object_format())).collect()
+}
+
+// The type and body of sha from whichever pack has it
+pub fn read_object(repo: &Repository, sha: &ObjectId) -> Result<Option<(String, Vec<u8>)>, Box<WitError>> {
+    for index in indexes(repo)?.iter() {
+        if let Some(object) = index.read(repo, sha)? {
+            return Ok(Some(object));
+        }
+    }
+    Ok(None)
+}
+
+// The type and size of sha from whichever pack has it
+pub fn stat_object(repo: &Repository, sha: &ObjectId) -> Result<Option<(String, usize)>, Box<WitError>> {
+    for index in indexes(repo)?.iter() {
+        if let Some(stat) = index.stat(repo, sha)? {
+            return Ok(Some(stat));
+        }
+    }
+    Ok(None)
+}
+
+pub fn contains(repo: &Repository, sha: &ObjectId) -> bool {
+    indexes(repo).map(|indexes| indexes.iter().any(|index| index.contains(sha))).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    // Two commits, the second on top of the first, and the objects each
+    // needs; the main branch points at the second
+    fn history(repo: &Repository) -> (String, Vec<ObjectId>, String, Vec<ObjectId>) {
+        let first = test_util::commit(repo, &test_util::tree(repo, &[("a", b"one\n")]), &[], "first\n");
+        reference::update(repo, "refs/heads/main", &first, None, "test").unwrap();
+        let first_objects = reachable(repo).unwrap();
+        let second = test_util::commit(repo, &test_util::tree(repo, &[("a", b"one\n"), ("b", &[b'x'; 5000])]), &[&first], "second\n");
+        reference::update(repo, "refs/heads/main", &second, Some(&first), "test").unwrap();
+        (first, first_objects, second, reachable(repo).unwrap())
+    }
+
+    // Sends pack, failing partway through the first time
+    struct Flaky {
+        packs: Vec<Vec<u8>>,
+        cut: usize,
+        resumable: bool,
+        // (offset, haves) of every request
+        requests: Vec<(usize, Vec<String>)>,
+    }
+
+    impl PackSource for Flaky {
+        fn resumable(&self) -> bool {
+            self.resumable
+        }
+
+        fn fetch(&mut self, _wants: &[String], haves: &[String], offset: usize, out: &mut dyn Write) -> Result<(), Box<WitError>> {
+            self.requests.push((offset, haves.to_vec()));
+            let pack = &self.packs[(self.requests.len() - 1).min(self.packs.len() - 1)];
+            if self.requests.len() == 1 {
+                out.write_all(&pack[offset..self.cut.min(pack.len())])?;
+                return Err(missing_data_err("Connection reset".to_owned()));
+            }
+            out.write_all(&pack[offset..])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn truncated_packs_keep_complete_entries() {
+        let (_dir, source) = test_util::repo();
+        let (_, _, _, objects) = history(&source);
+        let pack = test_util::pack_of(&source, &objects);
+
+        let (_dir, repo) = test_util::repo();
+        let whole = unpack(&repo, &pack, false).unwrap();
+        assert!(whole.complete);
+        assert_eq!(whole.objects.len(), objects.len());
+
+        let (_dir, repo) = test_util::repo();
+        // Cut one byte into the last entry, before the checksum
+        let cut = &pack[..pack.len() - 21];
+        assert!(unpack(&repo, cut, false).is_err());
+        let partial = unpack(&repo, cut, true).unwrap();
+        assert!(!partial.complete);
+        assert_eq!(partial.objects.len(), objects.len() - 1);
+        assert!(partial.objects.iter().all(|sha| object::exists(&repo, sha)));
+    }
+
+    #[test]
+    fn downloads_resume_from_their_checkpoint() {
+        let (_dir, repo) = test_util::repo();
+        let mut download = PackDownload::open(&repo, "key").unwrap();
+        download.write_all(b"PACK0123").unwrap();
+        download.checkpoint().unwrap();
+        // Written after the checkpoint, so lost
+        download.write_all(b"4567").unwrap();
+        drop(download);
+
+        let download = PackDownload::open(&repo, "key").unwrap();
+        assert_eq!(download.offset(), 8);
+        assert_eq!(download.data().unwrap(), b"PACK0123");
+        download.discard().unwrap();
+        assert_eq!(PackDownload::open(&repo, "key").unwrap().offset(), 0);
+    }
+
+    #[test]
+    fn resumable_fetch_continues_where_it_stopped() {
+        let (_dir, source) = test_util::repo();
+        let (_, _, second, objects) = history(&source);
+        let pack = test_util::pack_of(&source, &objects);
+
+        let (_dir, repo) = test_util::repo();
+        let mut flaky = Flaky { cut: pack.len() / 2, packs: vec![pack], resumable: true, requests: Vec::new() };
+        let unpacked = fetch(&repo, &mut flaky, &[second], &[], 3).unwrap();
+        assert!(unpacked.complete);
+        assert_eq!(flaky.requests.iter().map(|(offset, _)| *offset).collect::<Vec<usize>>(), vec![0, flaky.cut]);
+        assert!(objects.iter().all(|sha| object::exists(&repo, sha)));
+        // Nothing is left behind in objects/pack
+        let leftovers = fs::read_dir(repo.gitdir().join("objects/pack")).unwrap().count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn failed_fetch_offers_what_it_got_as_haves() {
+        let (_dir, source) = test_util::repo();
+        let (first, first_objects, second, objects) = history(&source);
+        let packs = vec![test_util::pack_of(&source, &first_objects), test_util::pack_of(&source, &objects)];
+
+        let (_dir, repo) = test_util::repo();
+        let mut flaky = Flaky { cut: packs[0].len(), packs, resumable: false, requests: Vec::new() };
+        fetch(&repo, &mut flaky, &[second], &[], 3).unwrap();
+        assert_eq!(flaky.requests, vec![(0, Vec::new()), (0, vec![first])]);
+        assert!(objects.iter().all(|sha| object::exists(&repo, sha)));
+    }
+
+    #[test]
+    fn written_packs_pass_verify_pack() {
+        let (dir, repo) = test_util::repo();
+        let (_, _, _, objects) = history(&repo);
+        let data = objects.iter().map(|sha| object::read_raw(&repo, sha).unwrap()).collect::<Vec<(String, Vec<u8>)>>();
+        // write leaves the loose objects alone
+        let copy = dir.path().join("copy.pack");
+        let copy_sha = write(&repo, &objects, &copy).unwrap();
+        assert!(copy.with_extension("idx").is_file());
+        assert!(objects.iter().all(|sha| object::loose_path(&repo, sha).unwrap().is_file()));
+
+        let pack = Repository::dir(&repo, vec!["objects", "pack"], true).unwrap().join("pack-written.pack");
+        let sha = write_pack(&repo, &objects, &pack, &pack.with_extension("idx")).unwrap();
+        assert_eq!(sha, copy_sha);
+        assert_eq!(fs::read(&copy).unwrap(), fs::read(&pack).unwrap());
+
+        assert_eq!(fs::read(&pack).unwrap()[fs::metadata(&pack).unwrap().len() as usize - 20..], sha.as_bytes()[..]);
+        let verified = test_util::git(dir.path(), &["verify-pack", "-v", pack.with_extension("idx").to_str().unwrap()]);
+        assert!(verified.ends_with(": ok\n"), "{}", verified);
+        for sha in &objects {
+            assert!(verified.contains(&sha.to_string()));
+            assert!(!object::loose_path(&repo, sha).unwrap().exists());
+        }
+        // git builds the same index from the pack alone
+        test_util::git(dir.path(), &["index-pack", "-o", "git.idx", pack.to_str().unwrap()]);
+        assert_eq!(fs::read(dir.path().join("git.idx")).unwrap(), fs::read(pack.with_extension("idx")).unwrap());
+        for (sha, expected) in objects.iter().zip(data) {
+            assert_eq!(object::read_raw(&repo, sha).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn repack_leaves_one_pack_of_what_is_reachable() {
+        let (dir, repo) = test_util::repo();
+        let (_, first_objects, _, objects) = history(&repo);
+        test_util::pack_of(&repo, &first_objects);
+        let unreachable = object::write_raw(&repo, "blob", b"nobody points here\n").unwrap();
+
+        assert_eq!(repack(&repo).unwrap(), objects.len());
+        let packs = fs::read_dir(dir.path().join(".git/objects/pack")).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(packs.len(), 2, "{:?}", packs);
+        assert!(packs.iter().all(|name| name.starts_with("pack-") && !name.starts_with("pack-test")));
+        assert_eq!(indexes(&repo).unwrap().len(), 1);
+        for sha in &objects {
+            assert!(contains(&repo, sha));
+        }
+        // Loose objects nothing reaches are left to gc
+        assert!(object::loose_path(&repo, &unreachable).unwrap().is_file());
+        assert!(test_util::git(dir.path(), &["count-objects", "-v"]).starts_with("count: 1\n"));
+        assert_eq!(test_util::git(dir.path(), &["rev-list", "--objects", "--all"]).lines().count(), objects.len());
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    blob::{self, Blob},
+    commit::{self, Commit},
+    diff,
+    error::{WitError, builder::*},
+    index::{Index, IndexEntry},
+    object::{self, WitObject},
+    object_id::ObjectId,
+    reference,
+    repository::Repository,
+    signature::Signature,
+    tree::{self, ChangeKind, DiffEntry, Leaf},
+    util::{format_rfc2822, parse_rfc2822, base64_decode, set_executable}
+};
+
+// The fixed date git uses in the mbox "From " line, so patches can be recognized
+const MBOX_DATE: &str = "Mon Sep 17 00:00:00 2001";
+const CONTEXT: usize = 3;
+
+struct FilePatch {
+    old_path: String,
+    new_path: String,
+    new_mode: Option<String>,
+    created: bool,
+    deleted: bool,
+    hunks: Vec<diff::Hunk>,
+}
+
+struct Mail {
+    author: Signature,
+    message: String,
+    files: Vec<FilePatch>,
+}
+
+pub fn format_patch(repo: &Repository, range: &str, output_dir: &Path) -> Result<Vec<PathBuf>, Box<WitError>> {
+    let commits = commit_range(repo, range)?;
+    fs::create_dir_all(output_dir)?;
+
+    let mut written = Vec::new();
+    for (i, sha) in commits.iter().enumerate() {
+        let commit = commit::read(repo, sha)?;
+        let (subject, _) = split_message(commit.message());
+        let path = output_dir.join(format!("{:04}-{}.patch", i + 1, slug(&subject)));
+        fs::write(&path, format_mail(repo, sha, &commit, i + 1, commits.len())?)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+pub fn am(repo: &Repository, patch_files: &[PathBuf]) -> Result<(), Box<WitError>> {
+    for file in patch_files {
+        let raw = fs::read(file)?;
+        for mail in split_mbox(&raw) {
+            let mail = parse_mail(mail).map_err(|e| {
+                malformed_object_err(format!("Could not parse {}: {}", file.display(), e))
+            })?;
+            apply_mail(repo, &mail)?;
+        }
+    }
+    Ok(())
+}
+
+// Commits in `since..until`, oldest first, without merges
+fn commit_range(repo: &Repository, range: &str) -> Result<Vec<String>, Box<WitError>> {
+    let (since, until) = match range.split_once("..") {
+        Some((since, until)) => (
+            if since.is_empty() { "HEAD" } else { since },
+            if until.is_empty() { "HEAD" } else { until }
+        ),
+        None => (range, "HEAD")
+    };
+    let since = object::find(repo, since, Some("commit"), true)?;
+    let until = object::find(repo, until, Some("commit"), true)?;
+
+    let order = commit::topo_order(repo, &until.to_string(), &commit::ancestors(repo, &since.to_string())?)?;
+
+    let mut commits = Vec::new();
+    for sha in order {
+        if commit::parents(repo, &sha)?.len() <= 1 {
+            commits.push(sha);
+        }
+    }
+    Ok(commits)
+}
+
+fn split_message(message: &str) -> (String, String) {
+    let message = message.trim_start_matches('\n');
+    match message.split_once("\n\n") {
+        Some((subject, body)) => (subject.replace('\n', " "), body.trim_start_matches('\n').to_owned()),
+        None => (message.trim_end().replace('\n', " "), String::new())
+    }
+}
+
+fn slug(subject: &str) -> String {
+    let mut slug = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches(|c| c == '-' || c == '.');
+    slug.chars().take(52).collect::<String>().trim_end_matches('-').to_owned()
+}
+
+// The headers and message are text, but the diffs are written byte for byte
+fn format_mail(repo: &Repository, sha: &str, commit: &Commit, number: usize, total: usize) -> Result<Vec<u8>, Box<WitError>> {
+    let author = commit.author()?;
+    let (subject, body) = split_message(commit.message());
+    let prefix = if total > 1 {
+        format!("[PATCH {}/{}]", number, total)
+    } else {
+        "[PATCH]".to_owned()
+    };
+
+    let parents = commit::parents(repo, sha)?;
+    let changes = tree::diff(
+        repo,
+        parents.first().map(|parent| parent.as_str()),
+        commit.tree().ok_or(malformed_object_err(format!("Commit {} has no tree", sha)))?
+    )?;
+    let (stat, diffs) = format_diff(repo, &changes)?;
+
+    let mut mail = format!("From {} {}\n", sha, MBOX_DATE);
+    mail += &format!("From: {} <{}>\n", encode_header(&author.name), author.email);
+    mail += &format!("Date: {}\n", format_rfc2822(author.time, author.offset));
+    mail += &format!("Subject: {} {}\n", prefix, encode_header(&subject));
+    if !subject.is_ascii() || !body.is_ascii() {
+        mail += "MIME-Version: 1.0\n";
+        mail += "Content-Type: text/plain; charset=UTF-8\n";
+        mail += "Content-Transfer-Encoding: 8bit\n";
+    }
+    mail += "\n";
+    if !body.is_empty() {
+        mail += &body;
+        if !body.ends_with('\n') {
+            mail += "\n";
+        }
+    }
+    mail += "---\n";
+    mail += &stat;
+    mail += "\n";
+    let mut mail = mail.into_bytes();
+    mail.extend(diffs);
+    mail.extend(format!("-- \nwit {}\n\n", env!("CARGO_PKG_VERSION")).as_bytes());
+    Ok(mail)
+}
+
+// Returns the diffstat and the concatenated per-file diffs
+fn format_diff(repo: &Repository, changes: &[DiffEntry]) -> Result<(String, Vec<u8>), Box<WitError>> {
+    let mut stats = Vec::new();
+    let mut summary = String::new();
+    let mut diffs = Vec::new();
+    for change in changes {
+        let name = change.path.to_str().ok_or(path_conversion_err())?;
+        let old_mode = change.old_mode.as_deref().unwrap_or("");
+        let new_mode = change.new_mode.as_deref().unwrap_or("");
+        match change.kind {
+            ChangeKind::Added => summary += &format!(" create mode {} {}\n", new_mode, name),
+            ChangeKind::Deleted => summary += &format!(" delete mode {} {}\n", old_mode, name),
+            _ if old_mode != new_mode => summary += &format!(" mode change {} => {} {}\n", old_mode, new_mode, name),
+            _ => ()
+        }
+
+        let patch = diff::entry_patch(repo, change, CONTEXT)?;
+        let stat = if patch.split(|&b| b == b'\n').any(|l| l.starts_with(b"Binary files ")) {
+            let size = |sha: &Option<ObjectId>| -> Result<usize, Box<WitError>> {
+                Ok(match sha { Some(sha) => blob::read(repo, sha)?.data().len(), None => 0 })
+            };
+            (0, 0, Some((size(&change.old_sha)?, size(&change.new_sha)?)))
+        } else {
+            // Only count lines inside hunks, not the ---/+++ headers
+            let body = patch.split(|&b| b == b'\n').skip_while(|l| !l.starts_with(b"@@"));
+            let (mut insertions, mut deletions) = (0usize, 0usize);
+            for line in body {
+                if line.starts_with(b"+") {
+                    insertions += 1;
+                } else if line.starts_with(b"-") {
+                    deletions += 1;
+                }
+            }
+            (insertions, deletions, None)
+        };
+        stats.push((name.to_owned(), stat.0, stat.1, stat.2));
+        diffs.extend(patch);
+    }
+
+    let width = stats.iter().map(|(name, _, _, _)| name.chars().count()).max().unwrap_or(0);
+    let count_width = stats.iter().map(|(_, i, d, bin)| if bin.is_some() { 3 } else { (i + d).to_string().len() }).max().unwrap_or(1);
+    let most = stats.iter().map(|(_, i, d, _)| i + d).max().unwrap_or(0);
+    let mut stat = String::new();
+    for (name, insertions, deletions, binary) in &stats {
+        if let Some((old_size, new_size)) = binary {
+            stat += &format!(" {}{} | Bin {} -> {} bytes\n", name, " ".repeat(width - name.chars().count()), old_size, new_size);
+            continue;
+        }
+        // Scale the bars down like git does for large changes
+        let (mut plus, mut minus) = (*insertions, *deletions);
+        if most > 50 {
+            plus = (plus * 50).div_ceil(most);
+            minus = (minus * 50).div_ceil(most);
+        }
+        let line = format!(
+            " {}{} | {:>count_width$} {}{}",
+            name,
+            " ".repeat(width - name.chars().count()),
+            insertions + deletions,
+            "+".repeat(plus),
+            "-".repeat(minus),
+            count_width = count_width
+        );
+        stat += line.trim_end();
+        stat += "\n";
+    }
+    let insertions: usize = stats.iter().map(|(_, i, _, _)| i).sum();
+    let deletions: usize = stats.iter().map(|(_, _, d, _)| d).sum();
+    stat += &format!(" {} file{} changed", stats.len(), if stats.len() == 1 { "" } else { "s" });
+    if insertions > 0 || deletions == 0 {
+        stat += &format!(", {} insertion{}(+)", insertions, if insertions == 1 { "" } else { "s" });
+    }
+    if deletions > 0 || insertions == 0 {
+        stat += &format!(", {} deletion{}(-)", deletions, if deletions == 1 { "" } else { "s" });
+    }
+    stat += "\n";
+    stat += &summary;
+    Ok((stat, diffs))
+}
+
+// RFC 2047 "Q" encoding for header values that are not plain ascii
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+    let mut encoded = String::from("=?UTF-8?q?");
+    for byte in value.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'*' | b'+' | b'-' | b'/' => encoded.push(byte as char),
+            _ => encoded += &format!("={:02X}", byte)
+        }
+    }
+    encoded += "?=";
+    encoded
+}
+
+fn decode_header(value: &str) -> String {
+    let mut decoded = Vec::new();
+    let mut rest = value;
+    let mut last_was_encoded = false;
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(3, '?').collect::<Vec<&str>>();
+        let end = word.get(2).and_then(|text| text.find("?="));
+        let (Some(end), [_, encoding, text]) = (end, word.as_slice()) else {
+            break;
+        };
+        let between = &rest[..start];
+        // Whitespace between two encoded words is dropped
+        if !(last_was_encoded && between.trim().is_empty()) {
+            decoded.extend_from_slice(between.as_bytes());
+        }
+        let text = &text[..end];
+        if encoding.eq_ignore_ascii_case("q") {
+            let bytes = text.as_bytes();
+            let mut i = 0;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'_' => decoded.push(b' '),
+                    b'=' if i + 2 < bytes.len() => {
+                        match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                            Some(byte) => decoded.push(byte),
+                            None => decoded.extend_from_slice(&bytes[i..i + 3])
+                        }
+                        i += 2;
+                    },
+                    byte => decoded.push(byte)
+                }
+                i += 1;
+            }
+        } else {
+            decoded.extend(base64_decode(text));
+        }
+        // "=?" + charset + "?" + encoding + "?" + text + "?="
+        let consumed = start + 2 + word[0].len() + 1 + encoding.len() + 1 + end + 2;
+        rest = &rest[consumed..];
+        last_was_encoded = true;
+    }
+    decoded.extend_from_slice(rest.as_bytes());
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn is_mbox_separator(line: &[u8]) -> bool {
+    line.strip_prefix(b"From ")
+        .and_then(|rest| rest.split(|&b| b == b' ').next())
+        .map(|sha| sha.len() == 40 && sha.iter().all(|b| b.is_ascii_hexdigit()))
+        .unwrap_or(false)
+}
+
+// A file can hold several mails, each starting with a "From <sha> <date>" line
+fn split_mbox(raw: &[u8]) -> Vec<&[u8]> {
+    let mut mails = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    for line in diff::lines(raw) {
+        if is_mbox_separator(line) {
+            if let Some(start) = start {
+                mails.push(&raw[start..pos]);
+            }
+            start = Some(pos + line.len());
+        }
+        pos += line.len();
+    }
+    match start {
+        Some(start) => mails.push(&raw[start..]),
+        // Not an mbox, treat the whole file as a single mail
+        None => mails.push(raw)
+    }
+    mails
+}
+
+// The headers and message are read as UTF-8; the diffs stay bytes
+fn parse_mail(raw: &[u8]) -> Result<Mail, Box<WitError>> {
+    let mut lines = diff::lines(raw).into_iter();
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines.by_ref() {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_lowercase(), value.trim().to_owned()));
+        }
+    }
+    let header = |key: &str| headers.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).ok_or(
+        missing_data_err(format!("Patch has no {} header", key))
+    );
+
+    let from = decode_header(&header("from")?);
+    let (name, email) = match (from.find('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (from[..start].trim().trim_matches('"').to_owned(), from[start + 1..end].to_owned()),
+        _ => (String::new(), from.trim().to_owned())
+    };
+    let (time, offset) = parse_rfc2822(&header("date")?)?;
+
+    let mut subject = decode_header(&header("subject")?);
+    if subject.starts_with("[PATCH") {
+        if let Some(end) = subject.find(']') {
+            subject = subject[end + 1..].trim().to_owned();
+        }
+    }
+
+    let mut body = String::new();
+    for line in lines.by_ref() {
+        let line = String::from_utf8_lossy(line);
+        if line.trim_end() == "---" {
+            break;
+        }
+        body += &line;
+    }
+    let body = body.trim_end();
+    let message = if body.is_empty() {
+        format!("{}\n", subject)
+    } else {
+        format!("{}\n\n{}\n", subject, body)
+    };
+
+    Ok(Mail {
+        author: Signature::new(&name, &email, time, offset),
+        message,
+        files: parse_files(lines.map(|l| l.strip_suffix(b"\n").unwrap_or(l)).collect())?
+    })
+}
+
+fn strip_newline(hunk: &mut diff::Hunk) {
+    if let Some(last) = hunk.lines.last_mut() {
+        if last.ends_with(b"\n") {
+            last.pop();
+        }
+    }
+}
+
+fn parse_files(lines: Vec<&[u8]>) -> Result<Vec<FilePatch>, Box<WitError>> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = String::from_utf8_lossy(lines[i]);
+        let line = line.as_ref();
+        i += 1;
+
+        if let Some(paths) = line.strip_prefix("diff --git a/") {
+            let (old_path, new_path) = paths.split_once(" b/").ok_or(
+                malformed_object_err(format!("Malformed diff header {}", line))
+            )?;
+            files.push(FilePatch {
+                old_path: old_path.to_owned(),
+                new_path: new_path.to_owned(),
+                new_mode: None,
+                created: false,
+                deleted: false,
+                hunks: Vec::new()
+            });
+            continue;
+        }
+        let Some(file) = files.last_mut() else {
+            continue;
+        };
+
+        if let Some(mode) = line.strip_prefix("new file mode ") {
+            file.created = true;
+            file.new_mode = Some(mode.to_owned());
+        } else if line.starts_with("deleted file mode ") {
+            file.deleted = true;
+        } else if let Some(mode) = line.strip_prefix("new mode ") {
+            file.new_mode = Some(mode.to_owned());
+        } else if let Some(index) = line.strip_prefix("index ") {
+            if let Some((_, mode)) = index.split_once(' ') {
+                file.new_mode = Some(mode.to_owned());
+            }
+        } else if let Some(path) = line.strip_prefix("--- a/") {
+            file.old_path = path.to_owned();
+        } else if let Some(path) = line.strip_prefix("+++ b/") {
+            file.new_path = path.to_owned();
+        } else if line.starts_with("@@ ") {
+            let mut hunk = diff::parse_hunk_header(line)?;
+            let (mut old, mut new) = (0, 0);
+            while (old < hunk.old_count || new < hunk.new_count) && i < lines.len() {
+                let line = lines[i];
+                i += 1;
+                match line.first() {
+                    // "\ No newline at end of file" belongs to the line before it
+                    Some(b'\\') => {
+                        strip_newline(&mut hunk);
+                        continue;
+                    },
+                    Some(b'-') => old += 1,
+                    Some(b'+') => new += 1,
+                    Some(b' ') => { old += 1; new += 1; },
+                    // Some mailers strip the space from empty context lines
+                    None => { old += 1; new += 1; },
+                    _ => return Err(malformed_object_err(format!("Malformed hunk line {}", String::from_utf8_lossy(line))))
+                }
+                let mut line = if line.is_empty() { b" ".to_vec() } else { line.to_vec() };
+                line.push(b'\n');
+                hunk.lines.push(line);
+            }
+            if i < lines.len() && lines[i].starts_with(b"\\") {
+                strip_newline(&mut hunk);
+                i += 1;
+            }
+            file.hunks.push(hunk);
+        } else if line.starts_with("Binary files ") || line == "GIT binary patch" {
+            return Err(malformed_object_err(format!("Binary patch for {} is not supported", file.new_path)));
+        }
+    }
+    Ok(files)
+}
+
+fn apply_mail(repo: &Repository, mail: &Mail) -> Result<(), Box<WitError>> {
+    let head = reference::resolve(repo, "HEAD").ok();
+    let mut leaves = match &head {
+        Some(head) => tree::flatten(repo, head)?,
+        None => BTreeMap::new()
+    };
+
+    let mut written = Vec::new();
+    let mut removed = Vec::new();
+    for file in &mail.files {
+        let old_path = PathBuf::from(&file.old_path);
+        let new_path = PathBuf::from(&file.new_path);
+        let old_leaf = leaves.remove(&old_path);
+
+        if file.deleted {
+            if old_leaf.is_none() {
+                return Err(unknown_object_err(format!("{} does not exist in HEAD", file.old_path)));
+            }
+            removed.push(old_path);
+            continue;
+        }
+
+        let old_data = match (&old_leaf, file.created) {
+            (Some(_), true) => return Err(malformed_object_err(format!("{} already exists in HEAD", file.new_path))),
+            (Some(leaf), false) => blob::read(repo, leaf.sha())?.data().clone(),
+            (None, true) => Vec::new(),
+            (None, false) => return Err(unknown_object_err(format!("{} does not exist in HEAD", file.old_path)))
+        };
+        let data = diff::apply(&old_data, &file.hunks).map_err(|e| {
+            malformed_object_err(format!("Patch failed at {}: {}", file.new_path, e))
+        })?;
+        let mode = file.new_mode.clone()
+            .or(old_leaf.map(|leaf| leaf.mode().to_owned()))
+            .unwrap_or("100644".to_owned());
+        let sha = object::write(WitObject::BlobObject(Blob::new(Some(repo), data.clone())), true)?;
+
+        if old_path != new_path {
+            removed.push(old_path);
+        }
+        leaves.insert(new_path.clone(), Leaf::new(mode, new_path.clone(), sha));
+        written.push((new_path, data));
+    }
+
+    let tree_sha = tree::write(repo, &leaves)?;
+    let commit_sha = commit::create(repo, &tree_sha, &head.into_iter().collect::<Vec<String>>(), &mail.message, Some(mail.author.clone()))?;
+
+    // Bring the worktree and index in line with the new commit
+    let mut index = Index::read(repo)?;
+    for path in removed {
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        let dest = tree::safe_dest(repo.worktree()?, &path)?;
+        if dest.is_file() {
+            fs::remove_file(dest)?;
+        }
+        index.remove(name);
+    }
+    for (path, data) in written {
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        let leaf = &leaves[&path];
+        let dest = tree::safe_dest(repo.worktree()?, &path)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&dest, data)?;
+        set_executable(&dest, leaf.mode() == "100755")?;
+        index.add(IndexEntry::create(repo, name, leaf.sha(), leaf.mode())?);
+    }
+    index.write(repo)?;
+
+    let (subject, _) = split_message(&mail.message);
+    reference::update_head(repo, &commit_sha.to_string(), &format!("am: {}", subject))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIL: &[u8] = b"From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001
+From: A U Thor <author@example.com>
+Date: Thu, 7 Apr 2005 15:13:13 -0700
+Subject: [PATCH] Add more
+
+---
+ f | 3 ++-
+ 1 file changed, 2 insertions(+), 1 deletion(-)
+
+diff --git a/f b/f
+index 5116351..7ca3e60 100644
+--- a/f
++++ b/f
+@@ -1,3 +1,4 @@
+ a
+ b
+-end
+\\ No newline at end of file
++end
++more
+diff --git a/g b/g
+index c1b0730..e25f181 100644
+--- a/g
++++ b/g
+@@ -1 +1 @@
+-caf\xe9
+\\ No newline at end of file
++cr\xe8me
+\\ No newline at end of file
+-- 
+2.39.5
+
+";
+
+    #[test]
+    fn no_newline_marker_inside_a_hunk() {
+        let mail = parse_mail(split_mbox(MAIL)[0]).unwrap();
+        assert_eq!(mail.message, "Add more\n");
+        assert_eq!(mail.author.email, "author@example.com");
+        assert_eq!(mail.files.len(), 2);
+        assert_eq!(diff::apply(b"a\nb\nend", &mail.files[0].hunks).unwrap(), b"a\nb\nend\nmore\n");
+    }
+
+    #[test]
+    fn non_utf8_lines_apply_unchanged() {
+        let mail = parse_mail(split_mbox(MAIL)[0]).unwrap();
+        assert_eq!(diff::apply(b"caf\xe9", &mail.files[1].hunks).unwrap(), b"cr\xe8me");
+        assert!(diff::apply(b"cafe", &mail.files[1].hunks).is_err());
+    }
+
+    #[test]
+    fn diff_round_trips_through_parse() {
+        let (old, new) = (b"one\ntwo\nthree".to_vec(), b"one\n2\nthree\nfour\n".to_vec());
+        let mut patch = b"diff --git a/f b/f\n".to_vec();
+        patch.extend(diff::unified(&old, &new, "a/f", "b/f", 3));
+        let files = parse_files(diff::lines(&patch).into_iter().map(|l| l.strip_suffix(b"\n").unwrap_or(l)).collect()).unwrap();
+        assert_eq!(diff::apply(&old, &files[0].hunks).unwrap(), new);
+    }
+
+    #[test]
+    fn slugs_and_headers() {
+        assert_eq!(slug("Fix: the (odd) thing."), "Fix-the-odd-thing");
+        assert_eq!(decode_header(&encode_header("Jürgen")), "Jürgen");
+        assert_eq!(decode_header("=?UTF-8?q?J=C3=BCr?= =?UTF-8?q?gen?="), "Jürgen");
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{
+    blob,
+    checkout::{commit_files, update_worktree, Files},
+    commit,
+    error::{WitError, builder::*},
+    index::Index,
+    merge::{self, Conflicts},
+    object,
+    reference,
+    repository::Repository,
+    tree::{self, Leaf}
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebaseStep {
+    // Every commit was replayed and the branch points at the result
+    Finished,
+    // Replaying this commit conflicted in these paths
+    Conflict(String, Vec<PathBuf>),
+}
+
+fn state_dir(repo: &Repository) -> PathBuf {
+    Repository::path(repo, vec!["rebase-merge"])
+}
+
+fn read_state(repo: &Repository, name: &str) -> Result<String, Box<WitError>> {
+    Ok(fs::read_to_string(state_dir(repo).join(name))?.trim_end().to_owned())
+}
+
+fn write_state(repo: &Repository, name: &str, data: &str) -> Result<(), Box<WitError>> {
+    Repository::write_file(repo, &state_dir(repo).join(name), data.as_bytes())
+}
+
+fn in_progress(repo: &Repository) -> Result<(), Box<WitError>> {
+    if !state_dir(repo).is_dir() {
+        return Err(missing_data_err("No rebase in progress.".to_owned()));
+    }
+    Ok(())
+}
+
+// Replays the commits of the current branch that are not in onto on top of it
+pub fn start(repo: &Repository, onto: &str) -> Result<RebaseStep, Box<WitError>> {
+    if state_dir(repo).exists() {
+        return Err(in_progress_err("A rebase is already in progress; use --continue or --abort.".to_owned()));
+    }
+    let head = fs::read_to_string(Repository::file(repo, vec!["HEAD"], false)?)?;
+    let head_name = head.trim().strip_prefix("ref: ").unwrap_or("detached HEAD").to_owned();
+    let orig = reference::resolve(repo, "HEAD")?;
+    let onto = object::find(repo, onto, Some("commit"), true)?.to_string();
+
+    // Everything above the fork point, oldest first. Merges are dropped, as git does.
+    let upstream = commit::ancestors(repo, &onto)?;
+    let mut todo = Vec::new();
+    for sha in commit::topo_order(repo, &orig, &upstream)? {
+        if commit::parents(repo, &sha)?.len() <= 1 {
+            todo.push(sha);
+        }
+    }
+
+    Repository::create_dir(repo, &state_dir(repo))?;
+    write_state(repo, "head-name", &format!("{}\n", head_name))?;
+    write_state(repo, "onto", &format!("{}\n", onto))?;
+    write_state(repo, "orig-head", &format!("{}\n", orig))?;
+    write_state(repo, "git-rebase-todo", &todo.iter().map(|sha| format!("{}\n", sha)).collect::<String>())?;
+    write_state(repo, "done", "")?;
+
+    update_worktree(repo, &commit_files(repo, &orig)?, &commit_files(repo, &onto)?)?;
+    reference::detach_head(repo, &onto, &format!("rebase (start): checkout {}", onto))?;
+    run(repo)
+}
+
+// Commits the resolution of the stopped commit and replays the rest
+pub fn resume(repo: &Repository) -> Result<RebaseStep, Box<WitError>> {
+    in_progress(repo)?;
+    if state_dir(repo).join("stopped-sha").exists() {
+        let sha = read_state(repo, "stopped-sha")?;
+        let Pick { ours, mut merged, .. } = pick(repo, &sha)?;
+        let index = Index::read(repo)?;
+        if !index.unmerged().is_empty() {
+            return Err(in_progress_err("You must edit all merge conflicts and then mark them as resolved using add.".to_owned()));
+        }
+
+        // The user's resolution is whatever they added to the index
+        for path in conflicts(repo)? {
+            let name = path.to_str().ok_or(path_conversion_err())?;
+            let Some(entry) = index.get(name) else {
+                merged.remove(&path);
+                continue;
+            };
+            let sha = entry.sha().parse()?;
+            if has_markers(blob::read(repo, &sha)?.data()) {
+                return Err(malformed_object_err(format!("{} still has conflict markers", path.display())));
+            }
+            merged.insert(path.clone(), Leaf::new(format!("{:o}", entry.mode()), path, sha));
+        }
+
+        commit_pick(repo, &sha, &ours, &merged)?;
+        fs::remove_file(state_dir(repo).join("stopped-sha"))?;
+        fs::remove_file(state_dir(repo).join("conflicts"))?;
+    }
+    run(repo)
+}
+
+// Puts the branch, HEAD and worktree back where they were before the rebase
+pub fn abort(repo: &Repository) -> Result<(), Box<WitError>> {
+    in_progress(repo)?;
+    let head_name = read_state(repo, "head-name")?;
+    let orig = read_state(repo, "orig-head")?;
+    let current = commit_files(repo, &reference::resolve(repo, "HEAD")?)?;
+
+    // Conflicted files don't match HEAD and their index entries are stages;
+    // dropping both makes update_worktree write them afresh
+    let root = repo.worktree()?.to_path_buf();
+    if state_dir(repo).join("conflicts").exists() {
+        let lock = Index::lock(repo)?;
+        let mut index = Index::read(repo)?;
+        for path in conflicts(repo)? {
+            let dest = tree::safe_dest(&root, &path)?;
+            if dest.is_file() {
+                fs::remove_file(dest)?;
+            }
+            index.remove(path.to_str().ok_or(path_conversion_err())?);
+        }
+        index.write_locked(repo, lock)?;
+    }
+    update_worktree(repo, &current, &commit_files(repo, &orig)?)?;
+
+    // The branch itself only moves when the rebase finishes
+    if head_name.starts_with("refs/") {
+        reference::attach_head(repo, &head_name, &format!("rebase (abort): returning to {}", head_name))?;
+    } else {
+        reference::detach_head(repo, &orig, "rebase (abort): returning to detached HEAD")?;
+    }
+    fs::remove_dir_all(state_dir(repo))?;
+    Ok(())
+}
+
+fn conflicts(repo: &Repository) -> Result<Vec<PathBuf>, Box<WitError>> {
+    Ok(read_state(repo, "conflicts")?.lines().map(PathBuf::from).collect())
+}
+
+fn has_markers(data: &[u8]) -> bool {
+    data.split(|&b| b == b'\n').any(|line| line.starts_with(b"<<<<<<< ") || line.starts_with(b">>>>>>> "))
+}
+
+fn run(repo: &Repository) -> Result<RebaseStep, Box<WitError>> {
+    loop {
+        let todo = read_state(repo, "git-rebase-todo")?;
+        let mut todo = todo.lines().filter(|line| !line.is_empty()).collect::<Vec<&str>>();
+        if todo.is_empty() {
+            return finish(repo);
+        }
+        let sha = todo.remove(0).to_owned();
+        write_state(repo, "git-rebase-todo", &todo.iter().map(|sha| format!("{}\n", sha)).collect::<String>())?;
+
+        let Pick { base, ours, theirs, merged, conflicts } = pick(repo, &sha)?;
+        if conflicts.is_empty() {
+            commit_pick(repo, &sha, &ours, &merged)?;
+            continue;
+        }
+
+        // Leave the clean part of the pick in place and mark up the rest,
+        // with every side of the conflicted paths in the index
+        update_worktree(repo, &ours, &merged)?;
+        let root = repo.worktree()?;
+        for (path, data) in &conflicts {
+            if let Some(data) = data {
+                let dest = tree::safe_dest(root, path)?;
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(dest, data)?;
+            }
+        }
+        merge::stage_conflicts(repo, &conflicts, &base, &ours, &theirs)?;
+        let paths = conflicts.into_iter().map(|(path, _)| path).collect::<Vec<PathBuf>>();
+        write_state(repo, "stopped-sha", &format!("{}\n", sha))?;
+        write_state(repo, "conflicts", &paths.iter().map(|path| format!("{}\n", path.display())).collect::<String>())?;
+        return Ok(RebaseStep::Conflict(sha, paths));
+    }
+}
+
+// Applying the changes a commit made to its parent onto HEAD
+struct Pick {
+    // The parent's files
+    base: Files,
+    // HEAD's files
+    ours: Files,
+    // The commit's files
+    theirs: Files,
+    merged: Files,
+    // Paths where both sides changed the same lines, or one deleted what the other changed
+    conflicts: Conflicts,
+}
+
+fn pick(repo: &Repository, sha: &str) -> Result<Pick, Box<WitError>> {
+    let base = match commit::parents(repo, sha)?.first() {
+        Some(parent) => commit_files(repo, parent)?,
+        None => Files::new()
+    };
+    let theirs = commit_files(repo, sha)?;
+    let ours = commit_files(repo, &reference::resolve(repo, "HEAD")?)?;
+    let subject = commit::read(repo, sha)?.message().lines().next().unwrap_or("").to_owned();
+    let label = format!("{} ({})", &sha[..7], subject);
+    let (merged, conflicts) = merge::merge_files(repo, &base, &ours, &theirs, "HEAD", &label)?;
+    Ok(Pick { base, ours, theirs, merged, conflicts })
+}
+
+// Commits the merged files on top of HEAD with sha's author and message,
+// dropping the commit if it changes nothing
+fn commit_pick(repo: &Repository, sha: &str, ours: &Files, merged: &Files) -> Result<(), Box<WitError>> {
+    let commit = commit::read(repo, sha)?;
+    let head = reference::resolve(repo, "HEAD")?;
+    update_worktree(repo, ours, merged)?;
+
+    let tree = tree::write(repo, merged)?;
+    if Some(&tree.to_string()) != commit::read(repo, &head)?.tree() {
+        let new = commit::create(repo, &tree, &[head], commit.message(), Some(commit.author()?))?;
+        let subject = commit.message().lines().next().unwrap_or("");
+        reference::detach_head(repo, &new.to_string(), &format!("rebase (pick): {}", subject))?;
+    }
+
+    let mut done = read_state(repo, "done")?;
+    if !done.is_empty() {
+        done.push('\n');
+    }
+    write_state(repo, "done", &format!("{}{}\n", done, sha))
+}
+
+fn finish(repo: &Repository) -> Result<RebaseStep, Box<WitError>> {
+    let head_name = read_state(repo, "head-name")?;
+    if head_name.starts_with("refs/") {
+        // Refuse to clobber the branch if something else moved it meanwhile
+        let orig = read_state(repo, "orig-head")?;
+        let onto = read_state(repo, "onto")?;
+        let head = reference::resolve(repo, "HEAD")?;
+        reference::update(repo, &head_name, &head, Some(&orig), &format!("rebase (finish): {} onto {}", head_name, onto))?;
+        reference::attach_head(repo, &head_name, &format!("rebase (finish): returning to {}", head_name))?;
+    }
+    fs::remove_dir_all(state_dir(repo))?;
+    Ok(RebaseStep::Finished)
+}
@@ -2,10 +2,12 @@ use std::path::{PathBuf, Path};
 use std::fs;
 use linked_hash_map::LinkedHashMap;
 use crate::{
+    lockfile::Lockfile,
+    reflog,
     repository::Repository,
     error::{
         WitError,
-        builder::utf8_err
+        builder::{utf8_err, malformed_object_err, ref_mismatch_err, unborn_branch_err, unknown_reference_err, invalid_ref_err}
     }
 };
 
@@ -17,23 +19,169 @@ pub enum Ref {
 pub type IndirectRef = LinkedHashMap<String, Ref>;
 pub type DirectRef = String;
 
+// How many symbolic refs in a row resolve follows, as in git
+const MAX_SYMREF_DEPTH: usize = 5;
+
+// The sha ref_path points at, through any symbolic refs
 pub fn resolve(repo: &Repository, ref_path: &str) -> Result<String, Box<WitError>> {
-    let paths = ref_path.split('/').collect::<Vec<&str>>();
-    let data = std::fs::read_to_string(Repository::file(repo, paths, false)?)?;
-    if data.starts_with("ref: ") {
-        self::resolve(repo, &data[5..])
-    } else {
-        Ok(data)
+    match resolve_symbolic(repo, ref_path)? {
+        (_, Some(sha)) => Ok(sha),
+        (chain, None) if chain.len() > 1 => Err(unborn_branch_err(&chain[chain.len() - 1])),
+        _ => Err(unknown_reference_err(format!("Unknown reference {}.", ref_path)))
+    }
+}
+
+// The refs name leads through, starting with name itself, and the sha the
+// last of them holds, which is None if it doesn't exist yet
+pub fn resolve_symbolic(repo: &Repository, name: &str) -> Result<(Vec<String>, Option<String>), Box<WitError>> {
+    let mut chain = vec![name.to_owned()];
+    loop {
+        let refname = &chain[chain.len() - 1];
+        let data = match fs::read_to_string(Repository::file(repo, refname.split('/').collect(), false)?) {
+            Ok(data) => data,
+            // Refs that haven't changed since the last gc only exist in packed-refs
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let sha = packed_sha(repo, refname)?;
+                return Ok((chain, sha));
+            },
+            Err(e) => Err(e)?
+        };
+        // trim also drops the \r of refs written with Windows line endings
+        match data.trim().strip_prefix("ref: ") {
+            Some(target) => {
+                if chain.len() > MAX_SYMREF_DEPTH {
+                    return Err(unknown_reference_err(format!("Too many levels of symbolic refs resolving {}", name)));
+                }
+                chain.push(target.trim().to_owned());
+            },
+            // A sha written in uppercase by something else is still the same object
+            None => return Ok((chain, Some(data.trim().to_lowercase())))
+        }
+    }
+}
+
+// The (name, sha) pairs in packed-refs. The header line and the ^sha lines
+// giving the peeled value of the tag above them are skipped.
+pub fn packed(repo: &Repository) -> Result<Vec<(String, String)>, Box<WitError>> {
+    let data = match fs::read_to_string(Repository::path(repo, vec!["packed-refs"])) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(e)?
+    };
+    let mut refs = Vec::new();
+    for line in data.lines() {
+        if line.is_empty() || line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let (sha, name) = line.split_once(' ').ok_or(malformed_object_err(format!("Invalid packed-refs line {:?}", line)))?;
+        refs.push((name.to_owned(), sha.to_owned()));
+    }
+    Ok(refs)
+}
+
+fn packed_sha(repo: &Repository, refname: &str) -> Result<Option<String>, Box<WitError>> {
+    Ok(packed(repo)?.into_iter().find(|(name, _)| name == refname).map(|(_, sha)| sha))
+}
+
+// Refuses names git check-ref-format would, so that no ref can be written
+// outside refs/ or over a lock file
+pub fn check_name(refname: &str) -> Result<(), Box<WitError>> {
+    let bad_component = |c: &str| c.is_empty() || c.starts_with('.') || c.ends_with(".lock");
+    let bad_char = |c: char| c.is_ascii_control() || " ~^:?*[\\".contains(c);
+    if refname.split('/').any(bad_component)
+        || refname.contains("..")
+        || refname.contains("@{")
+        || refname == "@"
+        || refname.ends_with('.')
+        || refname.chars().any(bad_char)
+    {
+        return Err(invalid_ref_err(refname));
+    }
+    Ok(())
+}
+
+// Whether refname (e.g. refs/heads/main) exists, loose or packed
+pub fn exists(repo: &Repository, refname: &str) -> bool {
+    Repository::path(repo, vec![refname]).is_file() || packed_sha(repo, refname).ok().flatten().is_some()
+}
+
+// The branch HEAD ends up at through however many symbolic refs, whether
+// or not it has commits yet, or None if HEAD is detached
+pub fn head_target(repo: &Repository) -> Option<String> {
+    let (mut chain, _) = resolve_symbolic(repo, "HEAD").ok()?;
+    if chain.len() > 1 { chain.pop() } else { None }
+}
+
+// Moves HEAD to sha, through the checked out branch if HEAD is symbolic
+pub fn update_head(repo: &Repository, sha: &str, message: &str) -> Result<(), Box<WitError>> {
+    match head_target(repo).as_deref().and_then(|target| target.strip_prefix("refs/")) {
+        Some(branch) => self::create(repo, branch.to_owned(), sha.to_owned(), message),
+        None => self::detach_head(repo, sha, message)
     }
 }
 
+// Points HEAD straight at sha
+pub fn detach_head(repo: &Repository, sha: &str, message: &str) -> Result<(), Box<WitError>> {
+    let old = resolve(repo, "HEAD").ok();
+    write_head(repo, &format!("{}\n", sha))?;
+    reflog::append(repo, "HEAD", old.as_deref(), sha, message)
+}
+
+// Makes HEAD a symbolic ref to refname, e.g. refs/heads/main
+pub fn attach_head(repo: &Repository, refname: &str, message: &str) -> Result<(), Box<WitError>> {
+    check_name(refname)?;
+    let old = resolve(repo, "HEAD").ok();
+    write_head(repo, &format!("ref: {}\n", refname))?;
+    match resolve(repo, refname) {
+        Ok(new) => reflog::append(repo, "HEAD", old.as_deref(), &new, message),
+        // An unborn branch has nothing to log
+        Err(_) => Ok(())
+    }
+}
+
+fn write_head(repo: &Repository, data: &str) -> Result<(), Box<WitError>> {
+    let mut lock = Lockfile::acquire(&Repository::path(repo, vec!["HEAD"]))?;
+    lock.write(data.as_bytes())?;
+    lock.commit(repo)
+}
+
+// The refs under path (refs/ by default) as a tree, loose refs taking
+// precedence over packed ones of the same name
 pub fn list(repo: &Repository, path: Option<PathBuf>) -> Result<IndirectRef, Box<WitError>> {
-    let path = if let None = path {
-        Repository::dir(repo, vec!["refs"], false)?
-    } else {
-        path.unwrap()
+    let path = match path {
+        Some(path) => path,
+        None => Repository::path(repo, vec!["refs"])
     };
+    let mut ret = if path.is_dir() { list_loose(repo, path.clone())? } else { IndirectRef::new() };
+
+    let prefix = path.strip_prefix(repo.commondir()).unwrap_or(&path).to_str().ok_or(utf8_err("Could not read file name.".to_owned()))?.to_owned();
+    for (name, sha) in packed(repo)? {
+        let rest = match name.strip_prefix(&prefix).and_then(|rest| rest.strip_prefix('/')) {
+            Some(rest) => rest,
+            None => continue
+        };
+        insert_packed(&mut ret, &rest.split('/').collect::<Vec<&str>>(), sha);
+    }
+    Ok(ret)
+}
 
+fn insert_packed(refs: &mut IndirectRef, parts: &[&str], sha: String) {
+    match parts {
+        [] => (),
+        [name] => {
+            if !refs.contains_key(*name) {
+                refs.insert((*name).to_owned(), Ref::Direct(sha));
+            }
+        },
+        [dir, rest @ ..] => match refs.entry((*dir).to_owned()).or_insert_with(|| Ref::Indirect(IndirectRef::new())) {
+            Ref::Indirect(refs) => insert_packed(refs, rest, sha),
+            // A loose ref where packed-refs has a directory; the loose one wins
+            Ref::Direct(_) => ()
+        }
+    }
+}
+
+fn list_loose(repo: &Repository, path: PathBuf) -> Result<IndirectRef, Box<WitError>> {
     let mut ret: IndirectRef = IndirectRef::new();
 
     for file in std::fs::read_dir(path)? {
@@ -42,27 +190,42 @@ pub fn list(repo: &Repository, path: Option<PathBuf>) -> Result<IndirectRef, Box
         let name = String::from(
             can
             .file_name()
-            .to_str().ok_or(utf8_err(format!("Could not read file name.")))?
+            .to_str().ok_or(utf8_err("Could not read file name.".to_owned()))?
         );
         if can.path().is_dir() {
             ret.insert(
                 name,
                 Ref::Indirect(
-                    self::list(
+                    self::list_loose(
                         repo,
-                        Some(can.path())
+                        can.path()
                     )?
                 )
             );
         } else {
-            ret.insert(name.clone(), Ref::Direct(resolve(repo, &name)?));
+            let ref_path = can.path().strip_prefix(repo.commondir()).map(|p| p.to_path_buf()).unwrap_or(can.path());
+            ret.insert(name.clone(), Ref::Direct(resolve(
+                repo,
+                ref_path.to_str().ok_or(utf8_err("Could not read file name.".to_owned()))?
+            )?));
         }
     }
 
     Ok(ret)
 }
 
-pub fn show(repo: &Repository, refs: &IndirectRef, with_hash: bool, prefix: &str) -> Result<(), Box<WitError>> {
+// (name, sha) for every ref in the tree, names joined up under prefix
+pub fn flatten(refs: &IndirectRef, prefix: &str, names: &mut Vec<(String, String)>) {
+    for (name, value) in refs.iter() {
+        let name = format!("{}{}", prefix, name);
+        match value {
+            Ref::Direct(sha) => names.push((name, sha.clone())),
+            Ref::Indirect(refs) => flatten(refs, &format!("{}/", name), names)
+        }
+    }
+}
+
+pub fn show(refs: &IndirectRef, with_hash: bool, prefix: &str) -> Result<(), Box<WitError>> {
     for (k, v) in refs.iter() {
         match v {
             Ref::Direct(ref_path) => {
@@ -73,26 +236,234 @@ pub fn show(repo: &Repository, refs: &IndirectRef, with_hash: bool, prefix: &str
                 );
             },
             Ref::Indirect(refs) => {
-                self::show(repo, refs, with_hash, format!("{}{}{}", prefix, if prefix.is_empty() { "" } else { "/" }, k).as_str())?
+                self::show(refs, with_hash, format!("{}{}{}", prefix, if prefix.is_empty() { "" } else { "/" }, k).as_str())?
             }
         }
     }
     Ok(())
 }
 
-pub fn create(repo: &Repository, ref_name: String, sha: String) -> Result<(), Box<WitError>> {
-    fs::write(
-        Repository::file(
-            repo,
-            Path::new("refs/")
-                .join(&ref_name)
-                .to_str()
-                .ok_or(utf8_err(format!("Could not read file name.")))?
-                .split('/')
-                .collect::<Vec<&str>>(),
-            true
-        )?,
-        sha + "\n"
+pub fn create(repo: &Repository, ref_name: String, sha: String, message: &str) -> Result<(), Box<WitError>> {
+    check_name(&format!("refs/{}", ref_name))?;
+    let path = Repository::file(
+        repo,
+        Path::new("refs/")
+            .join(&ref_name)
+            .to_str()
+            .ok_or(utf8_err("Could not read file name.".to_owned()))?
+            .split('/')
+            .collect::<Vec<&str>>(),
+        true
     )?;
+    let lock = Lockfile::acquire(&path)?;
+    let old = current(repo, &format!("refs/{}", ref_name), &path)?;
+    write_locked(repo, &format!("refs/{}", ref_name), lock, old.as_deref(), &sha, message)
+}
+
+// Points refname (a full name like refs/heads/main) at new_sha, but only if
+// it still points at expected_old. None means the ref must not exist yet.
+pub fn update(repo: &Repository, refname: &str, new_sha: &str, expected_old: Option<&str>, message: &str) -> Result<(), Box<WitError>> {
+    check_name(refname)?;
+    let path = Repository::file(repo, refname.split('/').collect(), true)?;
+    let lock = Lockfile::acquire(&path)?;
+    let current = current(repo, refname, &path)?;
+    if current.as_deref() != expected_old {
+        return Err(ref_mismatch_err(refname, expected_old, current));
+    }
+    write_locked(repo, refname, lock, expected_old, new_sha, message)
+}
+
+// What the ref file holds, or its packed value, or None if it doesn't exist
+fn current(repo: &Repository, refname: &str, path: &Path) -> Result<Option<String>, Box<WitError>> {
+    match fs::read_to_string(path) {
+        Ok(data) => Ok(Some(data.trim().to_owned())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => packed_sha(repo, refname),
+        Err(e) => Err(e)?
+    }
+}
+
+// Commits the new value and logs the move, for HEAD too if it points here
+fn write_locked(repo: &Repository, refname: &str, mut lock: Lockfile, old: Option<&str>, sha: &str, message: &str) -> Result<(), Box<WitError>> {
+    lock.write(format!("{}\n", sha).as_bytes())?;
+    lock.commit(repo)?;
+    reflog::append(repo, refname, old, sha, message)?;
+    if head_target(repo).as_deref() == Some(refname) {
+        reflog::append(repo, "HEAD", old, sha, message)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::WitError, test_util};
+
+    const A: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+    const B: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+    #[test]
+    fn update_compares_before_swapping() {
+        let (_dir, repo) = test_util::repo();
+        update(&repo, "refs/heads/topic", A, None, "create").unwrap();
+        assert_eq!(resolve(&repo, "refs/heads/topic").unwrap(), A);
+
+        // None means the ref must not exist yet
+        let err = update(&repo, "refs/heads/topic", B, None, "again").unwrap_err();
+        assert!(matches!(*err, WitError::ReferenceMismatch { ref actual, expected: None, .. } if actual.as_deref() == Some(A)));
+        let err = update(&repo, "refs/heads/topic", B, Some(B), "stale").unwrap_err();
+        assert!(matches!(*err, WitError::ReferenceMismatch { .. }));
+        assert_eq!(resolve(&repo, "refs/heads/topic").unwrap(), A);
+
+        update(&repo, "refs/heads/topic", B, Some(A), "move").unwrap();
+        assert_eq!(resolve(&repo, "refs/heads/topic").unwrap(), B);
+        // A failed update leaves no lock behind
+        assert!(!repo.gitdir().join("refs/heads/topic.lock").exists());
+
+        let log = reflog::read(&repo, "refs/heads/topic").unwrap();
+        assert_eq!(log.iter().map(|entry| entry.message.as_str()).collect::<Vec<&str>>(), vec!["create", "move"]);
+        assert_eq!(log[1].old_sha, A);
+    }
+
+    #[test]
+    fn invalid_names_are_refused() {
+        let (_dir, repo) = test_util::repo();
+        for name in [
+            "refs/tags/../../../escaped", "refs/remotes/origin/../../config", "/refs/heads/a", "refs/heads/a/",
+            "refs/heads//a", "refs/heads/a.lock", "refs/heads/.hidden", "refs/heads/a\x01", "refs/heads/a b",
+            "refs/heads/a~1", "refs/heads/a^", "refs/heads/a:b", "refs/heads/a?", "refs/heads/a*", "refs/heads/a[",
+            "refs/heads/a\\b", "refs/heads/a@{1}", "refs/heads/a."
+        ] {
+            assert!(matches!(*update(&repo, name, A, None, "bad").unwrap_err(), WitError::InvalidReference(_)), "{}", name);
+        }
+        assert!(matches!(*create(&repo, "heads/../../x".to_owned(), A.to_owned(), "bad").unwrap_err(), WitError::InvalidReference(_)));
+        assert!(attach_head(&repo, "refs/heads/a..b", "bad").is_err());
+        assert!(!repo.gitdir().join("escaped").exists());
+        assert!(!repo.gitdir().join("x").exists());
+
+        check_name("refs/heads/feature/with-dash_and.dot").unwrap();
+        check_name("refs/tags/v1.0").unwrap();
+    }
+
+    #[test]
+    fn racing_updates_have_one_winner() {
+        let (dir, _repo) = test_util::repo();
+        for round in 0..20 {
+            let refname = format!("refs/heads/race{}", round);
+            let barrier = std::sync::Barrier::new(2);
+            let results = std::thread::scope(|scope| {
+                let racers = [A, B].map(|sha| {
+                    let (barrier, refname) = (&barrier, &refname);
+                    let path = dir.path();
+                    scope.spawn(move || {
+                        let repo = Repository::find_with_git_dir(path, None, true).unwrap().unwrap();
+                        barrier.wait();
+                        update(&repo, refname, sha, None, "race").map_err(|err| err.to_string())
+                    })
+                });
+                racers.map(|racer| racer.join().unwrap())
+            });
+
+            let winners = results.iter().filter(|result| result.is_ok()).count();
+            assert_eq!(winners, 1, "{:?}", results);
+            for err in results.iter().filter_map(|result| result.as_ref().err()) {
+                assert!(err.starts_with("LockHeld") || err.starts_with("ReferenceMismatch"), "{}", err);
+            }
+            let repo = Repository::find_with_git_dir(dir.path(), None, true).unwrap().unwrap();
+            let winner = if results[0].is_ok() { A } else { B };
+            assert_eq!(resolve(&repo, &refname).unwrap(), winner);
+            assert_eq!(reflog::read(&repo, &refname).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn update_refuses_a_held_lock() {
+        let (_dir, repo) = test_util::repo();
+        update(&repo, "refs/heads/main", A, None, "create").unwrap();
+        let lock = Lockfile::acquire(&repo.gitdir().join("refs/heads/main")).unwrap();
+        let err = update(&repo, "refs/heads/main", B, Some(A), "blocked").unwrap_err();
+        assert!(matches!(*err, WitError::LockHeld(_)));
+        drop(lock);
+        update(&repo, "refs/heads/main", B, Some(A), "free").unwrap();
+    }
+
+    #[test]
+    fn packed_refs_count_as_the_current_value() {
+        let (_dir, repo) = test_util::repo();
+        fs::write(repo.gitdir().join("packed-refs"), format!("# pack-refs with: peeled fully-peeled sorted\n{} refs/tags/v1\n^{}\n", A, B)).unwrap();
+        assert!(exists(&repo, "refs/tags/v1"));
+        assert_eq!(resolve(&repo, "refs/tags/v1").unwrap(), A);
+        assert!(update(&repo, "refs/tags/v1", B, None, "clobber").is_err());
+        update(&repo, "refs/tags/v1", B, Some(A), "move").unwrap();
+        assert_eq!(resolve(&repo, "refs/tags/v1").unwrap(), B);
+    }
+
+    #[test]
+    fn head_follows_its_branch() {
+        let (_dir, repo) = test_util::repo();
+        assert!(matches!(*resolve(&repo, "HEAD").unwrap_err(), WitError::UnbornBranch(_)));
+        update_head(&repo, A, "first").unwrap();
+        assert_eq!(head_target(&repo).as_deref(), Some("refs/heads/main"));
+        assert_eq!(resolve(&repo, "refs/heads/main").unwrap(), A);
+        assert_eq!(reflog::read(&repo, "HEAD").unwrap().len(), 1);
+
+        detach_head(&repo, B, "detach").unwrap();
+        assert_eq!(head_target(&repo), None);
+        assert_eq!(resolve(&repo, "HEAD").unwrap(), B);
+        assert_eq!(resolve(&repo, "refs/heads/main").unwrap(), A);
+    }
+
+    #[test]
+    fn symbolic_refs_are_followed() {
+        let (_dir, repo) = test_util::repo();
+        let write = |name: &str, data: &str| {
+            let path = repo.gitdir().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            Repository::write_file(&repo, &path, data.as_bytes()).unwrap();
+        };
+        update(&repo, "refs/heads/main", A, None, "create").unwrap();
+        write("refs/remotes/origin/HEAD", "ref: refs/remotes/origin/trunk\r\n");
+        write("refs/remotes/origin/trunk", &format!("{}\n", B.to_uppercase()));
+        write("refs/alias", "ref: refs/remotes/origin/HEAD\n");
+
+        let (chain, sha) = resolve_symbolic(&repo, "refs/alias").unwrap();
+        assert_eq!(chain, vec!["refs/alias", "refs/remotes/origin/HEAD", "refs/remotes/origin/trunk"]);
+        assert_eq!(sha.as_deref(), Some(B));
+        assert_eq!(resolve(&repo, "HEAD").unwrap(), A);
+        assert!(matches!(*resolve(&repo, "refs/heads/missing").unwrap_err(), WitError::UnknownReference(_)));
+
+        // A dangling symref is an unborn branch, and a loop is given up on
+        write("refs/unborn", "ref: refs/heads/later\n");
+        assert!(matches!(*resolve(&repo, "refs/unborn").unwrap_err(), WitError::UnbornBranch(ref name) if name == "refs/heads/later"));
+        write("refs/loop", "ref: refs/loop\n");
+        assert!(matches!(*resolve(&repo, "refs/loop").unwrap_err(), WitError::UnknownReference(_)));
+    }
+
+    #[test]
+    fn head_can_be_detached_and_attached() {
+        let (_dir, repo) = test_util::repo();
+        update(&repo, "refs/heads/main", A, None, "create").unwrap();
+        detach_head(&repo, B, "detach").unwrap();
+        assert_eq!(fs::read_to_string(repo.gitdir().join("HEAD")).unwrap(), format!("{}\n", B));
+        assert_eq!(resolve_symbolic(&repo, "HEAD").unwrap(), (vec!["HEAD".to_owned()], Some(B.to_owned())));
+
+        // Moving a detached HEAD leaves the branches alone
+        update_head(&repo, A, "move").unwrap();
+        assert_eq!(head_target(&repo), None);
+        assert_eq!(resolve(&repo, "HEAD").unwrap(), A);
+
+        attach_head(&repo, "refs/heads/main", "attach").unwrap();
+        assert_eq!(head_target(&repo).as_deref(), Some("refs/heads/main"));
+        attach_head(&repo, "refs/heads/unborn", "attach").unwrap();
+        assert_eq!(head_target(&repo).as_deref(), Some("refs/heads/unborn"));
+        assert!(!repo.gitdir().join("HEAD.lock").exists());
+
+        // HEAD is locked like any other ref
+        let lock = Lockfile::acquire(&repo.gitdir().join("HEAD")).unwrap();
+        assert!(matches!(*detach_head(&repo, A, "blocked").unwrap_err(), WitError::LockHeld(_)));
+        assert!(matches!(*attach_head(&repo, "refs/heads/main", "blocked").unwrap_err(), WitError::LockHeld(_)));
+        drop(lock);
+        assert_eq!(head_target(&repo).as_deref(), Some("refs/heads/unborn"));
+        let log = reflog::read(&repo, "HEAD").unwrap();
+        assert_eq!(log.iter().map(|entry| entry.message.as_str()).collect::<Vec<&str>>(), vec!["create", "detach", "move", "attach"]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+use crate::{
+    error::{WitError, builder::*},
+    reference,
+    repository::Repository,
+    signature::Signature
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old_sha: String,
+    pub new_sha: String,
+    pub committer: Signature,
+    pub message: String,
+}
+
+// Like git's default core.logAllRefUpdates, only branches, remotes, notes and
+// HEAD get a log unless one was already started
+fn should_log(repo: &Repository, refname: &str) -> bool {
+    refname == "HEAD"
+        || ["refs/heads/", "refs/remotes/", "refs/notes/"].iter().any(|prefix| refname.starts_with(prefix))
+        || Repository::path(repo, vec!["logs", refname]).is_file()
+}
+
+// Records that refname moved from old (None for a new ref) to new
+pub fn append(repo: &Repository, refname: &str, old: Option<&str>, new: &str, message: &str) -> Result<(), Box<WitError>> {
+    if !should_log(repo, refname) {
+        return Ok(());
+    }
+    let zero = "0".repeat(repo.object_format().hex_len());
+    let committer = Signature::from_config(repo).unwrap_or_else(|_| Signature::now("unknown", "unknown"));
+    // Each entry is one line
+    let message = message.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).collect::<Vec<&str>>().join(" ");
+
+    let mut paths = vec!["logs"];
+    paths.extend(refname.split('/'));
+    let path = Repository::file(repo, paths, true)?;
+    let existed = path.exists();
+    let mut log = OpenOptions::new().create(true).append(true).open(&path)?;
+    log.write_all(format!("{} {} {}\t{}\n", old.unwrap_or(&zero), new, committer, message).as_bytes())?;
+    if !existed {
+        Repository::adjust_shared_perm(repo, &path)?;
+    }
+    Ok(())
+}
+
+// Every entry in refname's log, oldest first. A ref without a log has none.
+pub fn read(repo: &Repository, refname: &str) -> Result<Vec<ReflogEntry>, Box<WitError>> {
+    let data = match fs::read_to_string(Repository::path(repo, vec!["logs", refname])) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => Err(e)?
+    };
+
+    let mut entries = Vec::new();
+    for line in data.lines().filter(|line| !line.is_empty()) {
+        let bad_entry = || malformed_object_err(format!("Malformed reflog entry {}", line));
+        // The message may contain tabs, but the signature never does
+        let (meta, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = meta.splitn(3, ' ');
+        let old_sha = parts.next().ok_or_else(bad_entry)?.to_owned();
+        let new_sha = parts.next().ok_or_else(bad_entry)?.to_owned();
+        let committer = Signature::parse(parts.next().ok_or_else(bad_entry)?)?;
+        entries.push(ReflogEntry {
+            old_sha,
+            new_sha,
+            committer,
+            message: message.to_owned()
+        });
+    }
+    Ok(entries)
+}
+
+// Where refname pointed n moves ago, as in refname@{n}
+pub fn nth(repo: &Repository, refname: &str, n: usize) -> Result<String, Box<WitError>> {
+    let entries = read(repo, refname)?;
+    if entries.is_empty() && n == 0 {
+        return reference::resolve(repo, refname);
+    }
+    match entries.len().checked_sub(n + 1) {
+        Some(i) => Ok(entries[i].new_sha.clone()),
+        None => Err(unknown_reference_err(format!("Log for {} only has {} entries", refname, entries.len())))
+    }
+}
@@ -1,86 +1,378 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
 use std::path::{PathBuf, Path};
 use std::fs;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::SystemTime;
 
-use ini::configparser::ini::Ini;
+use crate::cache::{self, ObjectCache, PackCache};
+use crate::config::{parse_bool, Config, ConfigGet, ConfigSet};
 use crate::error::{builder::*, WitError};
+use crate::object_id::{ObjectFormat, ObjectId};
+use crate::pack::PackIndex;
 
-#[derive(Debug, Clone)]
-pub struct Repository {
-    pub worktree: PathBuf,
-    pub git_dir: PathBuf,
-    pub conf: Ini
+// core.sharedRepository
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedMode {
+    // Permissions are left to the umask
+    Umask,
+    // Readable and writable by the group
+    Group,
+    // Like group, and readable by everyone
+    All,
+    // An explicit octal mode for files, e.g. 0640
+    Mask(u32),
 }
 
-impl Repository {
-    pub fn new(path: &str, force: bool) -> Result<Repository, Box<WitError>> {
-        let git_dir = Path::new(path).join(".git");
-        let mut config = Ini::new();
-        let config_path = git_dir.join("config");
+impl SharedMode {
+    pub fn parse(value: &str) -> Result<SharedMode, Box<WitError>> {
+        match value.to_lowercase().as_str() {
+            "" | "umask" | "false" | "no" | "off" | "0" => Ok(SharedMode::Umask),
+            "group" | "true" | "yes" | "on" | "1" => Ok(SharedMode::Group),
+            "all" | "world" | "everybody" | "2" => Ok(SharedMode::All),
+            octal => match u32::from_str_radix(octal, 8) {
+                Ok(mask) if mask <= 0o777 => {
+                    // The owner must always be able to read and write
+                    if mask & 0o600 != 0o600 {
+                        return Err(repo_creation_err(format!("Problem with core.sharedRepository filemode value {}", value)));
+                    }
+                    Ok(SharedMode::Mask(mask))
+                },
+                _ => Err(repo_creation_err(format!("Invalid core.sharedRepository value {}", value)))
+            }
+        }
+    }
 
-        if !(force || git_dir.is_dir()) {
-            Err(repo_creation_err(format!("{} is not a git repository", path)))?
+    pub fn config_value(&self) -> String {
+        match self {
+            SharedMode::Umask => "false".to_owned(),
+            SharedMode::Group => "group".to_owned(),
+            SharedMode::All => "all".to_owned(),
+            SharedMode::Mask(mask) => format!("{:04o}", mask)
         }
+    }
 
-        if config_path.exists() && config_path.is_file() {
-            config.load(match config_path.to_str() {
-                Some(string) => string,
-                None => Err(repo_creation_err(format!("Could not load config from {:?}", config_path)))?
-            }).unwrap();
-        } else if !force {
-            Err(repo_creation_err(format!("Could not create repository in {}", path)))?
+    // The permissions a new file or directory with `mode` should end up with,
+    // following git's calc_shared_perm
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let mut tweak = match self {
+            SharedMode::Umask => return mode,
+            SharedMode::Group => 0o660,
+            SharedMode::All => 0o664,
+            SharedMode::Mask(mask) => *mask
+        };
+        if mode & 0o200 == 0 {
+            tweak &= !0o222;
+        }
+        if mode & 0o100 != 0 {
+            // Copy read bits to execute bits
+            tweak |= (tweak & 0o444) >> 2;
         }
+        let mut mode = match self {
+            SharedMode::Mask(_) => (mode & !0o777) | tweak,
+            _ => mode | tweak
+        };
+        if is_dir {
+            // New files inherit the directory's group
+            mode |= 0o2000;
+        }
+        mode
+    }
+}
+
+// How a hook run went
+#[derive(Debug, Clone)]
+pub struct HookResult {
+    // None if the hook was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl HookResult {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Repository {
+    // None for bare repositories
+    worktree: Option<PathBuf>,
+    git_dir: PathBuf,
+    // Where objects, refs and config live. The same as git_dir except in a
+    // linked worktree, whose git_dir only holds HEAD, the index and the like.
+    common_dir: PathBuf,
+    // The repository's own .git/config
+    pub conf: Config,
+    // Commits from .git/shallow, whose parents were never fetched
+    shallow: HashSet<String>,
+    // Object bodies read or written lately, see cache.rs
+    objects: RefCell<ObjectCache>,
+    // The pack indexes, read once rather than on every lookup
+    packs: RefCell<PackCache>
+}
+
+impl Repository {
+    fn load_config(config_path: &Path, check_version: bool) -> Result<Config, Box<WitError>> {
+        let config = if config_path.is_file() {
+            Config::read(config_path)?
+        } else {
+            Config::new()
+        };
 
-        if !force {
+        if check_version {
             let version = config
-                .get("core", "repositoryformatversion")
+                .get_int("core.repositoryformatversion")?
                 .ok_or(
-                    version_mismatch_err(format!("Could not read repository format version from config."))
-                )?.parse::<i32>()?;
-            if version != 0 {
+                    version_mismatch_err("Could not read repository format version from config.".to_owned())
+                )?;
+            // Version 1 is version 0 plus the extensions section
+            if version != 0 && version != 1 {
                 Err(version_mismatch_err(format!("Unsupported repositoryformatversion {}", version)))?
             }
+            if let Some(format) = config.get_str("extensions.objectformat") {
+                ObjectFormat::parse(&format)?;
+            }
         }
+        Ok(config)
+    }
+
+    // Opens the repository whose git directory is git_dir. The worktree is
+    // dropped if the config says the repository is bare.
+    pub fn open(git_dir: &Path, worktree: Option<&Path>) -> Result<Repository, Box<WitError>> {
+        if !Self::is_git_dir(git_dir) {
+            Err(repo_not_found_err(format!("{} is not a git directory", git_dir.display())))?
+        }
+        let git_dir = fs::canonicalize(git_dir)?;
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => fs::canonicalize(git_dir.join(common_dir.trim_end()))?,
+            Err(_) => git_dir.clone()
+        };
+        let config = Self::load_config(&common_dir.join("config"), true)?;
+        // A linked worktree is never bare, whatever the main repository is
+        let bare = common_dir == git_dir && config.get_bool("core.bare")?.unwrap_or(false);
+
+        let shallow = fs::read_to_string(common_dir.join("shallow"))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim().to_owned())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let limit = match config.get_int("core.objectcachelimit")? {
+            Some(limit) => usize::try_from(limit).map_err(|_| malformed_object_err(format!("Invalid core.objectCacheLimit {}", limit)))?,
+            None => cache::DEFAULT_LIMIT
+        };
+
         Ok(Repository {
-            worktree: PathBuf::from(path),
-            git_dir: git_dir,
-            conf: config
+            worktree: if bare { None } else { worktree.map(Path::to_path_buf) },
+            git_dir,
+            common_dir,
+            conf: config,
+            shallow,
+            objects: RefCell::new(ObjectCache::new(limit)),
+            packs: RefCell::new(PackCache::default())
         })
     }
 
-    pub fn find(path: &str, required: bool) -> Result<Option<Repository>, Box<WitError>> {
-        let path = fs::canonicalize(path)?;
+    // The repository start is in, found the way git finds it: GIT_DIR names
+    // it outright, and otherwise start and then each of its parents are tried
+    pub fn discover(start: &Path) -> Result<Repository, Box<WitError>> {
+        Self::find(start, true)?.ok_or(repo_not_found_err(format!("No git directory in {}", start.display())))
+    }
+
+    // Like discover, but with required unset a missing repository is None
+    pub fn find(start: &Path, required: bool) -> Result<Option<Repository>, Box<WitError>> {
+        let git_dir = env::var_os("GIT_DIR").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+        Self::find_with_git_dir(start, git_dir.as_deref(), required)
+    }
 
-        if path.join(".git").is_dir() {
-            return Ok(
-                Some(
-                    Repository::new(
-                        path
-                            .to_str()
-                            .ok_or(path_conversion_err())?,
-                        false
-                    )?
-                )
-            )
+    // Like find, but an explicit git_dir (as from --git-dir or GIT_DIR) skips
+    // the search and makes start the worktree. The search never goes up into
+    // a directory in GIT_CEILING_DIRECTORIES, nor onto another filesystem
+    // unless GIT_DISCOVERY_ACROSS_FILESYSTEM is set.
+    pub fn find_with_git_dir(start: &Path, git_dir: Option<&Path>, required: bool) -> Result<Option<Repository>, Box<WitError>> {
+        let start = fs::canonicalize(start)?;
+        if let Some(git_dir) = git_dir {
+            return Ok(Some(Self::open(&start.join(git_dir), Some(&start))?));
         }
 
-        let parent = fs::canonicalize(path.join(".."))?;
+        let ceilings = env::var_os("GIT_CEILING_DIRECTORIES")
+            .map(|dirs| env::split_paths(&dirs)
+                .filter(|dir| dir.is_absolute())
+                .map(|dir| fs::canonicalize(&dir).unwrap_or(dir))
+                .collect::<Vec<PathBuf>>())
+            .unwrap_or_default();
+        let across_filesystems = env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM").ok()
+            .map(|value| parse_bool(&value))
+            .transpose()?
+            .unwrap_or(false);
+        let device = Self::device(&start);
 
-        if parent == path {
-            return if required {
-                Err(repo_not_found_err(format!("No git directory in {:?}", path)))?
-            } else {
-                Ok(None)
+        for dir in start.ancestors() {
+            if dir != start {
+                if ceilings.iter().any(|ceiling| ceiling == dir) {
+                    break;
+                }
+                if !across_filesystems && Self::device(dir) != device {
+                    break;
+                }
+            }
+            let dot_git = dir.join(".git");
+            if dot_git.is_dir() {
+                return Ok(Some(Self::open(&dot_git, Some(dir))?));
+            }
+            if dot_git.is_file() {
+                // Linked worktrees and submodules point at their git directory
+                return Ok(Some(Self::open(&Self::read_gitdir_file(&dot_git)?, Some(dir))?));
+            }
+            if Self::is_git_dir(dir) {
+                return Ok(Some(Self::open(dir, None)?));
             }
         }
 
-        Self::find(
-            parent.to_str().ok_or(path_conversion_err())?,
-            required
+        if required {
+            Err(repo_not_found_err(format!("No git directory in {:?} or any of its parents", start)))?
+        }
+        Ok(None)
+    }
+
+    #[cfg(unix)]
+    fn device(path: &Path) -> Option<u64> {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).ok().map(|metadata| metadata.dev())
+    }
+
+    #[cfg(not(unix))]
+    fn device(_path: &Path) -> Option<u64> {
+        None
+    }
+
+    // Reads a "gitdir: <path>" file; relative paths are relative to the file
+    fn read_gitdir_file(path: &Path) -> Result<PathBuf, Box<WitError>> {
+        let contents = fs::read_to_string(path)?;
+        let target = contents.trim_end().strip_prefix("gitdir: ").ok_or(
+            repo_not_found_err(format!("Invalid gitfile format: {}", path.display()))
+        )?;
+        let base = path.parent().ok_or(path_conversion_err())?;
+        Ok(base.join(target))
+    }
+
+    fn is_git_dir(path: &Path) -> bool {
+        path.join("HEAD").is_file()
+            && (path.join("commondir").is_file() || (path.join("objects").is_dir() && path.join("refs").is_dir()))
+    }
+
+    pub fn gitdir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub fn commondir(&self) -> &Path {
+        &self.common_dir
+    }
+
+    // Whether history is cut off at sha, so its parents must not be followed
+    pub fn is_shallow(&self, sha: &str) -> bool {
+        self.shallow.contains(sha)
+    }
+
+    // Files that belong to one worktree rather than the whole repository
+    fn is_per_worktree(paths: &[&str]) -> bool {
+        match paths {
+            [] => true,
+            ["refs", "bisect" | "worktree" | "rewritten", ..] => true,
+            ["logs", "HEAD"] => true,
+            ["index" | "rebase-merge" | "rebase-apply" | "sequencer", ..] => true,
+            [first, ..] => first.ends_with("HEAD") || first.starts_with("BISECT_")
+        }
+    }
+
+    // The type and body of sha, if it was read or written recently
+    pub fn cached_object(&self, sha: &ObjectId) -> Option<(String, Rc<[u8]>)> {
+        self.objects.borrow_mut().get(sha)
+    }
+
+    pub fn cache_object(&self, sha: &ObjectId, fmt: &str, data: Rc<[u8]>) {
+        self.objects.borrow_mut().insert(*sha, fmt, data);
+    }
+
+    // The pack indexes, if they were read while the pack directory had this mtime
+    pub fn cached_packs(&self, mtime: Option<SystemTime>) -> Option<Rc<Vec<PackIndex>>> {
+        self.packs.borrow().get(mtime)
+    }
+
+    pub fn cache_packs(&self, mtime: Option<SystemTime>, indexes: Rc<Vec<PackIndex>>) {
+        self.packs.borrow_mut().set(mtime, indexes);
+    }
+
+    // For after writing or deleting a pack, in case the mtime didn't tick over
+    pub fn forget_packs(&self) {
+        self.packs.borrow_mut().clear();
+    }
+
+    // The hash objects are named by, SHA-1 unless extensions.objectformat says otherwise
+    pub fn object_format(&self) -> ObjectFormat {
+        self.conf.get_str("extensions.objectformat")
+            .and_then(|format| ObjectFormat::parse(&format).ok())
+            .unwrap_or_default()
+    }
+
+    // The user's global config with the repository's config layered over it
+    pub fn config(&self) -> Result<ConfigSet, Box<WitError>> {
+        let mut config = ConfigSet::user()?;
+        config.push(self.conf.clone());
+        Ok(config)
+    }
+
+    pub fn worktree(&self) -> Result<&Path, Box<WitError>> {
+        self.worktree.as_deref().ok_or(
+            repo_not_found_err(format!("{} has no worktree; this operation must be run in a work tree", self.git_dir.display()))
         )
     }
 
+    // Runs .git/hooks/<name> with args from the top of the worktree, or the
+    // git directory if the repository is bare. A missing or non-executable
+    // hook is skipped, so there is no result.
+    pub fn run_hook(&self, name: &str, args: &[&str]) -> Result<Option<HookResult>, Box<WitError>> {
+        let hook = Self::path(self, vec!["hooks", name]);
+        let Ok(metadata) = fs::metadata(&hook) else {
+            return Ok(None);
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                return Ok(None);
+            }
+        }
+        #[cfg(not(unix))]
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+
+        // Hooks that run git commands should see this repository, whatever
+        // their working directory
+        let git_dir = fs::canonicalize(&self.git_dir)?;
+        let output = Command::new(&hook)
+            .args(args)
+            .current_dir(self.worktree.as_deref().unwrap_or(&self.git_dir))
+            .env("GIT_DIR", &git_dir)
+            .env("GIT_INDEX_FILE", git_dir.join("index"))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|err| other_err(format!("Cannot run the {} hook: {}", name, err)))?;
+        Ok(Some(HookResult { code: output.status.code(), stdout: output.stdout, stderr: output.stderr }))
+    }
+
     pub fn path(base: &Repository, paths: Vec<&str>) -> PathBuf {
-        let mut result = base.git_dir.clone();
+        let parts = paths.iter().flat_map(|path| path.split('/')).collect::<Vec<&str>>();
+        let mut result = if Self::is_per_worktree(&parts) {
+            base.git_dir.clone()
+        } else {
+            base.common_dir.clone()
+        };
         for fragment in paths {
             result = result.join(fragment);
         }
@@ -88,7 +380,7 @@ impl Repository {
     }
 
     pub fn file(repo: &Repository, paths: Vec<&str>, mkdir: bool) -> Result<PathBuf, Box<WitError>> {
-        let dirs = if paths.len() > 0 {
+        let dirs = if !paths.is_empty() {
             paths[0..paths.len()-1].to_vec()
         } else {
             Vec::new()
@@ -106,66 +398,292 @@ impl Repository {
             if path.is_dir() {
                 Ok(path)
             } else  {
-                Err(io_err(format!("{:?} is not a directory.", path)))
+                Err(other_err(format!("{:?} is not a directory.", path)))
             }
         } else if mkdir {
-            return match fs::create_dir_all(&path) {
-                Ok(_) => Ok(path),
-                Err(err) => Err(Box::<WitError>::from(err))
-            }
+            Self::create_dir(repo, &path)?;
+            Ok(path)
         } else {
-            return Err(io_err(format!("Failed to create {:?}", path)))
+            Err(other_err(format!("Failed to create {:?}", path)))
         }
     }
 
-    pub fn create(path: &str) -> Result<Self, Box<WitError>> {
-        let repo = Self::new(path, true)?;
+    pub fn shared(repo: &Repository) -> SharedMode {
+        repo.conf
+            .get("core.sharedrepository")
+            .and_then(|value| SharedMode::parse(value).ok())
+            .unwrap_or(SharedMode::Umask)
+    }
 
-        if repo.worktree.exists() {
-            if !repo.worktree.is_dir() {
-                Err(repo_creation_err(format!("{} is not a directory.", path)))?
+    // Creates path and any missing parents with the repository's shared permissions
+    pub fn create_dir(repo: &Repository, path: &Path) -> Result<(), Box<WitError>> {
+        let mut missing = Vec::new();
+        let mut current = Some(path);
+        while let Some(dir) = current {
+            if dir.as_os_str().is_empty() || dir.is_dir() {
+                break;
             }
-            if !repo.worktree.read_dir().map(|mut i| i.next().is_none()).unwrap_or(false) {
-                Err(repo_creation_err(format!("Directory {} is not empty.", path)))?
+            missing.push(dir);
+            current = dir.parent();
+        }
+
+        for dir in missing.into_iter().rev() {
+            if let Err(err) = fs::create_dir(dir) {
+                // Another process may have created it in the meantime
+                if err.kind() != std::io::ErrorKind::AlreadyExists {
+                    Err(Box::<WitError>::from(err))?
+                }
             }
-        } else {
-            if let Err(e) = fs::create_dir_all(&repo.worktree) {
-                Err(Box::<WitError>::from(e))?
+            Self::adjust_shared_perm(repo, dir)?;
+        }
+        Ok(())
+    }
+
+    // Writes a file inside the git directory with the repository's shared permissions
+    pub fn write_file(repo: &Repository, path: &Path, data: &[u8]) -> Result<(), Box<WitError>> {
+        fs::write(path, data)?;
+        Self::adjust_shared_perm(repo, path)
+    }
+
+    #[cfg(unix)]
+    pub fn adjust_shared_perm(repo: &Repository, path: &Path) -> Result<(), Box<WitError>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let shared = Self::shared(repo);
+        if shared == SharedMode::Umask {
+            return Ok(());
+        }
+        let metadata = fs::metadata(path)?;
+        let mode = metadata.permissions().mode();
+        let new_mode = shared.apply(mode, metadata.is_dir());
+        if new_mode != mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(new_mode))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn adjust_shared_perm(_repo: &Repository, _path: &Path) -> Result<(), Box<WitError>> {
+        Ok(())
+    }
+
+    pub fn create(path: &Path, bare: bool, default_branch: &str) -> Result<Self, Box<WitError>> {
+        Self::create_shared(path, bare, default_branch, SharedMode::Umask)
+    }
+
+    // Lays out a new repository at path, or directly in path when bare
+    pub fn create_shared(path: &Path, bare: bool, default_branch: &str, shared: SharedMode) -> Result<Self, Box<WitError>> {
+        if default_branch.is_empty() || default_branch.contains(char::is_whitespace) {
+            Err(repo_creation_err(format!("Invalid branch name {:?}", default_branch)))?
+        }
+        if path.exists() && !path.is_dir() {
+            Err(repo_creation_err(format!("{} is not a directory.", path.display())))?
+        }
+
+        let git_dir = if bare { path.to_path_buf() } else { path.join(".git") };
+        if git_dir.exists() {
+            if !git_dir.is_dir() {
+                Err(repo_creation_err(format!("{} is not a directory.", git_dir.display())))?
+            }
+            // An empty directory is fine, anything else may be an existing repository
+            if git_dir.read_dir()?.next().is_some() {
+                Err(repo_creation_err(format!("{} already exists and is not empty.", git_dir.display())))?
             }
         }
 
+        let repo = Repository {
+            worktree: if bare { None } else { Some(path.to_path_buf()) },
+            common_dir: git_dir.clone(),
+            git_dir,
+            conf: Self::default_config(bare, shared)?,
+            shallow: HashSet::new(),
+            objects: RefCell::new(ObjectCache::new(cache::DEFAULT_LIMIT)),
+            packs: RefCell::new(PackCache::default())
+        };
+
+        let git_dir = repo.git_dir.clone();
+        Self::create_dir(&repo, &git_dir)?;
         Self::dir(&repo, vec!["branches"], true)?;
         Self::dir(&repo, vec!["objects"], true)?;
         Self::dir(&repo, vec!["refs", "tags"], true)?;
         Self::dir(&repo, vec!["refs", "heads"], true)?;
 
         // .git/description
-        if let Err(err) = fs::write(Self::file(&repo, vec!["description"], true)?, "Unnamed repository; edit this file 'description' to name the repository.\n") {
-            Err(Box::<WitError>::from(err))?
-        }
+        Self::write_file(
+            &repo,
+            &Self::file(&repo, vec!["description"], true)?,
+            b"Unnamed repository; edit this file 'description' to name the repository.\n"
+        )?;
 
         // .git/HEAD
-        if let Err(err) = fs::write(Self::file(&repo, vec!["HEAD"], true)?, "ref: refs/heads/main\n") {
-            Err(Box::<WitError>::from(err))?
-        }
+        Self::write_file(
+            &repo,
+            &Self::file(&repo, vec!["HEAD"], true)?,
+            format!("ref: refs/heads/{}\n", default_branch).as_bytes()
+        )?;
 
         // .git/config
-        if let Err(err) = Self::default_config().write(
-            Self::file(&repo, vec!["config"], true)?
-                .to_str()
-                .ok_or(repo_creation_err(format!("Error opening config file.")))?
-        ) {
-            Err(Box::<WitError>::from(err))?
-        }
+        Self::write_file(&repo, &Self::file(&repo, vec!["config"], true)?, repo.conf.serialize().as_bytes())?;
 
         Ok(repo)
     }
 
-    fn default_config() -> Ini {
-        let mut config = Ini::new();
-        config.set("core", "repositoryformatversion", Some(String::from("0")));
-        config.set("core", "filemode", Some(String::from("false")));
-        config.set("core", "bare", Some(String::from("false")));
-        config
+    fn default_config(bare: bool, shared: SharedMode) -> Result<Config, Box<WitError>> {
+        let mut config = Config::new();
+        config.set("core.repositoryformatversion", "0")?;
+        config.set("core.filemode", &cfg!(unix).to_string())?;
+        config.set("core.bare", &bare.to_string())?;
+        if shared != SharedMode::Umask {
+            config.set("core.sharedrepository", &shared.config_value())?;
+        }
+        Ok(config)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{object, test_util::{self, TempDir}};
+
+    #[test]
+    fn shared_mode_values() {
+        assert_eq!(SharedMode::parse("false").unwrap(), SharedMode::Umask);
+        assert_eq!(SharedMode::parse("true").unwrap(), SharedMode::Group);
+        assert_eq!(SharedMode::parse("World").unwrap(), SharedMode::All);
+        assert_eq!(SharedMode::parse("0640").unwrap(), SharedMode::Mask(0o640));
+        assert!(SharedMode::parse("0440").is_err());
+        assert!(SharedMode::parse("sometimes").is_err());
+        assert_eq!(SharedMode::parse(&SharedMode::Mask(0o600).config_value()).unwrap(), SharedMode::Mask(0o600));
+    }
+
+    #[test]
+    fn shared_mode_permissions() {
+        assert_eq!(SharedMode::Umask.apply(0o644, false), 0o644);
+        assert_eq!(SharedMode::Group.apply(0o644, false), 0o664);
+        assert_eq!(SharedMode::Group.apply(0o755, true), 0o2775);
+        assert_eq!(SharedMode::All.apply(0o600, false), 0o664);
+        // Read-only files stay read-only, executables stay executable
+        assert_eq!(SharedMode::Group.apply(0o444, false), 0o444);
+        assert_eq!(SharedMode::Group.apply(0o700, false), 0o770);
+        assert_eq!(SharedMode::Mask(0o640).apply(0o644, false), 0o640);
+        assert_eq!(SharedMode::Mask(0o640).apply(0o755, true), 0o2750);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_repository_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let repo = Repository::create_shared(dir.path(), false, "main", SharedMode::Group).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(Repository::shared(&repo), SharedMode::Group);
+        assert_eq!(mode(&repo.gitdir().join("refs/heads")) & 0o2070, 0o2070);
+        assert_eq!(mode(&repo.gitdir().join("HEAD")) & 0o060, 0o060);
+
+        let sha = object::write_raw(&repo, "blob", b"shared\n").unwrap();
+        let path = object::loose_path(&repo, &sha).unwrap();
+        assert_eq!(mode(path.parent().unwrap()) & 0o2070, 0o2070);
+        assert_eq!(mode(&path) & 0o040, 0o040);
+
+        // The setting survives reopening
+        let reopened = Repository::open(repo.gitdir(), Some(dir.path())).unwrap();
+        assert_eq!(Repository::shared(&reopened), SharedMode::Group);
+        let (_other, plain) = test_util::repo();
+        assert_eq!(Repository::shared(&plain), SharedMode::Umask);
+    }
+
+    #[test]
+    fn create_lays_out_a_repository() {
+        let dir = TempDir::new();
+        let repo = Repository::create(dir.path(), false, "trunk").unwrap();
+        let git_dir = dir.path().join(".git");
+        assert_eq!(repo.gitdir(), git_dir);
+        assert_eq!(repo.worktree().unwrap(), dir.path());
+        for sub in ["objects", "refs/heads", "refs/tags", "branches"] {
+            assert!(git_dir.join(sub).is_dir(), "{} is missing", sub);
+        }
+        assert!(git_dir.join("description").is_file());
+        assert_eq!(fs::read_to_string(git_dir.join("HEAD")).unwrap(), "ref: refs/heads/trunk\n");
+        assert_eq!(
+            fs::read_to_string(git_dir.join("config")).unwrap(),
+            format!("[core]\n\trepositoryformatversion = 0\n\tfilemode = {}\n\tbare = false\n", cfg!(unix))
+        );
+    }
+
+    #[test]
+    fn create_bare() {
+        let dir = TempDir::new();
+        let repo = Repository::create(dir.path(), true, "main").unwrap();
+        assert_eq!(repo.gitdir(), dir.path());
+        assert!(repo.worktree().is_err());
+        assert!(dir.path().join("refs/heads").is_dir());
+        assert!(!dir.path().join(".git").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("HEAD")).unwrap(), "ref: refs/heads/main\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config")).unwrap(),
+            format!("[core]\n\trepositoryformatversion = 0\n\tfilemode = {}\n\tbare = true\n", cfg!(unix))
+        );
+    }
+
+    #[test]
+    fn create_refuses_existing_repositories() {
+        let (dir, _repo) = test_util::repo();
+        assert!(Repository::create(dir.path(), false, "main").is_err());
+        assert!(Repository::create(dir.path(), false, "has space").is_err());
+
+        // An empty .git is fine
+        let dir = TempDir::new();
+        fs::create_dir(dir.path().join(".git")).unwrap();
+        assert!(Repository::create(dir.path(), false, "main").is_ok());
+
+        let dir = TempDir::new();
+        fs::write(dir.path().join("file"), b"").unwrap();
+        assert!(Repository::create(&dir.path().join("file"), false, "main").is_err());
+    }
+
+    #[test]
+    fn find_walks_up_from_subdirectories() {
+        let (dir, _repo) = test_util::repo();
+        let nested = dir.path().join("a/b/c");
+        fs::create_dir_all(&nested).unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+
+        let found = Repository::find_with_git_dir(&nested, None, true).unwrap().unwrap();
+        assert_eq!(found.worktree().unwrap(), root);
+        assert_eq!(found.gitdir(), root.join(".git"));
+
+        // From inside the git directory there is no worktree
+        let found = Repository::find_with_git_dir(&root.join(".git/refs"), None, true).unwrap().unwrap();
+        assert_eq!(found.gitdir(), root.join(".git"));
+        assert!(found.worktree().is_err());
+    }
+
+    #[test]
+    fn find_follows_gitdir_files() {
+        let dir = TempDir::new();
+        Repository::create(&dir.path().join("store"), true, "main").unwrap();
+        let work = dir.path().join("work");
+        fs::create_dir_all(work.join("sub")).unwrap();
+        fs::write(work.join(".git"), "gitdir: ../store\n").unwrap();
+
+        let found = Repository::find_with_git_dir(&work.join("sub"), None, true).unwrap().unwrap();
+        assert_eq!(found.gitdir(), fs::canonicalize(dir.path().join("store")).unwrap());
+
+        fs::write(work.join(".git"), "not a gitfile\n").unwrap();
+        assert!(Repository::find_with_git_dir(&work, None, true).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn find_with_explicit_git_dir() {
+        let dir = TempDir::new();
+        Repository::create(&dir.path().join("elsewhere"), true, "main").unwrap();
+        let work = dir.path().join("work");
+        fs::create_dir(&work).unwrap();
+
+        let found = Repository::find_with_git_dir(&work, Some(Path::new("../elsewhere")), true).unwrap().unwrap();
+        assert_eq!(found.gitdir(), fs::canonicalize(dir.path().join("elsewhere")).unwrap());
+        assert!(Repository::find_with_git_dir(&work, Some(Path::new("missing")), true).is_err());
+        assert!(Repository::find_with_git_dir(&work, None, false).unwrap().is_none());
+        assert!(Repository::find_with_git_dir(&work, None, true).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    config::ConfigGet,
+    error::{WitError, builder::*},
+    repository::Repository,
+    util::{format_offset, local_offset, parse_offset}
+};
+
+// An author, committer or tagger line: "Name <email> timestamp +hhmm"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: i64,
+    // Minutes east of UTC
+    pub offset: i32,
+}
+
+impl Signature {
+    pub fn new(name: &str, email: &str, time: i64, offset: i32) -> Self {
+        Signature {
+            name: name.to_owned(),
+            email: email.to_owned(),
+            time,
+            offset,
+        }
+    }
+
+    pub fn now(name: &str, email: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Self::new(name, email, time, local_offset(time))
+    }
+
+    // Identity for new commits, taken from user.name and user.email
+    pub fn from_config(repo: &Repository) -> Result<Self, Box<WitError>> {
+        let config = repo.config()?;
+        let name = config.get_str("user.name").ok_or(
+            missing_data_err("user.name is not set in the config.".to_owned())
+        )?;
+        let email = config.get_str("user.email").ok_or(
+            missing_data_err("user.email is not set in the config.".to_owned())
+        )?;
+        Ok(Self::now(&name, &email))
+    }
+
+    // "Name <email>", as given to --author, dated now
+    pub fn from_ident(ident: &str) -> Result<Self, Box<WitError>> {
+        let (name, email) = ident.trim_end().strip_suffix('>').and_then(|rest| rest.rsplit_once('<')).ok_or(
+            malformed_object_err(format!("Identity {} is not of the form 'Name <email>'", ident))
+        )?;
+        Ok(Self::now(name.trim(), email))
+    }
+
+    pub fn parse(raw: &str) -> Result<Self, Box<WitError>> {
+        let bad_signature = || malformed_object_err(format!("Malformed signature {}", raw));
+        let email_start = raw.find('<').ok_or_else(bad_signature)?;
+        let email_end = raw.rfind('>').ok_or_else(bad_signature)?;
+        if email_end < email_start {
+            return Err(bad_signature());
+        }
+
+        let mut date = raw[email_end + 1..].split_whitespace();
+        let time = date.next().ok_or_else(bad_signature)?.parse::<i64>()?;
+        let offset = parse_offset(date.next().ok_or_else(bad_signature)?)?;
+
+        Ok(Self::new(
+            raw[..email_start].trim(),
+            &raw[email_start + 1..email_end],
+            time,
+            offset
+        ))
+    }
+
+    // "Name <email>", without the date
+    pub fn ident(&self) -> String {
+        format!("{} <{}>", self.name, self.email)
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.ident(), self.time, format_offset(self.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        let raw = "A U Thor <author@example.com> 1112911993 -0700";
+        let signature = Signature::parse(raw).unwrap();
+        assert_eq!(signature, Signature::new("A U Thor", "author@example.com", 1112911993, -420));
+        assert_eq!(signature.to_string(), raw);
+        assert!(Signature::parse("A U Thor <author@example.com> 1112911993 0700").is_err());
+    }
+
+    #[test]
+    fn now_uses_the_local_offset() {
+        let signature = Signature::now("A U Thor", "author@example.com");
+        let date = std::process::Command::new("date").arg("-d").arg(format!("@{}", signature.time)).arg("+%z").output().unwrap();
+        assert_eq!(format_offset(signature.offset), String::from_utf8(date.stdout).unwrap().trim());
+    }
+}
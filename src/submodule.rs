@@ -0,0 +1,81 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config::{Config, ConfigGet},
+    error::{WitError, builder::*},
+    object::{self, WitObject},
+    object_id::ObjectId,
+    reference,
+    repository::Repository
+};
+
+// One [submodule "name"] section of .gitmodules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmoduleConfig {
+    pub name: String,
+    // Relative to the top of the superproject's worktree
+    pub path: PathBuf,
+    pub url: String,
+}
+
+// The submodules declared in the worktree's .gitmodules
+pub fn load(repo: &Repository) -> Result<Vec<SubmoduleConfig>, Box<WitError>> {
+    let path = repo.worktree()?.join(".gitmodules");
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    parse(&fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> Result<Vec<SubmoduleConfig>, Box<WitError>> {
+    let config = Config::parse(text)?;
+    let mut submodules = Vec::new();
+    for name in config.subsections("submodule") {
+        // Sections without both a path and a url are ignored, as git does
+        let path = config.get_str(&format!("submodule.{}.path", name));
+        let url = config.get_str(&format!("submodule.{}.url", name));
+        if let (Some(path), Some(url)) = (path, url) {
+            submodules.push(SubmoduleConfig {
+                name,
+                path: PathBuf::from(path),
+                url
+            });
+        }
+    }
+    Ok(submodules)
+}
+
+// Clones the submodule into dest and checks out the commit the superproject
+// records for it. Only repositories on the local filesystem can be cloned;
+// relative urls are taken from the superproject's worktree, as git does.
+pub fn clone(superproject: &Repository, submodule: &SubmoduleConfig, sha: &ObjectId, dest: &Path) -> Result<(), Box<WitError>> {
+    let source = superproject.worktree()?.join(&submodule.url);
+    let source = Repository::find(&source, false).ok().flatten().ok_or(
+        repo_not_found_err(format!("Cannot clone submodule {} from {}: only local repositories are supported", submodule.name, submodule.url))
+    )?;
+
+    let repo = Repository::create(dest, false, "main")?;
+    copy_dir(&source.commondir().join("objects"), &repo.commondir().join("objects"))?;
+
+    let tree = match object::read(&repo, &object::find(&repo, &sha.to_string(), Some("tree"), true)?)? {
+        WitObject::TreeObject(tree) => tree,
+        _ => return Err(malformed_object_err(format!("Submodule commit {} has no tree", sha)))
+    };
+    object::checkout(&repo, &tree, dest, true)?;
+    reference::detach_head(&repo, &sha.to_string(), &format!("clone: from {}", submodule.url))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), Box<WitError>> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else if !dest.exists() {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
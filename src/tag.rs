@@ -1,8 +1,15 @@
+use std::fmt;
+
+use regex::Regex;
+
 use crate::{
     object::{Object, WitObject::*, self},
+    object_id::ObjectId,
     repository::Repository,
-    error::WitError,
-    kvlm::{ KVLMExt, KVLM }, reference
+    error::{WitError, builder::*},
+    kvlm::{ self, KVLMExt, KVLM },
+    reference,
+    signature::Signature
 };
 
 pub struct Tag<'a> {
@@ -21,6 +28,11 @@ impl<'a> Tag<'a> {
     pub fn kvlm(&mut self) -> &mut KVLM {
         &mut self.kvlm
     }
+
+    // The sha of the tagged object
+    pub fn object(&self) -> Option<&String> {
+        self.kvlm.get("object").and_then(|v| v.first())
+    }
 }
 
 impl<'a> Object for Tag<'a> {
@@ -42,25 +54,84 @@ impl<'a> Object for Tag<'a> {
     }
 }
 
-pub fn create(repo: &Repository, name: &str, reference: &str, create_object: bool) -> Result<(), Box<WitError>> {
-    let sha = object::find(repo, reference, None, true)?;
+impl<'a> fmt::Display for Tag<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        kvlm::display(&self.kvlm, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagInfo {
+    // Relative to refs/tags
+    pub name: String,
+    // What the ref points at: the tag object for annotated tags
+    pub sha: String,
+    // The object the tag ultimately names, past any tag objects
+    pub target: String,
+    pub annotated: bool,
+}
 
-    if create_object {
+// Tags `object` as refs/tags/<name> and returns the sha the ref points at. An
+// annotated tag is a tag object with a message, by default from user.name and
+// user.email; a lightweight tag is just the ref.
+pub fn create(repo: &Repository, name: &str, object: &str, annotated: bool, message: Option<&str>, tagger: Option<Signature>) -> Result<String, Box<WitError>> {
+    let sha = object::find(repo, object, None, true)?;
+    let refname = format!("refs/tags/{}", name);
+    if reference::exists(repo, &refname) {
+        return Err(ref_mismatch_err(&refname, None, reference::resolve(repo, &refname).ok()));
+    }
+
+    let ref_sha = if annotated {
+        let message = message.ok_or(missing_data_err(format!("Annotated tag {} needs a message", name)))?;
+        let tagger = match tagger {
+            Some(tagger) => tagger,
+            None => Signature::from_config(repo)?
+        };
         let mut tag = Tag::new(Some(repo));
         let kvlm = tag.kvlm();
-        kvlm.insert("object".to_owned(), vec![sha]);
-        kvlm.insert("type".to_owned(), vec!["commit".to_owned()]);
+        kvlm.insert("object".to_owned(), vec![sha.to_string()]);
+        kvlm.insert("type".to_owned(), vec![object::read_type(repo, &sha)?]);
         kvlm.insert("tag".to_owned(), vec![name.to_owned()]);
-        kvlm.insert("tagger".to_owned(), vec![format!("{} <{}>", "Will Hopkins", "willothyh@gmail.com")]);
-        // Commit message
-        kvlm.insert("".to_owned(), vec![format!("{}", "Created by wit")]);
-
-        // Create the tag object
-        let tag_sha = object::write(TagObject(tag), true)?;
-        // Create the ref
-        reference::create(&repo, "tags/".to_owned() + name, tag_sha)
+        kvlm.insert("tagger".to_owned(), vec![tagger.to_string()]);
+        let message = if message.ends_with('\n') { message.to_owned() } else { format!("{}\n", message) };
+        kvlm.insert("".to_owned(), vec![message]);
+        object::write(TagObject(tag), true)?.to_string()
     } else {
-        // Create lightweight tag
-        reference::create(&repo, "tags/".to_owned() + name, sha)
+        sha.to_string()
+    };
+
+    // Never move an existing tag
+    reference::update(repo, &refname, &ref_sha, None, "tag")?;
+    Ok(ref_sha)
+}
+
+// Every tag whose name matches pattern, sorted by name
+pub fn list(repo: &Repository, pattern: Option<&Regex>) -> Result<Vec<TagInfo>, Box<WitError>> {
+    let mut names = Vec::new();
+    reference::flatten(&reference::list(repo, Some(Repository::path(repo, vec!["refs", "tags"])))?, "", &mut names);
+
+    let mut tags = Vec::new();
+    for (name, sha) in names {
+        if pattern.map(|pattern| !pattern.is_match(&name)).unwrap_or(false) {
+            continue;
+        }
+        let mut target = ObjectId::from_hex(&sha)?;
+        // Tags of tags are followed all the way down
+        while object::read_type(repo, &target)? == "tag" {
+            let tag = match object::read(repo, &target)? {
+                TagObject(tag) => tag,
+                _ => return Err(malformed_object_err(format!("Object {} is not a tag", target)))
+            };
+            let object = tag.object().ok_or(malformed_object_err(format!("Tag {} has no object", target)))?;
+            target = ObjectId::from_hex(object)?;
+        }
+        tags.push(TagInfo {
+            annotated: target.to_string() != sha,
+            name,
+            sha,
+            target: target.to_string()
+        });
     }
-}
\ No newline at end of file
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tags)
+}
@@ -0,0 +1,92 @@
+// Scratch repositories for the tests
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    commit,
+    object,
+    object_id::ObjectId,
+    pack,
+    repository::Repository,
+    signature::Signature,
+    tree::{self, Leaf}
+};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// A directory of its own under the system temp dir, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = std::env::temp_dir().join(format!("wit-test-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// A new repository with a worktree, on branch main
+pub fn repo() -> (TempDir, Repository) {
+    let dir = TempDir::new();
+    let repo = Repository::create(dir.path(), false, "main").unwrap();
+    (dir, repo)
+}
+
+// Writes a tree of regular files with the given contents and returns its sha
+pub fn tree(repo: &Repository, files: &[(&str, &[u8])]) -> String {
+    let mut leaves = BTreeMap::new();
+    for (path, data) in files {
+        let sha = object::write_raw(repo, "blob", data).unwrap();
+        leaves.insert(PathBuf::from(path), Leaf::new("100644".to_owned(), PathBuf::from(path), sha));
+    }
+    tree::write(repo, &leaves).unwrap().to_string()
+}
+
+// The signature every test commit is made with
+pub fn author() -> Signature {
+    Signature::new("A U Thor", "author@example.com", 1112911993, -420)
+}
+
+// Commits tree on top of parents, without touching any ref
+pub fn commit(repo: &Repository, tree: &str, parents: &[&str], message: &str) -> String {
+    let parents = parents.iter().map(|parent| parent.to_string()).collect::<Vec<String>>();
+    commit::write(repo, &ObjectId::from_hex(tree).unwrap(), &parents, message, &author(), &author()).unwrap().to_string()
+}
+
+// The bytes of a pack holding shas. The objects stay readable, as the pack
+// is written into the repository.
+pub fn pack_of(repo: &Repository, shas: &[ObjectId]) -> Vec<u8> {
+    let dir = Repository::dir(repo, vec!["objects", "pack"], true).unwrap();
+    let path = dir.join(format!("pack-test{}.pack", NEXT.fetch_add(1, Ordering::SeqCst)));
+    pack::write_pack(repo, shas, &path, &path.with_extension("idx")).unwrap();
+    fs::read(path).unwrap()
+}
+
+// Runs git in dir, away from any user or system config, and returns what it
+// printed; the exit status is left to the caller to judge from the output
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .output()
+        .expect("git is needed to run this test");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
@@ -0,0 +1,672 @@
+// The git wire protocol (version 0): pkt-line framing, the ref
+// advertisement and the upload-pack negotiation. How the bytes get there
+// depends on the url scheme; see the submodules.
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::{
+    checkout::{commit_files, update_worktree, Files},
+    commit,
+    config::{ConfigGet, ConfigSet},
+    error::{WitError, builder::*},
+    object::{self, WitObject},
+    object_id::ObjectId,
+    pack,
+    reference,
+    repository::Repository
+};
+
+pub mod http;
+
+pub const FLUSH: &[u8] = b"0000";
+
+// What a server said it has, before any objects are requested
+pub struct Advertisement {
+    // (name, sha) in the order they were sent, without the peeled ^{} entries
+    pub refs: Vec<(String, String)>,
+    // (name, sha) of what each annotated tag finally points at
+    pub peeled: Vec<(String, String)>,
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.refs.iter().find(|(refname, _)| refname == name).map(|(_, sha)| sha.as_str())
+    }
+
+    // The branch HEAD points at, from the symref capability
+    pub fn head_target(&self) -> Option<&str> {
+        self.capabilities.iter().find_map(|cap| cap.strip_prefix("symref=HEAD:"))
+    }
+
+    // What name points at once any tags are peeled off
+    pub fn peeled(&self, name: &str) -> Option<&str> {
+        self.peeled.iter().find(|(refname, _)| refname == name).map(|(_, sha)| sha.as_str()).or(self.get(name))
+    }
+}
+
+// Frames data with its length as four hex digits, the length included
+pub fn pkt_line(data: &[u8]) -> Vec<u8> {
+    let mut line = format!("{:04x}", data.len() + 4).into_bytes();
+    line.extend(data);
+    line
+}
+
+// The next packet, or None for a flush packet
+pub fn read_pkt(reader: &mut dyn Read) -> Result<Option<Vec<u8>>, Box<WitError>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).map_err(|_| missing_data_err("Connection closed before the next packet".to_owned()))?;
+    read_pkt_data(reader, &len)
+}
+
+// The rest of a packet whose length has already been read
+fn read_pkt_data(reader: &mut dyn Read, len: &[u8; 4]) -> Result<Option<Vec<u8>>, Box<WitError>> {
+    let len = usize::from_str_radix(std::str::from_utf8(len)?, 16)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if len < 4 {
+        return Err(malformed_object_err(format!("Invalid packet length {}", len)));
+    }
+    let mut data = vec![0u8; len - 4];
+    reader.read_exact(&mut data).map_err(|_| missing_data_err("Connection closed in the middle of a packet".to_owned()))?;
+    Ok(Some(data))
+}
+
+// Reads ref lines up to the next flush. The first one also carries the
+// capabilities after a NUL; an empty repository sends only that.
+pub fn read_advertisement(reader: &mut dyn Read) -> Result<Advertisement, Box<WitError>> {
+    let mut advertisement = Advertisement { refs: Vec::new(), peeled: Vec::new(), capabilities: Vec::new() };
+    while let Some(line) = read_pkt(reader)? {
+        let line = String::from_utf8(line)?;
+        let line = line.trim_end_matches('\n');
+        let (line, capabilities) = line.split_once('\0').unwrap_or((line, ""));
+        if !capabilities.is_empty() {
+            advertisement.capabilities = capabilities.split(' ').map(|cap| cap.to_owned()).collect();
+        }
+        let (sha, name) = line.split_once(' ').ok_or(malformed_object_err(format!("Invalid ref advertisement {:?}", line)))?;
+        if name == "capabilities^{}" {
+            continue;
+        }
+        if let Some(name) = name.strip_suffix("^{}") {
+            advertisement.peeled.push((name.to_owned(), sha.to_owned()));
+            continue;
+        }
+        advertisement.refs.push((name.to_owned(), sha.to_owned()));
+    }
+    Ok(advertisement)
+}
+
+// The want/have lines asking for `wants`. Only capabilities the server
+// offered are requested; include-tag adds the annotated tags of anything sent.
+pub fn upload_request(advertisement: &Advertisement, wants: &[String], haves: &[String]) -> Vec<u8> {
+    let capabilities = ["side-band-64k", "ofs-delta", "include-tag"]
+        .iter()
+        .filter(|cap| advertisement.has(cap))
+        .map(|cap| format!(" {}", cap))
+        .collect::<String>();
+
+    let mut request = Vec::new();
+    for (i, sha) in wants.iter().enumerate() {
+        let caps = if i == 0 { format!("{} agent=wit", capabilities) } else { String::new() };
+        request.extend(pkt_line(format!("want {}{}\n", sha, caps).as_bytes()));
+    }
+    request.extend(FLUSH);
+    for sha in haves {
+        request.extend(pkt_line(format!("have {}\n", sha).as_bytes()));
+    }
+    request.extend(pkt_line(b"done\n"));
+    request
+}
+
+// Copies the pack in an upload-pack response to out. With side-band the
+// pack comes on band 1, progress on band 2 and a fatal error on band 3.
+pub fn read_pack(reader: &mut dyn Read, out: &mut dyn Write, side_band: bool) -> Result<(), Box<WitError>> {
+    // Without multi_ack the negotiation ends with a NAK, or an ACK for the
+    // first common commit. Over stateless HTTP that ACK can come twice, once
+    // when the commit is found and again after done, so ACKs are skipped
+    // until the pack starts.
+    let first = loop {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).map_err(|_| missing_data_err("Connection closed before the pack".to_owned()))?;
+        if !side_band && &len == b"PACK" {
+            out.write_all(&len)?;
+            std::io::copy(reader, out)?;
+            return Ok(());
+        }
+        match read_pkt_data(reader, &len)? {
+            Some(line) if line.starts_with(b"NAK") || line.starts_with(b"ACK") => continue,
+            Some(line) if line.starts_with(b"ERR ") => {
+                return Err(missing_data_err(format!("Remote error: {}", String::from_utf8_lossy(&line[4..]).trim_end())));
+            },
+            Some(packet) if side_band => break packet,
+            Some(line) => return Err(malformed_object_err(format!("Unexpected negotiation line {:?}", String::from_utf8_lossy(&line)))),
+            None => continue
+        }
+    };
+
+    // Progress lines can span packets, so track where they start
+    let mut line_start = true;
+    let mut next = Some(first);
+    while let Some(packet) = match next.take() {
+        Some(packet) => Some(packet),
+        None => read_pkt(reader)?
+    } {
+        match packet.first() {
+            Some(1) => out.write_all(&packet[1..])?,
+            Some(2) => {
+                for c in String::from_utf8_lossy(&packet[1..]).chars() {
+                    if line_start {
+                        eprint!("remote: ");
+                    }
+                    eprint!("{}", c);
+                    line_start = c == '\n' || c == '\r';
+                }
+            },
+            Some(3) => return Err(missing_data_err(format!("Remote error: {}", String::from_utf8_lossy(&packet[1..]).trim_end()))),
+            _ => return Err(malformed_object_err("Invalid side-band packet".to_owned()))
+        }
+    }
+    Ok(())
+}
+
+// The advertised refs with names that are safe to store locally, warning
+// about any that aren't
+fn checked_refs(refs: &[(String, String)]) -> Vec<(String, String)> {
+    refs.iter()
+        .filter(|(name, _)| {
+            let valid = reference::check_name(name).is_ok();
+            if !valid {
+                eprintln!("warning: ignoring ref with invalid name {}", name.escape_debug());
+            }
+            valid
+        })
+        .cloned()
+        .collect()
+}
+
+// Clones the repository at url into dest: every branch becomes a
+// remote-tracking branch of origin, tags are copied, and the branch HEAD
+// points at is checked out
+pub fn clone(url: &str, dest: &Path) -> Result<Repository, Box<WitError>> {
+    if dest.exists() && (!dest.is_dir() || dest.read_dir()?.next().is_some()) {
+        return Err(dir_not_empty_err(dest));
+    }
+    let mut source = http::HttpSource::connect(url, &ConfigSet::user()?)?;
+    let advertisement = source.advertisement();
+
+    let refs = checked_refs(&advertisement.refs);
+
+    let head = advertisement.get("HEAD").map(|sha| sha.to_owned());
+    let symref = advertisement.head_target().filter(|target| reference::check_name(target).is_ok());
+    let head_target = symref.map(|target| target.to_owned()).or_else(|| {
+        // Without the symref capability, guess from which branch HEAD matches
+        refs.iter()
+            .find(|(name, sha)| name.starts_with("refs/heads/") && Some(sha) == head.as_ref())
+            .map(|(name, _)| name.clone())
+    });
+    let branch = head_target.as_deref().and_then(|target| target.strip_prefix("refs/heads/")).unwrap_or("main");
+
+    let mut repo = Repository::create(dest, false, branch)?;
+    repo.conf.set("remote.origin.url", url)?;
+    repo.conf.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+    repo.conf.write(&Repository::file(&repo, vec!["config"], false)?)?;
+
+    let mut wants = Vec::new();
+    let mut seen = HashSet::new();
+    for (name, sha) in &refs {
+        if (name == "HEAD" || name.starts_with("refs/heads/") || name.starts_with("refs/tags/")) && seen.insert(sha.clone()) {
+            wants.push(sha.clone());
+        }
+    }
+    if wants.is_empty() {
+        eprintln!("warning: You appear to have cloned an empty repository.");
+        return Ok(repo);
+    }
+    pack::fetch(&repo, &mut source, &wants, &[], 3)?;
+
+    let message = format!("clone: from {}", url);
+    for (name, sha) in &refs {
+        if let Some(branch) = name.strip_prefix("refs/heads/") {
+            reference::update(&repo, &format!("refs/remotes/origin/{}", branch), sha, None, &message)?;
+        } else if name.starts_with("refs/tags/") {
+            reference::update(&repo, name, sha, None, &message)?;
+        }
+    }
+
+    if let Some(head) = head {
+        match &head_target {
+            Some(target) => {
+                reference::update(&repo, target, &head, None, &message)?;
+                let origin_head = Repository::file(&repo, vec!["refs", "remotes", "origin", "HEAD"], true)?;
+                Repository::write_file(&repo, &origin_head, format!("ref: refs/remotes/origin/{}\n", branch).as_bytes())?;
+            },
+            None => reference::detach_head(&repo, &head, &message)?
+        }
+        update_worktree(&repo, &Files::new(), &commit_files(&repo, &head)?)?;
+    }
+    Ok(repo)
+}
+
+// A fetch refspec such as +refs/heads/*:refs/remotes/origin/*
+struct Refspec {
+    // Whether a ref may move to something that doesn't descend from it
+    force: bool,
+    src: String,
+    dst: String,
+}
+
+impl Refspec {
+    fn parse(spec: &str) -> Result<Refspec, Box<WitError>> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, spec)
+        };
+        let (src, dst) = spec.split_once(':').ok_or(malformed_object_err(format!("Invalid refspec {}", spec)))?;
+        if src.matches('*').count() != dst.matches('*').count() || src.matches('*').count() > 1 {
+            return Err(malformed_object_err(format!("Invalid refspec {}", spec)));
+        }
+        Ok(Refspec { force, src: src.to_owned(), dst: dst.to_owned() })
+    }
+
+    // Where the remote ref name is stored locally, if this refspec covers it
+    fn map(&self, name: &str) -> Option<String> {
+        match self.src.split_once('*') {
+            Some((prefix, suffix)) => {
+                let middle = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(self.dst.replacen('*', middle, 1))
+            },
+            None => (name == self.src).then(|| self.dst.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    // The local ref, e.g. refs/remotes/origin/main
+    pub name: String,
+    // None if the ref is new
+    pub old: Option<String>,
+    pub new: String,
+    // The remote ref it was fetched from
+    pub remote_ref: String,
+    // Whether it moved to something that doesn't descend from old
+    pub forced: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct FetchResult {
+    // Where the refs were fetched from
+    pub url: String,
+    pub updated: Vec<RefUpdate>,
+    // Refs left alone because the update wasn't a fast-forward and the
+    // refspec didn't allow forcing it
+    pub rejected: Vec<RefUpdate>,
+    // Objects unpacked from the pack the remote sent
+    pub objects: usize,
+}
+
+// Fetches the refs remote.<remote>.fetch asks for from remote.<remote>.url,
+// with the objects they need, then updates the local refs they map to.
+// Tags come along when they point at something the repository has: ones
+// for commits already here are asked for outright, and include-tag has the
+// server add those for the commits it sends.
+pub fn fetch(repo: &Repository, remote: &str) -> Result<FetchResult, Box<WitError>> {
+    let config = repo.config()?;
+    let url = config.get_str(&format!("remote.{}.url", remote)).ok_or(
+        repo_not_found_err(format!("No url configured for remote {}", remote))
+    )?;
+    let mut refspecs = config.get_all(&format!("remote.{}.fetch", remote)).into_iter()
+        .map(Refspec::parse)
+        .collect::<Result<Vec<Refspec>, Box<WitError>>>()?;
+    if refspecs.is_empty() {
+        refspecs.push(Refspec::parse(&format!("+refs/heads/*:refs/remotes/{}/*", remote))?);
+    }
+
+    let mut source = http::HttpSource::connect(&url, &config)?;
+    let advertisement = source.advertisement();
+    // (remote ref, local ref, sha, force)
+    let mut mapped = Vec::new();
+    let refs = checked_refs(&advertisement.refs);
+    for (name, sha) in &refs {
+        if let Some((dst, force)) = refspecs.iter().find_map(|spec| spec.map(name).map(|dst| (dst, spec.force))) {
+            if reference::check_name(&dst).is_err() {
+                eprintln!("warning: ignoring {}, which would be stored as invalid ref {}", name, dst);
+                continue;
+            }
+            mapped.push((name.clone(), dst, sha.clone(), force));
+        }
+    }
+    let tags = refs.iter()
+        .filter(|(name, _)| name.starts_with("refs/tags/") && !reference::exists(repo, name))
+        .cloned()
+        .collect::<Vec<(String, String)>>();
+
+    let mut wants = Vec::new();
+    for (_, _, sha, _) in &mapped {
+        if !object::exists(repo, &ObjectId::from_hex(sha)?) && !wants.contains(sha) {
+            wants.push(sha.clone());
+        }
+    }
+    for (name, sha) in &tags {
+        let target = advertisement.peeled(name).unwrap_or(sha);
+        // A lightweight tag has nothing of its own to fetch
+        if target == sha || wants.contains(sha) || object::exists(repo, &ObjectId::from_hex(sha)?) {
+            continue;
+        }
+        if object::exists(repo, &ObjectId::from_hex(target)?) {
+            wants.push(sha.clone());
+        }
+    }
+
+    let mut result = FetchResult { url: url.clone(), ..FetchResult::default() };
+    if !wants.is_empty() {
+        // Every commit we have a ref for, so the server can leave out what is already here
+        let mut local = Vec::new();
+        reference::flatten(&reference::list(repo, None)?, "refs/", &mut local);
+        let mut haves = local.into_iter().map(|(_, sha)| sha).collect::<Vec<String>>();
+        haves.extend(reference::resolve(repo, "HEAD").ok());
+        haves.sort();
+        haves.dedup();
+        haves.retain(|sha| {
+            ObjectId::from_hex(sha).map(|id| matches!(object::read(repo, &id), Ok(WitObject::CommitObject(_)))).unwrap_or(false)
+        });
+        result.objects = pack::fetch(repo, &mut source, &wants, &haves, 3)?.objects.len();
+    }
+
+    let message = format!("fetch {}", remote);
+    for (remote_ref, name, new, force) in mapped {
+        let old = reference::resolve(repo, &name).ok();
+        if old.as_ref() == Some(&new) {
+            continue;
+        }
+        let fast_forward = match &old {
+            Some(old) => commit::ancestors(repo, &new).map(|ancestors| ancestors.contains(old)).unwrap_or(false),
+            None => true
+        };
+        let update = RefUpdate { name: name.clone(), old: old.clone(), new: new.clone(), remote_ref, forced: !fast_forward };
+        if !fast_forward && !force {
+            result.rejected.push(update);
+            continue;
+        }
+        let reason = match (&old, fast_forward) {
+            (None, _) => "storing head",
+            (Some(_), true) => "fast-forward",
+            (Some(_), false) => "forced-update"
+        };
+        reference::update(repo, &name, &new, old.as_deref(), &format!("{}: {}", message, reason))?;
+        result.updated.push(update);
+    }
+
+    for (name, sha) in tags {
+        if !object::exists(repo, &ObjectId::from_hex(&sha)?) {
+            continue;
+        }
+        reference::update(repo, &name, &sha, None, &format!("{}: storing tag", message))?;
+        result.updated.push(RefUpdate { name: name.clone(), old: None, new: sha, remote_ref: name, forced: false });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    // Serves the repositories under root over smart HTTP with git
+    // http-backend, one request per connection, and returns the base url
+    fn serve(root: &Path) -> String {
+        serve_tampered(root, Vec::new())
+    }
+
+    // Like serve, but with (sha, name) lines git itself would never send
+    // added to the end of every ref advertisement
+    fn serve_tampered(root: &Path, extra: Vec<(String, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let root = root.to_path_buf();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                respond(&root, stream, &extra);
+            }
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    fn respond(root: &Path, mut stream: TcpStream, extra: &[(String, String)]) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut request = line.split(' ');
+        let (method, target) = (request.next().unwrap().to_owned(), request.next().unwrap().to_owned());
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap() == 0 || line.trim_end().is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.trim_end().split_once(':') {
+                headers.push((key.trim().to_ascii_lowercase(), value.trim().to_owned()));
+            }
+        }
+        let header = |name: &str| headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone()).unwrap_or_default();
+        let mut body = vec![0; header("content-length").parse::<usize>().unwrap_or(0)];
+        reader.read_exact(&mut body).unwrap();
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let mut backend = Command::new("git")
+            .arg("http-backend")
+            .env("GIT_PROJECT_ROOT", root)
+            .env("GIT_HTTP_EXPORT_ALL", "1")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("HOME", root)
+            .env("REQUEST_METHOD", method)
+            .env("PATH_INFO", path)
+            .env("QUERY_STRING", query)
+            .env("CONTENT_TYPE", header("content-type"))
+            .env("CONTENT_LENGTH", body.len().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = backend.stdin.take().unwrap();
+        thread::spawn(move || stdin.write_all(&body));
+        let output = backend.wait_with_output().unwrap().stdout;
+
+        // CGI headers, then the body
+        let end = output.windows(4).position(|w| w == b"\r\n\r\n").map(|i| (i, i + 4))
+            .or(output.windows(2).position(|w| w == b"\n\n").map(|i| (i, i + 2)))
+            .unwrap();
+        let mut status = "200 OK".to_owned();
+        let mut response = String::new();
+        for line in String::from_utf8_lossy(&output[..end.0]).lines() {
+            match line.strip_prefix("Status: ") {
+                Some(line) => status = line.to_owned(),
+                None => response += &format!("{}\r\n", line)
+            }
+        }
+        let mut body = output[end.1..].to_vec();
+        if path.ends_with("/info/refs") && !extra.is_empty() {
+            // In front of the flush that ends the ref list
+            let flush = body.len() - FLUSH.len();
+            let lines = extra.iter().map(|(sha, name)| pkt_line(format!("{} {}\n", sha, name).as_bytes())).collect::<Vec<Vec<u8>>>();
+            body.splice(flush..flush, lines.concat());
+        }
+        let head = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", status, response, body.len());
+        let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&body));
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let mut all = vec!["-c", "user.name=A U Thor", "-c", "user.email=author@example.com"];
+        all.extend(args);
+        test_util::git(dir, &all)
+    }
+
+    // A repository at root/repo with branches trunk, which HEAD is on, and
+    // side, an annotated tag v1 and a lightweight tag light
+    fn fixture(root: &Path) -> std::path::PathBuf {
+        let dir = root.join("repo");
+        fs::create_dir(&dir).unwrap();
+        git(&dir, &["init", "-q", "-b", "trunk"]);
+        fs::create_dir(dir.join("src")).unwrap();
+        fs::write(dir.join("README"), b"hello\n").unwrap();
+        fs::write(dir.join("src/lib.rs"), b"fn main() {}\n").unwrap();
+        git(&dir, &["add", "."]);
+        git(&dir, &["commit", "-qm", "first"]);
+        git(&dir, &["tag", "-a", "-m", "version one", "v1"]);
+        git(&dir, &["branch", "side"]);
+        fs::write(dir.join("README"), b"hello again\n").unwrap();
+        git(&dir, &["commit", "-qam", "second"]);
+        git(&dir, &["tag", "light"]);
+        dir
+    }
+
+    #[test]
+    fn advertisement_is_parsed() {
+        let mut raw = Vec::new();
+        raw.extend(pkt_line(b"1111111111111111111111111111111111111111 HEAD\0multi_ack side-band-64k symref=HEAD:refs/heads/trunk\n"));
+        raw.extend(pkt_line(b"1111111111111111111111111111111111111111 refs/heads/trunk\n"));
+        raw.extend(pkt_line(b"2222222222222222222222222222222222222222 refs/tags/v1\n"));
+        raw.extend(pkt_line(b"1111111111111111111111111111111111111111 refs/tags/v1^{}\n"));
+        raw.extend(FLUSH);
+        let advertisement = read_advertisement(&mut &raw[..]).unwrap();
+        assert_eq!(advertisement.refs.len(), 3);
+        assert_eq!(advertisement.head_target(), Some("refs/heads/trunk"));
+        assert!(advertisement.has("side-band-64k") && !advertisement.has("ofs-delta"));
+        assert_eq!(advertisement.get("refs/tags/v1"), Some("2222222222222222222222222222222222222222"));
+        assert_eq!(advertisement.peeled("refs/tags/v1"), Some("1111111111111111111111111111111111111111"));
+        assert_eq!(advertisement.peeled("refs/heads/trunk"), advertisement.get("refs/heads/trunk"));
+
+        let request = upload_request(&advertisement, &["1111111111111111111111111111111111111111".to_owned()], &[]);
+        assert_eq!(request, b"004awant 1111111111111111111111111111111111111111 side-band-64k agent=wit\n00000009done\n");
+    }
+
+    #[test]
+    fn clone_over_http() {
+        let root = test_util::TempDir::new();
+        let source = fixture(root.path());
+        let url = format!("{}/repo", serve(root.path()));
+        let dest = root.path().join("clone");
+        let repo = clone(&url, &dest).unwrap();
+
+        assert_eq!(reference::head_target(&repo).as_deref(), Some("refs/heads/trunk"));
+        assert_eq!(fs::read(dest.join("README")).unwrap(), b"hello again\n");
+        assert_eq!(fs::read(dest.join("src/lib.rs")).unwrap(), b"fn main() {}\n");
+        let expected = git(&source, &["show-ref"]).replace("refs/heads/", "refs/remotes/origin/");
+        let refs = git(&dest, &["show-ref"]);
+        assert_eq!(refs.lines().filter(|line| !line.ends_with(" refs/heads/trunk") && !line.ends_with(" refs/remotes/origin/HEAD")).collect::<Vec<&str>>(), expected.lines().collect::<Vec<&str>>());
+        // Every object git would consider part of the history arrived
+        assert_eq!(git(&dest, &["rev-list", "--objects", "--all"]).lines().count(), git(&source, &["rev-list", "--objects", "--all"]).lines().count());
+        assert_eq!(git(&dest, &["status", "--porcelain"]), "");
+        assert!(matches!(*clone(&url, &dest).unwrap_err(), WitError::DirectoryNotEmpty(_)));
+    }
+
+    #[test]
+    fn clone_ignores_invalid_ref_names() {
+        let root = test_util::TempDir::new();
+        let source = fixture(root.path());
+        let trunk = git(&source, &["rev-parse", "trunk"]).trim().to_owned();
+        let extra = ["refs/tags/../../../escaped", "refs/heads/../../../../escaped-branch", "refs/tags/v1.lock", "refs/heads/a\x01b"]
+            .map(|name| (trunk.clone(), name.to_owned()));
+        let url = format!("{}/repo", serve_tampered(root.path(), extra.to_vec()));
+        let dest = root.path().join("clone");
+        let repo = clone(&url, &dest).unwrap();
+
+        assert!(!root.path().join("escaped").exists() && !root.path().join("escaped-branch").exists());
+        assert!(!dest.join(".git/refs/tags/v1.lock").exists());
+        assert_eq!(reference::resolve(&repo, "refs/remotes/origin/trunk").unwrap(), trunk);
+        let expected = git(&source, &["show-ref"]).replace("refs/heads/", "refs/remotes/origin/");
+        let refs = git(&dest, &["show-ref"]);
+        assert_eq!(refs.lines().filter(|line| !line.ends_with(" refs/heads/trunk") && !line.ends_with(" refs/remotes/origin/HEAD")).collect::<Vec<&str>>(), expected.lines().collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn fetch_ignores_invalid_ref_names() {
+        let root = test_util::TempDir::new();
+        let source = fixture(root.path());
+        let dest = root.path().join("clone");
+        clone(&format!("{}/repo", serve(root.path())), &dest).unwrap();
+
+        let trunk = git(&source, &["rev-parse", "trunk"]).trim().to_owned();
+        let extra = ["refs/heads/../../config", "refs/heads/x/../../../../escaped", "refs/tags/../../../escaped-tag", "refs/tags/v1:x"]
+            .map(|name| (trunk.clone(), name.to_owned()));
+        let url = format!("{}/repo", serve_tampered(root.path(), extra.to_vec()));
+        git(&dest, &["config", "remote.origin.url", &url]);
+        // A refspec whose destination no valid name could map to
+        git(&dest, &["config", "--add", "remote.origin.fetch", "refs/tags/v1:refs/remotes/origin/v1.lock"]);
+        fs::write(source.join("new"), b"new\n").unwrap();
+        git(&source, &["add", "new"]);
+        git(&source, &["commit", "-qm", "third"]);
+
+        let repo = Repository::find_with_git_dir(&dest, None, true).unwrap().unwrap();
+        let result = fetch(&repo, "origin").unwrap();
+        assert_eq!(result.updated.iter().map(|update| update.name.as_str()).collect::<Vec<&str>>(), vec!["refs/remotes/origin/trunk"]);
+        assert!(!root.path().join("escaped").exists() && !root.path().join("escaped-tag").exists());
+        assert!(!dest.join(".git/refs/remotes/origin/v1.lock").exists());
+        // refs/heads/../../config would have landed on the config itself
+        assert_eq!(git(&dest, &["config", "remote.origin.url"]).trim(), url);
+    }
+
+    #[test]
+    fn pack_follows_any_number_of_acks() {
+        let ack = pkt_line(b"ACK 1111111111111111111111111111111111111111\n");
+        let mut raw = [&ack[..], &ack, b"PACKdata"].concat();
+        let mut out = Vec::new();
+        read_pack(&mut &raw[..], &mut out, false).unwrap();
+        assert_eq!(out, b"PACKdata");
+
+        raw = [&ack[..], &ack, &pkt_line(b"\x02progress\n"), &pkt_line(b"\x01PACK"), &pkt_line(b"\x01data"), FLUSH].concat();
+        out.clear();
+        read_pack(&mut &raw[..], &mut out, true).unwrap();
+        assert_eq!(out, b"PACKdata");
+        let raw = [&pkt_line(b"NAK\n")[..], &pkt_line(b"\x03no such object")].concat();
+        assert!(read_pack(&mut &raw[..], &mut out, true).is_err());
+    }
+
+    #[test]
+    fn fetch_updates_refs_and_brings_new_tags() {
+        let root = test_util::TempDir::new();
+        let source = fixture(root.path());
+        let url = format!("{}/repo", serve(root.path()));
+        let dest = root.path().join("clone");
+        let repo = clone(&url, &dest).unwrap();
+
+        let old_trunk = git(&source, &["rev-parse", "trunk"]).trim().to_owned();
+        let old_side = git(&source, &["rev-parse", "side"]).trim().to_owned();
+        fs::write(source.join("new"), b"new\n").unwrap();
+        git(&source, &["add", "new"]);
+        git(&source, &["commit", "-qm", "third"]);
+        git(&source, &["tag", "-a", "-m", "version two", "v2"]);
+        let rewritten = git(&source, &["commit-tree", "-m", "unrelated", "HEAD^{tree}"]).trim().to_owned();
+        git(&source, &["update-ref", "refs/heads/side", &rewritten]);
+
+        let result = fetch(&repo, "origin").unwrap();
+        assert_eq!(result.url, url);
+        assert!(result.rejected.is_empty());
+        let trunk = result.updated.iter().find(|update| update.name == "refs/remotes/origin/trunk").unwrap();
+        assert_eq!((trunk.old.as_deref(), trunk.forced), (Some(old_trunk.as_str()), false));
+        let side = result.updated.iter().find(|update| update.name == "refs/remotes/origin/side").unwrap();
+        assert_eq!((side.old.as_deref(), side.new.as_str(), side.forced), (Some(old_side.as_str()), rewritten.as_str(), true));
+        assert_eq!(reference::resolve(&repo, "refs/tags/v2").unwrap(), git(&source, &["rev-parse", "v2"]).trim());
+        assert_eq!(git(&dest, &["rev-list", "--objects", "--all"]).lines().count(), git(&source, &["rev-list", "--objects", "--all"]).lines().count());
+
+        // Without a + the refspec only allows fast-forwards
+        git(&dest, &["config", "remote.origin.fetch", "refs/heads/*:refs/remotes/origin/*"]);
+        git(&source, &["update-ref", "refs/heads/side", &old_side]);
+        let repo = Repository::find_with_git_dir(&dest, None, true).unwrap().unwrap();
+        let result = fetch(&repo, "origin").unwrap();
+        assert_eq!(result.rejected.iter().map(|update| update.name.as_str()).collect::<Vec<&str>>(), vec!["refs/remotes/origin/side"]);
+        assert_eq!(reference::resolve(&repo, "refs/remotes/origin/side").unwrap(), rewritten);
+        assert!(fetch(&repo, "origin").unwrap().updated.is_empty());
+    }
+}
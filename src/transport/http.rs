@@ -0,0 +1,369 @@
+// The smart HTTP transport: a GET of info/refs for the advertisement, then
+// a POST to git-upload-pack for the pack. HTTP/1.1 is spoken directly over
+// TCP, wrapped in TLS for https:// urls. A 401 is answered with a login
+// from the url or the credential helpers, sent as Basic auth. Over plain
+// http that would give the password away, so it is refused unless
+// http.allowInsecureAuth says otherwise.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+
+use crate::{
+    config::{ConfigGet, ConfigSet},
+    credential::Credential,
+    error::{WitError, builder::*},
+    pack::PackSource,
+    transport::{self, Advertisement},
+    util::base64_encode
+};
+
+// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    // Everything from the first slash after the host, without a trailing slash
+    path: String,
+    // From user:password@ before the host
+    login: Option<(String, String)>,
+}
+
+impl Url {
+    fn scheme(&self) -> &str {
+        if self.tls { "https" } else { "http" }
+    }
+
+    // The server as the Host header and credential helpers know it, with
+    // the port only if it isn't the scheme's default
+    fn credential_host(&self) -> String {
+        match (self.tls, self.port) {
+            (false, 80) | (true, 443) => self.host.clone(),
+            (_, port) => format!("{}:{}", self.host, port)
+        }
+    }
+
+    fn display(&self) -> String {
+        format!("{}://{}{}", self.scheme(), self.credential_host(), self.path)
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url, Box<WitError>> {
+    let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        _ => return Err(repo_not_found_err(format!("Cannot reach {}: not an http or https url", url)))
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "")
+    };
+    let (login, authority) = match authority.rsplit_once('@') {
+        Some((userinfo, authority)) => match userinfo.split_once(':') {
+            Some((username, password)) => (Some((username.to_owned(), password.to_owned())), authority),
+            // Without a password there is nothing to log in with yet
+            None => (None, authority)
+        },
+        None => (None, authority)
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()?),
+        None => (authority, if tls { 443 } else { 80 })
+    };
+    Ok(Url { tls, host: host.to_owned(), port, path: path.trim_end_matches('/').to_owned(), login })
+}
+
+// A connection to the server, encrypted for https
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush()
+        }
+    }
+}
+
+// Server certificates are checked against the bundled web roots, or, like
+// git, only against the certificates in http.sslCAInfo if that is set
+fn tls_config(config: &ConfigSet) -> Result<Arc<ClientConfig>, Box<WitError>> {
+    let mut roots = RootCertStore::empty();
+    match config.get_str("http.sslcainfo") {
+        Some(path) => {
+            let bad_file = |err| other_err(format!("Cannot read certificates from {}: {}", path, err));
+            for cert in CertificateDer::pem_file_iter(&path).map_err(bad_file)? {
+                roots.add(cert.map_err(bad_file)?).map_err(|err| other_err(format!("Invalid certificate in {}: {}", path, err)))?;
+            }
+        },
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| other_err(format!("Cannot set up TLS: {}", err)))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn open(url: &Url, tls: &Arc<ClientConfig>) -> Result<Stream, Box<WitError>> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port))?;
+    if !url.tls {
+        return Ok(Stream::Plain(stream));
+    }
+    let name = ServerName::try_from(url.host.clone()).map_err(|_| repo_not_found_err(format!("Invalid host name {}", url.host)))?;
+    let connection = ClientConnection::new(tls.clone(), name).map_err(|err| other_err(format!("Cannot start TLS with {}: {}", url.host, err)))?;
+    Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+enum Body {
+    Length(std::io::Take<BufReader<Stream>>),
+    // Bytes left in the current chunk, and whether the last chunk was read
+    Chunked(BufReader<Stream>, usize, bool),
+    UntilClose(BufReader<Stream>),
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Body::Length(reader) => reader.read(buf),
+            // Servers that end the body by closing often skip TLS's close_notify
+            Body::UntilClose(reader) => match reader.read(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result
+            },
+            Body::Chunked(reader, remaining, done) => {
+                if *done || buf.is_empty() {
+                    return Ok(0);
+                }
+                if *remaining == 0 {
+                    let mut line = String::new();
+                    reader.read_line(&mut line)?;
+                    let size = line.trim().split(';').next().unwrap_or("");
+                    *remaining = usize::from_str_radix(size, 16)
+                        .map_err(|_| std::io::Error::other(format!("Invalid chunk size {:?}", size)))?;
+                    if *remaining == 0 {
+                        // Skip the trailers up to the blank line that ends them
+                        loop {
+                            line.clear();
+                            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                                break;
+                            }
+                        }
+                        *done = true;
+                        return Ok(0);
+                    }
+                }
+                let len = buf.len().min(*remaining);
+                let read = reader.read(&mut buf[..len])?;
+                if read == 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed in the middle of a chunk"));
+                }
+                *remaining -= read;
+                if *remaining == 0 {
+                    let mut crlf = [0u8; 2];
+                    reader.read_exact(&mut crlf)?;
+                }
+                Ok(read)
+            }
+        }
+    }
+}
+
+// Sends one request on a fresh connection and reads the response head
+fn request(tls: &Arc<ClientConfig>, method: &str, url: &Url, target: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response, Box<WitError>> {
+    let mut stream = open(url, tls)?;
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: wit/{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method, target, url.credential_host(), env!("CARGO_PKG_VERSION"), body.len()
+    );
+    for (key, value) in headers {
+        head += &format!("{}: {}\r\n", key, value);
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).ok_or(malformed_object_err(format!("Invalid HTTP status line {:?}", line.trim_end())))?;
+    let status = status.parse::<u16>()?;
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.trim_end().split_once(':') {
+            headers.push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    let chunked = header(&headers, "Transfer-Encoding").map(|value| value.eq_ignore_ascii_case("chunked")).unwrap_or(false);
+    let body = match header(&headers, "Content-Length").and_then(|value| value.parse::<u64>().ok()) {
+        _ if chunked => Body::Chunked(reader, 0, false),
+        Some(length) => Body::Length(reader.take(length)),
+        None => Body::UntilClose(reader)
+    };
+    Ok(Response { status, headers, body })
+}
+
+fn basic_auth(username: &str, password: &str) -> String {
+    format!("Basic {}", base64_encode(format!("{}:{}", username, password).as_bytes()))
+}
+
+// A repository served by git http-backend or anything compatible with it
+pub struct HttpSource {
+    url: Url,
+    advertisement: Advertisement,
+    // The Authorization header value, once the server asked for one
+    auth: Option<String>,
+    tls: Arc<ClientConfig>,
+}
+
+impl HttpSource {
+    // Fetches the ref advertisement, following redirects to wherever the
+    // repository really is and logging in if the server wants that
+    pub fn connect(url: &str, config: &ConfigSet) -> Result<HttpSource, Box<WitError>> {
+        const SERVICE: &str = "/info/refs?service=git-upload-pack";
+        let credential = Credential::new(config)?;
+        let tls = tls_config(config)?;
+        let allow_insecure = config.get_bool("http.allowinsecureauth")?.unwrap_or(false);
+        let mut url = parse_url(url)?;
+        let mut login = url.login.clone();
+        // Whether the login came from a helper, which should hear how it went
+        let mut from_helper = false;
+        let mut redirects = 0;
+        loop {
+            if login.is_some() && !url.tls && !allow_insecure {
+                return Err(repo_not_found_err(format!(
+                    "Refusing to send credentials to {} over unencrypted http; use https, or set http.allowInsecureAuth",
+                    url.display()
+                )));
+            }
+            let auth = login.as_ref().map(|(username, password)| basic_auth(username, password));
+            let headers = auth.as_deref().map(|auth| vec![("Authorization", auth)]).unwrap_or_default();
+            let mut response = request(&tls, "GET", &url, &format!("{}{}", url.path, SERVICE), &headers, b"")?;
+            match response.status {
+                200 => (),
+                401 if login.is_none() => {
+                    if !url.tls && !allow_insecure {
+                        return Err(repo_not_found_err(format!(
+                            "{} wants a login, which wit will not send over unencrypted http; use https, or set http.allowInsecureAuth",
+                            url.display()
+                        )));
+                    }
+                    login = Some(credential.get(url.scheme(), &url.credential_host(), &url.path)?);
+                    from_helper = true;
+                    continue;
+                },
+                401 => {
+                    if let (true, Some((username, password))) = (from_helper, &login) {
+                        credential.erase(url.scheme(), &url.credential_host(), &url.path, username, password)?;
+                    }
+                    return Err(repo_not_found_err(format!("Authentication failed for {}", url.display())));
+                },
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = response.header("Location").ok_or(malformed_object_err("Redirect without a Location".to_owned()))?;
+                    redirects += 1;
+                    if redirects > MAX_REDIRECTS {
+                        return Err(repo_not_found_err(format!("Too many redirects for {}", url.path)));
+                    }
+                    let location = location.strip_suffix(SERVICE).unwrap_or(location);
+                    url = match location.strip_prefix('/') {
+                        Some(path) => Url { path: format!("/{}", path.trim_end_matches('/')), ..url },
+                        None => parse_url(location)?
+                    };
+                    continue;
+                },
+                status => return Err(repo_not_found_err(format!("Cannot read refs from {}: HTTP {}", url.path, status)))
+            }
+
+            if response.header("Content-Type") != Some("application/x-git-upload-pack-advertisement") {
+                return Err(repo_not_found_err("The server does not speak the smart HTTP protocol".to_owned()));
+            }
+            // The smart protocol starts with the service name and a flush
+            let service = transport::read_pkt(&mut response.body)?.unwrap_or_default();
+            if service != b"# service=git-upload-pack\n" {
+                return Err(malformed_object_err(format!("Unexpected service line {:?}", String::from_utf8_lossy(&service))));
+            }
+            transport::read_pkt(&mut response.body)?;
+            let advertisement = transport::read_advertisement(&mut response.body)?;
+            if let (true, Some((username, password))) = (from_helper, &login) {
+                credential.store(url.scheme(), &url.credential_host(), &url.path, username, password)?;
+            }
+            return Ok(HttpSource { url, advertisement, auth, tls });
+        }
+    }
+
+    pub fn advertisement(&self) -> &Advertisement {
+        &self.advertisement
+    }
+}
+
+impl PackSource for HttpSource {
+    // Each POST negotiates a fresh pack, so there is nothing to resume
+    fn resumable(&self) -> bool {
+        false
+    }
+
+    fn fetch(&mut self, wants: &[String], haves: &[String], _offset: usize, out: &mut dyn Write) -> Result<(), Box<WitError>> {
+        let mut headers = vec![
+            ("Content-Type", "application/x-git-upload-pack-request"),
+            ("Accept", "application/x-git-upload-pack-result")
+        ];
+        if let Some(auth) = &self.auth {
+            headers.push(("Authorization", auth));
+        }
+        let body = transport::upload_request(&self.advertisement, wants, haves);
+        let mut response = request(&self.tls, "POST", &self.url, &format!("{}/git-upload-pack", self.url.path), &headers, &body)?;
+        if response.status != 200 {
+            return Err(missing_data_err(format!("git-upload-pack failed: HTTP {}", response.status)));
+        }
+        transport::read_pack(&mut response.body, out, self.advertisement.has("side-band-64k"))
+    }
+}
@@ -1,24 +1,37 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
-use crate::{object::{Find, Object}, error::{WitError, builder::*}};
+use crate::{
+    blob,
+    diff,
+    object::{self, Find, Object, WitObject},
+    object_id::{ObjectFormat, ObjectId},
+    repository::Repository,
+    error::{WitError, builder::*}
+};
 
-pub struct Tree {
+pub struct Tree<'a> {
+    repo: Option<&'a Repository>,
     leaves: Vec<Leaf>,
 }
 
-impl Tree {
-    pub fn new() -> Self {
+impl<'a> Tree<'a> {
+    pub fn new(repo: Option<&'a Repository>) -> Self {
         Tree {
+            repo,
             leaves: Vec::new(),
         }
     }
 
-    pub fn from(raw: &Vec<u8>) -> Result<Self, Box<WitError>> {
-        let mut tree = Self::new();
+    pub fn from(repo: Option<&'a Repository>, raw: &[u8]) -> Result<Self, Box<WitError>> {
+        let mut tree = Self::new(repo);
+        let format = repo.map(|repo| repo.object_format()).unwrap_or_default();
         let mut pos = 0;
 
         while pos < raw.len() {
-            let (end, leaf) = Self::parse_one(raw, pos)?;
+            let (end, leaf) = Self::parse_one(raw, pos, format)?;
             tree.add_leaf(leaf);
             pos = end;
         }
@@ -34,47 +47,51 @@ impl Tree {
         &self.leaves
     }
 
-    pub fn parse_one(raw: &Vec<u8>, start: usize) -> Result<(usize, Leaf), Box<WitError>> {
-        let mode_end = raw.find_from(b'\n', start)?;
+    pub fn parse_one(raw: &[u8], start: usize, format: ObjectFormat) -> Result<(usize, Leaf), Box<WitError>> {
+        let mode_end = raw.find_from(b' ', start)?;
         if mode_end - start != 5 && mode_end - start != 6 {
             return Err(mode_err(mode_end - start));
         }
         let mode = String::from_utf8(raw[start..mode_end].to_vec())?;
 
         let path_end = raw.find_from(b'\x00', mode_end)?;
-        let path = PathBuf::from(String::from_utf8(raw[mode_end+1..path_end].to_vec())?);
+        let name = String::from_utf8(raw[mode_end+1..path_end].to_vec())?;
+        check_name(&name)?;
+        let path = PathBuf::from(name);
 
-        let sha = crate::util::hex(&raw[path_end+1..path_end+21].to_vec());
+        let end = path_end + 1 + format.raw_len();
+        if raw.len() < end {
+            return Err(malformed_object_err(format!("Truncated tree entry {}", path.display())));
+        }
+        let sha = ObjectId::from_bytes(&raw[path_end+1..end])?;
 
-        Ok((path_end + 21, Leaf::new(mode, path, sha)))
+        Ok((end, Leaf::new(mode, path, sha)))
     }
 }
 
-impl Object for Tree {
+impl<'a> Object for Tree<'a> {
     fn serialize(&self) -> Result<Vec<u8>, Box<WitError>> {
         let mut bytes = Vec::<u8>::new();
-        bytes.extend(b"");
 
         for leaf in self.leaves() {
             bytes.extend(leaf.mode().as_bytes());
             bytes.push(b' ');
             bytes.extend(leaf.path().to_str().ok_or(
-                utf8_err(format!("Could not convert {} to str.", leaf.path().to_str().unwrap_or("")))
+                utf8_err(format!("Could not convert {} to str.", leaf.path().display()))
             )?.as_bytes());
             bytes.push(b'\x00');
-            bytes.extend(
-                u32::from_str_radix(leaf.sha(), 16)?.to_be_bytes().to_vec()
-            );
+            bytes.extend(leaf.sha().as_bytes());
         }
 
         Ok(bytes)
     }
 
     fn deserialize(&mut self, data: Vec<u8>) -> Result<(), Box<WitError>> {
+        let format = self.repo.map(|repo| repo.object_format()).unwrap_or_default();
         let mut pos = 0;
 
         while pos < data.len() {
-            let (end, leaf) = Self::parse_one(&data, pos)?;
+            let (end, leaf) = Self::parse_one(&data, pos, format)?;
             self.add_leaf(leaf);
             pos = end;
         }
@@ -82,22 +99,33 @@ impl Object for Tree {
     }
 
     fn fmt(&self) -> Vec<u8> {
-        todo!()
+        b"tree".to_vec()
+    }
+
+    fn repo(&self) -> Option<&Repository> {
+        self.repo
     }
+}
 
-    fn repo(&self) -> Option<&crate::repository::Repository> {
-        todo!()
+// One line per leaf, like git cat-file -p
+impl<'a> fmt::Display for Tree<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for leaf in self.leaves() {
+            writeln!(f, "{:0>6} {} {}\t{}", leaf.mode(), leaf.object_type(), leaf.sha(), leaf.path().display())?;
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Leaf {
     mode: String,
     path: PathBuf,
-    sha: String,
+    sha: ObjectId,
 }
 
 impl Leaf {
-    pub fn new(mode: String, path: PathBuf, sha: String) -> Leaf {
+    pub fn new(mode: String, path: PathBuf, sha: ObjectId) -> Leaf {
         Leaf {
             mode,
             path,
@@ -113,8 +141,483 @@ impl Leaf {
         &self.path
     }
 
-    pub fn sha(&self) -> &str {
+    pub fn sha(&self) -> &ObjectId {
         &self.sha
     }
+
+    pub fn is_tree(&self) -> bool {
+        self.mode == "40000" || self.mode == "040000"
+    }
+
+    // The type of the object the leaf points to, going by its mode
+    pub fn object_type(&self) -> &str {
+        if self.is_tree() {
+            "tree"
+        } else if self.mode == "160000" {
+            "commit"
+        } else {
+            "blob"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    ModeChanged,
+    // Deleted at from and added at the entry's path, with this percentage of
+    // the content in common
+    Renamed { from: PathBuf, similarity: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub old_sha: Option<ObjectId>,
+    pub new_sha: Option<ObjectId>,
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+}
+
+// Whether a tree entry name can be written to disk as it is: a single, non-empty
+// component other than . and .., and not .git in any case, which would put
+// files in the repository's own metadata
+pub fn check_name(name: &str) -> Result<(), Box<WitError>> {
+    if name.is_empty() || name == "." || name == ".." || name.eq_ignore_ascii_case(".git") || name.contains(['/', '\0']) {
+        return Err(malformed_object_err(format!("Unsafe tree entry {:?}", name)));
+    }
+    Ok(())
+}
+
+// root/path, once every component of path passes check_name and nothing
+// already on disk along the way, such as a symlink, leads outside root
+pub fn safe_dest(root: &Path, path: &Path) -> Result<PathBuf, Box<WitError>> {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => check_name(name.to_str().ok_or(path_conversion_err())?)?,
+            _ => return Err(malformed_object_err(format!("Unsafe tree path {:?}", path.display().to_string())))
+        }
+    }
+    let dest = root.join(path);
+    let base = fs::canonicalize(root)?;
+    if let Some(dir) = dest.parent().into_iter().flat_map(Path::ancestors).find(|dir| dir.exists()) {
+        if !fs::canonicalize(dir)?.starts_with(&base) {
+            return Err(malformed_object_err(format!("Tree path {:?} leads outside {}", path.display().to_string(), root.display())));
+        }
+    }
+    Ok(dest)
+}
+
+pub fn read<'a>(repo: &'a Repository, sha: &str) -> Result<Tree<'a>, Box<WitError>> {
+    match object::read(repo, &object::find(repo, sha, Some("tree"), true)?)? {
+        WitObject::TreeObject(tree) => Ok(tree),
+        _ => Err(malformed_object_err(format!("Object {} is not a tree", sha)))
+    }
+}
+
+// Every non-tree leaf under the tree, keyed by its full path
+pub fn flatten(repo: &Repository, sha: &str) -> Result<BTreeMap<PathBuf, Leaf>, Box<WitError>> {
+    let mut leaves = BTreeMap::new();
+    flatten_into(repo, sha, &PathBuf::new(), &mut leaves)?;
+    Ok(leaves)
+}
+
+fn flatten_into(repo: &Repository, sha: &str, prefix: &Path, leaves: &mut BTreeMap<PathBuf, Leaf>) -> Result<(), Box<WitError>> {
+    for leaf in read(repo, sha)?.leaves() {
+        let path = prefix.join(leaf.path());
+        if leaf.is_tree() {
+            flatten_into(repo, &leaf.sha().to_string(), &path, leaves)?;
+        } else {
+            leaves.insert(path.clone(), Leaf::new(leaf.mode().to_owned(), path, *leaf.sha()));
+        }
+    }
+    Ok(())
+}
+
+// Writes the nested trees for a flat path -> leaf map and returns the root tree sha
+pub fn write(repo: &Repository, leaves: &BTreeMap<PathBuf, Leaf>) -> Result<ObjectId, Box<WitError>> {
+    let mut files = Vec::new();
+    let mut dirs: BTreeMap<String, BTreeMap<PathBuf, Leaf>> = BTreeMap::new();
+
+    for (path, leaf) in leaves {
+        let mut components = path.iter();
+        let first = components.next().ok_or(
+            malformed_object_err("Empty path in tree".to_owned())
+        )?.to_str().ok_or(path_conversion_err())?.to_owned();
+        let rest: PathBuf = components.collect();
+
+        if rest.as_os_str().is_empty() {
+            files.push(Leaf::new(leaf.mode().to_owned(), PathBuf::from(first), *leaf.sha()));
+        } else {
+            dirs.entry(first).or_default().insert(rest, leaf.clone());
+        }
+    }
+
+    for (name, children) in dirs {
+        let sha = write(repo, &children)?;
+        files.push(Leaf::new("40000".to_owned(), PathBuf::from(name), sha));
+    }
+
+    // Git sorts trees as if directory names had a trailing slash
+    files.sort_by_key(|leaf| {
+        let mut key = leaf.path().to_str().unwrap_or("").as_bytes().to_vec();
+        if leaf.is_tree() {
+            key.push(b'/');
+        }
+        key
+    });
+
+    let mut tree = Tree::new(Some(repo));
+    for leaf in files {
+        tree.add_leaf(leaf);
+    }
+    object::write(WitObject::TreeObject(tree), true)
+}
+
+// Compares two trees path by path. With no old tree, everything in new is Added.
+pub fn diff(repo: &Repository, old: Option<&str>, new: &str) -> Result<Vec<DiffEntry>, Box<WitError>> {
+    let old = match old {
+        Some(old) => flatten(repo, old)?,
+        None => BTreeMap::new()
+    };
+    Ok(diff_leaves(&old, &flatten(repo, new)?))
+}
+
+pub fn diff_leaves(old: &BTreeMap<PathBuf, Leaf>, new: &BTreeMap<PathBuf, Leaf>) -> Vec<DiffEntry> {
+    let mut paths = old.keys().chain(new.keys()).collect::<Vec<&PathBuf>>();
+    paths.sort();
+    paths.dedup();
+
+    let mut entries = Vec::new();
+    for path in paths {
+        let (before, after) = (old.get(path), new.get(path));
+        let kind = match (before, after) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Deleted,
+            (Some(b), Some(a)) if b.sha() != a.sha() => ChangeKind::Modified,
+            (Some(b), Some(a)) if b.mode() != a.mode() => ChangeKind::ModeChanged,
+            _ => continue
+        };
+        entries.push(DiffEntry {
+            path: path.clone(),
+            kind,
+            old_sha: before.map(|leaf| *leaf.sha()),
+            new_sha: after.map(|leaf| *leaf.sha()),
+            old_mode: before.map(|leaf| leaf.mode().to_owned()),
+            new_mode: after.map(|leaf| leaf.mode().to_owned()),
+        });
+    }
+    entries
 }
 
+// How hard detect_renames looks
+#[derive(Debug, Clone, Copy)]
+pub struct RenameOptions {
+    // The similarity, in percent, a delete and an add need to be paired
+    pub threshold: u8,
+    // Similarity is only scored when there are at most limit * limit
+    // delete/add pairs, like git's diff.renameLimit
+    pub limit: usize,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        RenameOptions { threshold: 50, limit: 1000 }
+    }
+}
+
+// Turns deletes and adds in entries into renames: identical blobs first, then
+// the most similar of the remaining pairs that reach the threshold. The flag
+// says whether there were too many pairs to score, in which case only
+// identical blobs were paired.
+pub fn detect_renames(repo: &Repository, entries: Vec<DiffEntry>, options: &RenameOptions) -> Result<(Vec<DiffEntry>, bool), Box<WitError>> {
+    let empty = object::hash_raw(repo.object_format(), "blob", b"");
+    let candidate = |sha: &Option<ObjectId>, mode: &Option<String>| {
+        sha.is_some() && mode.as_deref().map(|mode| mode.starts_with("100") || mode == "120000").unwrap_or(false)
+    };
+    let deleted = entries.iter().enumerate()
+        .filter(|(_, entry)| entry.kind == ChangeKind::Deleted && candidate(&entry.old_sha, &entry.old_mode))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    let added = entries.iter().enumerate()
+        .filter(|(_, entry)| entry.kind == ChangeKind::Added && candidate(&entry.new_sha, &entry.new_mode))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
+    // (deleted, added, similarity)
+    let mut pairs = Vec::new();
+    let mut by_sha = HashMap::<ObjectId, Vec<usize>>::new();
+    for &i in deleted.iter().rev() {
+        by_sha.entry(entries[i].old_sha.unwrap_or(empty)).or_default().push(i);
+    }
+    for &i in &added {
+        if let Some(from) = by_sha.get_mut(&entries[i].new_sha.unwrap_or(empty)).and_then(|from| from.pop()) {
+            pairs.push((from, i, 100));
+        }
+    }
+
+    let paired = |pairs: &[(usize, usize, u8)]| -> (HashSet<usize>, HashSet<usize>) {
+        (pairs.iter().map(|pair| pair.0).collect(), pairs.iter().map(|pair| pair.1).collect())
+    };
+    // Empty files all look alike, so only an exact match pairs them
+    let (used_from, used_to) = paired(&pairs);
+    let sources = deleted.into_iter().filter(|i| !used_from.contains(i) && entries[*i].old_sha != Some(empty)).collect::<Vec<usize>>();
+    let targets = added.into_iter().filter(|i| !used_to.contains(i) && entries[*i].new_sha != Some(empty)).collect::<Vec<usize>>();
+    let limited = sources.len().saturating_mul(targets.len()) > options.limit.saturating_mul(options.limit);
+
+    if !limited && !sources.is_empty() && !targets.is_empty() {
+        let read = |sha: &Option<ObjectId>| -> Result<Vec<u8>, Box<WitError>> {
+            Ok(blob::read(repo, &sha.unwrap_or(empty))?.data().clone())
+        };
+        let source_data = sources.iter().map(|&i| read(&entries[i].old_sha)).collect::<Result<Vec<Vec<u8>>, Box<WitError>>>()?;
+        let target_data = targets.iter().map(|&i| read(&entries[i].new_sha)).collect::<Result<Vec<Vec<u8>>, Box<WitError>>>()?;
+
+        let mut scored = Vec::new();
+        for (s, old) in source_data.iter().enumerate() {
+            for (t, new) in target_data.iter().enumerate() {
+                // Only what the smaller side has can be shared, so skip
+                // pairs whose sizes alone rule them out
+                let (small, large) = (old.len().min(new.len()), old.len().max(new.len()));
+                if small * 100 < options.threshold as usize * large {
+                    continue;
+                }
+                let score = similarity(old, new);
+                if score >= options.threshold {
+                    scored.push((score, sources[s], targets[t]));
+                }
+            }
+        }
+        // Best first, then in path order so ties always go the same way
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        let (mut used_from, mut used_to) = paired(&pairs);
+        for (score, from, to) in scored {
+            if used_from.contains(&from) || used_to.contains(&to) {
+                continue;
+            }
+            used_from.insert(from);
+            used_to.insert(to);
+            pairs.push((from, to, score));
+        }
+    }
+
+    let mut renames = HashMap::new();
+    for (from, to, similarity) in pairs {
+        renames.insert(to, (from, similarity));
+    }
+    let sources = renames.values().map(|(from, _)| *from).collect::<HashSet<usize>>();
+    let mut result = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if sources.contains(&i) {
+            continue;
+        }
+        let mut entry = entry.clone();
+        if let Some(&(from, similarity)) = renames.get(&i) {
+            entry.kind = ChangeKind::Renamed { from: entries[from].path.clone(), similarity };
+            entry.old_sha = entries[from].old_sha;
+            entry.old_mode = entries[from].old_mode.clone();
+        }
+        result.push(entry);
+    }
+    Ok((result, limited))
+}
+
+// How much of the larger of a and b is made of lines the other has too, in
+// percent. Binary data is compared in fixed-size chunks instead of lines.
+fn similarity(a: &[u8], b: &[u8]) -> u8 {
+    const CHUNK: usize = 64;
+    let largest = a.len().max(b.len());
+    if largest == 0 {
+        return 100;
+    }
+    fn chunks(data: &[u8], binary: bool) -> Vec<&[u8]> {
+        if binary {
+            data.chunks(CHUNK).collect()
+        } else {
+            data.split_inclusive(|&byte| byte == b'\n').collect()
+        }
+    }
+    let binary = diff::is_binary(a) || diff::is_binary(b);
+
+    let mut counts = HashMap::<&[u8], usize>::new();
+    for chunk in chunks(a, binary) {
+        *counts.entry(chunk).or_default() += 1;
+    }
+    let mut shared = 0;
+    for chunk in chunks(b, binary) {
+        if let Some(count) = counts.get_mut(chunk).filter(|count| **count > 0) {
+            *count -= 1;
+            shared += chunk.len();
+        }
+    }
+    (shared * 100 / largest) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn changes(entries: &[DiffEntry]) -> Vec<(&str, ChangeKind)> {
+        entries.iter().map(|entry| (entry.path.to_str().unwrap(), entry.kind.clone())).collect()
+    }
+
+    #[test]
+    fn diff_lists_changes_by_path() {
+        let (_dir, repo) = test_util::repo();
+        let old = test_util::tree(&repo, &[("a", b"a\n"), ("dir/b", b"b\n"), ("dir/sub/c", b"c\n"), ("same", b"same\n")]);
+        let new = test_util::tree(&repo, &[("a", b"changed\n"), ("dir/sub/c", b"c\n"), ("dir/sub/d", b"d\n"), ("same", b"same\n")]);
+
+        let entries = diff(&repo, Some(&old), &new).unwrap();
+        assert_eq!(changes(&entries), vec![
+            ("a", ChangeKind::Modified),
+            ("dir/b", ChangeKind::Deleted),
+            ("dir/sub/d", ChangeKind::Added)
+        ]);
+        assert_eq!(entries[1].new_sha, None);
+        assert_eq!(entries[1].old_mode.as_deref(), Some("100644"));
+        assert_eq!(entries[2].old_sha, None);
+        assert!(diff(&repo, Some(&new), &new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_without_old_tree_adds_everything() {
+        let (_dir, repo) = test_util::repo();
+        let new = test_util::tree(&repo, &[("x/y", b"y\n"), ("z", b"z\n")]);
+        assert_eq!(changes(&diff(&repo, None, &new).unwrap()), vec![("x/y", ChangeKind::Added), ("z", ChangeKind::Added)]);
+    }
+
+    #[test]
+    fn renames_are_a_delete_and_an_add() {
+        let (_dir, repo) = test_util::repo();
+        let old = test_util::tree(&repo, &[("dir/old", b"moved\n"), ("kept", b"kept\n")]);
+        let new = test_util::tree(&repo, &[("kept", b"kept\n"), ("new", b"moved\n")]);
+
+        let entries = diff(&repo, Some(&old), &new).unwrap();
+        assert_eq!(changes(&entries), vec![("dir/old", ChangeKind::Deleted), ("new", ChangeKind::Added)]);
+        assert_eq!(entries[0].old_sha, entries[1].new_sha);
+    }
+
+    #[test]
+    fn a_file_and_a_directory_of_the_same_name() {
+        let (_dir, repo) = test_util::repo();
+        let file = test_util::tree(&repo, &[("a", b"a\n")]);
+        let dir = test_util::tree(&repo, &[("a/x", b"x\n")]);
+
+        let entries = diff(&repo, Some(&file), &dir).unwrap();
+        assert_eq!(changes(&entries), vec![("a", ChangeKind::Deleted), ("a/x", ChangeKind::Added)]);
+        assert_eq!(entries[0].old_mode.as_deref(), Some("100644"));
+        assert_eq!(changes(&diff(&repo, Some(&dir), &file).unwrap()), vec![("a", ChangeKind::Added), ("a/x", ChangeKind::Deleted)]);
+    }
+
+    #[test]
+    fn mode_only_changes() {
+        let (_dir, repo) = test_util::repo();
+        let old = flatten(&repo, &test_util::tree(&repo, &[("run", b"#!/bin/sh\n")])).unwrap();
+        let mut new = old.clone();
+        let leaf = new[Path::new("run")].clone();
+        new.insert(PathBuf::from("run"), Leaf::new("100755".to_owned(), PathBuf::from("run"), *leaf.sha()));
+
+        let entries = diff_leaves(&old, &new);
+        assert_eq!(changes(&entries), vec![("run", ChangeKind::ModeChanged)]);
+        assert_eq!(entries[0].new_mode.as_deref(), Some("100755"));
+    }
+
+    #[test]
+    fn trees_sort_like_git() {
+        let (_dir, repo) = test_util::repo();
+        // "a.b" sorts before the directory "a", which sorts as "a/"
+        let sha = test_util::tree(&repo, &[("a/x", b"x\n"), ("a.b", b"ab\n"), ("a0", b"a0\n")]);
+        let names = read(&repo, &sha).unwrap().leaves().iter().map(|leaf| leaf.path().to_str().unwrap().to_owned()).collect::<Vec<String>>();
+        assert_eq!(names, vec!["a.b", "a", "a0"]);
+    }
+
+    #[test]
+    fn unsafe_names_are_rejected() {
+        for name in ["a", ".gitignore", "..a", "git", "dir.git"] {
+            check_name(name).unwrap();
+        }
+        for name in ["", ".", "..", ".git", ".GIT", ".Git", "a/b", "a\0b"] {
+            assert!(check_name(name).is_err(), "{:?}", name);
+        }
+
+        let mut raw = b"100644 .git\0".to_vec();
+        raw.extend([0; 20]);
+        assert!(Tree::parse_one(&raw, 0, ObjectFormat::Sha1).is_err());
+        let (_dir, repo) = test_util::repo();
+        let evil = object::write_raw(&repo, "tree", &raw).unwrap();
+        let commit = test_util::commit(&repo, &evil.to_string(), &[], "evil");
+        assert!(crate::checkout::switch(&repo, &commit).is_err());
+        assert!(repo.gitdir().join("config").is_file());
+    }
+
+    #[test]
+    fn safe_dest_stays_inside_the_root() {
+        let (dir, _repo) = test_util::repo();
+        let root = dir.path();
+        assert_eq!(safe_dest(root, Path::new("a/b")).unwrap(), root.join("a/b"));
+        for path in ["../outside", "/etc/passwd", "a/../../b", "a/.git/hooks/pre-commit", ".GIT/config", "./a"] {
+            assert!(safe_dest(root, Path::new(path)).is_err(), "{}", path);
+        }
+
+        #[cfg(unix)]
+        {
+            let outside = test_util::TempDir::new();
+            std::os::unix::fs::symlink(outside.path(), root.join("link")).unwrap();
+            assert!(safe_dest(root, Path::new("link/file")).is_err());
+            assert!(safe_dest(root, Path::new("link/deeper/file")).is_err());
+            // The link itself is replaced, not followed
+            safe_dest(root, Path::new("link")).unwrap();
+        }
+    }
+
+    #[test]
+    fn renames_pair_identical_and_similar_files() {
+        let (_dir, repo) = test_util::repo();
+        let text = (1..=10).map(|i| format!("line {}\n", i)).collect::<String>();
+        let edited = text.replace("line 5\n", "line five\n");
+        let old = test_util::tree(&repo, &[("exact", b"same\n"), ("old.txt", text.as_bytes()), ("gone", b"unrelated\n"), ("empty", b"")]);
+        let new = test_util::tree(&repo, &[("moved/exact", b"same\n"), ("new.txt", edited.as_bytes()), ("other", b"nothing alike\n"), ("full", b"x\n")]);
+
+        let (entries, limited) = detect_renames(&repo, diff(&repo, Some(&old), &new).unwrap(), &RenameOptions::default()).unwrap();
+        assert!(!limited);
+        let kinds = changes(&entries);
+        assert!(kinds.contains(&("moved/exact", ChangeKind::Renamed { from: PathBuf::from("exact"), similarity: 100 })), "{:?}", kinds);
+        let Some((_, ChangeKind::Renamed { from, similarity })) = kinds.iter().find(|(path, _)| *path == "new.txt") else {
+            panic!("new.txt was not paired: {:?}", kinds);
+        };
+        assert_eq!(from, Path::new("old.txt"));
+        assert!((80..100).contains(similarity), "{}", similarity);
+        // Too different to pair, and an empty file is only ever an exact match
+        for (path, kind) in [("gone", ChangeKind::Deleted), ("other", ChangeKind::Added), ("empty", ChangeKind::Deleted), ("full", ChangeKind::Added)] {
+            assert!(kinds.contains(&(path, kind)), "{} {:?}", path, kinds);
+        }
+        let renamed = entries.iter().find(|entry| entry.path == Path::new("new.txt")).unwrap();
+        assert_eq!(renamed.old_sha, Some(object::hash_raw(ObjectFormat::Sha1, "blob", text.as_bytes())));
+        assert_eq!(renamed.old_mode.as_deref(), Some("100644"));
+    }
+
+    #[test]
+    fn rename_limit_keeps_exact_matches_only() {
+        let (_dir, repo) = test_util::repo();
+        let old = test_util::tree(&repo, &[("a", b"1\n2\n3\n4\n"), ("b", b"same\n")]);
+        let new = test_util::tree(&repo, &[("c", b"1\n2\n3\nfour\n"), ("d", b"same\n")]);
+        let options = RenameOptions { threshold: 50, limit: 0 };
+        let (entries, limited) = detect_renames(&repo, diff(&repo, Some(&old), &new).unwrap(), &options).unwrap();
+        assert!(limited);
+        assert_eq!(changes(&entries), vec![
+            ("a", ChangeKind::Deleted),
+            ("c", ChangeKind::Added),
+            ("d", ChangeKind::Renamed { from: PathBuf::from("b"), similarity: 100 })
+        ]);
+
+        let strict = RenameOptions { threshold: 90, ..RenameOptions::default() };
+        let (entries, _) = detect_renames(&repo, diff(&repo, Some(&old), &new).unwrap(), &strict).unwrap();
+        assert_eq!(entries.len(), 3);
+        // Six of the eleven bytes are shared
+        let (entries, _) = detect_renames(&repo, diff(&repo, Some(&old), &new).unwrap(), &RenameOptions::default()).unwrap();
+        assert_eq!(changes(&entries)[0], ("c", ChangeKind::Renamed { from: PathBuf::from("a"), similarity: 54 }));
+    }
+}
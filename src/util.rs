@@ -1,16 +1,203 @@
-pub fn hex(vec: &Vec<u8>) -> String {
-    let mut sha = 0;
-    let mut buf: [u8; 4] = [0, 0, 0, 0];
-    vec.iter().enumerate().for_each(|(i, x)| {
-        if i % 4 == 0 {
-            // https://stackoverflow.com/questions/36669427/does-rust-have-a-way-to-convert-several-bytes-to-a-number
-            sha +=
-            ((buf[0] as u32) << 24) +
-            ((buf[1] as u32) << 16) +
-            ((buf[2] as u32) <<  8) +
-            ((buf[3] as u32) <<  0);
+use std::fs;
+use std::path::Path;
+
+use crate::error::{WitError, builder::*};
+
+pub fn hex(vec: &[u8]) -> String {
+    let mut sha = String::with_capacity(vec.len() * 2);
+    for byte in vec {
+        sha.push_str(&format!("{:02x}", byte));
+    }
+    sha
+}
+
+// Either case is accepted; ObjectId prints lowercase, so that is what ends
+// up in paths whatever the input was
+pub fn unhex(sha: &str) -> Result<Vec<u8>, Box<WitError>> {
+    // from_str_radix alone would take "+f" as a digit pair
+    if !sha.len().is_multiple_of(2) || !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(malformed_object_err(format!("Invalid hex string {}", sha)));
+    }
+    let mut bytes = Vec::with_capacity(sha.len() / 2);
+    for i in (0..sha.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&sha[i..i + 2], 16)?);
+    }
+    Ok(bytes)
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
+
+// https://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Formats a utc offset in minutes as "+hhmm"
+pub fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("{}{:02}{:02}", sign, offset.abs() / 60, offset.abs() % 60)
+}
+
+// Minutes east of UTC that local time was at time, as the TZ variable or
+// /etc/localtime has it
+#[cfg(unix)]
+pub fn local_offset(time: i64) -> i32 {
+    let time = time as libc::time_t;
+    // SAFETY: an all-zero tm is valid, and localtime_r only writes to the one it is given
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i32
+}
+
+#[cfg(not(unix))]
+pub fn local_offset(_time: i64) -> i32 {
+    0
+}
+
+pub fn parse_offset(offset: &str) -> Result<i32, Box<WitError>> {
+    let bad_offset = || malformed_object_err(format!("Invalid timezone offset {}", offset));
+    if offset.len() != 5 || !offset.is_ascii() {
+        return Err(bad_offset());
+    }
+    let sign = match &offset[..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return Err(bad_offset())
+    };
+    let hours = offset[1..3].parse::<i32>()?;
+    let minutes = offset[3..5].parse::<i32>()?;
+    Ok(sign * (hours * 60 + minutes))
+}
+
+// RFC 2822 date, as used in mail headers: "Mon, 17 Sep 2001 00:00:00 +0000"
+pub fn format_rfc2822(time: i64, offset: i32) -> String {
+    let local = time + offset as i64 * 60;
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} {}",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60,
+        format_offset(offset)
+    )
+}
+
+// The iso date format git uses for blame: "2001-09-17 00:00:00 +0000"
+pub fn format_iso(time: i64, offset: i32) -> String {
+    let local = time + offset as i64 * 60;
+    let secs = local.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+        year, month, day, secs / 3600, (secs % 3600) / 60, secs % 60, format_offset(offset)
+    )
+}
+
+pub fn parse_rfc2822(date: &str) -> Result<(i64, i32), Box<WitError>> {
+    let bad_date = || malformed_object_err(format!("Invalid date {}", date));
+    // The weekday is optional
+    let date = match date.find(',') {
+        Some(idx) => &date[idx + 1..],
+        None => date
+    };
+    let parts = date.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() < 5 {
+        return Err(bad_date());
+    }
+    let day = parts[0].parse::<u32>()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(parts[1])).ok_or_else(bad_date)? as u32 + 1;
+    let year = parts[2].parse::<i64>()?;
+    let clock = parts[3].split(':').map(|p| p.parse::<i64>()).collect::<Result<Vec<i64>, _>>()?;
+    if clock.len() < 2 {
+        return Err(bad_date());
+    }
+    let offset = parse_offset(parts[4])?;
+
+    let local = days_from_civil(year, month, day) * 86400
+        + clock[0] * 3600
+        + clock[1] * 60
+        + clock.get(2).unwrap_or(&0);
+    Ok((local - offset as i64 * 60, offset))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Encodes as standard base64, with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let buf = chunk.iter().enumerate().fold(0u32, |buf, (i, b)| buf | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(buf >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
         }
-        buf[i % 4] = *x;
-    });
-    format!("{:x}", &sha)
-}
\ No newline at end of file
+    }
+    encoded
+}
+
+// Decodes standard base64, skipping padding and any invalid characters
+pub fn base64_decode(data: &str) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let Some(value) = BASE64.iter().position(|b| *b == c) else {
+            continue;
+        };
+        buf = (buf << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buf >> bits) as u8);
+        }
+    }
+    decoded
+}
+
+#[cfg(unix)]
+pub fn set_executable(path: &Path, executable: bool) -> Result<(), Box<WitError>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(if executable { 0o755 } else { 0o644 });
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_executable(_path: &Path, _executable: bool) -> Result<(), Box<WitError>> {
+    Ok(())
+}
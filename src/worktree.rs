@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    blob,
+    commit,
+    config::ConfigGet,
+    error::{WitError, builder::*},
+    index::{Index, IndexEntry},
+    reference,
+    repository::Repository,
+    tree,
+    util::set_executable
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorktreeInfo {
+    // None for the main worktree
+    pub name: Option<String>,
+    pub path: PathBuf,
+    // The commit checked out, if HEAD points at one
+    pub head: Option<String>,
+    // The branch checked out, if HEAD is symbolic
+    pub branch: Option<String>,
+}
+
+fn admin_dir(repo: &Repository, name: &str) -> PathBuf {
+    repo.commondir().join("worktrees").join(name)
+}
+
+// HEAD's commit and branch from a HEAD file
+fn read_head(repo: &Repository, head_path: &Path) -> (Option<String>, Option<String>) {
+    let Ok(head) = fs::read_to_string(head_path) else {
+        return (None, None);
+    };
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(target) => (
+            reference::resolve(repo, target).ok(),
+            target.strip_prefix("refs/heads/").map(|branch| branch.to_owned())
+        ),
+        None => (Some(head.to_owned()), None)
+    }
+}
+
+pub fn list(repo: &Repository) -> Result<Vec<WorktreeInfo>, Box<WitError>> {
+    let mut worktrees = Vec::new();
+
+    // The main worktree is the one the common git directory belongs to
+    let common = repo.commondir();
+    let bare = repo.conf.get_bool("core.bare")?.unwrap_or(false);
+    if !bare {
+        let (head, branch) = read_head(repo, &common.join("HEAD"));
+        worktrees.push(WorktreeInfo {
+            name: None,
+            path: common.parent().unwrap_or(common).to_path_buf(),
+            head,
+            branch
+        });
+    }
+
+    let dir = common.join("worktrees");
+    if dir.is_dir() {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_str().ok_or(path_conversion_err())?.to_owned();
+            let gitdir = fs::read_to_string(entry.path().join("gitdir")).unwrap_or_default();
+            let path = Path::new(gitdir.trim()).parent().map(Path::to_path_buf).unwrap_or_default();
+            let (head, branch) = read_head(repo, &entry.path().join("HEAD"));
+            worktrees.push(WorktreeInfo { name: Some(name), path, head, branch });
+        }
+    }
+    Ok(worktrees)
+}
+
+// Creates a linked worktree at path with branch checked out. The branch is
+// created at HEAD if it does not exist yet.
+pub fn add(repo: &Repository, path: &Path, branch: &str) -> Result<(), Box<WitError>> {
+    if path.exists() && (!path.is_dir() || path.read_dir()?.next().is_some()) {
+        Err(dir_not_empty_err(path))?
+    }
+    if list(repo)?.iter().any(|worktree| worktree.branch.as_deref() == Some(branch)) {
+        Err(repo_creation_err(format!("Branch {} is already checked out in another worktree", branch)))?
+    }
+
+    let branch_ref = format!("refs/heads/{}", branch);
+    let head = match reference::resolve(repo, &branch_ref) {
+        Ok(sha) => sha,
+        Err(_) => {
+            let sha = reference::resolve(repo, "HEAD")?;
+            reference::create(repo, format!("heads/{}", branch), sha.clone(), "branch: Created from HEAD")?;
+            sha
+        }
+    };
+
+    // Name the admin directory after the worktree, made unique if needed
+    let base_name = path.file_name().and_then(|name| name.to_str()).ok_or(path_conversion_err())?.to_owned();
+    let mut name = base_name.clone();
+    let mut n = 1;
+    while admin_dir(repo, &name).exists() {
+        name = format!("{}{}", base_name, n);
+        n += 1;
+    }
+
+    Repository::create_dir(repo, path)?;
+    let path = fs::canonicalize(path)?;
+    let admin = admin_dir(repo, &name);
+    Repository::create_dir(repo, &admin)?;
+    Repository::write_file(repo, &admin.join("HEAD"), format!("ref: {}\n", branch_ref).as_bytes())?;
+    Repository::write_file(repo, &admin.join("commondir"), b"../..\n")?;
+    Repository::write_file(repo, &admin.join("gitdir"), format!("{}\n", path.join(".git").display()).as_bytes())?;
+    fs::write(path.join(".git"), format!("gitdir: {}\n", admin.display()))?;
+
+    let worktree = Repository::open(&admin, Some(&path))?;
+    checkout(&worktree, &head)
+}
+
+// Writes the commit's files into an empty worktree and indexes them
+fn checkout(repo: &Repository, commit_sha: &str) -> Result<(), Box<WitError>> {
+    let commit = commit::read(repo, commit_sha)?;
+    let tree_sha = commit.tree().ok_or(malformed_object_err(format!("Commit {} has no tree", commit_sha)))?;
+    let root = repo.worktree()?.to_path_buf();
+    let mut index = Index::new();
+
+    for (path, leaf) in tree::flatten(repo, tree_sha)? {
+        let dest = tree::safe_dest(&root, &path)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match leaf.mode() {
+            // Submodules just get an empty directory
+            "160000" => {
+                fs::create_dir_all(&dest)?;
+                continue;
+            },
+            #[cfg(unix)]
+            "120000" => std::os::unix::fs::symlink(String::from_utf8(blob::read(repo, leaf.sha())?.data().clone())?, &dest)?,
+            mode => {
+                fs::write(&dest, blob::read(repo, leaf.sha())?.data())?;
+                set_executable(&dest, mode == "100755")?;
+            }
+        }
+        let name = path.to_str().ok_or(path_conversion_err())?;
+        index.add(IndexEntry::create(repo, name, leaf.sha(), leaf.mode())?);
+    }
+    index.write(repo)
+}
+
+// Deletes a linked worktree's files and its admin directory
+pub fn remove(repo: &Repository, name: &str) -> Result<(), Box<WitError>> {
+    let admin = admin_dir(repo, name);
+    if !admin.is_dir() {
+        Err(repo_not_found_err(format!("{} is not a worktree", name)))?
+    }
+    if repo.gitdir() == admin.as_path() {
+        Err(repo_creation_err(format!("Cannot remove worktree {} from inside it", name)))?
+    }
+
+    let gitdir = fs::read_to_string(admin.join("gitdir"))?;
+    if let Some(path) = Path::new(gitdir.trim()).parent() {
+        if path.exists() {
+            // Only remove what still looks like our worktree
+            let dot_git = fs::read_to_string(path.join(".git")).unwrap_or_default();
+            if dot_git.trim().strip_prefix("gitdir: ").map(Path::new) != Some(admin.as_path()) {
+                Err(repo_creation_err(format!("{} does not belong to worktree {}", path.display(), name)))?
+            }
+            fs::remove_dir_all(path)?;
+        }
+    }
+    fs::remove_dir_all(&admin)?;
+
+    let worktrees = repo.commondir().join("worktrees");
+    if worktrees.read_dir()?.next().is_none() {
+        fs::remove_dir(worktrees)?;
+    }
+    Ok(())
+}
+